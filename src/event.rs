@@ -1,6 +1,146 @@
+use crate::*;
+
+/// Treasury events of the `treasury` standard, see NEP-297.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+enum TreasuryEvent<'a> {
+    Decision(&'a [DecisionData<'a>]),
+    SwapExecuted(&'a [SwapData<'a>]),
+    SwapFailed(&'a [SwapFailedData<'a>]),
+    AddLiquidityFailed(&'a [AddLiquidityFailedData<'a>]),
+    FillRejected(&'a [FillRejectedData<'a>]),
+    LiquidityWithdrawn(&'a [LiquidityWithdrawnData<'a>]),
+    TradeIntent(&'a [TradeIntentData<'a>]),
+    AuditResult(&'a [AuditResultData]),
+    RebalanceGasUsed(&'a [RebalanceGasUsedData<'a>]),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct DecisionData<'a> {
+    pool_id: u64,
+    action: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount_usd: Option<f64>,
+    executed: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct SwapData<'a> {
+    pool_id: u64,
+    token_out: &'a AccountId,
+    amount_out: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct SwapFailedData<'a> {
+    pool_id: u64,
+    token_out: &'a AccountId,
+    min_amount_out: U128,
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct AddLiquidityFailedData<'a> {
+    pool_id: u64,
+    token_id: &'a AccountId,
+    amount: U128,
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct FillRejectedData<'a> {
+    pool_id: u64,
+    token_out: &'a AccountId,
+    amount_out: U128,
+    min_fill: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct LiquidityWithdrawnData<'a> {
+    pool_id: u64,
+    shares: U128,
+    token_ids: &'a [AccountId],
+    amounts: &'a [U128],
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct TradeIntentData<'a> {
+    pool_id: u64,
+    action: &'a str,
+    amount_usd: f64,
+    token_in: &'a AccountId,
+    amount_in: U128,
+    token_out: &'a AccountId,
+    min_amount_out: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct AuditResultData {
+    pool_id: u64,
+    value_usd: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_usd: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drift_usd: Option<f64>,
+    flagged: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct RebalanceGasUsedData<'a> {
+    pool_id: u64,
+    used_gas: Gas,
+    checkpoints: &'a [GasCheckpointData<'a>],
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct GasCheckpointData<'a> {
+    checkpoint: &'a str,
+    used_gas: Gas,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventEnvelope<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: TreasuryEvent<'a>,
+}
+
+impl TreasuryEvent<'_> {
+    fn emit(self) {
+        let envelope = EventEnvelope {
+            standard: "treasury",
+            version: "1.0.0",
+            event: self,
+        };
+        env::log_str(&format!(
+            "EVENT_JSON:{}",
+            near_sdk::serde_json::to_string(&envelope).unwrap()
+        ));
+    }
+}
+
 pub mod emit {
     use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
 
+    use super::{
+        AddLiquidityFailedData, AuditResultData, DecisionData, FillRejectedData, GasCheckpointData,
+        LiquidityWithdrawnData, RebalanceGasUsedData, SwapData, SwapFailedData, TradeIntentData,
+        TreasuryEvent,
+    };
     use crate::*;
 
     pub fn ft_mint(owner_id: &AccountId, amount: Balance, memo: Option<&str>) {
@@ -20,4 +160,190 @@ pub mod emit {
         })
         .emit();
     }
+
+    /// `action` is one of `buy`, `sell` or `do_nothing`.
+    pub fn treasury_decision(pool_id: u64, action: &str, amount_usd: Option<f64>, executed: bool) {
+        TreasuryEvent::Decision(&[DecisionData {
+            pool_id,
+            action,
+            amount_usd,
+            executed,
+        }])
+        .emit();
+    }
+
+    pub fn treasury_swap(pool_id: u64, token_out: &AccountId, amount_out: Balance) {
+        TreasuryEvent::SwapExecuted(&[SwapData {
+            pool_id,
+            token_out,
+            amount_out: amount_out.into(),
+        }])
+        .emit();
+    }
+
+    /// Failed promises carry no error message, `reason` tells the kind of the failure.
+    pub fn treasury_swap_failed(
+        pool_id: u64,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+        reason: &str,
+    ) {
+        TreasuryEvent::SwapFailed(&[SwapFailedData {
+            pool_id,
+            token_out,
+            min_amount_out: min_amount_out.into(),
+            reason,
+        }])
+        .emit();
+    }
+
+    /// The swap output is withdrawn to the treasury instead of being used.
+    pub fn treasury_fill_rejected(
+        pool_id: u64,
+        token_out: &AccountId,
+        amount_out: Balance,
+        min_fill: Balance,
+    ) {
+        TreasuryEvent::FillRejected(&[FillRejectedData {
+            pool_id,
+            token_out,
+            amount_out: amount_out.into(),
+            min_fill: min_fill.into(),
+        }])
+        .emit();
+    }
+
+    /// `amount` of `token_id` is left in the ref-finance deposit.
+    pub fn treasury_add_liquidity_failed(
+        pool_id: u64,
+        token_id: &AccountId,
+        amount: Balance,
+        reason: &str,
+    ) {
+        TreasuryEvent::AddLiquidityFailed(&[AddLiquidityFailedData {
+            pool_id,
+            token_id,
+            amount: amount.into(),
+            reason,
+        }])
+        .emit();
+    }
+
+    /// Emitted before the promises of the trade are scheduled, `action` is `buy` or `sell`.
+    pub fn treasury_trade_intent(
+        pool_id: u64,
+        action: &str,
+        amount_usd: f64,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+        min_amount_out: Balance,
+    ) {
+        TreasuryEvent::TradeIntent(&[TradeIntentData {
+            pool_id,
+            action,
+            amount_usd,
+            token_in,
+            amount_in: amount_in.into(),
+            token_out,
+            min_amount_out: min_amount_out.into(),
+        }])
+        .emit();
+    }
+
+    /// The first audit of a pool has nothing to expect, `flagged` is set if the position
+    /// drifted from the expected value without recorded trades.
+    pub fn treasury_audit_result(
+        pool_id: u64,
+        value_usd: f64,
+        expected_usd: Option<f64>,
+        flagged: bool,
+    ) {
+        TreasuryEvent::AuditResult(&[AuditResultData {
+            pool_id,
+            value_usd,
+            expected_usd,
+            drift_usd: expected_usd.map(|expected_usd| value_usd - expected_usd),
+            flagged,
+        }])
+        .emit();
+    }
+
+    /// Gas used by the callbacks of the finished balancing, `used_gas` is their sum.
+    pub fn treasury_rebalance_gas_used(pool_id: u64, checkpoints: &[(&str, Gas)]) {
+        let checkpoints: Vec<GasCheckpointData> = checkpoints
+            .iter()
+            .map(|(checkpoint, used_gas)| GasCheckpointData {
+                checkpoint,
+                used_gas: *used_gas,
+            })
+            .collect();
+        TreasuryEvent::RebalanceGasUsed(&[RebalanceGasUsedData {
+            pool_id,
+            used_gas: Gas(checkpoints.iter().map(|data| data.used_gas.0).sum()),
+            checkpoints: &checkpoints,
+        }])
+        .emit();
+    }
+
+    /// The whole treasury position of the pool is removed and withdrawn without trading.
+    pub fn treasury_liquidity_withdrawn(
+        pool_id: u64,
+        shares: Balance,
+        token_ids: &[AccountId],
+        amounts: &[U128],
+    ) {
+        TreasuryEvent::LiquidityWithdrawn(&[LiquidityWithdrawnData {
+            pool_id,
+            shares: shares.into(),
+            token_ids,
+            amounts,
+        }])
+        .emit();
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::get_logs;
+
+    use super::*;
+
+    #[test]
+    fn test_treasury_decision_event() {
+        emit::treasury_decision(0, "sell", Some(23604.5), true);
+        emit::treasury_decision(1, "do_nothing", None, false);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"decision","data":[{"pool_id":0,"action":"sell","amount_usd":23604.5,"executed":true}]}"#,
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"decision","data":[{"pool_id":1,"action":"do_nothing","executed":false}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_treasury_swap_event() {
+        emit::treasury_swap(0, &"wrap.test.near".parse().unwrap(), 1000);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"swap_executed","data":[{"pool_id":0,"token_out":"wrap.test.near","amount_out":"1000"}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_treasury_swap_failed_event() {
+        emit::treasury_swap_failed(0, &"wrap.test.near".parse().unwrap(), 1000, "failed");
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"swap_failed","data":[{"pool_id":0,"token_out":"wrap.test.near","min_amount_out":"1000","reason":"failed"}]}"#,
+            ]
+        );
+    }
 }
@@ -24,7 +24,7 @@ use std::fmt::Debug;
 
 use crate::ft::FungibleTokenFreeStorage;
 use oracle::{ExchangeRate, Oracle, PriceData};
-use treasury::TreasuryData;
+use treasury::{TreasuryData, TreasuryDataV1};
 
 uint::construct_uint!(
     pub struct U256(4);
@@ -48,6 +48,7 @@ enum StorageKey {
     TokenMetadata,
     Blacklist,
     TreasuryData,
+    CollateralHistory,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
@@ -636,14 +637,19 @@ impl Contract {
         self.treasury.get().expect("Valid treasury")
     }
 
-    /// This is NOOP implementation. KEEP IT if you haven't changed contract state.
     /// Should only be called by this contract on migration.
     /// This method is called from `upgrade()` method.
     /// For next version upgrades, change this function.
+    ///
+    /// Migrates the treasury data adding the default treasury configuration.
     #[init(ignore_state)]
     #[private]
     pub fn migrate() -> Self {
-        let contract: Self = env::state_read().expect("Contract is not initialized");
+        let mut contract: Self = env::state_read().expect("Contract is not initialized");
+        let legacy: LazyOption<TreasuryDataV1> = LazyOption::new(StorageKey::TreasuryData, None);
+        if let Some(treasury) = legacy.get() {
+            contract.treasury.set(&treasury.into());
+        }
         contract
     }
 
@@ -60,9 +60,25 @@ impl Default for Oracle {
 }
 
 impl Oracle {
+    pub fn gas() -> Gas {
+        CONFIG.gas
+    }
+
+    /// Oracle asset id of the NEAR/USD price.
+    pub fn asset_id() -> &'static str {
+        CONFIG.asset_id
+    }
+
     pub fn get_exchange_rate_promise() -> Promise {
+        Self::get_price_data_promise(Vec::new())
+    }
+
+    /// Requests prices of `asset_ids` along with the NEAR/USD price.
+    pub fn get_price_data_promise(asset_ids: Vec<String>) -> Promise {
+        let mut all_asset_ids = vec![CONFIG.asset_id.to_string()];
+        all_asset_ids.extend(asset_ids);
         ext_priceoracle::get_price_data(
-            vec![CONFIG.asset_id.into()],
+            all_asset_ids,
             CONFIG.oracle_address.parse().unwrap(),
             0,
             CONFIG.gas,
@@ -74,7 +90,7 @@ impl From<PriceData> for ExchangeRate {
     fn from(price_data: PriceData) -> Self {
         let price = price_data.price(&CONFIG.asset_id.into());
 
-        if env::block_timestamp() >= price_data.timestamp() + price_data.recency_duration() {
+        if price_data.is_outdated() {
             env::panic_str("Oracle provided an outdated price data");
         }
 
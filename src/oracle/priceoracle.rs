@@ -42,6 +42,18 @@ impl PriceData {
         Timestamp::from(self.recency_duration_sec) * 10u64.pow(9)
     }
 
+    pub fn is_outdated(&self) -> bool {
+        env::block_timestamp() >= self.timestamp() + self.recency_duration()
+    }
+
+    /// Returns the price of the asset if the oracle has provided it.
+    pub fn find_price(&self, asset: &AssetId) -> Option<Price> {
+        self.prices
+            .iter()
+            .find(|aop| &aop.asset_id == asset)
+            .and_then(|aop| aop.price)
+    }
+
     pub fn price(&self, asset: &AssetId) -> Price {
         let asset_error = format!("Oracle has NOT provided an exchange rate for {}", asset);
         self.prices
@@ -4,11 +4,13 @@ use crate::*;
 
 use super::ft::REF_DEPOSIT_ACTION;
 use super::gas::*;
-use super::pool::{extend_decimals, remove_decimals, Pool};
+use super::pool::{extend_decimals, min_shares, remove_decimals, Pool, MIN_SHARES_TOLERANCE_BPS};
 use super::ref_finance::*;
 
 use near_sdk::require;
 
+/// Tolerance of LP shares received for added USN, in basis points.
+
 #[near_bindgen]
 impl Contract {
     #[payable]
@@ -22,6 +24,7 @@ impl Contract {
         );
 
         let pool = Pool::from_config_with_assert(pool_id);
+        self.internal_reset_reserve_snapshot(pool.id);
 
         ext_ref_finance::get_stable_pool(pool.id, pool.ref_id.clone(), 0, GAS_FOR_GET_DEPOSITS)
             .and(ext_ref_finance::get_deposits(
@@ -81,7 +84,7 @@ impl RefFinanceHandler for Contract {
         let normalized_amounts =
             pool.decimals
                 .iter()
-                .zip(info.amounts)
+                .zip(info.amounts.clone())
                 .map(move |(&decimals, amount)| {
                     if decimals < USN_DECIMALS {
                         extend_decimals(amount.into(), USN_DECIMALS - decimals)
@@ -168,7 +171,7 @@ impl RefFinanceHandler for Contract {
                 })
                 .collect::<Vec<U128>>();
 
-            let min_shares = NO_DEPOSIT.into();
+            let min_shares = min_shares(&info, &liquidity_amounts, MIN_SHARES_TOLERANCE_BPS);
 
             // Add liquidity.
             let add_liquidity = ext_ref_finance::add_stable_liquidity(
@@ -1,43 +1,49 @@
+use std::collections::HashMap;
+
 use easy_ml::matrices::Matrix;
-use near_sdk::{require, ONE_NEAR, ONE_YOCTO};
+use near_contract_standards::storage_management::StorageBalance;
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::{
+    require, BlockHeight, CryptoHash, PromiseError, PromiseResult, Timestamp, ONE_NEAR, ONE_YOCTO,
+};
 use partial_min_max::{max, min};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use crate::*;
 
+use super::cache::{CacheRepairReport, IntervalCache, MAX_CACHE_SIZE};
+use super::config::{
+    CounterPriceSource, DecisionParams, DecisionPriority, Denomination, ExcessLimitPolicy,
+    LimitUnit, LogVerbosity, OutOfOrderPricePolicy, RatePrecedence, RateSource,
+    ReferenceAggregation, SplitTarget, SwapConfig, TradeSizeBounds, TreasuryConfig,
+};
+use super::execution::{ExecutionAdapter, TradeOrder};
 use super::ft::{ext_ft, REF_DEPOSIT_ACTION};
 use super::gas::*;
-use super::pool::{Pool, USDT_DECIMALS};
+use super::pool::{counter_tokens, min_shares, token_decimals, Pool, MIN_SHARES_TOLERANCE_BPS};
 use super::ref_finance::*;
+use super::treasury::{ReserveSnapshot, TreasuryFlows, TreasuryStats};
 
 const NEAR_DECIMALS: u8 = 24;
 
-// 50% slippage: minimizing chance to get failed but not too much.
-const SWAP_SLIPPAGE: f64 = 0.5;
+const MAX_WARMUP_RETRIES: u8 = 3;
 
-struct TreasuryConfig {
-    pub wrap_id: &'static str,
-    pub swap_pool_id: u64,
-}
+/// Circulating USN below this amount isn't worth balancing, a decision would divide by ~0.
+const MIN_CIRCULATING_USN: f64 = 1_000.;
 
-const CONFIG: TreasuryConfig = if cfg!(feature = "mainnet") {
-    TreasuryConfig {
-        wrap_id: "wrap.near",
-        swap_pool_id: 4,
-    }
-} else if cfg!(feature = "testnet") {
-    TreasuryConfig {
-        wrap_id: "wrap.testnet",
-        swap_pool_id: 34,
-    }
-} else {
-    TreasuryConfig {
-        wrap_id: "wrap.test.near",
-        swap_pool_id: 3,
-    }
-};
+/// Swap fees of ref-finance pools are in basis points.
+const FEE_DIVISOR: u32 = 10_000;
 
-#[derive(BorshDeserialize, BorshSerialize, Debug, Serialize, PartialEq, Deserialize)]
+/// USN is minted and burned at $1 by the contract, the rate until a pool is observed.
+const USN_USD_RATE: f64 = 1.;
+
+/// A quadratic trend needs at least 3 distinct time points.
+const MIN_DISTINCT_TIME_POINTS: usize = 3;
+
+/// Minimum span of the time points in time units, closer samples make the fit unstable.
+const MIN_TIME_SPAN: f64 = 0.01;
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Serialize, PartialEq, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub enum TreasuryDecision {
     Buy(f64),
@@ -59,573 +65,8583 @@ impl std::fmt::Display for TreasuryDecision {
     }
 }
 
+impl TreasuryDecision {
+    /// The same decision with the amount rounded to `decimals`, if they are given.
+    pub fn rounded(&self, decimals: Option<u8>) -> TreasuryDecision {
+        let round = |amount: f64| match decimals {
+            Some(decimals) => {
+                let scale = 10f64.powi(decimals.into());
+                (amount * scale).round() / scale
+            }
+            None => amount,
+        };
+        match self {
+            TreasuryDecision::Buy(amount) => TreasuryDecision::Buy(round(*amount)),
+            TreasuryDecision::Sell(amount) => TreasuryDecision::Sell(round(*amount)),
+            TreasuryDecision::DoNothing => TreasuryDecision::DoNothing,
+        }
+    }
+
+    /// The same decision with the USD amount converted into the given units,
+    /// `usn_usd_rate` is the USD price of 1 USN.
+    pub fn in_denomination(
+        &self,
+        denomination: Denomination,
+        usn_usd_rate: f64,
+    ) -> TreasuryDecision {
+        match (denomination, self) {
+            (Denomination::Usn, TreasuryDecision::Buy(amount)) => {
+                TreasuryDecision::Buy(usd_to_usn(*amount, usn_usd_rate))
+            }
+            (Denomination::Usn, TreasuryDecision::Sell(amount)) => {
+                TreasuryDecision::Sell(usd_to_usn(*amount, usn_usd_rate))
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// The decision returned and logged: in the configured units and rounded.
+    pub fn reported(&self, config: &TreasuryConfig, usn_usd_rate: f64) -> TreasuryDecision {
+        self.in_denomination(config.denomination, usn_usd_rate)
+            .rounded(config.decision_decimals)
+    }
+
+    /// Describes the decision which amount is in the given units.
+    pub fn describe(&self, denomination: Denomination) -> String {
+        match (denomination, self) {
+            (Denomination::Usn, TreasuryDecision::Buy(amount)) => {
+                format!("Treasury decision is to buy USDT for {} USN", amount)
+            }
+            (Denomination::Usn, TreasuryDecision::Sell(amount)) => {
+                format!("Treasury decision is to sell USDT for {} USN", amount)
+            }
+            _ => format!("{}", self),
+        }
+    }
+}
+
+fn usd_to_usn(amount: f64, usn_usd_rate: f64) -> f64 {
+    amount / usn_usd_rate
+}
+
+/// USD price of 1 USN: its price in the counter-token by the stable pool ratio
+/// valued at the oracle price of the counter-token, if there is one.
+fn usn_usd_rate(
+    pool: &Pool,
+    info: &StablePoolInfo,
+    counter_idx: usize,
+    oracle_prices: &HashMap<AccountId, f64>,
+) -> f64 {
+    let usn_id = env::current_account_id();
+    let usn_amount = pool
+        .tokens
+        .iter()
+        .position(|token_id| token_id == &usn_id)
+        .map_or(0, |idx| info.c_amounts[idx].0);
+    let counter_amount = info.c_amounts[counter_idx].0;
+    if usn_amount == 0 || counter_amount == 0 {
+        // Nothing to compare against, assume the peg.
+        return USN_USD_RATE;
+    }
+    let counter_usd = oracle_prices
+        .get(&pool.tokens[counter_idx])
+        .copied()
+        .unwrap_or(1.);
+    counter_amount as f64 / usn_amount as f64 * counter_usd
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct InFlightBalance {
+    pub pool_id: u64,
+    pub started_at: BlockHeight,
+}
+
+/// Outcome of a pool balanced by `balance_treasuries`, the decision or the error.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct PerPoolResult {
+    pub pool_id: u64,
+    pub decision: Option<TreasuryDecision>,
+    pub error: Option<String>,
+}
+
+/// Least outputs of a swap: the swap fails below `min_amount_out`
+/// and its fill is rejected below `min_fill`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapBounds {
+    pub min_amount_out: U128,
+    pub min_fill: U128,
+}
+
+/// Decision made on the state identified by `state_hash`, see `decision_state_hash`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct LastDecision {
+    pub state_hash: CryptoHash,
+    pub decision: TreasuryDecision,
+}
+
+/// Share of a split decision to be traded in another pool.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingLeg {
+    pub decision: TreasuryDecision,
+}
+
+/// Limit of the traded amount picked by `balance_treasury`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct DecisionLimit {
+    pub amount: u64,
+    pub unit: LimitUnit,
+}
+
 #[near_bindgen]
 impl Contract {
+    /// Balances a single pool, see `balance_treasuries` for several pools.
+    /// `limits` bound the traded amount in whole `limit_unit`s, USD by default,
+    /// e.g. `[1000, 3000000]`. The decision converts them into USD at the latest rate.
+    /// They must fall within the configured limit bounds, if any, and default to them.
+    /// The traded amount is picked randomly within `limits`, `entropy` is mixed
+    /// into the block random seed. Nothing is executed in the shadow mode.
+    /// Execution is aborted unless the reference rate of the cache is within
+    /// `rate_tolerance`, a fraction, of `expected_rate`, if it's given.
+    /// Executed split decisions take 3 more yoctoNEAR per split leg.
     #[payable]
+    #[allow(clippy::too_many_arguments)]
     pub fn balance_treasury(
         &mut self,
         pool_id: u64,
         limits: Option<[u64; 2]>,
         execute: Option<bool>,
+        entropy: Option<Base64VecU8>,
+        expected_rate: Option<f64>,
+        rate_tolerance: Option<f64>,
+        limit_unit: Option<LimitUnit>,
     ) -> Promise {
-        self.assert_owner_or_guardian();
+        self.assert_owner_or_guardian_or_relayer();
+
+        let treasury = self.treasury.get().expect("Valid treasury");
+        let pool = Pool::from_config_with_assert(pool_id);
+        let execute = execute.unwrap_or(false) && !treasury.config.shadow_mode;
 
-        // Buy case: 2 yoctoNEAR, sell case: 3 yoctoNEAR.
+        // Buy case: 2 yoctoNEAR, sell case: 3 yoctoNEAR, the same for every split leg,
+        // the unused ones are refunded.
+        let paid_legs = if execute {
+            split_leg_count(&treasury.config.split_targets, pool.id)
+        } else {
+            0
+        };
+        let deposit = 3 * ONE_YOCTO * (1 + paid_legs) as Balance;
         require!(
-            env::attached_deposit() == 3 * ONE_YOCTO,
-            "3 yoctoNEAR of attached deposit is required"
+            env::attached_deposit() == deposit,
+            &format!("{} yoctoNEAR of attached deposit is required", deposit)
+        );
+
+        let exchange_rates = match treasury
+            .cache
+            .collect(env::block_timestamp(), treasury.config.decision.time_unit())
+        {
+            Ok((_, exchange_rates)) => exchange_rates,
+            Err(err) => env::panic_str(&err.to_string()),
+        };
+
+        let decision_limit = limit_in_unit(
+            &treasury.config,
+            limits,
+            limit_unit.unwrap_or_default(),
+            *exchange_rates.last().unwrap(),
+            limit_seed(entropy.as_ref()),
+        );
+        self.assert_plausible_decision_limit(
+            &treasury,
+            decision_limit,
+            *exchange_rates.last().unwrap(),
+        );
+
+        if execute {
+            assert_expected_rate(
+                reference_rate(
+                    &exchange_rates,
+                    treasury.config.decision.reference_window,
+                    treasury.config.decision.reference_aggregation,
+                ),
+                expected_rate,
+                rate_tolerance,
+            );
+            require!(
+                !treasury.in_flight.contains_key(&pool.id),
+                &format!("Balancing of the pool {} is in progress", pool.id)
+            );
+            assert_trade_interval(&treasury);
+            assert_burn_gas_fits(&treasury.config);
+        }
+
+        // Split legs are traded in the same transaction as far as the prepaid gas
+        // allows, the surplus covers the callback before it counts the legs.
+        let burn_gas = treasury.config.burn_gas();
+        let legs = if execute {
+            std::cmp::min(
+                paid_legs,
+                max_split_legs(gas_for_start_balancing(burn_gas) + GAS_SURPLUS, burn_gas),
+            )
+        } else {
+            0
+        };
+        let legs_gas = if legs > 0 {
+            GAS_SURPLUS + gas_for_leg(burn_gas) * legs
+        } else {
+            Gas(0)
+        };
+
+        start_balancing(
+            &treasury,
+            pool,
+            decision_limit,
+            execute,
+            env::attached_deposit(),
+            gas_for_start_balancing(burn_gas) + legs_gas,
+        )
+    }
+
+    /// Balances several pools with the same arguments as `balance_treasury`.
+    /// Every pool is balanced by its own branch of promises, so a failed pool
+    /// doesn't fail the others, and the result lists the outcome of each pool.
+    /// A pool which swap has failed is reported as failed.
+    /// 3 yoctoNEAR of attached deposit are required per pool. Split legs aren't
+    /// traded in the batch, they wait for `execute_treasury_leg`. An executed sell
+    /// takes most of the 300 TGas transaction limit, the batch fails upfront
+    /// unless all branches fit into the prepaid gas.
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn balance_treasuries(
+        &mut self,
+        pool_ids: Vec<u64>,
+        limits: Option<[u64; 2]>,
+        execute: Option<bool>,
+        entropy: Option<Base64VecU8>,
+        expected_rate: Option<f64>,
+        rate_tolerance: Option<f64>,
+        limit_unit: Option<LimitUnit>,
+    ) -> Promise {
+        self.assert_owner_or_guardian_or_relayer();
+
+        require!(!pool_ids.is_empty(), "No pools to balance");
+        let mut unique_ids = pool_ids.clone();
+        unique_ids.sort_unstable();
+        unique_ids.dedup();
+        require!(
+            unique_ids.len() == pool_ids.len(),
+            "Pools to balance must be unique"
+        );
+
+        require!(
+            env::attached_deposit() == 3 * ONE_YOCTO * pool_ids.len() as Balance,
+            &format!(
+                "{} yoctoNEAR of attached deposit is required",
+                3 * pool_ids.len()
+            )
+        );
+
+        let treasury = self.treasury.get().expect("Valid treasury");
+
+        let exchange_rates = match treasury
+            .cache
+            .collect(env::block_timestamp(), treasury.config.decision.time_unit())
+        {
+            Ok((_, exchange_rates)) => exchange_rates,
+            Err(err) => env::panic_str(&err.to_string()),
+        };
+
+        let decision_limit = limit_in_unit(
+            &treasury.config,
+            limits,
+            limit_unit.unwrap_or_default(),
+            *exchange_rates.last().unwrap(),
+            limit_seed(entropy.as_ref()),
+        );
+        self.assert_plausible_decision_limit(
+            &treasury,
+            decision_limit,
+            *exchange_rates.last().unwrap(),
         );
 
-        let decision_limit = if let Some(range) = limits {
-            let min = range[0];
-            let max = range[1];
+        let pools: Vec<Pool> = pool_ids
+            .iter()
+            .map(|pool_id| Pool::from_config_with_assert(*pool_id))
+            .collect();
+
+        let execute = execute.unwrap_or(false) && !treasury.config.shadow_mode;
 
-            require!(min <= max, "`limits` must be in [min; max] format");
+        if execute {
+            assert_expected_rate(
+                reference_rate(
+                    &exchange_rates,
+                    treasury.config.decision.reference_window,
+                    treasury.config.decision.reference_aggregation,
+                ),
+                expected_rate,
+                rate_tolerance,
+            );
+            for pool in pools.iter() {
+                require!(
+                    !treasury.in_flight.contains_key(&pool.id),
+                    &format!("Balancing of the pool {} is in progress", pool.id)
+                );
+            }
+            assert_trade_interval(&treasury);
+            assert_burn_gas_fits(&treasury.config);
+        }
 
-            let mut rng = StdRng::from_seed(env::random_seed_array());
-            Some(rng.gen_range(min..max))
+        let callback_gas = if execute {
+            gas_for_start_balancing(treasury.config.burn_gas())
         } else {
-            None
+            GAS_FOR_PREVIEW_BALANCING
         };
+        let batch_gas = (gas_for_queries(&treasury.config) + callback_gas) * pool_ids.len() as u64
+            + GAS_FOR_HANDLE_BALANCE_BATCH;
+        let available = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+        require!(
+            available >= batch_gas.0 + GAS_SURPLUS.0,
+            &format!(
+                "{} pools need {} TGas of prepaid gas",
+                pool_ids.len(),
+                (batch_gas + GAS_SURPLUS).0 / Gas::ONE_TERA.0
+            )
+        );
+
+        let branches = pools
+            .into_iter()
+            .map(|pool| {
+                start_balancing(
+                    &treasury,
+                    pool,
+                    decision_limit,
+                    execute,
+                    3 * ONE_YOCTO,
+                    callback_gas,
+                )
+            })
+            .reduce(|branches, branch| branches.and(branch))
+            .unwrap();
 
+        branches.then(ext_self::handle_balance_treasuries(
+            pool_ids,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_BALANCE_BATCH,
+        ))
+    }
+
+    /// Returns pools which balancing hasn't been finished yet.
+    pub fn get_in_flight_balances(&self) -> Vec<InFlightBalance> {
+        let treasury = self.treasury.get().expect("Valid treasury");
+        let mut balances: Vec<InFlightBalance> = treasury
+            .in_flight
+            .into_iter()
+            .map(|(pool_id, started_at)| InFlightBalance {
+                pool_id,
+                started_at,
+            })
+            .collect();
+        balances.sort_by_key(|balance| balance.pool_id);
+        balances
+    }
+
+    pub fn get_treasury_stats(&self) -> TreasuryStats {
+        self.treasury.get().expect("Valid treasury").stats
+    }
+
+    /// Returns the approximate realized PnL of buys and sells valued at the latest
+    /// cached exchange rate.
+    pub fn get_treasury_pnl(&self) -> TreasuryPnl {
         let treasury = self.treasury.get().expect("Valid treasury");
-        if let Err(_) = treasury.cache.collect(env::block_timestamp()) {
-            env::panic_str("Treasury cache is not warmed up. Use `warmup`.");
+        let exchange_rate = treasury
+            .cache
+            .items
+            .last()
+            .unwrap_or_else(|| env::panic_str("No cached exchange rate"))
+            .value;
+        TreasuryPnl {
+            pnl: treasury.flows.pnl(exchange_rate),
+            exchange_rate,
+            flows: treasury.flows,
+        }
+    }
+
+    /// Releases the balancing lock of the pool which final callback has never run.
+    pub fn force_clear_in_flight(&mut self, pool_id: u64) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.committed_near.remove(&pool_id);
+        treasury.gas_checkpoints.remove(&pool_id);
+        treasury.failed_swaps.remove(&pool_id);
+        if treasury.in_flight.remove(&pool_id).is_none() {
+            env::panic_str(&format!(
+                "Balancing of the pool {} is not in progress",
+                pool_id
+            ));
+        }
+        self.treasury.replace(&treasury);
+    }
+
+    /// Burns USN held by the treasury without any swap.
+    pub fn burn_treasury_usn(&mut self, amount: U128) {
+        self.assert_owner();
+        require!(amount.0 > 0, "Not allowed to burn 0 tokens");
+        let preview = self.preview_burn(amount);
+        require!(
+            preview.shortfall.0 == 0,
+            &format!(
+                "Not enough USN in the treasury: {} < {}",
+                preview.balance.0, amount.0
+            )
+        );
+        self.internal_burn_treasury_usn(amount.0);
+
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        treasury.withheld_usn = treasury
+            .withheld_usn
+            .map(|withheld| withheld.0.saturating_sub(amount.0))
+            .filter(|withheld| *withheld > 0)
+            .map(U128);
+        self.treasury.set(&treasury);
+    }
+
+    /// Returns the outcome of burning `amount` of USN held by the treasury,
+    /// which sells do after the swap. Nothing is burned.
+    pub fn preview_burn(&self, amount: U128) -> BurnPreview {
+        let balance = self.token.ft_balance_of(env::current_account_id()).0;
+        let total_supply = self.token.ft_total_supply().0;
+        let shortfall = amount.0.saturating_sub(balance);
+        BurnPreview {
+            balance: U128(balance),
+            shortfall: U128(shortfall),
+            // A burn beyond the balance fails leaving the supply as is.
+            total_supply: U128(if shortfall == 0 {
+                total_supply - amount.0
+            } else {
+                total_supply
+            }),
         }
+    }
 
+    /// Adds idle ref-finance deposits of the pool counter-tokens, e.g. left by
+    /// interrupted balancing or a failed liquidity addition, back to the pool.
+    /// Nothing is claimed or swapped: ref-finance accrues pool fees into the shares.
+    /// The pool is locked meanwhile, so a sell waiting to swap its counter-token
+    /// never has the deposit added back under it.
+    #[payable]
+    pub fn compound_treasury_rewards(&mut self, pool_id: u64) -> Promise {
+        assert_one_yocto();
+        self.assert_owner();
         let pool = Pool::from_config_with_assert(pool_id);
 
-        // Start with figuring out USDT part of reserve.
-        ext_ref_finance::get_pool_shares(
-            pool.id,
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        require!(
+            !treasury.in_flight.contains_key(&pool.id),
+            &format!("Balancing of the pool {} is in progress", pool.id)
+        );
+        treasury.in_flight.insert(pool.id, env::block_height());
+        self.treasury.replace(&treasury);
+
+        ext_ref_finance::get_deposits(
             env::current_account_id(),
-            pool.ref_id.clone(),
+            pool.ref_id,
             NO_DEPOSIT,
-            GAS_FOR_GET_SHARES,
+            GAS_FOR_GET_DEPOSITS,
         )
-        .then(ext_self::predict_remove_liquidity(
+        .then(ext_self::handle_compound_deposits(
             pool.id,
             env::current_account_id(),
-            NO_DEPOSIT,
-            GAS_SURPLUS + GAS_FOR_PREDICT_REMOVE_LIQUIDITY,
+            ONE_YOCTO,
+            GAS_SURPLUS + gas_for_add_liquidity(),
         ))
-        .and(ext_ref_finance::get_stable_pool(
+        // Released whether the liquidity was added or not.
+        .then(ext_self::finish_treasury_balancing(
             pool.id,
-            pool.ref_id,
+            TreasuryDecision::DoNothing,
+            env::current_account_id(),
             NO_DEPOSIT,
-            GAS_FOR_GET_STABLE_POOL,
+            GAS_FOR_FINISH_BALANCING,
         ))
-        .then(ext_self::handle_start_treasury_balancing(
+    }
+
+    /// Removes the whole treasury liquidity of the pool and withdraws its tokens
+    /// to the contract, wNEAR of the reserve is unwrapped. Nothing is swapped or burned.
+    /// Requires 1 yoctoNEAR for the removal, 1 for every pool token and 1 for unwrapping.
+    #[payable]
+    pub fn withdraw_all_liquidity(&mut self, pool_id: u64) -> Promise {
+        self.assert_owner();
+
+        let pool = Pool::from_config_with_assert(pool_id);
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let wrap_id = treasury.config.swap.wrap_id.clone();
+        let wrapped = treasury.reserve.get(&wrap_id).map_or(0, |amount| amount.0);
+
+        let deposit = (pool.tokens.len() + 1) as u128 + u128::from(wrapped > 0);
+        require!(
+            env::attached_deposit() == deposit * ONE_YOCTO,
+            &format!("{} yoctoNEAR of attached deposit is required", deposit)
+        );
+        require!(
+            !treasury.in_flight.contains_key(&pool.id),
+            &format!("Balancing of the pool {} is in progress", pool.id)
+        );
+
+        treasury.in_flight.insert(pool.id, env::block_height());
+        treasury.reserve_snapshots.remove(&pool.id);
+
+        let withdrawal = ext_ref_finance::get_pool_shares(
             pool.id,
-            decision_limit,
-            execute.unwrap_or(false),
             env::current_account_id(),
-            env::attached_deposit(),
-            GAS_SURPLUS * 6
+            pool.ref_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_SHARES,
+        )
+        .then(ext_self::handle_remove_all_liquidity(
+            pool.id,
+            env::current_account_id(),
+            (pool.tokens.len() + 1) as u128 * ONE_YOCTO,
+            GAS_SURPLUS * 2
                 + GAS_FOR_REMOVE_LIQUIDITY
-                + GAS_FOR_SWAP
-                + GAS_FOR_WITHDRAW * 2
-                + GAS_FOR_NEAR_WITHDRAW
-                + GAS_FOR_FINISH_BURNING,
+                + GAS_FOR_WITHDRAW * pool.tokens.len() as u64,
         ))
-    }
-
-    pub fn warmup(&mut self) -> Promise {
-        Oracle::get_exchange_rate_promise().then(ext_self::handle_exchange_rate_cache(
+        // Released whether the withdrawal succeeded or not.
+        .then(ext_self::finish_treasury_balancing(
+            pool.id,
+            TreasuryDecision::DoNothing,
             env::current_account_id(),
             NO_DEPOSIT,
-            GAS_FOR_HANDLE_EXCHANGE_RATE,
-        ))
+            GAS_FOR_FINISH_BALANCING,
+        ));
+
+        if wrapped == 0 {
+            self.treasury.set(&treasury);
+            return withdrawal;
+        }
+
+        // Put back by `handle_unwrap_reserve` if unwrapping fails.
+        treasury.reserve.remove(&wrap_id);
+        self.treasury.set(&treasury);
+
+        withdrawal.and(
+            ext_ft::near_withdraw(U128(wrapped), wrap_id, ONE_YOCTO, GAS_FOR_NEAR_WITHDRAW).then(
+                ext_self::handle_unwrap_reserve(
+                    U128(wrapped),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_HANDLE_RESERVE,
+                ),
+            ),
+        )
     }
-}
 
-#[ext_contract(ext_self)]
-trait SelfHandler {
-    #[private]
+    /// Checks that the contract is registered with `token_ids`, wNEAR and the pool
+    /// counter-tokens by default, to receive withdrawals from ref-finance.
+    /// Returns the tokens it isn't registered with. If `register` is set, they get
+    /// equal shares of the attached deposit as storage deposits.
     #[payable]
-    fn handle_start_treasury_balancing(
+    pub fn ensure_withdraw_registration(
         &mut self,
-        pool_id: u64,
-        decision_limit: Option<u64>,
-        execute: bool,
-        #[callback] predicted_amounts: Vec<U128>,
-        #[callback] info: StablePoolInfo,
-    ) -> PromiseOrValue<()>;
+        token_ids: Option<Vec<AccountId>>,
+        register: Option<bool>,
+    ) -> Promise {
+        self.assert_owner();
+        let treasury = self.treasury.get().expect("Valid treasury");
+        let token_ids = token_ids.unwrap_or_else(|| {
+            let mut token_ids = vec![treasury.config.swap.wrap_id];
+            token_ids.extend(counter_tokens());
+            token_ids
+        });
+        let register = register.unwrap_or(false);
+        require!(
+            !register || env::attached_deposit() > 0,
+            "Attach the storage deposit to register"
+        );
 
-    #[private]
+        token_ids
+            .iter()
+            .map(|token_id| {
+                ext_ft::storage_balance_of(
+                    env::current_account_id(),
+                    token_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_FOR_STORAGE_BALANCE_OF,
+                )
+            })
+            .reduce(|acc, promise| acc.and(promise))
+            .unwrap_or_else(|| env::panic_str("No tokens to check"))
+            .then(ext_self::handle_withdraw_registration(
+                token_ids.clone(),
+                register,
+                env::current_account_id(),
+                env::attached_deposit(),
+                GAS_SURPLUS
+                    + (GAS_FOR_STORAGE_DEPOSIT + GAS_FOR_HANDLE_RESERVE) * token_ids.len() as u64,
+            ))
+    }
+
+    /// Continues the sell which removed liquidity but failed before the swap.
+    /// Amounts are taken from the ref-finance deposit of the treasury:
+    /// `usdt_amount` is swapped to NEAR, `usn_amount` is withdrawn and burned.
+    /// `counter_id` may be omitted if the pool has the only counter-token.
     #[payable]
-    fn handle_withdraw_after_swap(
+    pub fn resume_sell(
         &mut self,
         pool_id: u64,
+        usdt_amount: U128,
         usn_amount: U128,
-        #[callback] wrap_amount: U128,
-    ) -> Promise;
-
-    #[private]
-    #[payable]
-    fn handle_liquidity_after_swap(&mut self, pool_id: u64, #[callback] amount: U128) -> Promise;
+        counter_id: Option<AccountId>,
+    ) -> Promise {
+        self.assert_owner();
 
-    #[private]
-    fn handle_exchange_rate_cache(&mut self, #[callback] price: PriceData);
+        require!(
+            env::attached_deposit() == 2 * ONE_YOCTO,
+            "2 yoctoNEAR of attached deposit is required"
+        );
+        require!(usdt_amount.0 > 0, "Nothing to swap");
 
-    #[private]
-    fn predict_remove_liquidity(&self, pool_id: u64, #[callback] shares: U128) -> Promise;
+        let pool = Pool::from_config_with_assert(pool_id);
+        let counter_idx = counter_token_index(&pool, counter_id);
 
-    #[private]
-    fn finish_withdraw_with_burn(&mut self, amount: U128);
-}
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        require!(
+            !treasury.in_flight.contains_key(&pool.id),
+            &format!("Balancing of the pool {} is in progress", pool.id)
+        );
 
-trait SelfHandler {
-    fn handle_start_treasury_balancing(
-        &mut self,
-        pool_id: u64,
-        decision_limit: Option<u64>,
-        execute: bool,
-        predicted_amounts: Vec<U128>,
-        info: StablePoolInfo,
-    ) -> PromiseOrValue<()>;
+        let exchange_rate = match treasury
+            .cache
+            .collect(env::block_timestamp(), treasury.config.decision.time_unit())
+        {
+            Ok((_, exchange_rates)) => *exchange_rates.last().unwrap(),
+            Err(err) => env::panic_str(&err.to_string()),
+        };
 
-    fn handle_withdraw_after_swap(
-        &mut self,
-        pool_id: u64,
-        usn_amount: U128,
-        wrap_amount: U128,
-    ) -> Promise;
+        let amount = whole_units(usdt_amount.0, pool.decimals[counter_idx]);
+        let min_amount = min_amount_out(
+            amount / exchange_rate,
+            treasury.config.swap_fee(),
+            treasury.config.slippage.slippage(amount),
+            NEAR_DECIMALS,
+        );
 
-    fn handle_liquidity_after_swap(&mut self, pool_id: u64, amount: U128) -> Promise;
+        let swap_action = SwapAction {
+            pool_id: treasury.config.swap.swap_pool_id,
+            amount_in: Some(usdt_amount),
+            token_in: pool.tokens[counter_idx].clone(),
+            token_out: treasury.config.swap.wrap_id.clone(),
+            min_amount_out: min_amount.into(),
+        };
 
-    fn handle_exchange_rate_cache(&mut self, price: PriceData);
+        treasury.in_flight.insert(pool.id, env::block_height());
+        self.treasury.replace(&treasury);
 
-    fn predict_remove_liquidity(&self, pool_id: u64, shares: U128) -> Promise;
+        swap_and_burn(
+            &treasury.config,
+            pool.id,
+            amount,
+            swap_action,
+            usn_amount,
+            None,
+        )
+    }
 
-    fn finish_withdraw_with_burn(&mut self, amount: U128);
-}
+    /// Trades the share of a split decision left for the pool. The leg is quoted
+    /// again on execution: the counter-token is selected in the pool itself and
+    /// the trade is sized at the latest cached exchange rate against the live swap pool.
+    #[payable]
+    pub fn execute_treasury_leg(&mut self, pool_id: u64) -> Promise {
+        self.assert_owner_or_guardian_or_relayer();
 
-enum Extract {
-    Usn,
-    Other,
-}
+        require!(
+            env::attached_deposit() == 3 * ONE_YOCTO,
+            "3 yoctoNEAR of attached deposit is required"
+        );
 
-fn extract_amount(pool: &Pool, amounts: &Vec<U128>, extract: Extract) -> U128 {
-    pool.tokens
-        .iter()
-        .zip(amounts)
-        .find_map(|(token_id, amount)| match extract {
-            Extract::Usn => {
-                if token_id == &env::current_account_id() {
-                    Some(amount.clone())
-                } else {
-                    None
-                }
-            }
-            _ => {
-                if token_id != &env::current_account_id() {
-                    Some(amount.clone())
-                } else {
-                    None
-                }
-            }
-        })
-        .unwrap()
-}
+        let pool = Pool::from_config_with_assert(pool_id);
+        let treasury = self.treasury.get().expect("Valid treasury");
+        require!(
+            !treasury.in_flight.contains_key(&pool.id),
+            &format!("Balancing of the pool {} is in progress", pool.id)
+        );
+        require!(
+            treasury.pending_legs.contains_key(&pool.id),
+            &format!("No pending leg for the pool {}", pool.id)
+        );
 
-#[near_bindgen]
-impl SelfHandler for Contract {
-    #[private]
-    #[payable]
-    fn handle_start_treasury_balancing(
-        &mut self,
+        quote_leg(
+            pool,
+            &treasury.config.swap,
+            treasury.config.burn_gas(),
+            env::attached_deposit(),
+        )
+    }
+
+    /// Returns the expected amount of the counter-token bought for `near_amount` yoctoNEAR
+    /// through the configured swap pool.
+    pub fn quote_buy(
+        &self,
+        near_amount: U128,
         pool_id: u64,
-        decision_limit: Option<u64>,
-        execute: bool,
-        #[callback] predicted_amounts: Vec<U128>,
-        #[callback] info: StablePoolInfo,
-    ) -> PromiseOrValue<()> {
+        counter_id: Option<AccountId>,
+    ) -> Promise {
+        let swap = self.treasury.get().expect("Valid treasury").config.swap;
         let pool = Pool::from_config_with_assert(pool_id);
+        let counter_idx = counter_token_index(&pool, counter_id);
+        let swap_action = buy_swap_action(&swap, &pool.tokens[counter_idx], near_amount.0, 0);
+        assert_swap_route(std::slice::from_ref(&swap_action));
 
+        ext_ref_finance::get_return(
+            swap_action.pool_id,
+            swap_action.token_in,
+            near_amount,
+            swap_action.token_out,
+            swap.swap_ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_RETURN,
+        )
+    }
+
+    /// Returns the amounts a sell of `amount` USD would remove from the pool, swap and burn
+    /// at the given NEAR/USD exchange rate.
+    pub fn preview_sell(
+        &self,
+        pool_id: u64,
+        amount: f64,
+        exchange_rate: f64,
+        counter_id: Option<AccountId>,
+    ) -> SellPreview {
+        require!(
+            amount.is_finite() && amount > 0.,
+            "Amount must be a positive number"
+        );
         require!(
-            predicted_amounts.len() == 2,
-            "A pool of 2 tokens is required"
+            exchange_rate.is_finite() && exchange_rate > 0.,
+            "Exchange rate must be a positive number"
         );
+        let config = self.treasury.get().expect("Valid treasury").config;
+        let pool = Pool::from_config_with_assert(pool_id);
+        let counter_idx = counter_token_index(&pool, counter_id);
+        plan_sell(&config, &pool, counter_idx, amount, exchange_rate).into()
+    }
 
+    /// Returns the decision `balance_treasury` would make on the live pools along with
+    /// the expected trade amounts. Nothing is traded or stored.
+    pub fn quote_rebalance(
+        &self,
+        pool_id: u64,
+        limits: Option<[u64; 2]>,
+        limit_unit: Option<LimitUnit>,
+    ) -> Promise {
         let treasury = self.treasury.get().expect("Valid treasury");
 
-        // Prepare input data to make decision about balancing.
+        let exchange_rates = match treasury
+            .cache
+            .collect(env::block_timestamp(), treasury.config.decision.time_unit())
+        {
+            Ok((_, exchange_rates)) => exchange_rates,
+            Err(err) => env::panic_str(&err.to_string()),
+        };
 
-        // 1. NEAR/USDT exchange rates.
-        let (time_points, exchange_rates) = match treasury.cache.collect(env::block_timestamp()) {
+        let decision_limit = limit_in_unit(
+            &treasury.config,
+            limits,
+            limit_unit.unwrap_or_default(),
+            *exchange_rates.last().unwrap(),
+            limit_seed(None),
+        );
+
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        ext_ref_finance::get_pool_shares(
+            pool.id,
+            env::current_account_id(),
+            pool.ref_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_SHARES,
+        )
+        .then(ext_self::predict_remove_liquidity(
+            pool.id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_SURPLUS + GAS_FOR_PREDICT_REMOVE_LIQUIDITY,
+        ))
+        .and(ext_ref_finance::get_stable_pool(
+            pool.id,
+            pool.ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_STABLE_POOL,
+        ))
+        .and(ext_ref_finance::get_pool(
+            treasury.config.swap.swap_pool_id,
+            treasury.config.swap.swap_ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_POOL,
+        ))
+        .then(ext_self::handle_quote_rebalance(
+            pool.id,
+            decision_limit,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_SURPLUS * 2,
+        ))
+    }
+
+    /// Compares the NEAR/USD price implied by the swap pool reserves to the latest
+    /// cached exchange rate.
+    pub fn check_oracle_divergence(&self) -> Promise {
+        let treasury = self.treasury.get().expect("Valid treasury");
+        require!(
+            !treasury.cache.items.is_empty(),
+            "No cached exchange rate, call warmup first"
+        );
+
+        ext_ref_finance::get_pool(
+            treasury.config.swap.swap_pool_id,
+            treasury.config.swap.swap_ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_POOL,
+        )
+        .then(ext_self::handle_oracle_divergence(
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_SURPLUS,
+        ))
+    }
+
+    /// Compares the live USD value of the treasury position in the pool to the one
+    /// observed by the previous audit adjusted by trades recorded since then, emits
+    /// an `audit_result` event and takes the live value as the new snapshot.
+    /// Other owner operations on the position reset the snapshot.
+    pub fn audit_treasury(&mut self, pool_id: u64) -> Promise {
+        self.assert_owner();
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        ext_ref_finance::get_pool_shares(
+            pool.id,
+            env::current_account_id(),
+            pool.ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_SHARES,
+        )
+        .then(ext_self::predict_remove_liquidity(
+            pool.id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_SURPLUS + GAS_FOR_PREDICT_REMOVE_LIQUIDITY,
+        ))
+        .then(ext_self::handle_audit_treasury(
+            pool.id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_SURPLUS,
+        ))
+    }
+
+    /// Returns the decision the proposed parameters would make on the current cache
+    /// for the given reserve: NEAR, circulating USN and USDT in whole tokens.
+    pub fn preview_decision_params(
+        &self,
+        new_params: DecisionParams,
+        near: f64,
+        usn: f64,
+        usdt: f64,
+    ) -> TreasuryDecision {
+        new_params.assert_valid();
+        let treasury = self.treasury.get().expect("Valid treasury");
+        let (time_points, exchange_rates) = match treasury
+            .cache
+            .collect(env::block_timestamp(), new_params.time_unit())
+        {
             Ok((time_points, exchange_rates)) => (time_points, exchange_rates),
-            Err(_) => env::panic_str("Treasury cache is not in a valid state."),
+            Err(err) => env::panic_str(&err.to_string()),
         };
+        make_treasury_decision(
+            &new_params,
+            exchange_rates,
+            time_points,
+            ReserveState {
+                near,
+                usn,
+                usdt,
+                limit: None,
+                elapsed: None,
+            },
+            false,
+        )
+    }
 
-        // 2. NEAR part of USN reserve in NEAR.
-        let near = env::account_balance() - env::attached_deposit();
+    /// Runs the decision algorithm with the current parameters on the supplied data:
+    /// 8 NEAR/USD exchange rates at time points relative to now in `time_unit_sec`
+    /// units (`-7, ..., 0` for 5-minute intervals and the default unit), the reserve
+    /// in whole NEAR and USDT, and circulating USN.
+    /// Volatility, TVL and trade size guards aren't applied.
+    pub fn decide_from_series(
+        &self,
+        exchange_rates: Vec<f64>,
+        time_points: Vec<f64>,
+        near: f64,
+        usn: f64,
+        usdt: f64,
+        limit: Option<u64>,
+    ) -> TreasuryDecision {
+        require!(
+            exchange_rates.len() == 8 && time_points.len() == 8,
+            "8 exchange rates and time points are required"
+        );
+        let treasury = self.treasury.get().expect("Valid treasury");
+        make_treasury_decision(
+            &treasury.config.decision,
+            exchange_rates,
+            time_points,
+            ReserveState {
+                near,
+                usn,
+                usdt,
+                limit: limit.map(|x| x as f64),
+                elapsed: None,
+            },
+            false,
+        )
+    }
 
-        // 3. Total value of circulating USN.
-        let usn =
-            self.token.ft_total_supply().0 - extract_amount(&pool, &info.amounts, Extract::Usn).0;
+    /// Returns intermediates of the trend fit `decide_from_series` makes
+    /// with the current decision parameters.
+    pub fn debug_trend_fit(&self, exchange_rates: Vec<f64>, time_points: Vec<f64>) -> TrendFit {
+        require!(
+            exchange_rates.len() == 8 && time_points.len() == 8,
+            "8 exchange rates and time points are required"
+        );
+        let treasury = self.treasury.get().expect("Valid treasury");
+        fit_trend(&treasury.config.decision, &exchange_rates, &time_points)
+    }
 
-        // 4. USDT reserve.
-        let usdt = extract_amount(&pool, &predicted_amounts, Extract::Other).0;
+    /// Returns the NEAR/USD rate at which the NEAR part of the reserve meets the collateral
+    /// floor: below it the treasury sells USDT to protect the floor, above it the trend
+    /// drives trades. `near` and `usn` are the reserve NEAR and circulating USN in whole tokens.
+    pub fn break_even_rate(&self, near: f64, usn: f64) -> f64 {
+        require!(near > 0., "NEAR of the reserve must be positive");
+        let treasury = self.treasury.get().expect("Valid treasury");
+        collateral_break_even_rate(treasury.config.decision.n_dn, near, usn)
+    }
 
-        // Convert everything into floats.
-        let near = near as f64 / ONE_NEAR as f64;
-        let usn = usn as f64 / 10f64.powi(USN_DECIMALS as i32);
-        let last_exch_rate = *exchange_rates.last().unwrap();
-        let usdt = usdt as f64 / 10f64.powi(USDT_DECIMALS as i32);
-        let limit = decision_limit.map(|x| x as f64);
+    /// Returns Borsh serialized cache to be imported to another contract.
+    pub fn export_treasury_cache(&self) -> Base64VecU8 {
+        let treasury = self.treasury.get().expect("Valid treasury");
+        treasury.cache.try_to_vec().unwrap().into()
+    }
 
-        // Make a decision.
-        let decision = make_treasury_decision(exchange_rates, time_points, near, usn, usdt, limit);
+    /// Replaces the cache with the one exported by `export_treasury_cache`.
+    pub fn import_treasury_cache(&mut self, blob: Base64VecU8) {
+        self.assert_owner();
+        let cache = IntervalCache::try_from_slice(&blob.0)
+            .unwrap_or_else(|_| env::panic_str("Malformed treasury cache"));
+        cache.assert_valid(env::block_timestamp());
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.cache = cache;
+        self.treasury.replace(&treasury);
+    }
 
-        env::log_str(format!("{}", decision).as_str());
+    /// Fixes the order of cached items dropping duplicated and invalid ones.
+    pub fn repair_treasury_cache(&mut self) -> CacheRepairReport {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        let report = treasury.cache.repair(env::block_timestamp());
+        self.treasury.replace(&treasury);
+        report
+    }
 
-        if execute {
-            match decision {
-                TreasuryDecision::DoNothing => PromiseOrValue::Value(()),
-                TreasuryDecision::Buy(f_amount) => buy(pool.id, f_amount, last_exch_rate).into(),
-                TreasuryDecision::Sell(f_amount) => sell(pool.id, f_amount, last_exch_rate).into(),
-            }
-        } else {
-            env::log_str("Execution bypassed");
-            PromiseOrValue::Value(())
-        }
+    /// Fetches the exchange rate into the cache. A failed or outdated oracle response
+    /// is requested again up to `retries` times, each retry lands in a later block.
+    pub fn warmup(&mut self, retries: Option<u8>) -> Promise {
+        let retries = retries.unwrap_or(0);
+        require!(
+            retries <= MAX_WARMUP_RETRIES,
+            &format!("Up to {} retries are allowed", MAX_WARMUP_RETRIES)
+        );
+        let treasury = self.treasury.get().expect("Valid treasury");
+        fetch_exchange_rate(&treasury.config, retries)
+    }
+
+    /// Fills `samples` trailing 5 minute slots of the cache with the NEAR price of
+    /// the ref-finance pool, a last resort if the oracle is unavailable.
+    /// The flat series leaves the trend out of decisions until real rates replace it.
+    pub fn warmup_from_pool(&mut self, pool_id: u64, samples: u8) -> Promise {
+        self.assert_owner();
+        require!(
+            (1..=MAX_CACHE_SIZE).contains(&(samples as usize)),
+            &format!("From 1 to {} samples are allowed", MAX_CACHE_SIZE)
+        );
+        let treasury = self.treasury.get().expect("Valid treasury");
+
+        ext_ref_finance::get_pool(
+            pool_id,
+            treasury.config.swap.swap_ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_GET_POOL,
+        )
+        .then(ext_self::handle_warmup_from_pool(
+            pool_id,
+            samples,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_SURPLUS,
+        ))
     }
+}
+
+#[ext_contract(ext_self)]
+trait SelfHandler {
+    #[private]
+    #[payable]
+    fn handle_start_treasury_balancing(
+        &mut self,
+        pool_id: u64,
+        decision_limit: Option<DecisionLimit>,
+        execute: bool,
+        #[callback] predicted_amounts: Vec<U128>,
+        #[callback] info: StablePoolInfo,
+        #[callback] swap_info: PoolInfo,
+    ) -> PromiseOrValue<TreasuryDecision>;
+
+    #[private]
+    fn handle_quote_rebalance(
+        &self,
+        pool_id: u64,
+        decision_limit: Option<DecisionLimit>,
+        #[callback] predicted_amounts: Vec<U128>,
+        #[callback] info: StablePoolInfo,
+        #[callback] swap_info: PoolInfo,
+    ) -> RebalanceQuote;
+
+    #[private]
+    #[payable]
+    fn handle_execute_treasury_leg(
+        &mut self,
+        pool_id: u64,
+        #[callback] info: StablePoolInfo,
+        #[callback] swap_info: PoolInfo,
+    ) -> PromiseOrValue<TreasuryDecision>;
+
+    #[private]
+    fn handle_oracle_divergence(&self, #[callback] swap_info: PoolInfo) -> OracleDivergence;
+
+    #[private]
+    fn handle_audit_treasury(
+        &mut self,
+        pool_id: u64,
+        #[callback] predicted_amounts: Vec<U128>,
+    ) -> ReserveAudit;
+
+    #[private]
+    fn handle_warmup_from_pool(&mut self, pool_id: u64, samples: u8, #[callback] info: PoolInfo);
+
+    #[private]
+    #[payable]
+    fn handle_swap_after_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        swap_actions: Vec<SwapAction>,
+        usn_amount: U128,
+        min_fill: U128,
+    ) -> Promise;
 
     #[private]
     #[payable]
     fn handle_withdraw_after_swap(
         &mut self,
         pool_id: u64,
+        counter_usd: f64,
         usn_amount: U128,
-        #[callback] wrap_amount: U128,
-    ) -> Promise {
-        let wrap_id: AccountId = CONFIG.wrap_id.parse().unwrap();
-        let usn_id = env::current_account_id();
-        let pool = Pool::from_config_with_assert(pool_id);
+        bounds: SwapBounds,
+        #[callback_result] wrap_amount: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()>;
 
-        ext_ref_finance::withdraw(
-            wrap_id.clone(),
-            wrap_amount,
-            None,
-            pool.ref_id.clone(),
-            ONE_YOCTO,
-            GAS_FOR_WITHDRAW,
-        )
-        .then(ext_ft::near_withdraw(
-            wrap_amount,
-            wrap_id,
-            ONE_YOCTO,
-            GAS_FOR_NEAR_WITHDRAW,
-        ))
-        .then(ext_ref_finance::withdraw(
-            usn_id.clone(),
-            usn_amount,
-            None,
-            pool.ref_id,
-            1,
-            GAS_FOR_WITHDRAW,
-        ))
-        .then(ext_self::finish_withdraw_with_burn(
-            usn_amount,
-            usn_id,
-            NO_DEPOSIT,
-            GAS_FOR_FINISH_BURNING,
-        ))
+    #[private]
+    #[payable]
+    fn handle_unwrap_after_withdraw(
+        &mut self,
+        usn_amount: U128,
+        wrap_amount: U128,
+    ) -> PromiseOrValue<()>;
+
+    #[private]
+    #[payable]
+    fn handle_liquidity_after_swap(
+        &mut self,
+        pool_id: u64,
+        counter_id: AccountId,
+        near_amount: U128,
+        bounds: SwapBounds,
+        #[callback_result] amount: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()>;
+
+    #[private]
+    fn handle_rejected_fill(&mut self, token_id: AccountId, amount: U128);
+
+    #[private]
+    #[payable]
+    fn handle_add_stable_liquidity(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        #[callback] info: StablePoolInfo,
+    ) -> Promise;
+
+    #[private]
+    fn handle_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        counter_id: AccountId,
+        amount: U128,
+        #[callback_result] shares: Result<U128, PromiseError>,
+    );
+
+    #[private]
+    #[payable]
+    fn handle_compound_deposits(
+        &mut self,
+        pool_id: u64,
+        #[callback] deposits: HashMap<AccountId, U128>,
+    ) -> PromiseOrValue<()>;
+
+    #[private]
+    #[payable]
+    fn handle_remove_all_liquidity(&mut self, pool_id: u64, #[callback] shares: U128) -> Promise;
+
+    #[private]
+    #[payable]
+    fn handle_withdraw_removed_liquidity(
+        &mut self,
+        pool_id: u64,
+        shares: U128,
+        #[callback] amounts: Vec<U128>,
+    ) -> PromiseOrValue<()>;
+
+    #[private]
+    fn handle_unwrap_reserve(&mut self, amount: U128);
+
+    #[private]
+    #[payable]
+    fn handle_withdraw_registration(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        register: bool,
+    ) -> Vec<AccountId>;
+
+    #[private]
+    fn handle_storage_registration(&mut self, token_id: AccountId);
+
+    #[private]
+    fn handle_exchange_rate_cache(
+        &mut self,
+        retries: u8,
+        #[callback_result] price: Result<PriceData, PromiseError>,
+        #[callback_result] swap_info: Result<PoolInfo, PromiseError>,
+    ) -> PromiseOrValue<()>;
+
+    #[private]
+    fn predict_remove_liquidity(&self, pool_id: u64, #[callback] shares: U128) -> Promise;
+
+    #[private]
+    fn finish_withdraw_with_burn(&mut self, amount: U128);
+
+    #[private]
+    fn finish_treasury_balancing(
+        &mut self,
+        pool_id: u64,
+        decision: TreasuryDecision,
+    ) -> PromiseOrValue<TreasuryDecision>;
+
+    #[private]
+    fn fail_treasury_balancing(&self, pool_id: u64);
+
+    #[private]
+    fn handle_balance_treasuries(&self, pool_ids: Vec<u64>) -> Vec<PerPoolResult>;
+}
+
+trait SelfHandler {
+    fn handle_start_treasury_balancing(
+        &mut self,
+        pool_id: u64,
+        decision_limit: Option<DecisionLimit>,
+        execute: bool,
+        predicted_amounts: Vec<U128>,
+        info: StablePoolInfo,
+        swap_info: PoolInfo,
+    ) -> PromiseOrValue<TreasuryDecision>;
+
+    fn handle_quote_rebalance(
+        &self,
+        pool_id: u64,
+        decision_limit: Option<DecisionLimit>,
+        predicted_amounts: Vec<U128>,
+        info: StablePoolInfo,
+        swap_info: PoolInfo,
+    ) -> RebalanceQuote;
+
+    fn handle_execute_treasury_leg(
+        &mut self,
+        pool_id: u64,
+        info: StablePoolInfo,
+        swap_info: PoolInfo,
+    ) -> PromiseOrValue<TreasuryDecision>;
+
+    fn handle_oracle_divergence(&self, swap_info: PoolInfo) -> OracleDivergence;
+
+    fn handle_audit_treasury(&mut self, pool_id: u64, predicted_amounts: Vec<U128>)
+        -> ReserveAudit;
+
+    fn handle_warmup_from_pool(&mut self, pool_id: u64, samples: u8, info: PoolInfo);
+
+    fn handle_swap_after_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        swap_actions: Vec<SwapAction>,
+        usn_amount: U128,
+        min_fill: U128,
+    ) -> Promise;
+
+    fn handle_withdraw_after_swap(
+        &mut self,
+        pool_id: u64,
+        counter_usd: f64,
+        usn_amount: U128,
+        bounds: SwapBounds,
+        wrap_amount: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()>;
+
+    fn handle_unwrap_after_withdraw(
+        &mut self,
+        usn_amount: U128,
+        wrap_amount: U128,
+    ) -> PromiseOrValue<()>;
+
+    fn handle_liquidity_after_swap(
+        &mut self,
+        pool_id: u64,
+        counter_id: AccountId,
+        near_amount: U128,
+        bounds: SwapBounds,
+        amount: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()>;
+
+    fn handle_rejected_fill(&mut self, token_id: AccountId, amount: U128);
+
+    fn handle_add_stable_liquidity(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        info: StablePoolInfo,
+    ) -> Promise;
+
+    fn handle_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        counter_id: AccountId,
+        amount: U128,
+        shares: Result<U128, PromiseError>,
+    );
+
+    fn handle_compound_deposits(
+        &mut self,
+        pool_id: u64,
+        deposits: HashMap<AccountId, U128>,
+    ) -> PromiseOrValue<()>;
+
+    fn handle_remove_all_liquidity(&mut self, pool_id: u64, shares: U128) -> Promise;
+
+    fn handle_withdraw_removed_liquidity(
+        &mut self,
+        pool_id: u64,
+        shares: U128,
+        amounts: Vec<U128>,
+    ) -> PromiseOrValue<()>;
+
+    fn handle_unwrap_reserve(&mut self, amount: U128);
+
+    fn handle_withdraw_registration(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        register: bool,
+    ) -> Vec<AccountId>;
+
+    fn handle_storage_registration(&mut self, token_id: AccountId);
+
+    fn handle_exchange_rate_cache(
+        &mut self,
+        retries: u8,
+        price: Result<PriceData, PromiseError>,
+        swap_info: Result<PoolInfo, PromiseError>,
+    ) -> PromiseOrValue<()>;
+
+    fn predict_remove_liquidity(&self, pool_id: u64, shares: U128) -> Promise;
+
+    fn finish_withdraw_with_burn(&mut self, amount: U128);
+
+    fn finish_treasury_balancing(
+        &mut self,
+        pool_id: u64,
+        decision: TreasuryDecision,
+    ) -> PromiseOrValue<TreasuryDecision>;
+
+    fn fail_treasury_balancing(&self, pool_id: u64);
+
+    fn handle_balance_treasuries(&self, pool_ids: Vec<u64>) -> Vec<PerPoolResult>;
+}
+
+fn counter_token_index(pool: &Pool, counter_id: Option<AccountId>) -> usize {
+    let usn_id = env::current_account_id();
+    match counter_id {
+        Some(counter_id) => {
+            require!(counter_id != usn_id, "USN is not a counter-token");
+            pool.tokens
+                .iter()
+                .position(|token_id| token_id == &counter_id)
+                .unwrap_or_else(|| {
+                    env::panic_str(&format!("{} is not in the pool {}", counter_id, pool.id))
+                })
+        }
+        None => {
+            let mut counter_tokens =
+                (0..pool.tokens.len()).filter(|&idx| pool.tokens[idx] != usn_id);
+            match (counter_tokens.next(), counter_tokens.next()) {
+                (Some(idx), None) => idx,
+                _ => env::panic_str(&format!(
+                    "The pool {} has several counter-tokens, specify `counter_id`",
+                    pool.id
+                )),
+            }
+        }
+    }
+}
+
+/// Requests the oracle feeds of mapped counter-tokens along with the NEAR price.
+fn fetch_exchange_rate(config: &TreasuryConfig, retries: u8) -> Promise {
+    let swap = &config.swap;
+    // Every retry needs gas for other oracle and pool calls and the callback.
+    let retry_gas = Oracle::gas() + GAS_FOR_GET_POOL + GAS_FOR_HANDLE_EXCHANGE_RATE + GAS_SURPLUS;
+    Oracle::get_price_data_promise(config.counter_asset_ids())
+        .and(ext_ref_finance::get_pool(
+            swap.swap_pool_id,
+            swap.swap_ref_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_POOL,
+        ))
+        .then(ext_self::handle_exchange_rate_cache(
+            retries,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_EXCHANGE_RATE + retry_gas * retries as u64,
+        ))
+}
+
+/// USD of the counter-token amount taking the counter-token at $1.
+fn usd_of_counter(counter_id: &AccountId, amount: u128) -> f64 {
+    let decimals = token_decimals(counter_id)
+        .unwrap_or_else(|| env::panic_str(&format!("{} is not a pool token", counter_id)));
+    whole_units(amount, decimals)
+}
+
+/// Converts the amount of the token with `decimals` into whole tokens.
+/// The integer part is split off before the conversion, so reserves above 2^53
+/// of the smallest units are rounded once, not on the conversion and the division.
+fn whole_units(amount: u128, decimals: u8) -> f64 {
+    let scale = 10u128.pow(decimals as u32);
+    (amount / scale) as f64 + (amount % scale) as f64 / scale as f64
+}
+
+/// Returns the USD price of 1 NEAR in the swap pool taking the counter-token at $1.
+fn swap_pool_rate(swap: &SwapConfig, info: &PoolInfo) -> Option<f64> {
+    if swap_reserve(info, &swap.wrap_id)? == 0 {
+        return None;
+    }
+    counter_tokens().into_iter().find_map(|counter_id| {
+        swap_reserve(info, &counter_id)?;
+        let decimals = token_decimals(&counter_id)?;
+        Some(swap_spot_price(&swap.wrap_id, &counter_id, decimals, info))
+    })
+}
+
+/// Takes the rate of the first available source in the order of precedence,
+/// all available rates must agree within the allowed disagreement.
+fn select_rate(
+    precedence: &RatePrecedence,
+    oracle_rate: Option<f64>,
+    pool_rate: Option<f64>,
+) -> Result<f64, String> {
+    let rates: Vec<(RateSource, f64)> = precedence
+        .sources
+        .iter()
+        .filter_map(|&source| {
+            match source {
+                RateSource::Oracle => oracle_rate,
+                RateSource::SwapPool => pool_rate,
+            }
+            .map(|rate| (source, rate))
+        })
+        .collect();
+
+    let (source, rate) = *rates.first().ok_or_else(|| {
+        let sources: Vec<String> = precedence
+            .sources
+            .iter()
+            .map(|source| format!("{:?}", source))
+            .collect();
+        format!(
+            "{} failed to provide an exchange rate",
+            sources.join(" and ")
+        )
+    })?;
+
+    if let Some(max_disagreement) = precedence.max_disagreement {
+        for &(other_source, other_rate) in &rates[1..] {
+            if (other_rate - rate).abs() / rate > max_disagreement {
+                return Err(format!(
+                    "{:?} rate {} disagrees with {:?} rate {}",
+                    other_source, other_rate, source, rate
+                ));
+            }
+        }
+    }
+
+    Ok(rate)
+}
+
+/// Logs or aborts on the oracle price older than the latest cached rate.
+fn reject_out_of_order_price(
+    policy: OutOfOrderPricePolicy,
+    price_at: Timestamp,
+    cached_at: Timestamp,
+) {
+    let message = format!(
+        "Oracle price at {} predates the cached rate at {}",
+        price_at, cached_at
+    );
+    match policy {
+        OutOfOrderPricePolicy::Skip => env::log_str(&format!("{}, skipped", message)),
+        OutOfOrderPricePolicy::Abort => env::panic_str(&message),
+    }
+}
+
+/// Converts the oracle price of 1 yoctoNEAR into USD per 1 NEAR.
+fn price_data_to_rate(price: PriceData) -> f64 {
+    let rate: ExchangeRate = price.into();
+    rate.multiplier() as f64 / 10f64.powi(rate.decimals() as i32 - NEAR_DECIMALS as i32)
+}
+
+/// USD prices of the counter-tokens with oracle asset ids the oracle has provided.
+fn oracle_counter_prices(config: &TreasuryConfig, price: &PriceData) -> HashMap<AccountId, f64> {
+    config
+        .oracle_asset_ids
+        .iter()
+        .filter_map(|(token_id, asset_id)| {
+            let price = price.find_price(asset_id)?;
+            let decimals = token_decimals(token_id)?;
+            let usd =
+                price.multiplier.0 as f64 / 10f64.powi(price.decimals as i32 - decimals as i32);
+            Some((token_id.clone(), usd))
+        })
+        .collect()
+}
+
+fn extract_usn_amount(pool: &Pool, amounts: &Vec<U128>) -> U128 {
+    pool.tokens
+        .iter()
+        .zip(amounts)
+        .find_map(|(token_id, amount)| {
+            if token_id == &env::current_account_id() {
+                Some(*amount)
+            } else {
+                None
+            }
+        })
+        .unwrap()
+}
+
+#[near_bindgen]
+impl SelfHandler for Contract {
+    #[private]
+    #[payable]
+    fn handle_start_treasury_balancing(
+        &mut self,
+        pool_id: u64,
+        decision_limit: Option<DecisionLimit>,
+        execute: bool,
+        #[callback] predicted_amounts: Vec<U128>,
+        #[callback] info: StablePoolInfo,
+        #[callback] swap_info: PoolInfo,
+    ) -> PromiseOrValue<TreasuryDecision> {
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        require!(
+            predicted_amounts.len() == pool.tokens.len(),
+            "Wrong number of predicted amounts"
+        );
+
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+
+        // Choose the stable token to trade against.
+        let counter_idx = treasury.config.counter_token_policy.select(&pool, &info);
+
+        // Execution follows the decision made without execution on the same state.
+        let state_hash = decision_state_hash(&treasury, decision_limit, &predicted_amounts, &info);
+        let last_decision = treasury
+            .last_decisions
+            .remove(&pool.id)
+            .filter(|last| execute && last.state_hash == state_hash)
+            .map(|last| last.decision);
+
+        if treasury.config.log_verbosity == LogVerbosity::Verbose {
+            let raw = |amounts: &[U128]| amounts.iter().map(|amount| amount.0).collect::<Vec<_>>();
+            env::log_str(&format!(
+                "Pool {}: amounts = {:?}, predicted amounts = {:?}",
+                pool.id,
+                raw(&info.amounts),
+                raw(&predicted_amounts)
+            ));
+        }
+
+        let wrap_balance = if treasury.config.fetch_wrap_balance {
+            fetched_wrap_balance()
+        } else {
+            None
+        };
+
+        let (decision, last_exch_rate, collateral_ratio) = self.internal_make_decision(
+            &treasury,
+            &pool,
+            counter_idx,
+            &predicted_amounts,
+            &info,
+            &swap_info,
+            decision_limit,
+            last_decision,
+            wrap_balance,
+        );
+        let verbosity = treasury.config.log_verbosity;
+
+        // Previews aren't counted, the execution of a previewed decision counts it once.
+        let in_flight = treasury.in_flight.contains_key(&pool.id);
+        if execute && !in_flight {
+            if collateral_ratio.is_finite() {
+                treasury.record_collateral_ratio(env::block_timestamp(), collateral_ratio);
+            }
+            match decision {
+                TreasuryDecision::Buy(_) => treasury.stats.buys += 1,
+                TreasuryDecision::Sell(_) => treasury.stats.sells += 1,
+                TreasuryDecision::DoNothing => treasury.stats.do_nothings += 1,
+            }
+        }
+
+        if !execute {
+            treasury.last_decisions.insert(
+                pool.id,
+                LastDecision {
+                    state_hash,
+                    decision: decision.clone(),
+                },
+            );
+        }
+        let usn_usd_rate = usn_usd_rate(&pool, &info, counter_idx, &treasury.counter_prices);
+        treasury.usn_usd_rate = Some(usn_usd_rate);
+        self.treasury.set(&treasury);
+
+        // Trades are sized with the full precision, the rest shows the rounded amount
+        // in the configured units. Events are in USD.
+        let shown = decision.rounded(treasury.config.decision_decimals);
+        let reported = decision.reported(&treasury.config, usn_usd_rate);
+        if verbosity != LogVerbosity::Off {
+            env::log_str(&reported.describe(treasury.config.denomination));
+        }
+
+        let (action, amount_usd) = match shown {
+            TreasuryDecision::Buy(amount) => ("buy", Some(amount)),
+            TreasuryDecision::Sell(amount) => ("sell", Some(amount)),
+            TreasuryDecision::DoNothing => ("do_nothing", None),
+        };
+        if decision != TreasuryDecision::DoNothing || treasury.config.emit_on_do_nothing() {
+            event::emit::treasury_decision(
+                pool.id,
+                action,
+                amount_usd,
+                execute && !in_flight && decision != TreasuryDecision::DoNothing,
+            );
+        }
+
+        if execute && in_flight {
+            refund_unused_deposit(&TreasuryDecision::DoNothing);
+            refund_unquoted_legs(0);
+            if verbosity != LogVerbosity::Off {
+                env::log_str(&format!(
+                    "Balancing of the pool {} is in progress. Execution bypassed",
+                    pool.id
+                ));
+            }
+            PromiseOrValue::Value(reported)
+        } else if execute {
+            // Shares of other pools are pending until their chains run.
+            let mut legs = split_decision(&decision, pool.id, &treasury.config.split_targets);
+            let (_, own_decision) = legs.remove(0);
+            for (pool_id, leg_decision) in &legs {
+                treasury.pending_legs.insert(
+                    *pool_id,
+                    PendingLeg {
+                        decision: leg_decision.clone(),
+                    },
+                );
+            }
+            // Legs which don't fit into the prepaid gas beside the own chain
+            // or aren't paid by the attached deposit wait for `execute_treasury_leg`.
+            let swap = treasury.config.swap.clone();
+            let burn_gas = treasury.config.burn_gas();
+            let fitting = std::cmp::min(
+                max_split_legs(gas_for_start_balancing(burn_gas), burn_gas),
+                paid_leg_count(),
+            ) as usize;
+            if fitting < legs.len() && verbosity != LogVerbosity::Off {
+                env::log_str(&format!(
+                    "{} of {} split legs fit into the prepaid gas, the rest waits for `execute_treasury_leg`",
+                    fitting,
+                    legs.len()
+                ));
+            }
+            refund_unquoted_legs(std::cmp::min(fitting, legs.len()));
+
+            let result = self.internal_execute_decision(
+                treasury,
+                pool,
+                counter_idx,
+                own_decision,
+                last_exch_rate,
+                Some(&swap_info),
+            );
+            for (pool_id, _) in legs.into_iter().take(fitting) {
+                quote_leg(
+                    Pool::from_config_with_assert(pool_id),
+                    &swap,
+                    burn_gas,
+                    3 * ONE_YOCTO,
+                );
+            }
+
+            result
+        } else {
+            refund_unused_deposit(&TreasuryDecision::DoNothing);
+            if verbosity != LogVerbosity::Off {
+                env::log_str("Execution bypassed");
+            }
+            PromiseOrValue::Value(reported)
+        }
+    }
+
+    #[private]
+    fn handle_quote_rebalance(
+        &self,
+        pool_id: u64,
+        decision_limit: Option<DecisionLimit>,
+        #[callback] predicted_amounts: Vec<U128>,
+        #[callback] info: StablePoolInfo,
+        #[callback] swap_info: PoolInfo,
+    ) -> RebalanceQuote {
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        require!(
+            predicted_amounts.len() == pool.tokens.len(),
+            "Wrong number of predicted amounts"
+        );
+
+        let treasury = self.treasury.get().expect("Valid treasury");
+        let config = &treasury.config;
+        let counter_idx = config.counter_token_policy.select(&pool, &info);
+
+        let (decision, exchange_rate, _) = self.internal_make_decision(
+            &treasury,
+            &pool,
+            counter_idx,
+            &predicted_amounts,
+            &info,
+            &swap_info,
+            decision_limit,
+            None,
+            None,
+        );
+
+        let mut quote = RebalanceQuote {
+            decision: decision.clone(),
+            exchange_rate,
+            expected_amount_out: None,
+            min_amount_out: None,
+            expected_shares: None,
+            burn_amount: None,
+            slippage: None,
+        };
+
+        let counter_id = &pool.tokens[counter_idx];
+        match decision {
+            TreasuryDecision::DoNothing => {}
+            TreasuryDecision::Buy(amount) => {
+                let near = near_to_wrap(amount, exchange_rate);
+                let (amount_out, slippage) =
+                    swap_return(&swap_info, &config.swap.wrap_id, near, counter_id);
+                let add_amounts: Vec<U128> = pool
+                    .tokens
+                    .iter()
+                    .map(|token_id| {
+                        if token_id == counter_id {
+                            U128(amount_out)
+                        } else {
+                            U128(0)
+                        }
+                    })
+                    .collect();
+                quote.expected_amount_out = Some(U128(amount_out));
+                quote.min_amount_out = Some(U128(min_amount_out(
+                    amount,
+                    config.swap_fee(),
+                    config.slippage.slippage(amount),
+                    pool.decimals[counter_idx],
+                )));
+                quote.expected_shares = Some(min_shares(&info, &add_amounts, 0));
+                quote.slippage = Some(slippage);
+            }
+            TreasuryDecision::Sell(amount) => {
+                let plan = plan_sell(config, &pool, counter_idx, amount, exchange_rate);
+                let (amount_out, slippage) = swap_return(
+                    &swap_info,
+                    counter_id,
+                    plan.swap_action.amount_in.unwrap().0,
+                    &config.swap.wrap_id,
+                );
+                quote.expected_amount_out = Some(U128(amount_out));
+                quote.min_amount_out = Some(plan.swap_action.min_amount_out);
+                quote.burn_amount = Some(plan.usn_amount);
+                quote.slippage = Some(slippage);
+            }
+        }
+        quote
+    }
+
+    /// Executes the pending leg of the pool, the stale leg is dropped if the swap
+    /// pool price diverges from the latest cached rate.
+    #[private]
+    #[payable]
+    fn handle_execute_treasury_leg(
+        &mut self,
+        pool_id: u64,
+        #[callback] info: StablePoolInfo,
+        #[callback] swap_info: PoolInfo,
+    ) -> PromiseOrValue<TreasuryDecision> {
+        let pool = Pool::from_config_with_assert(pool_id);
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        require!(
+            !treasury.in_flight.contains_key(&pool.id),
+            &format!("Balancing of the pool {} is in progress", pool.id)
+        );
+        let leg = treasury
+            .pending_legs
+            .remove(&pool.id)
+            .unwrap_or_else(|| env::panic_str(&format!("No pending leg for the pool {}", pool.id)));
+        let counter_idx = treasury.config.counter_token_policy.select(&pool, &info);
+
+        let exchange_rate = match treasury
+            .cache
+            .collect(env::block_timestamp(), treasury.config.decision.time_unit())
+        {
+            Ok((_, exchange_rates)) => *exchange_rates.last().unwrap(),
+            Err(err) => env::panic_str(&err.to_string()),
+        };
+
+        if let Some(max_divergence) = treasury.config.max_spot_divergence {
+            let spot = swap_spot_price(
+                &treasury.config.swap.wrap_id,
+                &pool.tokens[counter_idx],
+                pool.decimals[counter_idx],
+                &swap_info,
+            ) * treasury.config.counter_price_source.price(
+                &pool,
+                &info,
+                counter_idx,
+                &treasury.counter_prices,
+            );
+            if (spot - exchange_rate).abs() / exchange_rate > max_divergence {
+                env::log_str(&format!(
+                    "Swap pool price ${} diverges from the latest rate ${}, the leg of the pool {} is dropped",
+                    spot, exchange_rate, pool.id
+                ));
+                self.treasury.set(&treasury);
+                refund_unused_deposit(&TreasuryDecision::DoNothing);
+                return PromiseOrValue::Value(TreasuryDecision::DoNothing);
+            }
+        }
+
+        self.internal_execute_decision(
+            treasury,
+            pool,
+            counter_idx,
+            leg.decision,
+            exchange_rate,
+            Some(&swap_info),
+        )
+    }
+
+    #[private]
+    fn handle_oracle_divergence(&self, #[callback] swap_info: PoolInfo) -> OracleDivergence {
+        let treasury = self.treasury.get().expect("Valid treasury");
+        let cached = treasury
+            .cache
+            .items
+            .last()
+            .unwrap_or_else(|| env::panic_str("No cached exchange rate"));
+        let pool_rate = swap_pool_rate(&treasury.config.swap, &swap_info)
+            .unwrap_or_else(|| env::panic_str("Swap pool has no NEAR price"));
+
+        OracleDivergence {
+            pool_rate,
+            cached_rate: cached.value,
+            cached_at: cached.timestamp,
+            divergence: (pool_rate - cached.value) / cached.value,
+        }
+    }
+
+    #[private]
+    fn handle_audit_treasury(
+        &mut self,
+        pool_id: u64,
+        #[callback] predicted_amounts: Vec<U128>,
+    ) -> ReserveAudit {
+        let pool = Pool::from_config_with_assert(pool_id);
+        require!(
+            predicted_amounts.len() == pool.tokens.len(),
+            "Wrong number of predicted amounts"
+        );
+
+        let value_usd = predicted_amounts
+            .iter()
+            .zip(pool.decimals.iter())
+            .map(|(amount, decimals)| whole_units(amount.0, *decimals))
+            .sum::<f64>();
+
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        let expected_usd = treasury
+            .reserve_snapshots
+            .get(&pool.id)
+            .map(|snapshot| snapshot.value_usd + snapshot.recorded_usd);
+        let flagged = expected_usd.map_or(false, |expected_usd| {
+            (value_usd - expected_usd).abs() > treasury.config.max_audit_drift() * expected_usd
+        });
+        treasury.reserve_snapshots.insert(
+            pool.id,
+            ReserveSnapshot {
+                timestamp: env::block_timestamp(),
+                value_usd,
+                recorded_usd: 0.,
+            },
+        );
+        self.treasury.replace(&treasury);
+
+        event::emit::treasury_audit_result(pool.id, value_usd, expected_usd, flagged);
+
+        ReserveAudit {
+            value_usd,
+            expected_usd,
+            flagged,
+        }
+    }
+
+    #[private]
+    fn handle_warmup_from_pool(&mut self, pool_id: u64, samples: u8, #[callback] info: PoolInfo) {
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        let rate = swap_pool_rate(&treasury.config.swap, &info)
+            .unwrap_or_else(|| env::panic_str(&format!("Pool {} has no NEAR price", pool_id)));
+        require!(
+            rate.is_finite() && rate > 0.,
+            "Pool rate must be a positive number"
+        );
+
+        // A manipulated pool must not replace the cached rates.
+        let cached = &treasury.cache.items;
+        if let Some(max_divergence) = treasury.config.max_spot_divergence {
+            if !cached.is_empty() {
+                let average =
+                    cached.iter().map(|item| item.value).sum::<f64>() / cached.len() as f64;
+                require!(
+                    (rate - average).abs() / average <= max_divergence,
+                    &format!(
+                        "Pool rate {} diverges from the cached average {}",
+                        rate, average
+                    )
+                );
+            }
+        }
+
+        treasury
+            .cache
+            .backfill(env::block_timestamp(), samples as usize, rate);
+        self.treasury.replace(&treasury);
+    }
+
+    #[private]
+    #[payable]
+    fn handle_swap_after_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        swap_actions: Vec<SwapAction>,
+        usn_amount: U128,
+        min_fill: U128,
+    ) -> Promise {
+        require!(is_promise_success(), "Liquidity removal failed");
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let removed_usd = swap_actions
+            .iter()
+            .map(|action| usd_of_counter(&action.token_in, action.amount_in.map_or(0, |a| a.0)))
+            .sum::<f64>()
+            + whole_units(usn_amount.0, USN_DECIMALS);
+        treasury.record_position_change(pool_id, -removed_usd);
+        treasury.record_gas_checkpoint(pool_id, "swap_after_remove_liquidity");
+        self.treasury.set(&treasury);
+        let config = treasury.config;
+        swap_and_withdraw(
+            &config.swap,
+            pool_id,
+            swap_actions,
+            usn_amount,
+            min_fill,
+            config.burn_gas(),
+        )
+    }
+
+    #[private]
+    #[payable]
+    fn handle_withdraw_after_swap(
+        &mut self,
+        pool_id: u64,
+        counter_usd: f64,
+        usn_amount: U128,
+        bounds: SwapBounds,
+        #[callback_result] wrap_amount: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()> {
+        let SwapBounds {
+            min_amount_out: min_wrap_amount,
+            min_fill,
+        } = bounds;
+        let config = self.treasury.get().expect("Valid treasury").config;
+        let burn_gas = config.burn_gas();
+        let swap = config.swap;
+        let wrap_id = swap.wrap_id;
+        let usn_id = env::current_account_id();
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        let wrap_amount = match wrap_amount {
+            Ok(wrap_amount) => wrap_amount,
+            Err(err) => {
+                emit_swap_failure(pool.id, &wrap_id, min_wrap_amount, err);
+                self.record_swap_failure(pool.id);
+                return PromiseOrValue::Value(());
+            }
+        };
+
+        event::emit::treasury_swap(pool.id, &wrap_id, wrap_amount.0);
+        self.record_swap_flows(TreasuryDecision::Sell(counter_usd), wrap_amount.0);
+        self.record_gas_checkpoint(pool.id, "withdraw_after_swap");
+
+        let withdraw = ext_ref_finance::withdraw(
+            wrap_id.clone(),
+            wrap_amount,
+            None,
+            swap.swap_ref_id,
+            ONE_YOCTO,
+            GAS_FOR_WITHDRAW,
+        )
+        .and(ext_ref_finance::withdraw(
+            usn_id.clone(),
+            usn_amount,
+            None,
+            pool.ref_id,
+            ONE_YOCTO,
+            GAS_FOR_WITHDRAW,
+        ));
+
+        // A bad fill keeps wNEAR in the reserve and USN unburned, the treasury
+        // holds it out of the circulating USN.
+        if wrap_amount.0 < min_fill.0 {
+            event::emit::treasury_fill_rejected(pool.id, &wrap_id, wrap_amount.0, min_fill.0);
+            return withdraw
+                .then(ext_self::handle_rejected_fill(
+                    wrap_id,
+                    wrap_amount,
+                    usn_id,
+                    NO_DEPOSIT,
+                    GAS_FOR_HANDLE_RESERVE,
+                ))
+                .into();
+        }
+
+        withdraw
+            .then(ext_self::handle_unwrap_after_withdraw(
+                usn_amount,
+                wrap_amount,
+                usn_id,
+                ONE_YOCTO,
+                GAS_SURPLUS + GAS_FOR_NEAR_WITHDRAW + burn_gas,
+            ))
+            .into()
+    }
+
+    #[private]
+    #[payable]
+    fn handle_unwrap_after_withdraw(
+        &mut self,
+        usn_amount: U128,
+        wrap_amount: U128,
+    ) -> PromiseOrValue<()> {
+        require!(
+            (0..env::promise_results_count())
+                .all(|idx| matches!(env::promise_result(idx), PromiseResult::Successful(_))),
+            "Withdrawal failed"
+        );
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let wrap_id = treasury.config.swap.wrap_id.clone();
+
+        if treasury.config.keep_wrapped {
+            // wNEAR counts as NEAR of the reserve.
+            let held = treasury.reserve.get(&wrap_id).map_or(0, |amount| amount.0);
+            treasury.reserve.insert(wrap_id, U128(held + wrap_amount.0));
+            self.treasury.set(&treasury);
+            self.internal_burn_sold_usn(usn_amount.0);
+            return PromiseOrValue::Value(());
+        }
+
+        ext_ft::near_withdraw(wrap_amount, wrap_id, ONE_YOCTO, GAS_FOR_NEAR_WITHDRAW)
+            .then(ext_self::finish_withdraw_with_burn(
+                usn_amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                treasury.config.burn_gas(),
+            ))
+            .into()
+    }
+
+    #[private]
+    #[payable]
+    fn handle_liquidity_after_swap(
+        &mut self,
+        pool_id: u64,
+        counter_id: AccountId,
+        near_amount: U128,
+        bounds: SwapBounds,
+        #[callback_result] amount: Result<U128, PromiseError>,
+    ) -> PromiseOrValue<()> {
+        let SwapBounds {
+            min_amount_out: min_amount,
+            min_fill,
+        } = bounds;
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        let amount = match amount {
+            Ok(amount) => amount,
+            Err(err) => {
+                emit_swap_failure(pool.id, &counter_id, min_amount, err);
+                self.record_swap_failure(pool.id);
+                return PromiseOrValue::Value(());
+            }
+        };
+
+        event::emit::treasury_swap(pool.id, &counter_id, amount.0);
+        self.record_swap_flows(
+            TreasuryDecision::Buy(usd_of_counter(&counter_id, amount.0)),
+            near_amount.0,
+        );
+        self.record_gas_checkpoint(pool.id, "liquidity_after_swap");
+
+        // A bad fill is withdrawn to the reserve instead of the pool.
+        if amount.0 < min_fill.0 {
+            event::emit::treasury_fill_rejected(pool.id, &counter_id, amount.0, min_fill.0);
+            let swap_ref_id = self
+                .treasury
+                .get()
+                .expect("Valid treasury")
+                .config
+                .swap
+                .swap_ref_id;
+            return ext_ref_finance::withdraw(
+                counter_id.clone(),
+                amount,
+                None,
+                swap_ref_id,
+                ONE_YOCTO,
+                GAS_FOR_WITHDRAW,
+            )
+            .then(ext_self::handle_rejected_fill(
+                counter_id,
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_RESERVE,
+            ))
+            .into();
+        }
+
+        let add_amounts = pool
+            .tokens
+            .iter()
+            .map(|token_id| {
+                if token_id == &counter_id {
+                    amount
+                } else {
+                    U128(0u128)
+                }
+            })
+            .collect();
+
+        let pool_id = pool.id;
+        add_liquidity(pool, add_amounts)
+            .then(ext_self::handle_add_liquidity(
+                pool_id,
+                counter_id,
+                amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_ADD_LIQUIDITY,
+            ))
+            .into()
+    }
+
+    /// Keeps the withdrawn output of the rejected fill in the reserve.
+    #[private]
+    fn handle_rejected_fill(&mut self, token_id: AccountId, amount: U128) {
+        require!(
+            (0..env::promise_results_count())
+                .all(|idx| matches!(env::promise_result(idx), PromiseResult::Successful(_))),
+            "Withdrawal failed"
+        );
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let held = treasury.reserve.get(&token_id).map_or(0, |amount| amount.0);
+        treasury.reserve.insert(token_id, U128(held + amount.0));
+        self.treasury.set(&treasury);
+    }
+
+    /// Adds `amounts` to the pool expecting at least the proportional shares
+    /// of the current pool state less the tolerance.
+    #[private]
+    #[payable]
+    fn handle_add_stable_liquidity(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+        #[callback] info: StablePoolInfo,
+    ) -> Promise {
+        let pool = Pool::from_config_with_assert(pool_id);
+        let min_shares = min_shares(&info, &amounts, MIN_SHARES_TOLERANCE_BPS);
+
+        ext_ref_finance::add_stable_liquidity(
+            pool.id,
+            amounts,
+            min_shares,
+            pool.ref_id,
+            ONE_YOCTO,
+            GAS_FOR_ADD_LIQUIDITY,
+        )
+    }
+
+    /// The bought counter-token stays in the ref-finance deposit if the liquidity
+    /// isn't added, `compound_treasury_rewards` adds it later.
+    #[private]
+    fn handle_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        counter_id: AccountId,
+        amount: U128,
+        #[callback_result] shares: Result<U128, PromiseError>,
+    ) {
+        match shares {
+            Ok(_) => {
+                let mut treasury = self.treasury.get().expect("Valid treasury");
+                treasury.record_position_change(pool_id, usd_of_counter(&counter_id, amount.0));
+                treasury.record_gas_checkpoint(pool_id, "add_liquidity");
+                self.treasury.set(&treasury);
+            }
+            Err(err) => event::emit::treasury_add_liquidity_failed(
+                pool_id,
+                &counter_id,
+                amount.0,
+                failure_reason(&err),
+            ),
+        }
+    }
+
+    #[private]
+    #[payable]
+    fn handle_compound_deposits(
+        &mut self,
+        pool_id: u64,
+        #[callback] deposits: HashMap<AccountId, U128>,
+    ) -> PromiseOrValue<()> {
+        let pool = Pool::from_config_with_assert(pool_id);
+        let usn_id = env::current_account_id();
+
+        let add_amounts: Vec<U128> = pool
+            .tokens
+            .iter()
+            .map(|token_id| match deposits.get(token_id) {
+                Some(amount) if token_id != &usn_id => *amount,
+                _ => U128(0),
+            })
+            .collect();
+
+        if add_amounts.iter().all(|amount| amount.0 == 0) {
+            env::log_str(&format!(
+                "No idle deposits to compound in the pool {}",
+                pool.id
+            ));
+            return PromiseOrValue::Value(());
+        }
+
+        add_liquidity(pool, add_amounts).into()
+    }
+
+    #[private]
+    #[payable]
+    fn handle_remove_all_liquidity(&mut self, pool_id: u64, #[callback] shares: U128) -> Promise {
+        require!(
+            shares.0 > 0,
+            &format!("No liquidity in the pool {}", pool_id)
+        );
+        let pool = Pool::from_config_with_assert(pool_id);
+
+        ext_ref_finance::remove_liquidity(
+            pool.id,
+            shares,
+            vec![U128(0); pool.tokens.len()],
+            pool.ref_id,
+            ONE_YOCTO,
+            GAS_FOR_REMOVE_LIQUIDITY,
+        )
+        .then(ext_self::handle_withdraw_removed_liquidity(
+            pool.id,
+            shares,
+            env::current_account_id(),
+            env::attached_deposit() - ONE_YOCTO,
+            GAS_SURPLUS + GAS_FOR_WITHDRAW * pool.tokens.len() as u64,
+        ))
+    }
+
+    #[private]
+    #[payable]
+    fn handle_withdraw_removed_liquidity(
+        &mut self,
+        pool_id: u64,
+        shares: U128,
+        #[callback] amounts: Vec<U128>,
+    ) -> PromiseOrValue<()> {
+        let pool = Pool::from_config_with_assert(pool_id);
+        require!(
+            amounts.len() == pool.tokens.len(),
+            "Wrong number of removed amounts"
+        );
+
+        event::emit::treasury_liquidity_withdrawn(pool.id, shares.0, &pool.tokens, &amounts);
+
+        pool.tokens
+            .iter()
+            .zip(amounts)
+            .filter(|(_, amount)| amount.0 > 0)
+            .map(|(token_id, amount)| {
+                ext_ref_finance::withdraw(
+                    token_id.clone(),
+                    amount,
+                    None,
+                    pool.ref_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FOR_WITHDRAW,
+                )
+            })
+            .reduce(|acc, promise| acc.and(promise))
+            .map_or(PromiseOrValue::Value(()), PromiseOrValue::Promise)
+    }
+
+    /// Puts the wNEAR back to the reserve if it hasn't been unwrapped.
+    #[private]
+    fn handle_unwrap_reserve(&mut self, amount: U128) {
+        if is_promise_success() {
+            return;
+        }
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let wrap_id = treasury.config.swap.wrap_id.clone();
+        let held = treasury.reserve.get(&wrap_id).map_or(0, |amount| amount.0);
+        treasury.reserve.insert(wrap_id, U128(held + amount.0));
+        self.treasury.set(&treasury);
+    }
+
+    /// Results of `storage_balance_of` come in the order of `token_ids`.
+    #[private]
+    #[payable]
+    fn handle_withdraw_registration(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        register: bool,
+    ) -> Vec<AccountId> {
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let unregistered: Vec<AccountId> = token_ids
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, token_id)| {
+                let registered = match env::promise_result(idx as u64) {
+                    PromiseResult::Successful(data) => {
+                        near_sdk::serde_json::from_slice::<Option<StorageBalance>>(&data)
+                            .map_or(false, |balance| balance.is_some())
+                    }
+                    _ => false,
+                };
+                if registered {
+                    treasury.registered_tokens.insert(token_id);
+                    None
+                } else {
+                    treasury.registered_tokens.remove(&token_id);
+                    Some(token_id)
+                }
+            })
+            .collect();
+        self.treasury.set(&treasury);
+
+        if register && !unregistered.is_empty() {
+            let deposit = env::attached_deposit() / unregistered.len() as u128;
+            let remainder = env::attached_deposit() - deposit * unregistered.len() as u128;
+            if remainder > 0 {
+                Promise::new(env::signer_account_id()).transfer(remainder);
+            }
+            for token_id in &unregistered {
+                ext_ft::storage_deposit(
+                    Some(env::current_account_id()),
+                    Some(true),
+                    token_id.clone(),
+                    deposit,
+                    GAS_FOR_STORAGE_DEPOSIT,
+                )
+                .then(ext_self::handle_storage_registration(
+                    token_id.clone(),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_HANDLE_RESERVE,
+                ));
+            }
+        } else if env::attached_deposit() > 0 {
+            Promise::new(env::signer_account_id()).transfer(env::attached_deposit());
+        }
+
+        unregistered
+    }
+
+    #[private]
+    fn handle_storage_registration(&mut self, token_id: AccountId) {
+        if !is_promise_success() {
+            env::log_str(&format!("Registration with {} failed", token_id));
+            return;
+        }
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        treasury.registered_tokens.insert(token_id);
+        self.treasury.set(&treasury);
+    }
+
+    #[private]
+    fn handle_exchange_rate_cache(
+        &mut self,
+        retries: u8,
+        #[callback_result] price: Result<PriceData, PromiseError>,
+        #[callback_result] swap_info: Result<PoolInfo, PromiseError>,
+    ) -> PromiseOrValue<()> {
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let cached_at = treasury.cache.items.last().map(|item| item.timestamp);
+
+        let oracle_rate = match price {
+            // Appending an older price would break the order of the cached rates.
+            Ok(price) if cached_at.map_or(false, |cached_at| price.timestamp() < cached_at) => {
+                reject_out_of_order_price(
+                    treasury.config.out_of_order_price_policy,
+                    price.timestamp(),
+                    cached_at.unwrap(),
+                );
+                None
+            }
+            Ok(price) if !price.is_outdated() => {
+                treasury.counter_prices = oracle_counter_prices(&treasury.config, &price);
+                Some(price_data_to_rate(price))
+            }
+            _ => None,
+        };
+        let pool_rate = swap_info
+            .ok()
+            .and_then(|info| swap_pool_rate(&treasury.config.swap, &info));
+
+        let rate = match select_rate(&treasury.config.rate_precedence, oracle_rate, pool_rate) {
+            Ok(rate) => rate,
+            Err(err) if retries > 0 => {
+                env::log_str(&format!("{}, retrying ({} left)", err, retries - 1));
+                return PromiseOrValue::Promise(fetch_exchange_rate(&treasury.config, retries - 1));
+            }
+            Err(err) => env::panic_str(&err),
+        };
+        treasury.cache.append(env::block_timestamp(), rate);
+        self.treasury.set(&treasury);
+        PromiseOrValue::Value(())
+    }
+
+    #[private]
+    fn predict_remove_liquidity(&self, pool_id: u64, #[callback] shares: U128) -> Promise {
+        let pool = Pool::from_config_with_assert(pool_id);
+        ext_ref_finance::predict_remove_liquidity(
+            pool.id,
+            shares,
+            pool.ref_id,
+            NO_DEPOSIT,
+            GAS_FOR_PREDICT_REMOVE_LIQUIDITY,
+        )
+    }
+
+    #[private]
+    fn finish_withdraw_with_burn(&mut self, amount: U128) {
+        if is_promise_success() {
+            self.internal_burn_sold_usn(amount.into());
+        }
+    }
+
+    #[private]
+    fn finish_treasury_balancing(
+        &mut self,
+        pool_id: u64,
+        decision: TreasuryDecision,
+    ) -> PromiseOrValue<TreasuryDecision> {
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.in_flight.remove(&pool_id);
+        treasury.committed_near.remove(&pool_id);
+        let swap_failed = treasury.failed_swaps.remove(&pool_id);
+        let decision = decision.reported(
+            &treasury.config,
+            treasury.usn_usd_rate.unwrap_or(USN_USD_RATE),
+        );
+        treasury.record_gas_checkpoint(pool_id, "finish");
+        if let Some(checkpoints) = treasury.gas_checkpoints.remove(&pool_id) {
+            let checkpoints: Vec<(&str, Gas)> = checkpoints
+                .iter()
+                .map(|checkpoint| (checkpoint.checkpoint.as_str(), checkpoint.used_gas))
+                .collect();
+            event::emit::treasury_rebalance_gas_used(pool_id, &checkpoints);
+        }
+        self.treasury.replace(&treasury);
+
+        // The lock is released above, the failure is reported by the next receipt.
+        if swap_failed {
+            return ext_self::fail_treasury_balancing(
+                pool_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_FAIL_BALANCING,
+            )
+            .into();
+        }
+        PromiseOrValue::Value(decision)
+    }
+
+    #[private]
+    fn fail_treasury_balancing(&self, pool_id: u64) {
+        env::panic_str(&format!("Swap of the pool {} failed", pool_id));
+    }
+
+    #[private]
+    fn handle_balance_treasuries(&self, pool_ids: Vec<u64>) -> Vec<PerPoolResult> {
+        pool_ids
+            .into_iter()
+            .enumerate()
+            .map(|(idx, pool_id)| {
+                let outcome = match env::promise_result(idx as u64) {
+                    PromiseResult::Successful(data) => {
+                        near_sdk::serde_json::from_slice::<TreasuryDecision>(&data)
+                            .map_err(|_| "Invalid decision".to_string())
+                    }
+                    _ => Err("Balancing failed".to_string()),
+                };
+                PerPoolResult {
+                    pool_id,
+                    decision: outcome.clone().ok(),
+                    error: outcome.err(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Rejects the decision limit above the USD value of the whole reserve before
+    /// the pools are queried, whatever the excess limit policy is. The counter-token
+    /// part of the reserve isn't known yet, the USN supply it backs bounds it.
+    fn assert_plausible_decision_limit(
+        &self,
+        treasury: &TreasuryData,
+        decision_limit: Option<DecisionLimit>,
+        near_rate: f64,
+    ) {
+        if let Some(limit) = decision_limit {
+            let wrapped = treasury
+                .reserve
+                .get(&treasury.config.swap.wrap_id)
+                .map_or(0, |amount| amount.0);
+            let near = (env::account_balance() - env::attached_deposit()) + wrapped;
+            let reserve_usd = whole_units(near, NEAR_DECIMALS) * near_rate
+                + whole_units(self.token.ft_total_supply().0, USN_DECIMALS);
+            assert_plausible_limit(
+                limit.unit.to_usd(limit.amount as f64, near_rate),
+                reserve_usd,
+            );
+        }
+    }
+
+    /// Marks the pool being balanced to fail in `finish_treasury_balancing`.
+    fn record_swap_failure(&mut self, pool_id: u64) {
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        if treasury.in_flight.contains_key(&pool_id) {
+            treasury.failed_swaps.insert(pool_id);
+            self.treasury.set(&treasury);
+        }
+    }
+
+    fn record_gas_checkpoint(&mut self, pool_id: u64, checkpoint: &str) {
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        treasury.record_gas_checkpoint(pool_id, checkpoint);
+        self.treasury.set(&treasury);
+    }
+
+    /// Adds the realized swap of a buy or a sell: the USD of the counter-token
+    /// and the yocto NEAR on the other side of the swap.
+    fn record_swap_flows(&mut self, swap: TreasuryDecision, near: u128) {
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        match swap {
+            TreasuryDecision::Buy(usd) => treasury.flows.record_buy(near, usd),
+            TreasuryDecision::Sell(usd) => treasury.flows.record_sell(usd, near),
+            TreasuryDecision::DoNothing => return,
+        }
+        self.treasury.set(&treasury);
+    }
+
+    /// Makes the decision on the live pool state, follows `last_decision` if it's given.
+    /// Returns the decision, the latest exchange rate and the collateral ratio of the reserve.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_make_decision(
+        &self,
+        treasury: &TreasuryData,
+        pool: &Pool,
+        counter_idx: usize,
+        predicted_amounts: &[U128],
+        info: &StablePoolInfo,
+        swap_info: &PoolInfo,
+        decision_limit: Option<DecisionLimit>,
+        last_decision: Option<TreasuryDecision>,
+        wrap_balance: Option<Balance>,
+    ) -> (TreasuryDecision, f64, f64) {
+        // Prepare input data to make decision about balancing.
+
+        // 1. NEAR/USDT exchange rates.
+        let (time_points, exchange_rates) = match treasury
+            .cache
+            .collect(env::block_timestamp(), treasury.config.decision.time_unit())
+        {
+            Ok((time_points, exchange_rates)) => (time_points, exchange_rates),
+            Err(err) => env::panic_str(&err.to_string()),
+        };
+
+        // 2. NEAR part of USN reserve in NEAR, including wNEAR kept after sells,
+        // without NEAR committed to buys still in flight and the NEAR buffer.
+        // The fetched wNEAR balance supersedes the wNEAR recorded in the reserve.
+        let wrapped = wrap_balance.unwrap_or_else(|| {
+            treasury
+                .reserve
+                .get(&treasury.config.swap.wrap_id)
+                .map_or(0, |amount| amount.0)
+        });
+        let committed: u128 = treasury.committed_near.values().map(|near| near.0).sum();
+        // The attached deposit isn't a part of the reserve: the trade consumes it
+        // and the rest is refunded.
+        let balance = env::account_balance() - env::attached_deposit();
+        let buffer = treasury
+            .config
+            .near_buffer
+            .as_ref()
+            .map_or(0, |buffer| buffer.amount(balance));
+        let near = (balance + wrapped).saturating_sub(committed + buffer);
+
+        // 3. Total value of circulating USN, without USN of the pool and USN held
+        // by the treasury like the USN of rejected sell fills.
+        let usn = self
+            .token
+            .ft_total_supply()
+            .0
+            .saturating_sub(extract_usn_amount(pool, &info.amounts).0)
+            .saturating_sub(self.token.ft_balance_of(env::current_account_id()).0);
+
+        // 4. USDT reserve.
+        let usdt = predicted_amounts[counter_idx].0;
+
+        // Convert everything into floats.
+        let near = whole_units(near, NEAR_DECIMALS);
+        let usn = whole_units(usn, USN_DECIMALS);
+        let last_exch_rate = *exchange_rates.last().unwrap();
+        let twap = exchange_rates.iter().sum::<f64>() / exchange_rates.len() as f64;
+        let counter_price = treasury.config.counter_price_source.price(
+            pool,
+            info,
+            counter_idx,
+            &treasury.counter_prices,
+        );
+        let usdt = whole_units(usdt, pool.decimals[counter_idx]) * counter_price;
+        let limit =
+            decision_limit.map(|limit| limit.unit.to_usd(limit.amount as f64, last_exch_rate));
+
+        let verbosity = treasury.config.log_verbosity;
+
+        if verbosity == LogVerbosity::Verbose {
+            env::log_str(&format!(
+                "Reserve: NEAR = {}, USN = {}, USDT = {}, exchange rate = {}, limit = {:?}",
+                near, usn, usdt, last_exch_rate, limit
+            ));
+        }
+
+        let reused = last_decision.is_some();
+
+        // Make a decision unless the market is too volatile to act safely.
+        let tvl = pool_tvl(info);
+        let elapsed = treasury
+            .last_trade_at
+            .map(|last_trade_at| (env::block_timestamp() - last_trade_at) as f64 / 1e9);
+        let decision = if let Some(last_decision) = last_decision {
+            if verbosity == LogVerbosity::Verbose {
+                env::log_str("Treasury decision is reused");
+            }
+            last_decision
+        } else if usn < MIN_CIRCULATING_USN {
+            if verbosity != LogVerbosity::Off {
+                env::log_str(&format!(
+                    "Circulating USN is too low for balancing: {}",
+                    usn
+                ));
+            }
+            TreasuryDecision::DoNothing
+        } else if is_too_volatile(&exchange_rates, treasury.config.max_volatility) {
+            if verbosity != LogVerbosity::Off {
+                env::log_str("Market is too volatile for balancing");
+            }
+            TreasuryDecision::DoNothing
+        } else if treasury
+            .config
+            .min_pool_tvl
+            .map_or(false, |min_tvl| tvl < min_tvl)
+        {
+            if verbosity != LogVerbosity::Off {
+                env::log_str(&format!("Pool TVL is too low for balancing: ${}", tvl));
+            }
+            TreasuryDecision::DoNothing
+        } else {
+            make_treasury_decision(
+                &treasury.config.decision,
+                exchange_rates,
+                time_points,
+                ReserveState {
+                    near,
+                    usn,
+                    usdt,
+                    limit,
+                    elapsed,
+                },
+                verbosity == LogVerbosity::Verbose,
+            )
+        };
+
+        // The limit above the reserve to trade is a mistake of the caller if rejected.
+        let reserve_usd = match decision {
+            TreasuryDecision::Buy(_) => near * last_exch_rate,
+            TreasuryDecision::Sell(_) => usdt,
+            TreasuryDecision::DoNothing => f64::INFINITY,
+        };
+        let decision = match limit {
+            Some(limit)
+                if !reused
+                    && treasury.config.excess_limit_policy == ExcessLimitPolicy::Reject
+                    && limit > reserve_usd =>
+            {
+                if verbosity != LogVerbosity::Off {
+                    env::log_str(&format!(
+                        "Decision limit {} exceeds the reserve to trade of {} USD",
+                        limit, reserve_usd
+                    ));
+                }
+                TreasuryDecision::DoNothing
+            }
+            _ => decision,
+        };
+
+        // The reused decision has been already smoothed and clamped.
+        let decision = if reused {
+            decision
+        } else {
+            clamp_trade_size(
+                smooth_decision(decision, treasury.config.smoothing),
+                &treasury.config.trade_size_bounds,
+            )
+        };
+
+        // A swap pool price far from the cached rates may be manipulated.
+        let decision = match treasury.config.max_spot_divergence {
+            Some(max_divergence) if decision != TreasuryDecision::DoNothing => {
+                let spot = swap_spot_price(
+                    &treasury.config.swap.wrap_id,
+                    &pool.tokens[counter_idx],
+                    pool.decimals[counter_idx],
+                    swap_info,
+                ) * counter_price;
+                if (spot - twap).abs() / twap > max_divergence {
+                    if verbosity != LogVerbosity::Off {
+                        env::log_str(&format!(
+                            "Swap pool price ${} diverges from the cached average ${}",
+                            spot, twap
+                        ));
+                    }
+                    TreasuryDecision::DoNothing
+                } else {
+                    decision
+                }
+            }
+            _ => decision,
+        };
+
+        // The counter-token removed from or added to the stable pool is swapped in
+        // the swap pool, diverging prices of the pools leak value to arbitrage.
+        let decision = match treasury.config.max_cross_pool_divergence {
+            Some(max_divergence) if decision != TreasuryDecision::DoNothing => {
+                let counter_id = &pool.tokens[counter_idx];
+                let swap_price = swap_spot_price(
+                    &treasury.config.swap.wrap_id,
+                    counter_id,
+                    pool.decimals[counter_idx],
+                    swap_info,
+                );
+                let implied_price = last_exch_rate
+                    / CounterPriceSource::PoolRatio.price(
+                        pool,
+                        info,
+                        counter_idx,
+                        &treasury.counter_prices,
+                    );
+                if (swap_price - implied_price).abs() / implied_price > max_divergence {
+                    if verbosity != LogVerbosity::Off {
+                        env::log_str(&format!(
+                            "Swap pool price {} {} of NEAR diverges from {} implied by the stable pool",
+                            swap_price, counter_id, implied_price
+                        ));
+                    }
+                    TreasuryDecision::DoNothing
+                } else {
+                    decision
+                }
+            }
+            _ => decision,
+        };
+
+        let decision = enabled_direction(decision, &treasury.config, verbosity);
+
+        (
+            decision,
+            last_exch_rate,
+            (near * last_exch_rate + usdt) / usn,
+        )
+    }
+
+    /// Locks the pool and starts the trade, stores the treasury in any case.
+    /// Swapped amounts are limited by the swap pool reserves if `swap_info` is known.
+    fn internal_execute_decision(
+        &mut self,
+        mut treasury: TreasuryData,
+        pool: Pool,
+        counter_idx: usize,
+        decision: TreasuryDecision,
+        exchange_rate: f64,
+        swap_info: Option<&PoolInfo>,
+    ) -> PromiseOrValue<TreasuryDecision> {
+        if let TreasuryDecision::Sell(_) = decision {
+            assert_withdraw_registration(&treasury, &pool.tokens[counter_idx]);
+        }
+        refund_unused_deposit(&decision);
+        if decision != TreasuryDecision::DoNothing {
+            treasury.in_flight.insert(pool.id, env::block_height());
+            treasury.gas_checkpoints.insert(pool.id, Vec::new());
+            treasury.record_gas_checkpoint(pool.id, "start");
+            treasury.last_trade_at = Some(env::block_timestamp());
+            match decision {
+                TreasuryDecision::Buy(f_amount) => {
+                    treasury
+                        .committed_near
+                        .insert(pool.id, U128(near_to_wrap(f_amount, exchange_rate)));
+                    treasury.stats.bought_usd += f_amount;
+                }
+                TreasuryDecision::Sell(f_amount) => treasury.stats.sold_usd += f_amount,
+                TreasuryDecision::DoNothing => {}
+            }
+        }
+        self.treasury.set(&treasury);
+
+        execute_with(
+            treasury.config.execution_venue.adapter(),
+            &treasury.config,
+            pool,
+            counter_idx,
+            decision,
+            exchange_rate,
+            swap_info,
+        )
+    }
+
+    /// Burns USN withdrawn by a sell up to the burn ceiling. USN above it stays
+    /// in the treasury as withheld, the ceiling left by next sells burns it,
+    /// or `burn_treasury_usn` if it's legit.
+    fn internal_burn_sold_usn(&mut self, amount: Balance) {
+        let mut treasury = self.treasury.get().expect("Valid treasury");
+        let withheld = treasury.withheld_usn.map_or(0, |amount| amount.0);
+        let (allowed, backlog) = match &treasury.config.burn_ceiling {
+            Some(ceiling) => {
+                let allowed = ceiling.allowed(amount);
+                (allowed, min(withheld, ceiling.max_amount.0 - allowed))
+            }
+            None => (amount, withheld),
+        };
+        if allowed < amount {
+            env::log_str(&format!(
+                "USN burn of {} exceeds the ceiling, {} is burned, {} stays in the treasury",
+                amount,
+                allowed,
+                amount - allowed
+            ));
+        }
+        if backlog > 0 {
+            env::log_str(&format!("Withheld USN of {} is burned", backlog));
+        }
+        let withheld = withheld - backlog + (amount - allowed);
+        treasury.withheld_usn = (withheld > 0).then_some(U128(withheld));
+        self.treasury.set(&treasury);
+        if allowed + backlog > 0 {
+            self.internal_burn_treasury_usn(allowed + backlog);
+        }
+    }
+
+    /// Drops the audit snapshot of the pool position changed beyond treasury trades,
+    /// the next audit observes a new one.
+    pub(super) fn internal_reset_reserve_snapshot(&mut self, pool_id: u64) {
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.reserve_snapshots.remove(&pool_id);
+        self.treasury.replace(&treasury);
+    }
+
+    fn internal_burn_treasury_usn(&mut self, amount: Balance) {
+        self.token
+            .internal_withdraw(&env::current_account_id(), amount);
+        event::emit::ft_burn(&env::current_account_id(), amount, None);
+    }
+}
+
+/// Reports the failed swap, ref-finance reasons like the slippage or a paused pool
+/// don't reach callbacks.
+fn emit_swap_failure(pool_id: u64, token_out: &AccountId, min_amount_out: U128, err: PromiseError) {
+    event::emit::treasury_swap_failed(pool_id, token_out, min_amount_out.0, failure_reason(&err));
+}
+
+fn failure_reason(err: &PromiseError) -> &'static str {
+    match err {
+        PromiseError::NotReady => "not_ready",
+        _ => "failed",
+    }
+}
+
+/// Trades the decision through the adapter, the swap pool reserves limit the swap input.
+fn execute_with(
+    adapter: &dyn ExecutionAdapter,
+    config: &TreasuryConfig,
+    pool: Pool,
+    counter_idx: usize,
+    decision: TreasuryDecision,
+    exchange_rate: f64,
+    swap_info: Option<&PoolInfo>,
+) -> PromiseOrValue<TreasuryDecision> {
+    let max_in = |token_id: &AccountId| swap_info.and_then(|info| swap_reserve(info, token_id));
+    match decision {
+        TreasuryDecision::DoNothing => PromiseOrValue::Value(decision),
+        TreasuryDecision::Buy(amount) => {
+            let max_in = max_in(&config.swap.wrap_id);
+            adapter
+                .execute_buy(
+                    config,
+                    TradeOrder {
+                        pool,
+                        counter_idx,
+                        amount,
+                        exchange_rate,
+                        max_in,
+                    },
+                )
+                .into()
+        }
+        TreasuryDecision::Sell(amount) => {
+            let max_in = max_in(&pool.tokens[counter_idx]);
+            adapter
+                .execute_sell(
+                    config,
+                    TradeOrder {
+                        pool,
+                        counter_idx,
+                        amount,
+                        exchange_rate,
+                        max_in,
+                    },
+                )
+                .into()
+        }
+    }
+}
+
+/// YoctoNEAR attached to ref-finance calls of the decision: a buy swaps and adds
+/// liquidity, a sell removes liquidity, swaps and withdraws.
+fn deposit_for(decision: &TreasuryDecision) -> Balance {
+    match decision {
+        TreasuryDecision::Buy(_) => 2 * ONE_YOCTO,
+        TreasuryDecision::Sell(_) => 3 * ONE_YOCTO,
+        TreasuryDecision::DoNothing => 0,
+    }
+}
+
+/// Returns the share of the attached deposit the decision doesn't consume to the signer.
+/// Deposits of split legs beyond the first 3 yoctoNEAR are left for their quotes.
+fn refund_unused_deposit(decision: &TreasuryDecision) {
+    let own = std::cmp::min(env::attached_deposit(), 3 * ONE_YOCTO);
+    let unused = own.saturating_sub(deposit_for(decision));
+    if unused > 0 {
+        Promise::new(env::signer_account_id()).transfer(unused);
+    }
+}
+
+/// Number of split legs paid by the attached deposit besides the own chain.
+fn paid_leg_count() -> u64 {
+    (env::attached_deposit() / (3 * ONE_YOCTO)).saturating_sub(1) as u64
+}
+
+/// Returns deposits of the split legs which aren't quoted to the signer.
+fn refund_unquoted_legs(quoted: usize) {
+    let unquoted = paid_leg_count().saturating_sub(quoted as u64);
+    if unquoted > 0 {
+        Promise::new(env::signer_account_id()).transfer(3 * ONE_YOCTO * unquoted as Balance);
+    }
+}
+
+/// Quotes the pool before adding `add_amounts` to bound the minted shares.
+fn add_liquidity(pool: Pool, add_amounts: Vec<U128>) -> Promise {
+    ext_ref_finance::get_stable_pool(pool.id, pool.ref_id, NO_DEPOSIT, GAS_FOR_GET_STABLE_POOL)
+        .then(ext_self::handle_add_stable_liquidity(
+            pool.id,
+            add_amounts,
+            env::current_account_id(),
+            ONE_YOCTO,
+            GAS_FOR_HANDLE_STABLE_POOL + GAS_FOR_ADD_LIQUIDITY,
+        ))
+}
+
+fn gas_for_add_liquidity() -> Gas {
+    GAS_FOR_GET_STABLE_POOL + GAS_FOR_HANDLE_STABLE_POOL + GAS_FOR_ADD_LIQUIDITY
+}
+
+/// Identifies what a decision is made on: the cached rates, the limits, the pool
+/// reserves and the treasury reserve. A change of any of them makes a new decision.
+fn decision_state_hash(
+    treasury: &TreasuryData,
+    decision_limit: Option<DecisionLimit>,
+    predicted_amounts: &[U128],
+    info: &StablePoolInfo,
+) -> CryptoHash {
+    let state = (
+        treasury.cache.hash(),
+        decision_limit.map(|limit| (limit.amount, limit.unit)),
+        predicted_amounts,
+        &info.amounts,
+        &treasury.reserve,
+    );
+    env::sha256_array(&state.try_to_vec().unwrap())
+}
+
+/// Checks `balance_treasury` limits against the configured bounds,
+/// missing limits default to the bounds.
+fn bounded_limits(limits: Option<[u64; 2]>, bounds: Option<[u64; 2]>) -> Option<[u64; 2]> {
+    let limits = limits.or(bounds)?;
+
+    require!(
+        limits[0] <= limits[1],
+        "`limits` must be in [min; max] format"
+    );
+
+    if let Some(bounds) = bounds {
+        require!(
+            bounds[0] <= limits[0] && limits[1] <= bounds[1],
+            &format!("`limits` must be within [{}; {}]", bounds[0], bounds[1])
+        );
+    }
+
+    Some(limits)
+}
+
+/// Sells withdraw wNEAR and the counter-token `counter_id` they swap from
+/// ref-finance, a withdrawal fails after the swap if the contract isn't
+/// registered with the token.
+fn assert_withdraw_registration(treasury: &TreasuryData, counter_id: &AccountId) {
+    if !treasury.config.require_withdraw_registration {
+        return;
+    }
+    for token_id in [&treasury.config.swap.wrap_id, counter_id] {
+        require!(
+            treasury.registered_tokens.contains(token_id),
+            &format!(
+                "Not registered with {}, use `ensure_withdraw_registration`",
+                token_id
+            )
+        );
+    }
+}
+
+/// Checks that the configured interval has passed since the latest trade.
+fn assert_trade_interval(treasury: &TreasuryData) {
+    if let (Some(interval), Some(last_trade_at)) =
+        (treasury.config.min_trade_interval, treasury.last_trade_at)
+    {
+        let elapsed = env::block_timestamp().saturating_sub(last_trade_at);
+        require!(
+            elapsed >= interval,
+            &format!(
+                "Only {} of {} ns passed since the latest trade",
+                elapsed, interval
+            )
+        );
+    }
+}
+
+/// Picks the decision limit within the bounded `limits` by the random `seed`.
+fn decision_limit(
+    limits: Option<[u64; 2]>,
+    bounds: Option<[u64; 2]>,
+    seed: [u8; 32],
+) -> Option<u64> {
+    bounded_limits(limits, bounds).map(|[min, max]| {
+        let mut rng = StdRng::from_seed(seed);
+        rng.gen_range(min..=max)
+    })
+}
+
+/// Picks the decision limit in `unit`s, the limit bounds in USD are converted
+/// into them at the `near_rate`.
+fn limit_in_unit(
+    config: &TreasuryConfig,
+    limits: Option<[u64; 2]>,
+    unit: LimitUnit,
+    near_rate: f64,
+    seed: [u8; 32],
+) -> Option<DecisionLimit> {
+    // Equal bounds may round past each other, the lower one is kept within the upper one.
+    let bounds = config.limit_bounds.map(|[min, max]| {
+        let max = unit.usd_in(max as f64, near_rate).floor() as u64;
+        [
+            std::cmp::min(unit.usd_in(min as f64, near_rate).ceil() as u64, max),
+            max,
+        ]
+    });
+    decision_limit(limits, bounds, seed).map(|amount| DecisionLimit { amount, unit })
+}
+
+/// Block random seed, hashed together with the operator `entropy` if it's given.
+fn limit_seed(entropy: Option<&Base64VecU8>) -> [u8; 32] {
+    let seed = env::random_seed_array();
+    match entropy {
+        Some(entropy) => env::sha256_array(&[&seed[..], &entropy.0].concat()),
+        None => seed,
+    }
+}
+
+/// Returns the minimal amount of tokens to receive for the expected `amount`
+/// considering the expected swap fee and the slippage tolerance on top of it.
+fn min_amount_out(amount: f64, swap_fee: f64, slippage: f64, decimals: u8) -> u128 {
+    (amount * (1. - swap_fee) * (1. - slippage) * 10f64.powi(decimals as i32)) as u128
+}
+
+pub(super) fn buy(
+    config: &TreasuryConfig,
+    pool: Pool,
+    counter_idx: usize,
+    amount: f64,
+    exchange_rate: f64,
+    max_in: Option<u128>,
+) -> Promise {
+    let swap = &config.swap;
+    let wrap_id = swap.wrap_id.clone();
+    let near = near_to_wrap(amount, exchange_rate);
+    let min_amount = min_amount_out(
+        amount,
+        config.swap_fee(),
+        config.slippage.slippage(amount),
+        pool.decimals[counter_idx],
+    );
+
+    env::log_str(&format!("Trying to wrap {} NEAR", near));
+
+    let usdt_name = &pool.tokens[counter_idx];
+
+    assert_amount_in(near, max_in);
+    let swap_action = buy_swap_action(swap, usdt_name, near, min_amount);
+
+    let swap_actions = vec![swap_action];
+    assert_swap_route(&swap_actions);
+    let min_fill = config.min_fill(amount, min_amount);
+    assert_swaps_fit(
+        GAS_FOR_NEAR_DEPOSIT
+            + GAS_FOR_FT_TRANSFER_CALL
+            + gas_for_liquidity_after_swap()
+            + GAS_FOR_FINISH_BALANCING,
+    );
+
+    event::emit::treasury_trade_intent(
+        pool.id, "buy", amount, &wrap_id, near, usdt_name, min_amount,
+    );
+
+    ext_ft::near_deposit(wrap_id.clone(), near, GAS_FOR_NEAR_DEPOSIT)
+        .then(ext_ft::ft_transfer_call(
+            swap.swap_ref_id.clone(),
+            near.into(),
+            None,
+            REF_DEPOSIT_ACTION.into(),
+            wrap_id,
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER_CALL,
+        ))
+        .then(ext_ref_finance::swap(
+            swap_actions,
+            None,
+            swap.swap_ref_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_SWAP,
+        ))
+        .then(ext_self::handle_liquidity_after_swap(
+            pool.id,
+            usdt_name.clone(),
+            U128(near),
+            SwapBounds {
+                min_amount_out: U128(min_amount),
+                min_fill: U128(min_fill),
+            },
+            env::current_account_id(),
+            ONE_YOCTO,
+            gas_for_liquidity_after_swap(),
+        ))
+        // Released whether the chain succeeded or not.
+        .then(ext_self::finish_treasury_balancing(
+            pool.id,
+            TreasuryDecision::Buy(amount),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_FINISH_BALANCING,
+        ))
+}
+
+/// Gas of `handle_liquidity_after_swap` adding the liquidity or withdrawing a bad fill.
+fn gas_for_liquidity_after_swap() -> Gas {
+    GAS_SURPLUS
+        + std::cmp::max(
+            gas_for_add_liquidity() + GAS_FOR_HANDLE_ADD_LIQUIDITY,
+            GAS_FOR_WITHDRAW + GAS_FOR_HANDLE_RESERVE,
+        )
+}
+
+/// Yocto NEAR wrapped to buy `amount` USD.
+fn near_to_wrap(amount: f64, exchange_rate: f64) -> u128 {
+    ((amount / exchange_rate) * ONE_NEAR as f64) as u128
+}
+
+/// wNEAR -> counter-token swap of the buy.
+fn buy_swap_action(
+    swap: &SwapConfig,
+    counter_id: &AccountId,
+    near: u128,
+    min_amount: u128,
+) -> SwapAction {
+    SwapAction {
+        pool_id: swap.swap_pool_id,
+        amount_in: Some(near.into()),
+        token_in: swap.wrap_id.clone(),
+        token_out: counter_id.clone(),
+        min_amount_out: U128(min_amount),
+    }
+}
+
+/// Supply accounting of a burn computed without burning.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct BurnPreview {
+    /// USN held by the treasury.
+    pub balance: U128,
+    /// Part of the amount the treasury doesn't hold, zero if the burn succeeds.
+    pub shortfall: U128,
+    /// Total supply of USN after the burn.
+    pub total_supply: U128,
+}
+
+/// Amounts of a sell computed before any promise is made.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct SellPreview {
+    /// Amounts removed from the pool in the pool token order.
+    pub remove_amounts: Vec<U128>,
+    /// Minimum wNEAR received for the counter-token.
+    pub min_amount_out: U128,
+    /// USN burned after the swap.
+    pub burn_amount: U128,
+}
+
+/// Full balancing cycle computed on the live pools before any trade.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct RebalanceQuote {
+    pub decision: TreasuryDecision,
+    /// NEAR/USD exchange rate of the decision.
+    pub exchange_rate: f64,
+    /// Expected swap output: the counter-token of a buy, wNEAR of a sell.
+    pub expected_amount_out: Option<U128>,
+    /// Minimum swap output allowed by the slippage tolerance.
+    pub min_amount_out: Option<U128>,
+    /// LP shares expected for the bought counter-token.
+    pub expected_shares: Option<U128>,
+    /// USN burned after a sell.
+    pub burn_amount: Option<U128>,
+    /// Part of the spot price output lost to the swap fee and price impact.
+    pub slippage: Option<f64>,
+}
+
+/// Realized result of buys and sells.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryPnl {
+    pub flows: TreasuryFlows,
+    /// Latest cached exchange rate the net NEAR is valued at.
+    pub exchange_rate: f64,
+    /// USD received minus USD spent plus the net NEAR received in USD.
+    pub pnl: f64,
+}
+
+/// Outcome of `audit_treasury`.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReserveAudit {
+    /// Live USD value of the treasury position in the pool.
+    pub value_usd: f64,
+    /// Value of the previous audit adjusted by recorded trades, none on the first audit.
+    pub expected_usd: Option<f64>,
+    pub flagged: bool,
+}
+
+/// NEAR/USD price implied by the swap pool against the cached exchange rate.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleDivergence {
+    /// Price of 1 NEAR in the swap pool taking the counter-token at $1.
+    pub pool_rate: f64,
+    /// Latest cached exchange rate.
+    pub cached_rate: f64,
+    pub cached_at: Timestamp,
+    /// Relative difference of the pool price from the cached rate, e.g. 0.02 if
+    /// NEAR is 2% more expensive in the pool.
+    pub divergence: f64,
+}
+
+struct SellPlan {
+    remove_amounts: Vec<U128>,
+    swap_action: SwapAction,
+    usn_amount: U128,
+}
+
+impl From<SellPlan> for SellPreview {
+    fn from(plan: SellPlan) -> Self {
+        Self {
+            remove_amounts: plan.remove_amounts,
+            min_amount_out: plan.swap_action.min_amount_out,
+            burn_amount: plan.usn_amount,
+        }
+    }
+}
+
+pub(super) fn sell(
+    config: &TreasuryConfig,
+    pool: Pool,
+    counter_idx: usize,
+    amount: f64,
+    exchange_rate: f64,
+    max_in: Option<u128>,
+) -> Promise {
+    let SellPlan {
+        remove_amounts,
+        swap_action,
+        usn_amount,
+    } = plan_sell(config, &pool, counter_idx, amount, exchange_rate);
+    assert_amount_in(swap_action.amount_in.unwrap().0, max_in);
+
+    let max_burn_shares = U128(u128::MAX); // TODO: Any limits?
+
+    assert_swaps_fit(
+        GAS_FOR_REMOVE_LIQUIDITY
+            + GAS_SURPLUS * 4
+            + GAS_FOR_WITHDRAW * 2
+            + GAS_FOR_NEAR_WITHDRAW
+            + config.burn_gas()
+            + GAS_FOR_FINISH_BALANCING,
+    );
+
+    event::emit::treasury_trade_intent(
+        pool.id,
+        "sell",
+        amount,
+        &swap_action.token_in,
+        swap_action.amount_in.unwrap().0,
+        &swap_action.token_out,
+        swap_action.min_amount_out.0,
+    );
+
+    let remove_liquidity = ext_ref_finance::remove_liquidity_by_tokens(
+        pool.id,
+        remove_amounts,
+        max_burn_shares,
+        pool.ref_id.clone(),
+        ONE_YOCTO,
+        GAS_FOR_REMOVE_LIQUIDITY,
+    );
+
+    swap_and_burn(
+        config,
+        pool.id,
+        amount,
+        swap_action,
+        usn_amount,
+        Some(remove_liquidity),
+    )
+}
+
+/// Sizes the removal of `amount` USD from the pool, the swap of the counter-token
+/// into wNEAR and the burn of USN.
+fn plan_sell(
+    config: &TreasuryConfig,
+    pool: &Pool,
+    counter_idx: usize,
+    amount: f64,
+    exchange_rate: f64,
+) -> SellPlan {
+    let swap = &config.swap;
+    let wrap_id = swap.wrap_id.clone();
+    let min_amount = min_amount_out(
+        amount / exchange_rate,
+        config.swap_fee(),
+        config.slippage.slippage(amount),
+        NEAR_DECIMALS,
+    );
+
+    // Remove USN and the counter-token only, keeping other pool tokens untouched.
+    // The margin keeps rounding from requesting more than the position holds.
+    let remove_amounts: Vec<U128> = pool
+        .tokens
+        .iter()
+        .zip(pool.decimals.iter())
+        .enumerate()
+        .map(|(idx, (token_id, decimals))| {
+            if idx == counter_idx || token_id == &env::current_account_id() {
+                U128(
+                    config
+                        .net_of_sell_safety_margin((amount * 10f64.powi(*decimals as i32)) as u128),
+                )
+            } else {
+                U128(0)
+            }
+        })
+        .collect();
+
+    // Swap and burn what actually arrives to the ref-finance deposit.
+    let usn_amount = config.net_of_transfer_fee(
+        &env::current_account_id(),
+        extract_usn_amount(pool, &remove_amounts),
+    );
+
+    let usdt_name = pool.tokens[counter_idx].clone();
+    let usdt_amount = config.net_of_transfer_fee(&usdt_name, remove_amounts[counter_idx]);
+
+    let swap_action = SwapAction {
+        pool_id: swap.swap_pool_id,
+        amount_in: Some(usdt_amount),
+        token_in: usdt_name,
+        token_out: wrap_id,
+        min_amount_out: min_amount.into(),
+    };
+
+    SellPlan {
+        remove_amounts,
+        swap_action,
+        usn_amount,
+    }
+}
+
+/// Rejects swaps ref-finance would fail after the gas is spent.
+fn assert_amount_in(amount_in: u128, max_in: Option<u128>) {
+    require!(amount_in > 0, "Nothing to swap");
+    if let Some(max_in) = max_in {
+        require!(
+            amount_in <= max_in,
+            &format!(
+                "Swap amount {} exceeds the swap pool reserve {}",
+                amount_in, max_in
+            )
+        );
+    }
+}
+
+/// Returns the swap pool reserve of the token.
+fn swap_reserve(swap_info: &PoolInfo, token_id: &AccountId) -> Option<u128> {
+    swap_info
+        .token_account_ids
+        .iter()
+        .position(|id| id == token_id)
+        .map(|idx| swap_info.amounts[idx].0)
+}
+
+/// Number of sequential swaps which fit into the remaining prepaid gas
+/// after the `fixed` part of the chain.
+fn max_sequential_swaps(fixed: Gas) -> u64 {
+    let available = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+    available.saturating_sub(fixed.0) / GAS_FOR_SWAP.0
+}
+
+/// Number of split legs which fit into the remaining prepaid gas
+/// after the `fixed` part of the chain. Every leg is a chain with its own swap.
+fn max_split_legs(fixed: Gas, burn_gas: Gas) -> u64 {
+    let available = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+    available.saturating_sub(fixed.0) / gas_for_leg(burn_gas).0
+}
+
+/// Gas of the quote and the chain of a split leg.
+fn gas_for_leg(burn_gas: Gas) -> Gas {
+    GAS_FOR_GET_STABLE_POOL + GAS_FOR_GET_POOL + gas_for_start_balancing(burn_gas)
+}
+
+/// Number of legs the decision in the pool is split into besides its own.
+fn split_leg_count(targets: &Option<Vec<SplitTarget>>, pool_id: u64) -> u64 {
+    match targets {
+        Some(targets) if targets.iter().any(|target| target.pool_id == pool_id) => {
+            targets.len() as u64 - 1
+        }
+        _ => 0,
+    }
+}
+
+/// Quotes the pool and the swap pool for `handle_execute_treasury_leg`.
+fn quote_leg(pool: Pool, swap: &SwapConfig, burn_gas: Gas, deposit: Balance) -> Promise {
+    ext_ref_finance::get_stable_pool(pool.id, pool.ref_id, NO_DEPOSIT, GAS_FOR_GET_STABLE_POOL)
+        .and(ext_ref_finance::get_pool(
+            swap.swap_pool_id,
+            swap.swap_ref_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_POOL,
+        ))
+        .then(ext_self::handle_execute_treasury_leg(
+            pool.id,
+            env::current_account_id(),
+            deposit,
+            gas_for_start_balancing(burn_gas),
+        ))
+}
+
+/// Queries the pool, the swap pool and optionally the wNEAR balance for
+/// `handle_start_treasury_balancing`.
+fn start_balancing(
+    treasury: &TreasuryData,
+    pool: Pool,
+    decision_limit: Option<DecisionLimit>,
+    execute: bool,
+    deposit: Balance,
+    callback_gas: Gas,
+) -> Promise {
+    // Start with figuring out USDT part of reserve.
+    let queries = ext_ref_finance::get_pool_shares(
+        pool.id,
+        env::current_account_id(),
+        pool.ref_id.clone(),
+        NO_DEPOSIT,
+        GAS_FOR_GET_SHARES,
+    )
+    .then(ext_self::predict_remove_liquidity(
+        pool.id,
+        env::current_account_id(),
+        NO_DEPOSIT,
+        GAS_SURPLUS + GAS_FOR_PREDICT_REMOVE_LIQUIDITY,
+    ))
+    .and(ext_ref_finance::get_stable_pool(
+        pool.id,
+        pool.ref_id,
+        NO_DEPOSIT,
+        GAS_FOR_GET_STABLE_POOL,
+    ))
+    .and(ext_ref_finance::get_pool(
+        treasury.config.swap.swap_pool_id,
+        treasury.config.swap.swap_ref_id.clone(),
+        NO_DEPOSIT,
+        GAS_FOR_GET_POOL,
+    ));
+
+    // The wNEAR balance goes last, `handle_start_treasury_balancing` reads it
+    // beyond its callback arguments.
+    let queries = if treasury.config.fetch_wrap_balance {
+        queries.and(ext_ft::ft_balance_of(
+            env::current_account_id(),
+            treasury.config.swap.wrap_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_FT_BALANCE_OF,
+        ))
+    } else {
+        queries
+    };
+
+    queries.then(ext_self::handle_start_treasury_balancing(
+        pool.id,
+        decision_limit,
+        execute,
+        env::current_account_id(),
+        deposit,
+        callback_gas,
+    ))
+}
+
+/// Gas of the queries made by `start_balancing`.
+fn gas_for_queries(config: &TreasuryConfig) -> Gas {
+    let queries = GAS_FOR_GET_SHARES
+        + GAS_SURPLUS
+        + GAS_FOR_PREDICT_REMOVE_LIQUIDITY
+        + GAS_FOR_GET_STABLE_POOL
+        + GAS_FOR_GET_POOL;
+    if config.fetch_wrap_balance {
+        queries + GAS_FOR_FT_BALANCE_OF
+    } else {
+        queries
+    }
+}
+
+/// Gas of `handle_start_treasury_balancing` and the worst-case sell chain after it.
+fn gas_for_start_balancing(burn_gas: Gas) -> Gas {
+    GAS_SURPLUS * 7
+        + GAS_FOR_REMOVE_LIQUIDITY
+        + GAS_FOR_SWAP
+        + GAS_FOR_WITHDRAW * 2
+        + GAS_FOR_NEAR_WITHDRAW
+        + burn_gas
+        + GAS_FOR_FINISH_BALANCING
+}
+
+/// wNEAR balance of the contract requested after the pools by `balance_treasury`,
+/// `None` if it wasn't requested or failed.
+fn fetched_wrap_balance() -> Option<Balance> {
+    if env::promise_results_count() < 4 {
+        return None;
+    }
+    match env::promise_result(3) {
+        PromiseResult::Successful(data) => near_sdk::serde_json::from_slice::<U128>(&data)
+            .ok()
+            .map(|balance| balance.0),
+        _ => {
+            env::log_str("Failed to fetch the wNEAR balance, the reserve record is used");
+            None
+        }
+    }
+}
+
+/// Rejects executions which prepaid gas leaves less than the burn gas to
+/// `finish_withdraw_with_burn` of the worst-case sell: a starved burn leaves
+/// the supply unbalanced.
+fn assert_burn_gas_fits(config: &TreasuryConfig) {
+    let burn_gas = config.burn_gas();
+    let wrap_balance_gas = if config.fetch_wrap_balance {
+        GAS_FOR_FT_BALANCE_OF
+    } else {
+        Gas(0)
+    };
+    let before_burn = GAS_FOR_GET_SHARES
+        + GAS_SURPLUS
+        + GAS_FOR_PREDICT_REMOVE_LIQUIDITY
+        + GAS_FOR_GET_STABLE_POOL
+        + GAS_FOR_GET_POOL
+        + wrap_balance_gas
+        + gas_for_start_balancing(burn_gas)
+        - burn_gas;
+    let left = env::prepaid_gas()
+        .0
+        .saturating_sub(env::used_gas().0)
+        .saturating_sub(before_burn.0);
+    require!(
+        left >= burn_gas.0,
+        &format!(
+            "Prepaid gas leaves {} of {} gas required by the burn callback",
+            left, burn_gas.0
+        )
+    );
+}
+
+/// A buy or sell chain swaps once, legs of a split decision run in chains
+/// of their own, see `max_split_legs`.
+fn assert_swaps_fit(fixed: Gas) {
+    require!(
+        max_sequential_swaps(fixed) >= 1,
+        "Not enough prepaid gas for the swap chain"
+    );
+}
+
+/// Rejects degenerate routes swapping a token into itself.
+fn assert_swap_route(actions: &[SwapAction]) {
+    for action in actions {
+        require!(
+            action.token_in != action.token_out,
+            &format!(
+                "Swap of {} into itself in the pool {}",
+                action.token_in, action.pool_id
+            )
+        );
+    }
+}
+
+/// The part of the sell which follows the liquidity removal:
+/// USDT -> wNEAR swap, withdrawal of wNEAR and USN, and burning of USN.
+/// Each step starts only if the previous ones succeeded.
+fn swap_and_burn(
+    config: &TreasuryConfig,
+    pool_id: u64,
+    amount: f64,
+    swap_action: SwapAction,
+    usn_amount: U128,
+    previous: Option<Promise>,
+) -> Promise {
+    let min_fill = U128(config.min_fill(amount, swap_action.min_amount_out.0));
+    let swap_actions = vec![swap_action];
+    assert_swap_route(&swap_actions);
+    let burn_gas = config.burn_gas();
+
+    match previous {
+        Some(previous) => previous.then(ext_self::handle_swap_after_remove_liquidity(
+            pool_id,
+            swap_actions,
+            usn_amount,
+            min_fill,
+            env::current_account_id(),
+            2 * ONE_YOCTO,
+            GAS_SURPLUS + GAS_FOR_SWAP + gas_for_withdraw_after_swap(burn_gas),
+        )),
+        None => swap_and_withdraw(
+            &config.swap,
+            pool_id,
+            swap_actions,
+            usn_amount,
+            min_fill,
+            burn_gas,
+        ),
+    }
+    // Released whether the chain succeeded or not.
+    .then(ext_self::finish_treasury_balancing(
+        pool_id,
+        TreasuryDecision::Sell(amount),
+        env::current_account_id(),
+        NO_DEPOSIT,
+        GAS_FOR_FINISH_BALANCING,
+    ))
+}
+
+fn swap_and_withdraw(
+    swap: &SwapConfig,
+    pool_id: u64,
+    swap_actions: Vec<SwapAction>,
+    usn_amount: U128,
+    min_fill: U128,
+    burn_gas: Gas,
+) -> Promise {
+    let min_wrap_amount = swap_actions
+        .last()
+        .map_or(U128(0), |action| action.min_amount_out);
+    // USD of the counter-token swapped into wNEAR.
+    let counter_usd = swap_actions.first().map_or(0., |action| {
+        usd_of_counter(
+            &action.token_in,
+            action.amount_in.map_or(0, |amount| amount.0),
+        )
+    });
+
+    ext_ref_finance::swap(
+        swap_actions,
+        None,
+        swap.swap_ref_id.clone(),
+        NO_DEPOSIT,
+        GAS_FOR_SWAP,
+    )
+    .then(ext_self::handle_withdraw_after_swap(
+        pool_id,
+        counter_usd,
+        usn_amount,
+        SwapBounds {
+            min_amount_out: min_wrap_amount,
+            min_fill,
+        },
+        env::current_account_id(),
+        2 * ONE_YOCTO,
+        gas_for_withdraw_after_swap(burn_gas),
+    ))
+}
+
+/// Gas of `handle_withdraw_after_swap` and the rest of the sell chain.
+fn gas_for_withdraw_after_swap(burn_gas: Gas) -> Gas {
+    GAS_SURPLUS * 3 + GAS_FOR_WITHDRAW * 2 + GAS_FOR_NEAR_WITHDRAW + burn_gas
+}
+
+/// Checks if the latest exchange rate deviates from the mean of the cached rates
+/// by more than `max_volatility` sample standard deviations.
+fn is_too_volatile(exchange_rates: &[f64], max_volatility: Option<f64>) -> bool {
+    let max_volatility = match max_volatility {
+        Some(max_volatility) => max_volatility,
+        None => return false,
+    };
+
+    let count = exchange_rates.len() as f64;
+    let mean = exchange_rates.iter().sum::<f64>() / count;
+    let variance = exchange_rates
+        .iter()
+        .map(|er| (er - mean).powi(2))
+        .sum::<f64>()
+        / (count - 1.);
+    let std_dev = variance.sqrt();
+
+    if std_dev == 0. {
+        return false;
+    }
+
+    let last = *exchange_rates.last().unwrap();
+
+    (last - mean).abs() / std_dev > max_volatility
+}
+
+/// Returns the output of the simple swap pool for `amount_in` of `token_in`
+/// and the part of the spot price output it loses.
+fn swap_return(
+    swap_info: &PoolInfo,
+    token_in: &AccountId,
+    amount_in: u128,
+    token_out: &AccountId,
+) -> (u128, f64) {
+    let reserve = |token_id: &AccountId| {
+        swap_reserve(swap_info, token_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Swap pool doesn't contain {}", token_id)))
+    };
+    let reserve_in = reserve(token_in);
+    let reserve_out = reserve(token_out);
+
+    // The constant product formula of ref-finance simple pools.
+    let amount_with_fee = U256::from(amount_in) * U256::from(FEE_DIVISOR - swap_info.total_fee);
+    let amount_out = (amount_with_fee * U256::from(reserve_out)
+        / (U256::from(FEE_DIVISOR) * U256::from(reserve_in) + amount_with_fee))
+        .as_u128();
+
+    let spot_out = amount_in as f64 * reserve_out as f64 / reserve_in as f64;
+    let slippage = if spot_out > 0. {
+        1. - amount_out as f64 / spot_out
+    } else {
+        0.
+    };
+    (amount_out, slippage)
+}
+
+/// Limits are whole USD, so a limit above the USD value of the whole reserve
+/// means it was scaled by token decimals by mistake.
+fn assert_plausible_limit(limit: f64, reserve_usd: f64) {
+    if limit > reserve_usd {
+        env::panic_str(&format!(
+            "Decision limit {} exceeds the reserve value of {:.0} USD, check the unit of limits",
+            limit, reserve_usd
+        ));
+    }
+}
+
+/// Returns the price of 1 NEAR in the counter-token implied by the swap pool reserves.
+fn swap_spot_price(
+    wrap_id: &AccountId,
+    counter_id: &AccountId,
+    counter_decimals: u8,
+    swap_info: &PoolInfo,
+) -> f64 {
+    let reserve = |token_id: &AccountId| {
+        swap_reserve(swap_info, token_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Swap pool doesn't contain {}", token_id)))
+            as f64
+    };
+    let wrap = reserve(wrap_id) / 10f64.powi(NEAR_DECIMALS as i32);
+    let counter = reserve(counter_id) / 10f64.powi(counter_decimals as i32);
+    counter / wrap
+}
+
+/// Returns the value of the pool reserves in USD, every stable token is counted as $1.
+fn pool_tvl(info: &StablePoolInfo) -> f64 {
+    info.amounts
+        .iter()
+        .zip(info.decimals.iter())
+        .map(|(amount, decimals)| whole_units(amount.0, *decimals))
+        .sum()
+}
+
+/// Drops decisions smaller than `min_usd` and caps ones bigger than `max_usd`.
+fn clamp_trade_size(
+    decision: TreasuryDecision,
+    bounds: &Option<TradeSizeBounds>,
+) -> TreasuryDecision {
+    let bounds = match bounds {
+        Some(bounds) => bounds,
+        None => return decision,
+    };
+    let clamp = |amount: f64| {
+        if amount < bounds.min_usd {
+            None
+        } else {
+            Some(min(amount, bounds.max_usd))
+        }
+    };
+    match decision {
+        TreasuryDecision::Buy(amount) => clamp(amount)
+            .map(TreasuryDecision::Buy)
+            .unwrap_or(TreasuryDecision::DoNothing),
+        TreasuryDecision::Sell(amount) => clamp(amount)
+            .map(TreasuryDecision::Sell)
+            .unwrap_or(TreasuryDecision::DoNothing),
+        TreasuryDecision::DoNothing => TreasuryDecision::DoNothing,
+    }
+}
+
+/// Turns the decision of a disabled direction into `DoNothing`.
+fn enabled_direction(
+    decision: TreasuryDecision,
+    config: &TreasuryConfig,
+    verbosity: LogVerbosity,
+) -> TreasuryDecision {
+    let disabled = match decision {
+        TreasuryDecision::Buy(_) if !config.buys_enabled() => "Buys",
+        TreasuryDecision::Sell(_) if !config.sells_enabled() => "Sells",
+        _ => return decision,
+    };
+    if verbosity != LogVerbosity::Off {
+        env::log_str(&format!("{} are disabled: {}", disabled, decision));
+    }
+    TreasuryDecision::DoNothing
+}
+
+/// Divides the decision between the split targets if the pool is one of them,
+/// the share of `pool_id` goes first.
+fn split_decision(
+    decision: &TreasuryDecision,
+    pool_id: u64,
+    targets: &Option<Vec<SplitTarget>>,
+) -> Vec<(u64, TreasuryDecision)> {
+    let targets = match targets {
+        Some(targets)
+            if *decision != TreasuryDecision::DoNothing
+                && targets.iter().any(|target| target.pool_id == pool_id) =>
+        {
+            targets
+        }
+        _ => return vec![(pool_id, decision.clone())],
+    };
+    let mut legs: Vec<(u64, TreasuryDecision)> = targets
+        .iter()
+        .map(|target| {
+            let leg = match decision {
+                TreasuryDecision::Buy(amount) => TreasuryDecision::Buy(amount * target.fraction),
+                TreasuryDecision::Sell(amount) => TreasuryDecision::Sell(amount * target.fraction),
+                TreasuryDecision::DoNothing => TreasuryDecision::DoNothing,
+            };
+            (target.pool_id, leg)
+        })
+        .collect();
+    legs.sort_by_key(|(leg_pool_id, _)| *leg_pool_id != pool_id);
+    legs
+}
+
+/// Scales the decided amount down to the configured fraction.
+fn smooth_decision(decision: TreasuryDecision, smoothing: Option<f64>) -> TreasuryDecision {
+    match (decision, smoothing) {
+        (TreasuryDecision::Buy(amount), Some(fraction)) => TreasuryDecision::Buy(amount * fraction),
+        (TreasuryDecision::Sell(amount), Some(fraction)) => {
+            TreasuryDecision::Sell(amount * fraction)
+        }
+        (decision, _) => decision,
+    }
+}
+
+/// Returns a factor in [0; 1] growing with `elapsed` seconds since the last trade
+/// until `full_size_gap_sec` passes. Zero gap disables the scaling.
+fn gap_factor(elapsed: Option<f64>, full_size_gap_sec: u64) -> f64 {
+    match elapsed {
+        Some(elapsed) if full_size_gap_sec > 0 => {
+            min(max(elapsed / full_size_gap_sec as f64, 0.), 1.)
+        }
+        _ => 1.,
+    }
+}
+
+/// Mean or median of the latest `window` exchange rates.
+fn reference_rate(exchange_rates: &[f64], window: u8, aggregation: ReferenceAggregation) -> f64 {
+    let window = (window as usize).clamp(1, exchange_rates.len());
+    let latest = &exchange_rates[exchange_rates.len() - window..];
+    match aggregation {
+        ReferenceAggregation::Mean => latest.iter().sum::<f64>() / window as f64,
+        ReferenceAggregation::Median => {
+            let mut sorted = latest.to_vec();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            if window % 2 == 1 {
+                sorted[window / 2]
+            } else {
+                (sorted[window / 2 - 1] + sorted[window / 2]) / 2.
+            }
+        }
+    }
+}
+
+/// Aborts the execution if the rate moved away from the one the caller expects.
+fn assert_expected_rate(rate: f64, expected_rate: Option<f64>, rate_tolerance: Option<f64>) {
+    let expected_rate = match expected_rate {
+        Some(expected_rate) => expected_rate,
+        None => {
+            require!(
+                rate_tolerance.is_none(),
+                "`rate_tolerance` requires `expected_rate`"
+            );
+            return;
+        }
+    };
+    let tolerance = rate_tolerance
+        .unwrap_or_else(|| env::panic_str("`expected_rate` requires `rate_tolerance`"));
+    require!(
+        expected_rate > 0. && tolerance >= 0.,
+        "`expected_rate` must be positive and `rate_tolerance` non-negative"
+    );
+    let deviation = (rate - expected_rate).abs() / expected_rate;
+    require!(
+        deviation <= tolerance,
+        &format!(
+            "Reference rate {} deviates from the expected rate {} by {}, beyond the tolerance {}",
+            rate, expected_rate, deviation, tolerance
+        )
+    );
+}
+
+/// Rate at which `n_dn * usn - rate * near` turns zero.
+fn collateral_break_even_rate(n_dn: f64, near: f64, usn: f64) -> f64 {
+    n_dn * usn / near
+}
+
+/// Checks if the time points are spread enough to fit the trend into, samples
+/// collapsed into the same timestamp make the basis matrix degenerate.
+fn has_time_spread(time_points: &[f64]) -> bool {
+    let mut distinct = time_points.to_vec();
+    distinct.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    distinct.dedup();
+    distinct.len() >= MIN_DISTINCT_TIME_POINTS
+        && distinct.last().unwrap() - distinct[0] >= MIN_TIME_SPAN
+}
+
+/// Returns a factor in (0; 1] decreasing with the distance of the `vertex`
+/// from the observed time window.
+fn extrapolation_confidence(time_points: &[f64], vertex: f64, decay: f64) -> f64 {
+    let first = time_points[0];
+    let last = *time_points.last().unwrap();
+    let distance = max(max(first - vertex, vertex - last), 0.);
+    if distance.is_finite() {
+        1. / (1. + decay * distance / (last - first))
+    } else {
+        // Linear trend has no vertex.
+        1.
+    }
+}
+
+/// Intermediates of the quadratic trend fit.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct TrendFit {
+    /// Time points and exchange rates the trend is fitted into, smoothed if enabled.
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    /// Rows of the basis matrix: `[1, x, x²]`.
+    pub basis: Vec<Vec<f64>>,
+    /// Solution of the normal equations: `[c, b, a]`.
+    pub w: Vec<f64>,
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub r_squared: f64,
+    /// Trend coefficient `C`.
+    pub trend_coefficient: f64,
+}
+
+fn fit_trend(params: &DecisionParams, exchange_rates: &[f64], time_points: &[f64]) -> TrendFit {
+    let m = params.m;
+    let t_0 = params.t_0;
+
+    // 3. Make the data smoothing with moving average, if enabled
+    let mut x: Vec<f64> = Vec::new();
+    let mut y: Vec<f64> = Vec::new();
+    if params.smoothing_enabled {
+        for k in 1..7 {
+            x.push((time_points[k - 1] + time_points[k] + time_points[k + 1]) / 3.);
+            y.push((exchange_rates[k - 1] + exchange_rates[k] + exchange_rates[k + 1]) / 3.);
+        }
+    } else {
+        x = time_points.to_vec();
+        y = exchange_rates.to_vec();
+    }
+
+    // 4. Fit a quadratic trend into the 6 NEAR/USDT smoothed (or 8 raw) exchange rate values using OLS:
+    let x_column: Matrix<f64> = Matrix::column(x.clone());
+    let y_column: Matrix<f64> = Matrix::column(y.clone());
+
+    let mut basis = x_column.clone();
+    basis.insert_column(0, 1.0);
+    basis.insert_column_with(2, x_column.column_iter(0).map(|x| x * x));
+
+    let w = (basis.transpose() * &basis).inverse().unwrap() * (basis.transpose() * &y_column);
+
+    // 5. Get coefficients a, b, c and R2 for this trend
+    let a = w.get(2, 0);
+    let b = w.get(1, 0);
+    let c = w.get(0, 0);
+
+    // Stot = ∑((Y − Y _mean)2)
+    let er_mean: f64 = exchange_rates.iter().sum::<f64>() / exchange_rates.len() as f64;
+
+    let s_tot = exchange_rates
+        .iter()
+        .map(|er| (er - er_mean).powi(2))
+        .sum::<f64>();
+
+    // Sres = ∑(Vk − (a · Tk^2 + b · Tk + c))2
+    let mut s_res: f64 = 0.;
+    for n in 0..exchange_rates.len() {
+        s_res +=
+            (exchange_rates[n] - (time_points[n].powi(2) * a + time_points[n] * b + c)).powi(2);
+    }
+
+    // R2 = 1 − Sres/Stot
+    let r_squared = 1. - s_res / s_tot;
+
+    // 5. Calculate coefficient C
+    // C = sign(a) · R^2/(t0 + b/2a)^m + 1)
+    let trend_coefficient = f64::signum(a) * r_squared / ((t_0 + b / (2. * a)).powi(m) + 1.);
+
+    TrendFit {
+        x,
+        y,
+        basis: (0..basis.rows())
+            .map(|row| basis.row_iter(row).collect())
+            .collect(),
+        w: w.column_iter(0).collect(),
+        a,
+        b,
+        c,
+        r_squared,
+        trend_coefficient,
+    }
+}
+
+/// Reserve a decision is made on in whole units: NEAR, circulating USN and USD
+/// of the counter-token, along with the USD limit and seconds since the latest trade.
+struct ReserveState {
+    near: f64,
+    usn: f64,
+    usdt: f64,
+    limit: Option<f64>,
+    elapsed: Option<f64>,
+}
+
+fn make_treasury_decision(
+    params: &DecisionParams,
+    exchange_rates: Vec<f64>,
+    time_points: Vec<f64>,
+    reserve: ReserveState,
+    verbose: bool,
+) -> TreasuryDecision {
+    let ReserveState {
+        near,
+        usn,
+        usdt,
+        limit,
+        elapsed,
+    } = reserve;
+
+    // 1. Set constant values for further calculations
+    let n_dn = params.n_dn;
+    let u_up = params.u_up;
+    let u_dn = params.u_dn;
+    let p_dn = params.p_dn;
+    let p_up = params.p_up;
+    let t_buy_min = params.t_buy_min;
+    let t_sell_min = params.t_sell_min;
+    let t_buy_step = params.t_buy_step;
+    let t_sell_step = params.t_sell_step;
+
+    let n = near;
+    let q = usn;
+    let u = usdt;
+
+    debug_assert_eq!(exchange_rates.len(), time_points.len());
+    debug_assert_eq!(exchange_rates.len(), 8);
+
+    // Neither the trend nor the collateral floor would trade, skip the trend fit.
+    if params.neutral_zone > 0. {
+        let n_er = exchange_rates.last().unwrap();
+        let usdt_share = u / (u + n_er * n);
+        let collateral_gap = n_dn * q
+            - reference_rate(
+                &exchange_rates,
+                params.reference_window,
+                params.reference_aggregation,
+            ) * n;
+        if (usdt_share - (p_dn + p_up) / 2.).abs() <= params.neutral_zone
+            && collateral_gap < t_sell_min
+        {
+            if verbose {
+                env::log_str(&format!(
+                    "USDT share of the reserve {} is within the neutral zone",
+                    usdt_share
+                ));
+            }
+            return TreasuryDecision::DoNothing;
+        }
+    }
+
+    if !has_time_spread(&time_points) {
+        if verbose {
+            env::log_str("Time points are too close to fit the trend");
+        }
+        return TreasuryDecision::DoNothing;
+    }
+
+    // 2. Set NER = ER[t − 0] = V8
+    let n_er = exchange_rates.last().unwrap();
+
+    // 3-5. Fit the trend and get its coefficients.
+    let TrendFit {
+        a,
+        b,
+        c,
+        r_squared,
+        trend_coefficient,
+        ..
+    } = fit_trend(params, &exchange_rates, &time_points);
+
+    // Expected change of the rate in bps as far ahead of the latest rate as the window spans.
+    let horizon = 2. * time_points.last().unwrap() - time_points[0];
+    let expected_change_bps = ((a * horizon.powi(2) + b * horizon + c) / n_er - 1.) * 10_000.;
+
+    if verbose {
+        env::log_str(&format!(
+            "Trend: a = {}, b = {}, c = {}, R2 = {}",
+            a, b, c, r_squared
+        ));
+    }
+
+    let c = trend_coefficient.clamp(params.c_min, params.c_max);
+
+    if verbose {
+        env::log_str(&format!("Trend coefficient: C = {}", c));
+        if c != trend_coefficient {
+            env::log_str(&format!(
+                "Trend coefficient {} is clamped",
+                trend_coefficient
+            ));
+        }
+    }
+
+    let has_edge = |edge_bps: f64| {
+        let has_edge =
+            params.min_expected_edge_bps == 0 || edge_bps > params.min_expected_edge_bps as f64;
+        if verbose && !has_edge {
+            env::log_str(&format!("Expected edge {} bps is too small", edge_bps));
+        }
+        has_edge
+    };
+
+    let trend_scale =
+        extrapolation_confidence(&time_points, -b / (2. * a), params.extrapolation_decay)
+            * gap_factor(elapsed, params.full_size_gap_sec);
+
+    // The reserve is close enough to the target composition to skip trading on the trend.
+    let usdt_share = u / (u + n_er * n);
+    let in_dead_band = (usdt_share - (p_dn + p_up) / 2.).abs() <= params.dead_band;
+
+    if verbose && in_dead_band {
+        env::log_str(&format!(
+            "USDT share of the reserve {} is within the dead-band",
+            usdt_share
+        ));
+    }
+
+    // The collateral floor is checked against the average of the latest rates,
+    // so a single noisy print doesn't move it.
+    let reference_er = reference_rate(
+        &exchange_rates,
+        params.reference_window,
+        params.reference_aggregation,
+    );
+
+    if verbose && params.reference_window > 1 {
+        env::log_str(&format!("Reference rate: {}", reference_er));
+    }
+
+    // The NEAR part of the reserve is below the collateral floor.
+    let below_floor = n_dn * q - reference_er * n >= 0.;
+
+    let collateral_decision = || {
+        let r_sell = min(
+            min(min(n_dn * q - reference_er * n, t_sell_step), u),
+            limit.unwrap_or(t_sell_step),
+        );
+
+        if r_sell >= t_sell_min {
+            TreasuryDecision::Sell(r_sell)
+        } else {
+            TreasuryDecision::DoNothing
+        }
+    };
+
+    let trend_decision = || {
+        if in_dead_band {
+            TreasuryDecision::DoNothing
+        } else if !has_edge(expected_change_bps * c.signum()) {
+            // Sells buy NEAR expecting it to rise, buys sell NEAR expecting it to fall.
+            TreasuryDecision::DoNothing
+        } else if c > 0. {
+            let u_sell = max(c * (u - min(p_up * (u + n_er * n), u_up * q)), 0.) * trend_scale;
+
+            let r_sell = min(
+                min(min(u_sell, t_sell_step), u),
+                limit.unwrap_or(t_sell_step),
+            );
+
+            if r_sell >= t_sell_min {
+                TreasuryDecision::Sell(r_sell)
+            } else {
+                TreasuryDecision::DoNothing
+            }
+        } else {
+            let u_buy = c * min(u - min(p_dn * (u + n_er * n), u_dn * q), 0.) * trend_scale;
+
+            let r_buy = min(
+                min(min(u_buy, t_buy_step), n_er * n),
+                limit.unwrap_or(t_buy_step),
+            );
+
+            if r_buy >= t_buy_min {
+                TreasuryDecision::Buy(r_buy)
+            } else {
+                TreasuryDecision::DoNothing
+            }
+        }
+    };
+
+    match params.priority {
+        DecisionPriority::CollateralFirst if below_floor => collateral_decision(),
+        DecisionPriority::CollateralFirst => trend_decision(),
+        DecisionPriority::TrendFirst => match trend_decision() {
+            TreasuryDecision::DoNothing if below_floor => collateral_decision(),
+            decision => decision,
+        },
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::super::cache::CacheItem;
+    use super::super::config::{BurnCeiling, NearBuffer, SlippageCurve};
+    use super::super::execution::ExecutionVenue;
+    use super::*;
+
+    fn usn_contract() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+        (context, Contract::new(accounts(1)))
+    }
+
+    fn warmed_usn_contract(exchange_rates: &[f64]) -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        for (i, rate) in exchange_rates.iter().enumerate() {
+            treasury.cache.append(i as u64 * 300_000_000_000, *rate);
+        }
+        contract.treasury.replace(&treasury);
+        testing_env!(context
+            .block_timestamp((exchange_rates.len() as u64 - 1) * 300_000_000_000)
+            .build());
+        (context, contract)
+    }
+
+    /// Starts balancing of the pool 0 with the reserve of the sell scenario.
+    fn start_sell_scenario(
+        execute: bool,
+        config: TreasuryConfig,
+    ) -> (Contract, PromiseOrValue<TreasuryDecision>) {
+        let (mut context, mut contract) =
+            warmed_usn_contract(&[6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611]);
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config = config;
+        contract.treasury.replace(&treasury);
+        contract
+            .token
+            .internal_deposit(&accounts(2), 1_241_195_491_765_770 * 10u128.pow(12));
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .account_balance(191_937_460_531_210 * 10u128.pow(18) + 3)
+            .attached_deposit(3)
+            .block_index(100)
+            .build());
+
+        let result = start_sell_balancing(&mut contract, execute);
+        (contract, result)
+    }
+
+    /// Starts balancing of the pool 0 with the USDT reserve of the sell scenario.
+    fn start_sell_balancing(
+        contract: &mut Contract,
+        execute: bool,
+    ) -> PromiseOrValue<TreasuryDecision> {
+        start_sell_balancing_at_spot(contract, execute, 6.611)
+    }
+
+    /// Same with the swap pool reserves giving the `spot` USDT price of NEAR.
+    fn start_sell_balancing_at_spot(
+        contract: &mut Contract,
+        execute: bool,
+        spot: f64,
+    ) -> PromiseOrValue<TreasuryDecision> {
+        let (predicted_amounts, info, swap_info) = sell_scenario_pools(spot);
+        contract.handle_start_treasury_balancing(
+            0,
+            None,
+            execute,
+            predicted_amounts,
+            info,
+            swap_info,
+        )
+    }
+
+    /// Predicted amounts, the pool 0 and the swap pool of the sell scenario.
+    fn sell_scenario_pools(spot: f64) -> (Vec<U128>, StablePoolInfo, PoolInfo) {
+        let swap_info = PoolInfo {
+            pool_kind: "SIMPLE_POOL".to_string(),
+            token_account_ids: vec![
+                "wrap.test.near".parse().unwrap(),
+                "usdt.test.near".parse().unwrap(),
+            ],
+            amounts: vec![
+                U128(1_000_000 * ONE_NEAR),
+                U128((spot * 1e6) as u128 * 1_000_000),
+            ],
+            total_fee: 30,
+            shares_total_supply: U128(0),
+            amp: 0,
+        };
+        let tokens: Vec<AccountId> = vec![
+            "usn.test.near".parse().unwrap(),
+            "usdt.test.near".parse().unwrap(),
+        ];
+        let info = StablePoolInfo {
+            token_account_ids: tokens,
+            decimals: vec![18, 6],
+            amounts: vec![U128(0), U128(1_367_351_872_047_690)],
+            c_amounts: vec![U128(0), U128(1_367_351_872_047_690 * 10u128.pow(12))],
+            total_fee: 5,
+            shares_total_supply: U128(0),
+            amp: 240,
+        };
+        (vec![U128(0), U128(1_367_351_872_047_690)], info, swap_info)
+    }
+
+    #[test]
+    fn test_collateral_ratio_recorded() {
+        // Previews aren't recorded.
+        let (contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+        assert!(contract.get_collateral_history(0, 10).is_empty());
+
+        let (contract, _) = start_sell_scenario(true, TreasuryConfig::default());
+        let history = contract.get_collateral_history(0, 10);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].timestamp, 7 * 300_000_000_000);
+        // NEAR at $6.611 and USDT against circulating USN.
+        let expected = (191_937_460.531_21 * 6.611 + 1_367_351_872.047_69) / 1_241_195_491.765_77;
+        assert!(
+            (history[0].ratio - expected).abs() < 1e-6,
+            "{}",
+            history[0].ratio
+        );
+    }
+
+    fn refunds() -> Vec<(AccountId, Balance)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id;
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::Transfer { deposit } => Some((receiver_id.clone(), deposit)),
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unused_deposit_refunded() {
+        // Without execution all 3 yoctoNEAR are unused.
+        start_sell_scenario(false, TreasuryConfig::default());
+        assert_eq!(refunds(), vec![(env::signer_account_id(), 3)]);
+
+        // A sell consumes all of them.
+        start_sell_scenario(true, TreasuryConfig::default());
+        assert!(refunds().is_empty());
+
+        // A buy leaves one.
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        refund_unused_deposit(&TreasuryDecision::Buy(1000.));
+        assert_eq!(refunds(), vec![(env::signer_account_id(), 1)]);
+        assert_eq!(
+            deposit_for(&TreasuryDecision::Buy(1000.)) + 1,
+            env::attached_deposit()
+        );
+    }
+
+    #[test]
+    fn test_excess_limit_policy() {
+        let decide = |excess_limit_policy, amount| {
+            let (mut contract, _) = start_sell_scenario(
+                false,
+                TreasuryConfig {
+                    excess_limit_policy,
+                    ..Default::default()
+                },
+            );
+            let (predicted_amounts, info, swap_info) = sell_scenario_pools(6.611);
+            match contract.handle_start_treasury_balancing(
+                0,
+                Some(DecisionLimit {
+                    amount,
+                    unit: LimitUnit::Usd,
+                }),
+                false,
+                predicted_amounts,
+                info,
+                swap_info,
+            ) {
+                PromiseOrValue::Value(decision) => decision,
+                _ => panic!("Expected the decision"),
+            }
+        };
+
+        // Above 1367351872 USDT of the pool, below the whole reserve.
+        assert_eq!(
+            decide(ExcessLimitPolicy::Clamp, 1_500_000_000),
+            TreasuryDecision::Sell(23604.588213058174)
+        );
+        assert_eq!(
+            decide(ExcessLimitPolicy::Reject, 1_500_000_000),
+            TreasuryDecision::DoNothing
+        );
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Decision limit 1500000000 exceeds the reserve to trade")));
+    }
+
+    #[test]
+    fn test_sells_disabled() {
+        let (_, result) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                sells_enabled: Some(false),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Sells are disabled")));
+
+        let (_, result) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                buys_enabled: Some(false),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::Sell(_))
+        ));
+    }
+
+    #[test]
+    fn test_buys_disabled() {
+        let (_, mut contract) = usn_contract();
+        contract.set_buys_enabled(false);
+        assert_eq!(contract.get_trade_directions(), [false, true]);
+
+        let config = contract.treasury.get().unwrap().config;
+        assert_eq!(
+            enabled_direction(TreasuryDecision::Buy(1000.), &config, LogVerbosity::Summary),
+            TreasuryDecision::DoNothing
+        );
+        assert_eq!(
+            get_logs(),
+            vec!["Buys are disabled: Treasury decision is to buy $1000 USDT"]
+        );
+        assert_eq!(
+            enabled_direction(
+                TreasuryDecision::Sell(1000.),
+                &config,
+                LogVerbosity::Summary
+            ),
+            TreasuryDecision::Sell(1000.)
+        );
+
+        contract.set_buys_enabled(true);
+        assert_eq!(contract.get_trade_directions(), [true, true]);
+    }
+
+    #[test]
+    fn test_near_zero_circulating_usn() {
+        let (mut context, mut contract) =
+            warmed_usn_contract(&[6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611]);
+        contract
+            .token
+            .internal_deposit(&accounts(2), 10u128.pow(USN_DECIMALS as u32));
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .account_balance(191_937_460_531_210 * 10u128.pow(18) + 3)
+            .attached_deposit(3)
+            .build());
+
+        assert!(matches!(
+            start_sell_balancing(&mut contract, true),
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(get_logs().contains(&"Circulating USN is too low for balancing: 1".to_string()));
+        assert!(contract.treasury.get().unwrap().in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_quote_rebalance_sell() {
+        let (contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+        let stats = contract.get_treasury_stats();
+
+        let (predicted_amounts, info, swap_info) = sell_scenario_pools(6.611);
+        let quote = contract.handle_quote_rebalance(0, None, predicted_amounts, info, swap_info);
+
+        assert_eq!(quote.decision, TreasuryDecision::Sell(23604.588213058174));
+        assert_eq!(quote.exchange_rate, 6.611);
+        let preview = contract.preview_sell(0, 23604.588213058174, 6.611, None);
+        assert_eq!(quote.burn_amount, Some(preview.burn_amount));
+        assert_eq!(quote.min_amount_out, Some(preview.min_amount_out));
+        assert!(quote.expected_amount_out.unwrap().0 > preview.min_amount_out.0);
+        assert_eq!(quote.expected_shares, None);
+        let slippage = quote.slippage.unwrap();
+        // The 0.3% fee and the price impact of 23604 USDT on the 6.6M USDT reserve.
+        assert!(slippage > 0.006 && slippage < 0.007, "{}", slippage);
+
+        // Quoting doesn't count decisions.
+        assert_eq!(contract.get_treasury_stats().sells, stats.sells);
+        assert!(contract.treasury.get().unwrap().in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_oracle_divergence() {
+        let (contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+        let (_, _, swap_info) = sell_scenario_pools(6.744);
+
+        let divergence = contract.handle_oracle_divergence(swap_info);
+        assert_eq!(divergence.pool_rate, 6.744);
+        assert_eq!(divergence.cached_rate, 6.611);
+        assert_eq!(divergence.cached_at, 7 * 300_000_000_000);
+        assert!((divergence.divergence - 0.020118).abs() < 1e-6);
+    }
+
+    #[test]
+    #[should_panic(expected = "No cached exchange rate, call warmup first")]
+    fn test_oracle_divergence_empty_cache() {
+        let (_, contract) = usn_contract();
+        contract.check_oracle_divergence();
+    }
+
+    #[test]
+    fn test_swap_return() {
+        let wrap_id: AccountId = "wrap.test.near".parse().unwrap();
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+        let swap_info = PoolInfo {
+            pool_kind: "SIMPLE_POOL".to_string(),
+            token_account_ids: vec![wrap_id.clone(), usdt_id.clone()],
+            amounts: vec![U128(1_000 * ONE_NEAR), U128(5_000 * 10u128.pow(6))],
+            total_fee: 0,
+            shares_total_supply: U128(0),
+            amp: 0,
+        };
+
+        // 1000 NEAR doubles the NEAR reserve and takes a half of USDT.
+        let (amount_out, slippage) = swap_return(&swap_info, &wrap_id, 1_000 * ONE_NEAR, &usdt_id);
+        assert_eq!(amount_out, 2_500 * 10u128.pow(6));
+        assert_eq!(slippage, 0.5);
+
+        let (amount_out, _) = swap_return(&swap_info, &usdt_id, 5 * 10u128.pow(6), &wrap_id);
+        assert_eq!(amount_out, 999_000_999_000_999_000_999_000);
+    }
+
+    #[test]
+    fn test_keep_wrapped_after_sell() {
+        let (_, mut contract) = usn_contract();
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        contract.token.internal_deposit(&usn_id, 1000);
+        contract.set_keep_wrapped(true);
+
+        assert!(matches!(
+            contract.handle_unwrap_after_withdraw(U128(400), U128(5000)),
+            PromiseOrValue::Value(())
+        ));
+        assert!(get_created_receipts().is_empty());
+        assert_eq!(contract.token.ft_balance_of(usn_id), U128(600));
+        assert_eq!(
+            contract
+                .treasury
+                .get()
+                .unwrap()
+                .reserve
+                .get(&"wrap.test.near".parse().unwrap()),
+            Some(&U128(5000))
+        );
+    }
+
+    #[test]
+    fn test_wrapped_near_in_reserve() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                log_verbosity: LogVerbosity::Verbose,
+                ..Default::default()
+            },
+        );
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury
+            .reserve
+            .insert("wrap.test.near".parse().unwrap(), U128(1000 * ONE_NEAR));
+        contract.treasury.replace(&treasury);
+
+        start_sell_balancing(&mut contract, false);
+
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Reserve: NEAR = 191938460.5312")));
+    }
+
+    #[test]
+    fn test_treasury_usn_excluded_from_circulating() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                log_verbosity: LogVerbosity::Verbose,
+                ..Default::default()
+            },
+        );
+        // USN of a rejected sell fill withdrawn to the treasury.
+        contract
+            .token
+            .internal_deposit(&env::current_account_id(), 1000 * ONE_NEAR);
+        start_sell_balancing(&mut contract, false);
+
+        // The circulating USN of the scenario.
+        assert!(get_logs()
+            .iter()
+            .filter(|log| log.starts_with("Reserve: NEAR = "))
+            .all(|log| log.contains("USN = 1241195491.76577,")));
+    }
+
+    #[test]
+    fn test_near_buffer_excluded_from_reserve() {
+        let reserve_log = |near_buffer| {
+            let (mut contract, _) = start_sell_scenario(
+                false,
+                TreasuryConfig {
+                    log_verbosity: LogVerbosity::Verbose,
+                    near_buffer: Some(near_buffer),
+                    ..Default::default()
+                },
+            );
+            start_sell_balancing(&mut contract, false);
+            get_logs()
+                .into_iter()
+                .find(|log| log.starts_with("Reserve: NEAR = "))
+                .unwrap()
+        };
+
+        assert!(reserve_log(NearBuffer::Absolute(U128(1000 * ONE_NEAR)))
+            .starts_with("Reserve: NEAR = 191936460.5312"));
+        assert!(reserve_log(NearBuffer::Fraction(0.5)).starts_with("Reserve: NEAR = 95968730.2656"));
+    }
+
+    #[test]
+    fn test_fetched_wrap_balance_in_reserve() {
+        let reserve_log = |fetch_wrap_balance| {
+            let (mut contract, _) = start_sell_scenario(
+                false,
+                TreasuryConfig {
+                    log_verbosity: LogVerbosity::Verbose,
+                    fetch_wrap_balance,
+                    ..Default::default()
+                },
+            );
+            // The reserve doesn't record the wNEAR held by the contract.
+            let mut context = VMContextBuilder::new();
+            context
+                .current_account_id("usn.test.near".parse().unwrap())
+                .predecessor_account_id("usn.test.near".parse().unwrap())
+                .account_balance(env::account_balance())
+                .attached_deposit(env::attached_deposit())
+                .block_timestamp(env::block_timestamp());
+            let balance = near_sdk::serde_json::to_vec(&U128(1000 * ONE_NEAR)).unwrap();
+            with_promise_results(
+                &context,
+                vec![
+                    PromiseResult::Successful(vec![]),
+                    PromiseResult::Successful(vec![]),
+                    PromiseResult::Successful(vec![]),
+                    PromiseResult::Successful(balance),
+                ],
+            );
+            start_sell_balancing(&mut contract, false);
+            get_logs()
+                .into_iter()
+                .find(|log| log.starts_with("Reserve: NEAR = "))
+                .unwrap()
+        };
+
+        assert!(reserve_log(true).starts_with("Reserve: NEAR = 191938460.5312"));
+        assert!(reserve_log(false).starts_with("Reserve: NEAR = 191937460.5312"));
+    }
+
+    #[test]
+    fn test_pool_data_in_verbose_logs() {
+        let pool_log = |log_verbosity| {
+            start_sell_scenario(
+                false,
+                TreasuryConfig {
+                    log_verbosity,
+                    ..Default::default()
+                },
+            );
+            get_logs()
+                .into_iter()
+                .find(|log| log.starts_with("Pool 0: "))
+        };
+
+        let (predicted_amounts, info, _) = sell_scenario_pools(6.611);
+        assert_eq!(
+            pool_log(LogVerbosity::Verbose),
+            Some(format!(
+                "Pool 0: amounts = [{}, {}], predicted amounts = [{}, {}]",
+                info.amounts[0].0,
+                info.amounts[1].0,
+                predicted_amounts[0].0,
+                predicted_amounts[1].0
+            ))
+        );
+        assert_eq!(pool_log(LogVerbosity::Summary), None);
+    }
+
+    #[test]
+    fn test_committed_near_excluded_from_reserve() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                log_verbosity: LogVerbosity::Verbose,
+                ..Default::default()
+            },
+        );
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.in_flight.insert(1, 100);
+        treasury.committed_near.insert(1, U128(1000 * ONE_NEAR));
+        contract.treasury.replace(&treasury);
+
+        start_sell_balancing(&mut contract, false);
+
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Reserve: NEAR = 191936460.5312")));
+
+        drop(contract.finish_treasury_balancing(1, TreasuryDecision::Buy(1.)));
+        let treasury = contract.treasury.get().unwrap();
+        assert!(treasury.in_flight.is_empty());
+        assert!(treasury.committed_near.is_empty());
+    }
+
+    #[test]
+    fn test_rebalance_gas_used() {
+        let (mut contract, _) = start_sell_scenario(true, TreasuryConfig::default());
+        // Each callback runs in its own receipt.
+        testing_env!(VMContextBuilder::new()
+            .current_account_id("usn.test.near".parse().unwrap())
+            .build());
+        contract.handle_add_liquidity(
+            0,
+            "usdt.test.near".parse().unwrap(),
+            U128(1_000_000),
+            Ok(U128(1)),
+        );
+        testing_env!(VMContextBuilder::new()
+            .current_account_id("usn.test.near".parse().unwrap())
+            .build());
+        drop(contract.finish_treasury_balancing(0, TreasuryDecision::Sell(23604.59)));
+        assert!(contract.treasury.get().unwrap().gas_checkpoints.is_empty());
+
+        let log = get_logs().pop().unwrap();
+        let event: near_sdk::serde_json::Value =
+            near_sdk::serde_json::from_str(log.strip_prefix("EVENT_JSON:").unwrap()).unwrap();
+        assert_eq!(event["event"], "rebalance_gas_used");
+        let data = &event["data"][0];
+        assert_eq!(data["pool_id"], 0);
+        let checkpoints: Vec<&str> = data["checkpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|checkpoint| checkpoint["checkpoint"].as_str().unwrap())
+            .collect();
+        assert_eq!(checkpoints, vec!["start", "add_liquidity", "finish"]);
+
+        // Every callback uses some gas and all of them fit into a transaction.
+        let gas = |value: &near_sdk::serde_json::Value| -> u64 {
+            value.as_str().unwrap().parse().unwrap()
+        };
+        let used_gas = gas(&data["used_gas"]);
+        assert!(
+            data["checkpoints"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .all(|checkpoint| gas(&checkpoint["used_gas"]) > 0)
+                && used_gas < 300_000_000_000_000,
+            "{}",
+            log
+        );
+    }
+
+    #[test]
+    fn test_do_nothing_event() {
+        let config = |emit_on_do_nothing| TreasuryConfig {
+            decision: DecisionParams {
+                t_sell_min: 100000.,
+                ..Default::default()
+            },
+            emit_on_do_nothing,
+            ..Default::default()
+        };
+        let is_decision_event = |log: &String| log.contains(r#""event":"decision""#);
+
+        let (_, result) = start_sell_scenario(false, config(None));
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(get_logs().iter().any(is_decision_event));
+
+        let (_, result) = start_sell_scenario(false, config(Some(false)));
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(!get_logs().iter().any(is_decision_event));
+    }
+
+    #[test]
+    fn test_execution_reuses_last_decision() {
+        let (mut contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+        let treasury = contract.treasury.get().unwrap();
+        assert_eq!(
+            treasury.last_decisions.get(&0),
+            Some(&LastDecision {
+                state_hash: {
+                    let (predicted_amounts, info, _) = sell_scenario_pools(6.611);
+                    decision_state_hash(&treasury, None, &predicted_amounts, &info)
+                },
+                decision: TreasuryDecision::Sell(23604.588213058174),
+            })
+        );
+
+        // Recomputation would do nothing.
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.decision.t_sell_min = 30000.;
+        contract.treasury.replace(&treasury);
+
+        assert!(matches!(
+            start_sell_balancing(&mut contract, true),
+            PromiseOrValue::Promise(_)
+        ));
+        assert!(contract.treasury.get().unwrap().last_decisions.is_empty());
+    }
+
+    #[test]
+    fn test_changed_state_remakes_last_decision() {
+        let recomputed = |change: &dyn Fn(&mut TreasuryData), decision_limit| {
+            let (mut contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+
+            // Recomputation would do nothing.
+            let mut treasury = contract.treasury.take().unwrap();
+            treasury.config.decision.t_sell_min = 30000.;
+            change(&mut treasury);
+            contract.treasury.replace(&treasury);
+
+            let (predicted_amounts, info, swap_info) = sell_scenario_pools(6.611);
+            contract.handle_start_treasury_balancing(
+                0,
+                decision_limit,
+                true,
+                predicted_amounts,
+                info,
+                swap_info,
+            )
+        };
+
+        // Other limits.
+        let limit = DecisionLimit {
+            amount: 10_000,
+            unit: LimitUnit::Usd,
+        };
+        assert!(matches!(
+            recomputed(&|_| {}, Some(limit)),
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+
+        // Other treasury reserve.
+        assert!(matches!(
+            recomputed(
+                &|treasury| {
+                    treasury
+                        .reserve
+                        .insert("usdt.test.near".parse().unwrap(), U128(1_000_000));
+                },
+                None
+            ),
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+    }
+
+    #[test]
+    fn test_manipulated_spot_price() {
+        let config = || TreasuryConfig {
+            max_spot_divergence: Some(0.02),
+            ..Default::default()
+        };
+        let (mut contract, result) = start_sell_scenario(false, config());
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::Sell(_))
+        ));
+
+        // The swap pool is pumped 10% above the cached rates.
+        assert!(matches!(
+            start_sell_balancing_at_spot(&mut contract, true, 7.3),
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(contract.treasury.get().unwrap().in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_cross_pool_divergence() {
+        let config = || TreasuryConfig {
+            max_cross_pool_divergence: Some(0.02),
+            ..Default::default()
+        };
+        // Without USN in the stable pool the counter-token is taken at the peg.
+        let (mut contract, result) = start_sell_scenario(false, config());
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::Sell(_))
+        ));
+
+        // The stable pool values USDT at 1.1 USN, so the swap pool pays 10% more
+        // USDT for NEAR than the latest rate implies.
+        let (predicted_amounts, mut info, swap_info) = sell_scenario_pools(6.611);
+        info.c_amounts[0] = U128(info.c_amounts[1].0 / 10 * 11);
+        assert!(matches!(
+            contract.handle_start_treasury_balancing(
+                0,
+                None,
+                true,
+                predicted_amounts,
+                info,
+                swap_info
+            ),
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(get_logs().iter().any(|log| log.starts_with(
+            "Swap pool price 6.611 usdt.test.near of NEAR diverges from 6.0100000000000"
+        )));
+        assert!(contract.treasury.get().unwrap().in_flight.is_empty());
+    }
+
+    fn split_config() -> TreasuryConfig {
+        TreasuryConfig {
+            split_targets: Some(vec![
+                SplitTarget {
+                    pool_id: 0,
+                    fraction: 0.75,
+                },
+                SplitTarget {
+                    pool_id: 1,
+                    fraction: 0.25,
+                },
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_split_execution_leaves_pending_leg() {
+        let (mut contract, _) = start_sell_scenario(false, split_config());
+
+        // 300 TGas fit only the chain of the pool itself.
+        assert!(matches!(
+            start_sell_balancing(&mut contract, true),
+            PromiseOrValue::Promise(_)
+        ));
+        assert!(!created_function_names().contains(&"handle_execute_treasury_leg".to_string()));
+        assert!(get_logs().contains(
+            &"0 of 1 split legs fit into the prepaid gas, the rest waits for `execute_treasury_leg`"
+                .to_string()
+        ));
+
+        let treasury = contract.treasury.get().unwrap();
+        assert!(treasury.in_flight.contains_key(&0));
+        assert_eq!(
+            treasury.pending_legs.get(&1),
+            Some(&PendingLeg {
+                decision: TreasuryDecision::Sell(23604.588213058174 * 0.25),
+            })
+        );
+    }
+
+    #[test]
+    fn test_split_legs_run_within_prepaid_gas() {
+        let (mut contract, _) = start_sell_scenario(false, split_config());
+        testing_env!(VMContextBuilder::new()
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .account_balance(191_937_460_531_210 * 10u128.pow(18) + 6)
+            .attached_deposit(6)
+            .block_index(100)
+            .block_timestamp(7 * 300_000_000_000)
+            .prepaid_gas(Gas(1_000_000_000_000_000))
+            .build());
+
+        assert!(matches!(
+            start_sell_balancing(&mut contract, true),
+            PromiseOrValue::Promise(_)
+        ));
+        // The leg is quoted in the same transaction and taken by the quote callback.
+        let function_names = created_function_names();
+        assert_eq!(
+            function_names
+                .iter()
+                .filter(|name| *name == "handle_execute_treasury_leg")
+                .count(),
+            1
+        );
+        assert!(refunds().is_empty());
+        assert!(contract
+            .treasury
+            .get()
+            .unwrap()
+            .pending_legs
+            .contains_key(&1));
+    }
+
+    #[test]
+    fn test_split_legs_paid_by_caller() {
+        let (mut contract, _) = start_sell_scenario(false, split_config());
+        testing_env!(VMContextBuilder::new()
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .account_balance(191_937_460_531_210 * 10u128.pow(18) + 3)
+            .attached_deposit(3)
+            .block_index(100)
+            .block_timestamp(7 * 300_000_000_000)
+            .prepaid_gas(Gas(1_000_000_000_000_000))
+            .build());
+
+        // The leg fits into the prepaid gas, but the deposit pays only the own chain.
+        assert!(matches!(
+            start_sell_balancing(&mut contract, true),
+            PromiseOrValue::Promise(_)
+        ));
+        assert!(!created_function_names().contains(&"handle_execute_treasury_leg".to_string()));
+        assert!(get_logs().contains(
+            &"0 of 1 split legs fit into the prepaid gas, the rest waits for `execute_treasury_leg`"
+                .to_string()
+        ));
+        assert!(contract
+            .treasury
+            .get()
+            .unwrap()
+            .pending_legs
+            .contains_key(&1));
+    }
+
+    #[test]
+    fn test_unquoted_leg_deposit_refunded() {
+        let (mut contract, _) = start_sell_scenario(false, split_config());
+        testing_env!(VMContextBuilder::new()
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .account_balance(191_937_460_531_210 * 10u128.pow(18) + 6)
+            .attached_deposit(6)
+            .block_index(100)
+            .block_timestamp(7 * 300_000_000_000)
+            .build());
+
+        // 300 TGas don't fit the leg, its deposit goes back.
+        start_sell_balancing(&mut contract, true);
+        assert!(!created_function_names().contains(&"handle_execute_treasury_leg".to_string()));
+        assert_eq!(refunds(), vec![(env::signer_account_id(), 3)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "6 yoctoNEAR of attached deposit is required")]
+    fn test_split_balancing_requires_leg_deposit() {
+        let (mut context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config = split_config();
+        contract.treasury.replace(&treasury);
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        contract.balance_treasury(0, None, Some(true), None, None, None, None);
+    }
+
+    #[test]
+    fn test_split_legs_fit_in_prepaid_gas() {
+        let (mut context, _) = usn_contract();
+        let burn_gas = TreasuryConfig::default().burn_gas();
+        testing_env!(context
+            .prepaid_gas(gas_for_leg(burn_gas) * 2 + GAS_SURPLUS)
+            .build());
+
+        assert_eq!(max_split_legs(Gas(0), burn_gas), 2);
+        assert_eq!(max_split_legs(GAS_SURPLUS, burn_gas), 1);
+        assert_eq!(max_split_legs(gas_for_leg(burn_gas) * 2, burn_gas), 0);
+        assert_eq!(split_leg_count(&split_config().split_targets, 0), 1);
+        assert_eq!(split_leg_count(&split_config().split_targets, 2), 0);
+        assert_eq!(split_leg_count(&None, 0), 0);
+    }
+
+    #[test]
+    fn test_execute_leg_quotes_pool() {
+        let (mut context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.pending_legs.insert(
+            1,
+            PendingLeg {
+                decision: TreasuryDecision::Sell(1000.),
+            },
+        );
+        contract.treasury.replace(&treasury);
+
+        testing_env!(context.attached_deposit(3).build());
+        drop(contract.execute_treasury_leg(1));
+        assert_eq!(
+            created_function_names(),
+            vec!["get_stable_pool", "get_pool", "handle_execute_treasury_leg"]
+        );
+        // The leg is taken by the quote callback.
+        assert!(contract
+            .treasury
+            .get()
+            .unwrap()
+            .pending_legs
+            .contains_key(&1));
+    }
+
+    #[test]
+    fn test_execute_leg_at_spot() {
+        let execute_leg = |spot| {
+            let (mut context, mut contract) =
+                warmed_usn_contract(&[6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611]);
+            let mut treasury = contract.treasury.take().unwrap();
+            treasury.config.max_spot_divergence = Some(0.02);
+            treasury.pending_legs.insert(
+                1,
+                PendingLeg {
+                    decision: TreasuryDecision::Sell(1000.),
+                },
+            );
+            contract.treasury.replace(&treasury);
+
+            let (_, info, swap_info) = sell_scenario_pools(spot);
+            testing_env!(context
+                .attached_deposit(3)
+                .prepaid_gas(Gas(1_000_000_000_000_000))
+                .build());
+            let result = contract.handle_execute_treasury_leg(1, info, swap_info);
+            (contract.treasury.get().unwrap(), result)
+        };
+
+        let (treasury, result) = execute_leg(6.611);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert!(treasury.in_flight.contains_key(&1));
+        assert!(treasury.pending_legs.is_empty());
+
+        // The swap pool is pumped 10% above the cached rate.
+        let (treasury, result) = execute_leg(7.3);
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(treasury.in_flight.is_empty());
+        assert!(treasury.pending_legs.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "No pending leg for the pool 1")]
+    fn test_execute_missing_leg() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context.attached_deposit(3).build());
+        contract.execute_treasury_leg(1);
+    }
+
+    #[test]
+    fn test_treasury_stats() {
+        // The preview isn't counted.
+        let (mut contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+        assert_eq!(contract.get_treasury_stats(), TreasuryStats::default());
+
+        // Neither is the reused decision counted twice.
+        start_sell_balancing(&mut contract, true);
+        assert_eq!(
+            contract.get_treasury_stats(),
+            TreasuryStats {
+                sells: 1,
+                sold_usd: 23604.588213058174,
+                ..Default::default()
+            }
+        );
+
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.decision.t_sell_min = 100000.;
+        treasury.in_flight.clear();
+        treasury.last_trade_at = None;
+        contract.treasury.replace(&treasury);
+        start_sell_balancing(&mut contract, false);
+        start_sell_balancing(&mut contract, true);
+
+        assert_eq!(
+            contract.get_treasury_stats(),
+            TreasuryStats {
+                buys: 0,
+                sells: 1,
+                do_nothings: 1,
+                bought_usd: 0.,
+                sold_usd: 23604.588213058174,
+            }
+        );
+    }
+
+    #[test]
+    fn test_smoothed_decision() {
+        let (mut contract, result) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                smoothing: Some(0.25),
+                ..Default::default()
+            },
+        );
+        match result {
+            PromiseOrValue::Value(TreasuryDecision::Sell(amount)) => {
+                assert!((amount - 23604.588213058174 * 0.25).abs() < 1.)
+            }
+            _ => panic!("Expected the sell decision"),
+        }
+
+        // Executing the reused decision doesn't scale it again.
+        start_sell_balancing(&mut contract, true);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("5901.147053264543")));
+    }
+
+    #[test]
+    fn test_execution_recomputes_on_changed_cache() {
+        let (mut contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.decision.t_sell_min = 100000.;
+        treasury.cache.append(8 * 300_000_000_000, 6.611);
+        contract.treasury.replace(&treasury);
+
+        assert!(matches!(
+            start_sell_balancing(&mut contract, true),
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+    }
+
+    #[test]
+    fn test_start_balancing_returns_decision() {
+        let (contract, result) = start_sell_scenario(false, TreasuryConfig::default());
+        match result {
+            PromiseOrValue::Value(TreasuryDecision::Sell(amount)) => {
+                assert!((amount - 23604.588213058174).abs() < 1.)
+            }
+            _ => panic!("Expected the sell decision"),
+        }
+        assert!(contract.get_in_flight_balances().is_empty());
+        let event = r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"decision""#;
+        assert!(get_logs().iter().any(|log| {
+            log.starts_with(event)
+                && log.contains(r#""action":"sell""#)
+                && log.ends_with(r#""executed":false}]}"#)
+        }));
+
+        let (contract, result) = start_sell_scenario(true, TreasuryConfig::default());
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert_eq!(contract.get_in_flight_balances().len(), 1);
+    }
+
+    #[test]
+    fn test_trade_intent_before_swap() {
+        let (_, result) = start_sell_scenario(true, TreasuryConfig::default());
+        drop(result);
+
+        let logs = get_logs();
+        let intent = logs
+            .iter()
+            .position(|log| log.contains(r#""event":"trade_intent""#))
+            .expect("Trade intent event");
+        let decision = logs
+            .iter()
+            .position(|log| log.contains(r#""event":"decision""#))
+            .unwrap();
+        assert!(decision < intent);
+        assert!(logs[intent].contains(
+            r#""action":"sell","amount_usd":23604.588213058174,"token_in":"usdt.test.near""#
+        ));
+        assert!(logs[intent].contains(r#""token_out":"wrap.test.near""#));
+
+        // The swap waits for the liquidity removal scheduled along with the intent.
+        let function_names = created_function_names();
+        assert_eq!(function_names[0], "remove_liquidity_by_tokens");
+        assert!(function_names.contains(&"handle_swap_after_remove_liquidity".to_string()));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Not registered with wrap.test.near, use `ensure_withdraw_registration`"
+    )]
+    fn test_sell_requires_withdraw_registration() {
+        start_sell_scenario(
+            true,
+            TreasuryConfig {
+                require_withdraw_registration: true,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_sell_with_withdraw_registration() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                require_withdraw_registration: true,
+                ..Default::default()
+            },
+        );
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury
+            .registered_tokens
+            .insert("wrap.test.near".parse().unwrap());
+        treasury
+            .registered_tokens
+            .insert("usdt.test.near".parse().unwrap());
+        contract.treasury.replace(&treasury);
+
+        let result = start_sell_balancing(&mut contract, true);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Not registered with usdt.test.near, use `ensure_withdraw_registration`"
+    )]
+    fn test_sell_requires_counter_token_registration() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                require_withdraw_registration: true,
+                ..Default::default()
+            },
+        );
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury
+            .registered_tokens
+            .insert("wrap.test.near".parse().unwrap());
+        contract.treasury.replace(&treasury);
+
+        drop(start_sell_balancing(&mut contract, true));
+    }
+
+    #[test]
+    fn test_withdraw_registration() {
+        let (mut context, mut contract) = usn_contract();
+        let wrap_id: AccountId = "wrap.test.near".parse().unwrap();
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+        let results = || {
+            vec![
+                PromiseResult::Successful(
+                    br#"{"total":"1250000000000000000000","available":"0"}"#.to_vec(),
+                ),
+                PromiseResult::Successful(b"null".to_vec()),
+            ]
+        };
+
+        with_promise_results(&context, results());
+        let unregistered =
+            contract.handle_withdraw_registration(vec![wrap_id.clone(), usdt_id.clone()], false);
+        assert_eq!(unregistered, vec![usdt_id.clone()]);
+        let treasury = contract.treasury.get().unwrap();
+        assert_eq!(treasury.registered_tokens.len(), 1);
+        assert!(treasury.registered_tokens.contains(&wrap_id));
+        assert!(created_function_names().is_empty());
+
+        context.attached_deposit(1_250_000_000_000_000_000_000);
+        with_promise_results(&context, results());
+        contract.handle_withdraw_registration(vec![wrap_id.clone(), usdt_id.clone()], true);
+        assert_eq!(
+            created_function_names(),
+            vec!["storage_deposit", "handle_storage_registration"]
+        );
+
+        with_promise_results(&context, vec![PromiseResult::Successful(vec![])]);
+        contract.handle_storage_registration(usdt_id.clone());
+        let treasury = contract.treasury.get().unwrap();
+        assert!(treasury.registered_tokens.contains(&usdt_id));
+
+        // The remainder of the split deposit is refunded.
+        context.attached_deposit(2_500_000_000_000_000_000_001);
+        with_promise_results(
+            &context,
+            vec![
+                PromiseResult::Successful(b"null".to_vec()),
+                PromiseResult::Successful(b"null".to_vec()),
+            ],
+        );
+        contract.handle_withdraw_registration(vec![wrap_id.clone(), usdt_id], true);
+        let deposits: Vec<Balance> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::Transfer { deposit } => Some(deposit),
+                VmAction::FunctionCall {
+                    function_name,
+                    deposit,
+                    ..
+                } if function_name == "storage_deposit" => Some(deposit),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            deposits,
+            vec![
+                1,
+                1_250_000_000_000_000_000_000,
+                1_250_000_000_000_000_000_000
+            ]
+        );
+    }
+
+    #[test]
+    fn test_min_pool_tvl() {
+        // The pool holds $1367351872 of USDT.
+        let (contract, result) = start_sell_scenario(
+            true,
+            TreasuryConfig {
+                min_pool_tvl: Some(2_000_000_000.),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::DoNothing)
+        ));
+        assert!(contract.get_in_flight_balances().is_empty());
+        assert!(get_logs()
+            .contains(&"Pool TVL is too low for balancing: $1367351872.04769".to_string()));
+
+        let (_, result) = start_sell_scenario(
+            true,
+            TreasuryConfig {
+                min_pool_tvl: Some(1_000_000_000.),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Treasury cache is empty. Use `warmup`.")]
+    fn test_balance_treasury_not_warmed() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        contract.balance_treasury(0, None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Treasury cache has 3 of 8 exchange rates. Use `warmup` 5 more times in 5 minute intervals."
+    )]
+    fn test_balance_treasury_partially_warmed() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 3]);
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        contract.balance_treasury(0, None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_balance_treasuries_branches() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context.attached_deposit(6 * ONE_YOCTO).build());
+        drop(contract.balance_treasuries(vec![0, 1], None, None, None, None, None, None));
+
+        let names = created_function_names();
+        assert_eq!(
+            names
+                .iter()
+                .filter(|name| *name == "handle_start_treasury_balancing")
+                .count(),
+            2
+        );
+        assert_eq!(names.last().unwrap(), "handle_balance_treasuries");
+    }
+
+    #[test]
+    #[should_panic(expected = "6 yoctoNEAR of attached deposit is required")]
+    fn test_balance_treasuries_deposit() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        drop(contract.balance_treasuries(vec![0, 1], None, None, None, None, None, None));
+    }
+
+    #[test]
+    #[should_panic(expected = "2 pools need")]
+    fn test_balance_treasuries_gas() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context.attached_deposit(6 * ONE_YOCTO).build());
+        drop(contract.balance_treasuries(vec![0, 1], None, Some(true), None, None, None, None));
+    }
+
+    #[test]
+    fn test_balance_treasuries_partial_results() {
+        let (context, contract) = usn_contract();
+        with_promise_results(
+            &context,
+            vec![
+                PromiseResult::Successful(
+                    near_sdk::serde_json::to_vec(&TreasuryDecision::Sell(100.)).unwrap(),
+                ),
+                PromiseResult::Failed,
+                PromiseResult::Successful(
+                    near_sdk::serde_json::to_vec(&TreasuryDecision::DoNothing).unwrap(),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            contract.handle_balance_treasuries(vec![0, 1, 2]),
+            vec![
+                PerPoolResult {
+                    pool_id: 0,
+                    decision: Some(TreasuryDecision::Sell(100.)),
+                    error: None,
+                },
+                PerPoolResult {
+                    pool_id: 1,
+                    decision: None,
+                    error: Some("Balancing failed".to_string()),
+                },
+                PerPoolResult {
+                    pool_id: 2,
+                    decision: Some(TreasuryDecision::DoNothing),
+                    error: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limits_in_bounds() {
+        let bounds = Some([1000, 100000]);
+        assert_eq!(
+            bounded_limits(Some([1000, 20000]), bounds),
+            Some([1000, 20000])
+        );
+        assert_eq!(
+            bounded_limits(Some([1000, 100000]), bounds),
+            Some([1000, 100000])
+        );
+        // Limits default to the bounds.
+        assert_eq!(bounded_limits(None, bounds), bounds);
+        assert_eq!(bounded_limits(None, None), None);
+    }
+
+    #[test]
+    fn test_limit_units() {
+        let decide = |amount, unit| {
+            let (mut contract, _) = start_sell_scenario(false, TreasuryConfig::default());
+            let (predicted_amounts, info, swap_info) = sell_scenario_pools(6.611);
+            match contract.handle_start_treasury_balancing(
+                0,
+                Some(DecisionLimit { amount, unit }),
+                false,
+                predicted_amounts,
+                info,
+                swap_info,
+            ) {
+                PromiseOrValue::Value(decision) => decision,
+                _ => panic!("Expected the decision"),
+            }
+        };
+
+        assert_eq!(
+            decide(20000, LimitUnit::Usd),
+            TreasuryDecision::Sell(20000.)
+        );
+        assert_eq!(
+            decide(20000, LimitUnit::Usn),
+            TreasuryDecision::Sell(20000.)
+        );
+        // 3000 NEAR at the latest rate of $6.611.
+        assert_eq!(
+            decide(3000, LimitUnit::Near),
+            TreasuryDecision::Sell(3000. * 6.611)
+        );
+
+        assert_eq!(LimitUnit::Near.usd_in(19833., 6.611), 3000.);
+        assert_eq!(LimitUnit::Usn.usd_in(19833., 6.611), 19833.);
+    }
+
+    #[test]
+    fn test_limit_bounds_in_unit() {
+        let config = TreasuryConfig {
+            limit_bounds: Some([1000, 100000]),
+            ..Default::default()
+        };
+        let limit = |limits, unit| limit_in_unit(&config, limits, unit, 6.6, [7; 32]);
+
+        // USD bounds are converted into NEAR.
+        let near_limit = limit(None, LimitUnit::Near).unwrap();
+        assert_eq!(near_limit.unit, LimitUnit::Near);
+        assert!((152..15151).contains(&near_limit.amount));
+        assert!(limit(Some([152, 15151]), LimitUnit::Near).is_some());
+        assert_eq!(
+            limit(Some([1000, 20000]), LimitUnit::Usd),
+            Some(DecisionLimit {
+                amount: decision_limit(Some([1000, 20000]), None, [7; 32]).unwrap(),
+                unit: LimitUnit::Usd,
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`limits` must be within [152; 15151]")]
+    fn test_limits_out_of_bounds_in_near() {
+        let config = TreasuryConfig {
+            limit_bounds: Some([1000, 100000]),
+            ..Default::default()
+        };
+        limit_in_unit(&config, Some([1000, 20000]), LimitUnit::Near, 6.6, [7; 32]);
+    }
+
+    #[test]
+    fn test_equal_limit_bounds() {
+        let config = TreasuryConfig {
+            limit_bounds: Some([1000, 1000]),
+            ..Default::default()
+        };
+        let limit = |unit| limit_in_unit(&config, None, unit, 6.6, [7; 32]).unwrap();
+
+        assert_eq!(limit(LimitUnit::Usd).amount, 1000);
+        assert_eq!(limit(LimitUnit::Near).amount, 151);
+        assert_eq!(decision_limit(Some([500, 500]), None, [7; 32]), Some(500));
+    }
+
+    #[test]
+    fn test_decision_limit_seed() {
+        let limit = decision_limit(Some([1000, 100000]), None, [7; 32]);
+        assert_eq!(limit, Some(73735));
+        assert_eq!(decision_limit(Some([1000, 100000]), None, [7; 32]), limit);
+        assert_ne!(decision_limit(Some([1000, 100000]), None, [8; 32]), limit);
+        assert_eq!(decision_limit(None, None, [7; 32]), None);
+
+        let (mut context, _) = usn_contract();
+        testing_env!(context.random_seed([7; 32]).build());
+        assert_eq!(limit_seed(None), [7; 32]);
+        let entropy = Base64VecU8(vec![1, 2, 3]);
+        assert_ne!(limit_seed(Some(&entropy)), [7; 32]);
+        assert_eq!(limit_seed(Some(&entropy)), limit_seed(Some(&entropy)));
+    }
+
+    #[test]
+    fn test_min_trade_interval() {
+        let (mut context, mut contract) = usn_contract();
+        contract.set_min_trade_interval(Some(600_000_000_000));
+        let mut treasury = contract.treasury.get().unwrap();
+
+        // No trades yet.
+        assert_trade_interval(&treasury);
+
+        treasury.last_trade_at = Some(2_000_000_000_000);
+        for now in [2_600_000_000_000, 2_600_000_000_001, 5_000_000_000_000] {
+            testing_env!(context.block_timestamp(now).build());
+            assert_trade_interval(&treasury);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Only 599999999999 of 600000000000 ns passed since the latest trade")]
+    fn test_min_trade_interval_within() {
+        let (mut context, mut contract) = usn_contract();
+        contract.set_min_trade_interval(Some(600_000_000_000));
+        let mut treasury = contract.treasury.get().unwrap();
+        treasury.last_trade_at = Some(2_000_000_000_000);
+
+        testing_env!(context.block_timestamp(2_599_999_999_999).build());
+        assert_trade_interval(&treasury);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only 100000000000 of 600000000000 ns passed since the latest trade")]
+    fn test_min_trade_interval_not_passed() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        contract.set_min_trade_interval(Some(600_000_000_000));
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.last_trade_at = Some(2_000_000_000_000);
+        contract.treasury.replace(&treasury);
+
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        contract.balance_treasury(0, None, Some(true), None, None, None, None);
+    }
+
+    #[test]
+    fn test_expected_rate() {
+        let execute = |expected_rate, rate_tolerance| {
+            let (mut context, mut contract) =
+                warmed_usn_contract(&[6.6, 6.6, 6.6, 6.6, 6.6, 6.6, 6.5, 6.7]);
+            testing_env!(context
+                .attached_deposit(3 * ONE_YOCTO)
+                .prepaid_gas(Gas(1_000_000_000_000_000))
+                .build());
+            drop(contract.balance_treasury(
+                0,
+                None,
+                Some(true),
+                None,
+                expected_rate,
+                rate_tolerance,
+                None,
+            ));
+            created_function_names()
+        };
+
+        // Within 1% of the latest rate.
+        assert!(execute(Some(6.65), Some(0.01))
+            .contains(&"handle_start_treasury_balancing".to_string()));
+        assert!(execute(Some(6.7), Some(0.)).contains(&"get_pool".to_string()));
+        assert!(!execute(None, None).is_empty());
+
+        // The reference window averages the latest rates.
+        assert_eq!(
+            reference_rate(
+                &[6.6, 6.6, 6.6, 6.6, 6.6, 6.6, 6.5, 6.7],
+                2,
+                ReferenceAggregation::Mean
+            ),
+            6.6
+        );
+        assert_eq!(
+            reference_rate(
+                &[6.6, 6.6, 6.6, 6.6, 6.6, 6.6, 6.5, 6.7],
+                1,
+                ReferenceAggregation::Mean
+            ),
+            6.7
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Reference rate 6.7 deviates from the expected rate 6.5 by 0.030769230769230795, beyond the tolerance 0.03"
+    )]
+    fn test_expected_rate_out_of_tolerance() {
+        let (mut context, mut contract) =
+            warmed_usn_contract(&[6.6, 6.6, 6.6, 6.6, 6.6, 6.6, 6.5, 6.7]);
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        contract.balance_treasury(0, None, Some(true), None, Some(6.5), Some(0.03), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "`expected_rate` requires `rate_tolerance`")]
+    fn test_expected_rate_without_tolerance() {
+        assert_expected_rate(6.7, Some(6.7), None);
+    }
+
+    #[test]
+    fn test_shadow_mode() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        contract.set_shadow_mode(true);
+        // Execution would be rejected with the pool in flight.
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.in_flight.insert(0, 1);
+        contract.treasury.replace(&treasury);
+
+        testing_env!(context
+            .attached_deposit(3 * ONE_YOCTO)
+            .prepaid_gas(Gas(1_000_000_000_000_000))
+            .build());
+        drop(contract.balance_treasury(0, None, Some(true), None, None, None, None));
+
+        let start_args = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "handle_start_treasury_balancing" => {
+                    Some(String::from_utf8(args).unwrap())
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert!(start_args.contains("\"execute\":false"), "{}", start_args);
+    }
+
+    #[test]
+    #[should_panic(expected = "`limits` must be within [1000; 100000]")]
+    fn test_balance_treasury_limits_out_of_bounds() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        contract.set_limit_bounds(Some([1000, 100000]));
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        contract.balance_treasury(0, Some([1000, 1_000_000_000]), None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "`limit_bounds` must be in [min; max] format")]
+    fn test_inverted_limit_bounds() {
+        let (_, mut contract) = usn_contract();
+        contract.set_limit_bounds(Some([100000, 1000]));
+    }
+
+    #[test]
+    fn test_export_import_treasury_cache() {
+        let (_, old_contract) = warmed_usn_contract(&[6.6, 6.7, 6.8, 6.7, 6.6, 6.5, 6.6, 6.7]);
+        let blob = old_contract.export_treasury_cache();
+
+        let (_, mut new_contract) = usn_contract();
+        testing_env!(VMContextBuilder::new()
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * 300_000_000_000)
+            .build());
+        new_contract.import_treasury_cache(blob);
+
+        assert_eq!(
+            new_contract.treasury.get().unwrap().cache,
+            old_contract.treasury.get().unwrap().cache
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Cached items must be in 5 minute slots in increasing order")]
+    fn test_import_unordered_treasury_cache() {
+        let (_, mut contract) = warmed_usn_contract(&[6.6, 6.6]);
+        let item = |timestamp| CacheItem {
+            timestamp,
+            value: 6.6,
+            n: 1,
+        };
+        let cache = IntervalCache {
+            items: vec![item(300_000_000_000), item(0)],
+        };
+        contract.import_treasury_cache(cache.try_to_vec().unwrap().into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Cached exchange rates must be positive numbers")]
+    fn test_import_negative_treasury_cache() {
+        let (_, mut contract) = usn_contract();
+        let mut cache = IntervalCache::default();
+        cache.append(0, -6.6);
+        contract.import_treasury_cache(cache.try_to_vec().unwrap().into());
+    }
+
+    #[test]
+    fn test_repair_treasury_cache() {
+        let (_, mut contract) = warmed_usn_contract(&[6.6, 6.7, 6.8]);
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.cache.items.swap(0, 2);
+        contract.treasury.replace(&treasury);
+
+        let report = contract.repair_treasury_cache();
+
+        assert!(report.reordered);
+        let cache = contract.treasury.get().unwrap().cache;
+        assert_eq!(
+            cache
+                .items
+                .iter()
+                .map(|item| item.value)
+                .collect::<Vec<_>>(),
+            vec![6.6, 6.7, 6.8]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Malformed treasury cache")]
+    fn test_import_malformed_treasury_cache() {
+        let (_, mut contract) = usn_contract();
+        contract.import_treasury_cache(vec![1, 2, 3].into());
+    }
+
+    #[test]
+    fn test_resume_sell() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context
+            .attached_deposit(2 * ONE_YOCTO)
+            .block_index(100)
+            .build());
+
+        drop(contract.resume_sell(0, U128(1_000_000_000), U128(1000 * 10u128.pow(18)), None));
+
+        assert_eq!(
+            contract.get_in_flight_balances(),
+            vec![InFlightBalance {
+                pool_id: 0,
+                started_at: 100
+            }]
+        );
+
+        // 1000 USDT buy 151.5 wNEAR at $6.6, 50% of slippage leaves 75.7 wNEAR in yocto.
+        let swap_args = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "swap" => Some(
+                    near_sdk::serde_json::from_slice::<near_sdk::serde_json::Value>(&args).unwrap(),
+                ),
+                _ => None,
+            })
+            .unwrap();
+        let min_amount_out: u128 = swap_args["actions"][0]["min_amount_out"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(min_amount_out / ONE_NEAR, 75);
+    }
+
+    #[test]
+    #[should_panic(expected = "Balancing of the pool 0 is in progress")]
+    fn test_resume_sell_in_flight() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context.attached_deposit(2 * ONE_YOCTO).build());
+
+        contract.resume_sell(0, U128(1_000_000_000), U128(1000 * 10u128.pow(18)), None);
+        contract.resume_sell(0, U128(1_000_000_000), U128(1000 * 10u128.pow(18)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "USN is not a counter-token")]
+    fn test_resume_sell_usn_counter() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context.attached_deposit(2 * ONE_YOCTO).build());
+
+        contract.resume_sell(
+            0,
+            U128(1_000_000_000),
+            U128(1000 * 10u128.pow(18)),
+            Some("usn.test.near".parse().unwrap()),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "This method can be called only by owner")]
+    fn test_resume_sell_not_owner() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context
+            .attached_deposit(2 * ONE_YOCTO)
+            .predecessor_account_id(accounts(2))
+            .build());
+
+        contract.resume_sell(0, U128(1_000_000_000), U128(1000 * 10u128.pow(18)), None);
+    }
+
+    #[test]
+    fn test_burn_treasury_usn() {
+        let (_, mut contract) = usn_contract();
+        let usn_id = env::current_account_id();
+        contract.token.internal_deposit(&usn_id, 1000);
+
+        contract.burn_treasury_usn(U128(400));
+
+        assert_eq!(contract.ft_balance_of(usn_id).0, 600);
+        assert_eq!(contract.ft_total_supply().0, 600);
+    }
+
+    #[test]
+    fn test_preview_burn() {
+        let (_, mut contract) = usn_contract();
+        let usn_id = env::current_account_id();
+        contract.token.internal_deposit(&usn_id, 1000);
+        contract.token.internal_deposit(&accounts(2), 500);
+
+        assert_eq!(
+            contract.preview_burn(U128(400)),
+            BurnPreview {
+                balance: U128(1000),
+                shortfall: U128(0),
+                total_supply: U128(1100),
+            }
+        );
+        assert_eq!(
+            contract.preview_burn(U128(1200)),
+            BurnPreview {
+                balance: U128(1000),
+                shortfall: U128(200),
+                total_supply: U128(1500),
+            }
+        );
+
+        // The preview matches the burn.
+        contract.burn_treasury_usn(U128(400));
+        assert_eq!(contract.ft_total_supply(), U128(1100));
+    }
+
+    /// Records orders and calls `mock_buy` or `mock_sell` of a DEX.
+    struct MockAdapter {
+        orders: std::cell::RefCell<Vec<(&'static str, f64, Option<u128>)>>,
+    }
+
+    impl MockAdapter {
+        fn trade(&self, action: &'static str, order: TradeOrder) -> Promise {
+            self.orders
+                .borrow_mut()
+                .push((action, order.amount, order.max_in));
+            Promise::new("dex.test.near".parse().unwrap()).function_call(
+                format!("mock_{}", action),
+                vec![],
+                NO_DEPOSIT,
+                GAS_FOR_SWAP,
+            )
+        }
+    }
+
+    impl ExecutionAdapter for MockAdapter {
+        fn execute_buy(&self, _config: &TreasuryConfig, order: TradeOrder) -> Promise {
+            self.trade("buy", order)
+        }
+
+        fn execute_sell(&self, _config: &TreasuryConfig, order: TradeOrder) -> Promise {
+            self.trade("sell", order)
+        }
+    }
+
+    #[test]
+    fn test_execute_with_mock_adapter() {
+        usn_contract();
+        let (_, _, swap_info) = sell_scenario_pools(6.611);
+        let config = TreasuryConfig::default();
+        let execute = |decision| {
+            let adapter = MockAdapter {
+                orders: Default::default(),
+            };
+            match execute_with(
+                &adapter,
+                &config,
+                Pool::from_config_with_assert(0),
+                1,
+                decision,
+                6.611,
+                Some(&swap_info),
+            ) {
+                PromiseOrValue::Promise(promise) => drop(promise),
+                PromiseOrValue::Value(_) => {}
+            }
+            adapter.orders.into_inner()
+        };
+
+        // The swap input is limited by the reserve of the sold token.
+        assert_eq!(
+            execute(TreasuryDecision::Buy(100.)),
+            vec![("buy", 100., Some(1_000_000 * ONE_NEAR))]
+        );
+        assert_eq!(created_function_names(), vec!["mock_buy"]);
+        assert_eq!(
+            execute(TreasuryDecision::Sell(200.)),
+            vec![("sell", 200., Some(6_611_000 * 1_000_000))]
+        );
+        assert!(execute(TreasuryDecision::DoNothing).is_empty());
+    }
+
+    #[test]
+    fn test_default_execution_venue() {
+        assert_eq!(
+            TreasuryConfig::default().execution_venue,
+            ExecutionVenue::RefFinance
+        );
+    }
+
+    #[test]
+    fn test_burn_ceiling() {
+        let burn = |amount, clamp| {
+            let (context, mut contract) = usn_contract();
+            let usn_id = env::current_account_id();
+            let mut treasury = contract.treasury.take().unwrap();
+            treasury.config.burn_ceiling = Some(BurnCeiling {
+                max_amount: U128(1000),
+                clamp,
+            });
+            contract.treasury.replace(&treasury);
+            contract.token.internal_deposit(&usn_id, 5000);
+
+            with_promise_results(&context, vec![PromiseResult::Successful(vec![])]);
+            contract.finish_withdraw_with_burn(U128(amount));
+            (contract.ft_total_supply().0, get_logs())
+        };
+
+        // The ceiling itself is burned.
+        assert_eq!(burn(1000, false).0, 4000);
+        assert_eq!(burn(1000, true).0, 4000);
+
+        let (supply, logs) = burn(1001, true);
+        assert_eq!(supply, 4000);
+        assert_eq!(
+            logs[0],
+            "USN burn of 1001 exceeds the ceiling, 1000 is burned, 1 stays in the treasury"
+        );
+
+        let (supply, logs) = burn(1001, false);
+        assert_eq!(supply, 5000);
+        assert_eq!(
+            logs,
+            vec!["USN burn of 1001 exceeds the ceiling, 0 is burned, 1001 stays in the treasury"]
+        );
+    }
+
+    #[test]
+    fn test_withheld_usn_burned_later() {
+        let (_, mut contract) = usn_contract();
+        let usn_id = env::current_account_id();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.burn_ceiling = Some(BurnCeiling {
+            max_amount: U128(1000),
+            clamp: false,
+        });
+        contract.treasury.replace(&treasury);
+        contract.token.internal_deposit(&usn_id, 5000);
+
+        contract.internal_burn_sold_usn(1500);
+        assert_eq!(
+            contract.treasury.get().unwrap().withheld_usn,
+            Some(U128(1500))
+        );
+
+        // The ceiling left by the next sell burns a part of the withheld USN.
+        contract.internal_burn_sold_usn(400);
+        assert_eq!(
+            contract.treasury.get().unwrap().withheld_usn,
+            Some(U128(900))
+        );
+        assert_eq!(contract.ft_total_supply().0, 4000);
+        assert!(get_logs().contains(&"Withheld USN of 600 is burned".to_string()));
+
+        contract.burn_treasury_usn(U128(1000));
+        assert_eq!(contract.treasury.get().unwrap().withheld_usn, None);
+    }
+
+    #[test]
+    fn test_withheld_usn_excluded_from_circulating() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                log_verbosity: LogVerbosity::Verbose,
+                burn_ceiling: Some(BurnCeiling {
+                    max_amount: U128(ONE_NEAR),
+                    clamp: false,
+                }),
+                ..Default::default()
+            },
+        );
+        // USN of the previous sell above the ceiling.
+        contract
+            .token
+            .internal_deposit(&env::current_account_id(), 1000 * ONE_NEAR);
+        contract.internal_burn_sold_usn(1000 * ONE_NEAR);
+        assert_eq!(
+            contract.treasury.get().unwrap().withheld_usn,
+            Some(U128(1000 * ONE_NEAR))
+        );
+
+        start_sell_balancing(&mut contract, false);
+        assert!(get_logs()
+            .iter()
+            .filter(|log| log.starts_with("Reserve: NEAR = "))
+            .all(|log| log.contains("USN = 1241195491.76577,")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough USN in the treasury: 1000 < 1001")]
+    fn test_burn_treasury_usn_not_enough() {
+        let (_, mut contract) = usn_contract();
+        contract
+            .token
+            .internal_deposit(&env::current_account_id(), 1000);
+
+        contract.burn_treasury_usn(U128(1001));
+    }
+
+    #[test]
+    #[should_panic(expected = "This method can be called only by owner")]
+    fn test_burn_treasury_usn_not_owner() {
+        let (mut context, mut contract) = usn_contract();
+        contract
+            .token
+            .internal_deposit(&env::current_account_id(), 1000);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.burn_treasury_usn(U128(1000));
+    }
+
+    #[test]
+    fn test_in_flight_balances() {
+        let (mut context, mut contract) = usn_contract();
+        assert!(contract.get_in_flight_balances().is_empty());
+
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.in_flight.insert(3, 100);
+        treasury.in_flight.insert(1, 120);
+        contract.treasury.replace(&treasury);
+
+        assert_eq!(
+            contract.get_in_flight_balances(),
+            vec![
+                InFlightBalance {
+                    pool_id: 1,
+                    started_at: 120
+                },
+                InFlightBalance {
+                    pool_id: 3,
+                    started_at: 100
+                },
+            ]
+        );
+
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .build());
+        assert!(matches!(
+            contract.finish_treasury_balancing(1, TreasuryDecision::Sell(1000.)),
+            PromiseOrValue::Value(TreasuryDecision::Sell(_))
+        ));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.force_clear_in_flight(3);
+
+        assert!(contract.get_in_flight_balances().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Balancing of the pool 3 is not in progress")]
+    fn test_force_clear_not_in_flight() {
+        let (_, mut contract) = usn_contract();
+        contract.force_clear_in_flight(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "This method can be called only by owner")]
+    fn test_force_clear_not_owner() {
+        let (mut context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.in_flight.insert(3, 100);
+        contract.treasury.replace(&treasury);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.force_clear_in_flight(3);
+    }
+
+    #[test]
+    fn test_decision_denomination() {
+        let sell = TreasuryDecision::Sell(1000.);
+        let buy = TreasuryDecision::Buy(990.);
+
+        assert_eq!(
+            sell.in_denomination(Denomination::Usd, 0.99),
+            TreasuryDecision::Sell(1000.)
+        );
+        assert_eq!(
+            sell.in_denomination(Denomination::Usn, 1.25),
+            TreasuryDecision::Sell(800.)
+        );
+        assert_eq!(
+            buy.in_denomination(Denomination::Usn, 0.99),
+            TreasuryDecision::Buy(1000.)
+        );
+        assert_eq!(
+            TreasuryDecision::DoNothing.in_denomination(Denomination::Usn, 0.99),
+            TreasuryDecision::DoNothing
+        );
+        assert_eq!(
+            sell.describe(Denomination::Usd),
+            "Treasury decision is to sell $1000 USDT"
+        );
+        assert_eq!(
+            TreasuryDecision::Sell(800.).describe(Denomination::Usn),
+            "Treasury decision is to sell USDT for 800 USN"
+        );
+        assert_eq!(usd_to_usn(1010., 1.01), 1000.);
+    }
+
+    #[test]
+    fn test_usn_usd_rate() {
+        let (_, _) = usn_contract();
+        let pool = Pool::from_config_with_assert(0);
+        let (_, mut info, _) = sell_scenario_pools(6.611);
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+        let oracle_prices = HashMap::from([(usdt_id, 0.98)]);
+
+        // No USN in the pool.
+        assert_eq!(usn_usd_rate(&pool, &info, 1, &HashMap::new()), 1.);
+
+        // 1.25 USN for 1 USDT.
+        info.c_amounts[0] = U128(info.c_amounts[1].0 / 4 * 5);
+        assert_eq!(usn_usd_rate(&pool, &info, 1, &HashMap::new()), 0.8);
+        assert!((usn_usd_rate(&pool, &info, 1, &oracle_prices) - 0.784).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_decision_in_usn() {
+        let (mut contract, _) = start_sell_scenario(
+            false,
+            TreasuryConfig {
+                denomination: Denomination::Usn,
+                decision_decimals: Some(2),
+                ..Default::default()
+            },
+        );
+        // USN trades at $0.8 in the pool.
+        let (predicted_amounts, mut info, swap_info) = sell_scenario_pools(6.611);
+        info.c_amounts[0] = U128(info.c_amounts[1].0 / 4 * 5);
+        let result = contract.handle_start_treasury_balancing(
+            0,
+            None,
+            false,
+            predicted_amounts,
+            info,
+            swap_info,
+        );
+
+        // $23604.588213058174 in USN.
+        assert!(matches!(
+            result,
+            PromiseOrValue::Value(TreasuryDecision::Sell(amount)) if amount == 29505.74
+        ));
+        assert!(
+            get_logs().contains(&"Treasury decision is to sell USDT for 29505.74 USN".to_string())
+        );
+        assert_eq!(contract.treasury.get().unwrap().usn_usd_rate, Some(0.8));
+
+        // The executed decision is returned in USN as well.
+        assert!(matches!(
+            contract.finish_treasury_balancing(0, TreasuryDecision::Sell(23604.588213058174)),
+            PromiseOrValue::Value(TreasuryDecision::Sell(amount)) if amount == 29505.74
+        ));
+    }
+
+    #[test]
+    fn test_decision_decimals() {
+        let config = || TreasuryConfig {
+            decision_decimals: Some(2),
+            ..Default::default()
+        };
+
+        let (_, result) = start_sell_scenario(false, config());
+        let shown = match result {
+            PromiseOrValue::Value(decision) => decision,
+            _ => panic!("Expected the decision"),
+        };
+        assert_eq!(shown, TreasuryDecision::Sell(23604.59));
+        assert!(get_logs().contains(&"Treasury decision is to sell $23604.59 USDT".to_string()));
+
+        // The sell is sized with the full precision.
+        let (contract, _) = start_sell_scenario(true, config());
+        let treasury = contract.treasury.get().unwrap();
+        assert_eq!(treasury.stats.sold_usd, 23604.588213058174);
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap of usn.test.near into itself in the pool 4")]
+    fn test_degenerate_swap_route() {
+        usn_contract();
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        let actions = vec![
+            SwapAction {
+                pool_id: 3,
+                token_in: "wrap.test.near".parse().unwrap(),
+                amount_in: Some(U128(1)),
+                token_out: usn_id.clone(),
+                min_amount_out: U128(0),
+            },
+            SwapAction {
+                pool_id: 4,
+                token_in: usn_id.clone(),
+                amount_in: None,
+                token_out: usn_id,
+                min_amount_out: U128(0),
+            },
+        ];
+
+        assert_swap_route(&actions);
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap of usdt.test.near into itself in the pool 3")]
+    fn test_resume_sell_into_counter_token() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        testing_env!(context.attached_deposit(2 * ONE_YOCTO).build());
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.swap.wrap_id = "usdt.test.near".parse().unwrap();
+        contract.treasury.replace(&treasury);
+
+        contract.resume_sell(0, U128(1_000_000_000), U128(1000 * 10u128.pow(18)), None);
+    }
+
+    fn price_data(timestamp: u64) -> PriceData {
+        near_sdk::serde_json::from_str(&format!(
+            r#"{{
+                "timestamp": "{}",
+                "recency_duration_sec": 90,
+                "prices": [{{
+                    "asset_id": "wrap.test.near",
+                    "price": {{ "multiplier": "111439", "decimals": 28 }}
+                }}]
+            }}"#,
+            timestamp
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_oracle_counter_prices() {
+        let (mut context, mut contract) = usn_contract();
+        contract.set_oracle_asset_id(
+            "usdt.test.near".parse().unwrap(),
+            Some("usdt.oracle".to_string()),
+        );
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.counter_price_source = CounterPriceSource::Oracle;
+        contract.treasury.replace(&treasury);
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .build());
+
+        // USDT of 6 decimals at $0.98.
+        let price: PriceData = near_sdk::serde_json::from_str(
+            r#"{
+                "timestamp": "0",
+                "recency_duration_sec": 90,
+                "prices": [{
+                    "asset_id": "wrap.test.near",
+                    "price": { "multiplier": "111439", "decimals": 28 }
+                }, {
+                    "asset_id": "usdt.oracle",
+                    "price": { "multiplier": "9800", "decimals": 10 }
+                }]
+            }"#,
+        )
+        .unwrap();
+        contract.handle_exchange_rate_cache(0, Ok(price), Err(PromiseError::Failed));
+
+        let treasury = contract.treasury.get().unwrap();
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+        assert_eq!(
+            treasury.counter_prices,
+            HashMap::from([(usdt_id.clone(), 0.98)])
+        );
+
+        // The oracle price values the counter-token of the decision.
+        let (_, info, _) = sell_scenario_pools(6.611);
+        let pool = Pool::from_config_with_assert(0);
+        assert_eq!(
+            treasury
+                .config
+                .counter_price_source
+                .price(&pool, &info, 1, &treasury.counter_prices),
+            0.98
+        );
+    }
+
+    #[test]
+    fn test_price_data_to_rate() {
+        usn_contract();
+        assert_eq!(price_data_to_rate(price_data(0)), 11.1439);
+    }
+
+    #[test]
+    fn test_warmup_retry() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .block_timestamp(100 * 1_000_000_000)
+            .build());
+
+        // Failed and outdated responses are requested again.
+        let result = contract.handle_exchange_rate_cache(
+            2,
+            Err(PromiseError::Failed),
+            Err(PromiseError::Failed),
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        let result =
+            contract.handle_exchange_rate_cache(1, Ok(price_data(0)), Err(PromiseError::Failed));
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert!(contract.treasury.get().unwrap().cache.items.is_empty());
+
+        let fresh = 100 * 1_000_000_000;
+        let result = contract.handle_exchange_rate_cache(
+            1,
+            Ok(price_data(fresh)),
+            Err(PromiseError::Failed),
+        );
+        assert!(matches!(result, PromiseOrValue::Value(())));
+        assert_eq!(contract.treasury.get().unwrap().cache.items.len(), 1);
+    }
+
+    #[test]
+    fn test_warmup_requests_counter_asset_ids() {
+        let (mut context, mut contract) = usn_contract();
+        contract.set_oracle_asset_id(
+            "usdt.test.near".parse().unwrap(),
+            Some("usdt.oracle".to_string()),
+        );
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .build());
+
+        let result = contract.handle_exchange_rate_cache(
+            1,
+            Err(PromiseError::Failed),
+            Err(PromiseError::Failed),
+        );
+        match result {
+            PromiseOrValue::Promise(promise) => drop(promise),
+            PromiseOrValue::Value(_) => panic!("Expected the promise"),
+        }
+
+        match &get_created_receipts()[0].actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "get_price_data");
+                assert_eq!(
+                    String::from_utf8(args.clone()).unwrap(),
+                    "{\"asset_ids\":[\"wrap.test.near\",\"usdt.oracle\"]}"
+                );
+            }
+            _ => panic!("Expected the function call"),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle failed to provide an exchange rate")]
+    fn test_warmup_no_retries_left() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .build());
+
+        contract.handle_exchange_rate_cache(
+            0,
+            Err(PromiseError::Failed),
+            Err(PromiseError::Failed),
+        );
+    }
+
+    #[test]
+    fn test_warmup_out_of_order_price() {
+        let (mut context, mut contract) = usn_contract();
+        let cached_at = 100 * 1_000_000_000;
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.cache.append(cached_at, 11.);
+        contract.treasury.replace(&treasury);
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .block_timestamp(cached_at + 1_000_000_000)
+            .build());
+
+        // The older price is skipped and requested again.
+        let result = contract.handle_exchange_rate_cache(
+            1,
+            Ok(price_data(cached_at - 1)),
+            Err(PromiseError::Failed),
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert!(get_logs()[0].starts_with(&format!(
+            "Oracle price at {} predates the cached rate at {}, skipped",
+            cached_at - 1,
+            cached_at
+        )));
+        let treasury = contract.treasury.get().unwrap();
+        assert_eq!(treasury.cache.items.len(), 1);
+        assert_eq!(treasury.cache.items[0].value, 11.);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Oracle price at 99999999999 predates the cached rate at 100000000000"
+    )]
+    fn test_warmup_out_of_order_price_abort() {
+        let (mut context, mut contract) = usn_contract();
+        contract.set_out_of_order_price_policy(OutOfOrderPricePolicy::Abort);
+        let cached_at = 100 * 1_000_000_000;
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.cache.append(cached_at, 11.);
+        contract.treasury.replace(&treasury);
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .block_timestamp(cached_at + 1_000_000_000)
+            .build());
+
+        contract.handle_exchange_rate_cache(
+            1,
+            Ok(price_data(cached_at - 1)),
+            Err(PromiseError::Failed),
+        );
+    }
+
+    fn rate_precedence(sources: Vec<RateSource>, max_disagreement: Option<f64>) -> RatePrecedence {
+        RatePrecedence {
+            sources,
+            max_disagreement,
+        }
+    }
+
+    #[test]
+    fn test_select_rate_agreement() {
+        let precedence =
+            rate_precedence(vec![RateSource::Oracle, RateSource::SwapPool], Some(0.01));
+        assert_eq!(select_rate(&precedence, Some(6.6), Some(6.61)), Ok(6.6));
+    }
+
+    #[test]
+    fn test_select_rate_disagreement() {
+        let precedence =
+            rate_precedence(vec![RateSource::Oracle, RateSource::SwapPool], Some(0.01));
+        assert_eq!(
+            select_rate(&precedence, Some(6.6), Some(7.3)),
+            Err("SwapPool rate 7.3 disagrees with Oracle rate 6.6".to_string())
+        );
+        // Disagreement is allowed without the threshold.
+        let precedence = rate_precedence(vec![RateSource::Oracle, RateSource::SwapPool], None);
+        assert_eq!(select_rate(&precedence, Some(6.6), Some(7.3)), Ok(6.6));
+    }
+
+    #[test]
+    fn test_select_rate_fallback() {
+        let precedence =
+            rate_precedence(vec![RateSource::Oracle, RateSource::SwapPool], Some(0.01));
+        assert_eq!(select_rate(&precedence, None, Some(6.61)), Ok(6.61));
+        assert_eq!(
+            select_rate(&precedence, None, None),
+            Err("Oracle and SwapPool failed to provide an exchange rate".to_string())
+        );
+        // Sources out of the precedence are ignored.
+        let precedence = rate_precedence(vec![RateSource::Oracle], None);
+        assert!(select_rate(&precedence, None, Some(6.61)).is_err());
+    }
+
+    #[test]
+    fn test_warmup_from_swap_pool() {
+        let (mut context, mut contract) = usn_contract();
+        contract.set_rate_precedence(rate_precedence(
+            vec![RateSource::Oracle, RateSource::SwapPool],
+            None,
+        ));
+        testing_env!(context
+            .predecessor_account_id("usn.test.near".parse().unwrap())
+            .build());
+
+        let swap_info = PoolInfo {
+            pool_kind: "SIMPLE_POOL".to_string(),
+            token_account_ids: vec![
+                "wrap.test.near".parse().unwrap(),
+                "usdt.test.near".parse().unwrap(),
+            ],
+            amounts: vec![U128(1_000 * ONE_NEAR), U128(6_610 * 10u128.pow(6))],
+            total_fee: 30,
+            shares_total_supply: U128(0),
+            amp: 0,
+        };
+        contract.handle_exchange_rate_cache(0, Err(PromiseError::Failed), Ok(swap_info));
+
+        assert_eq!(contract.treasury.get().unwrap().cache.items[0].value, 6.61);
+    }
+
+    #[test]
+    #[should_panic(expected = "Up to 3 retries are allowed")]
+    fn test_warmup_too_many_retries() {
+        let (_, mut contract) = usn_contract();
+        contract.warmup(Some(4));
+    }
+
+    #[test]
+    fn test_plausible_limit() {
+        assert_plausible_limit(3_000_000., 1_269_678_130.);
+        assert_plausible_limit(1_269_678_130., 1_269_678_130.);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Decision limit 3000000000000 exceeds the reserve value of 1269678130 USD, check the unit of limits"
+    )]
+    fn test_implausible_limit() {
+        // $3M scaled by USDT decimals.
+        assert_plausible_limit(3_000_000_000_000., 1_269_678_130.);
+    }
+
+    #[test]
+    #[should_panic(expected = "USD, check the unit of limits")]
+    fn test_implausible_limit_rejected_upfront() {
+        let (mut context, mut contract) = warmed_usn_contract(&[6.6; 8]);
+        contract.set_excess_limit_policy(ExcessLimitPolicy::Clamp);
+        testing_env!(context.attached_deposit(3 * ONE_YOCTO).build());
+        drop(contract.balance_treasury(
+            0,
+            Some([1_000_000_000_000, 3_000_000_000_000]),
+            None,
+            None,
+            None,
+            None,
+            None,
+        ));
+    }
+
+    #[test]
+    fn test_min_amount_out_by_order_size() {
+        let curve = SlippageCurve {
+            base: 0.01,
+            linear: 0.1,
+            quadratic: 0.,
+        };
+
+        // $1000 order tolerates ~1% slippage.
+        let small = 1_000.;
+        assert_eq!(
+            min_amount_out(small, 0., curve.slippage(small), 6),
+            989_900_000
+        );
+
+        // $2M order tolerates 21% slippage.
+        let large = 2_000_000.;
+        assert_eq!(
+            min_amount_out(large, 0., curve.slippage(large), 6),
+            1_580_000_000_000
+        );
+    }
+
+    #[test]
+    fn test_min_amount_out_with_swap_fee() {
+        let (_, mut contract) = usn_contract();
+        let slippage = SlippageCurve {
+            base: 0.01,
+            linear: 0.,
+            quadratic: 0.,
+        };
+        let min_out = |contract: &Contract| {
+            let config = contract.treasury.get().unwrap().config;
+            min_amount_out(1000., config.swap_fee(), slippage.slippage(1000.), 6)
+        };
+        assert_eq!(min_out(&contract), 990_000_000);
+
+        // 0.3% fee of the swap pool.
+        let swap_pool_id = contract.treasury.get().unwrap().config.swap.swap_pool_id;
+        contract.set_swap_fee(swap_pool_id, 30);
+        assert_eq!(min_out(&contract), 987_030_000);
+
+        // 0.05% after a rebate.
+        contract.set_swap_fee(swap_pool_id, 5);
+        assert_eq!(min_out(&contract), 989_505_000);
+
+        // Fees of other pools don't apply.
+        contract.set_swap_fee(swap_pool_id, 0);
+        contract.set_swap_fee(swap_pool_id + 1, 30);
+        assert_eq!(min_out(&contract), 990_000_000);
+    }
+
+    #[test]
+    fn test_volatility_guard() {
+        let calm = vec![6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611];
+        let volatile = vec![6.61, 6.62, 6.6, 6.61, 6.62, 6.6, 6.61, 7.5];
+
+        assert!(!is_too_volatile(&calm, Some(2.)));
+        assert!(is_too_volatile(&volatile, Some(2.)));
+        assert!(!is_too_volatile(&volatile, None));
+        assert!(!is_too_volatile(&[6.5; 8], Some(0.)));
+    }
+
+    #[test]
+    fn test_make_treasury_decision_verbose() {
+        make_treasury_decision(
+            &DecisionParams::default(),
+            vec![6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611],
+            vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+            ReserveState {
+                near: 191937460.53121,
+                usn: 1241195491.76577,
+                usdt: 1367351872.04769,
+                limit: None,
+                elapsed: None,
+            },
+            true,
+        );
+
+        let logs = get_logs();
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].starts_with("Trend: a = "));
+        assert!(logs[1].starts_with("Trend coefficient: C = "));
+    }
+
+    #[test]
+    fn test_preview_decision_params() {
+        let (_, contract) =
+            warmed_usn_contract(&[6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611]);
+        let preview = |params| {
+            contract.preview_decision_params(
+                params,
+                191937460.53121,
+                1241195491.76577,
+                1367351872.04769,
+            )
+        };
+
+        assert_eq!(
+            preview(DecisionParams::default()),
+            TreasuryDecision::Sell(23604.588213058174)
+        );
+        assert_eq!(
+            preview(DecisionParams {
+                t_sell_min: 30000.,
+                ..Default::default()
+            }),
+            TreasuryDecision::DoNothing
+        );
+        assert_eq!(
+            contract.treasury.get().unwrap().config.decision,
+            DecisionParams::default()
+        );
+    }
+
+    #[test]
+    fn test_decision_with_nanosecond_timestamps() {
+        let exchange_rates = [6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611];
+        let start = 1_650_000_000_000_000_000;
+        let (mut context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        for (i, rate) in exchange_rates.iter().enumerate() {
+            treasury
+                .cache
+                .append(start + i as u64 * 300_000_000_000, *rate);
+        }
+        contract.treasury.replace(&treasury);
+        testing_env!(context.block_timestamp(start + 7 * 300_000_000_000).build());
+
+        let preview = |params| {
+            contract.preview_decision_params(
+                params,
+                191937460.53121,
+                1241195491.76577,
+                1367351872.04769,
+            )
+        };
+
+        // The same as with integer time points.
+        assert_eq!(
+            preview(DecisionParams::default()),
+            TreasuryDecision::Sell(23604.588213058174)
+        );
+
+        let params = DecisionParams {
+            time_unit_sec: 60,
+            ..Default::default()
+        };
+        assert_eq!(
+            preview(params.clone()),
+            make_treasury_decision(
+                &params,
+                exchange_rates.to_vec(),
+                vec![-35., -30., -25., -20., -15., -10., -5., -0.],
+                ReserveState {
+                    near: 191937460.53121,
+                    usn: 1241195491.76577,
+                    usdt: 1367351872.04769,
+                    limit: None,
+                    elapsed: None
+                },
+                false,
+            )
+        );
+    }
+
+    #[test]
+    fn test_clamp_trade_size() {
+        let bounds = Some(TradeSizeBounds {
+            min_usd: 5_000.,
+            max_usd: 100_000.,
+        });
+
+        // Lower bound.
+        assert_eq!(
+            clamp_trade_size(TreasuryDecision::Sell(4_999.), &bounds),
+            TreasuryDecision::DoNothing
+        );
+        assert_eq!(
+            clamp_trade_size(TreasuryDecision::Buy(5_000.), &bounds),
+            TreasuryDecision::Buy(5_000.)
+        );
+        // Upper bound.
+        assert_eq!(
+            clamp_trade_size(TreasuryDecision::Buy(100_000.), &bounds),
+            TreasuryDecision::Buy(100_000.)
+        );
+        assert_eq!(
+            clamp_trade_size(TreasuryDecision::Sell(207_013.), &bounds),
+            TreasuryDecision::Sell(100_000.)
+        );
+        // No bounds.
+        assert_eq!(
+            clamp_trade_size(TreasuryDecision::Sell(207_013.), &None),
+            TreasuryDecision::Sell(207_013.)
+        );
+        assert_eq!(
+            clamp_trade_size(TreasuryDecision::DoNothing, &bounds),
+            TreasuryDecision::DoNothing
+        );
+    }
+
+    #[test]
+    fn test_split_decision() {
+        let targets = Some(vec![
+            SplitTarget {
+                pool_id: 1,
+                fraction: 0.3,
+            },
+            SplitTarget {
+                pool_id: 0,
+                fraction: 0.7,
+            },
+        ]);
+
+        assert_eq!(
+            split_decision(&TreasuryDecision::Buy(1000.), 0, &targets),
+            vec![
+                (0, TreasuryDecision::Buy(700.)),
+                (1, TreasuryDecision::Buy(300.))
+            ]
+        );
+        assert_eq!(
+            split_decision(&TreasuryDecision::Sell(1000.), 1, &targets),
+            vec![
+                (1, TreasuryDecision::Sell(300.)),
+                (0, TreasuryDecision::Sell(700.))
+            ]
+        );
+        // Pools out of the split trade the whole amount.
+        assert_eq!(
+            split_decision(&TreasuryDecision::Sell(1000.), 2, &targets),
+            vec![(2, TreasuryDecision::Sell(1000.))]
+        );
+        assert_eq!(
+            split_decision(&TreasuryDecision::Sell(1000.), 0, &None),
+            vec![(0, TreasuryDecision::Sell(1000.))]
+        );
+        assert_eq!(
+            split_decision(&TreasuryDecision::DoNothing, 0, &targets),
+            vec![(0, TreasuryDecision::DoNothing)]
+        );
+    }
+
+    #[test]
+    fn test_smooth_decision() {
+        assert_eq!(
+            smooth_decision(TreasuryDecision::Buy(100_000.), Some(0.25)),
+            TreasuryDecision::Buy(25_000.)
+        );
+        assert_eq!(
+            smooth_decision(TreasuryDecision::Sell(100_000.), Some(0.25)),
+            TreasuryDecision::Sell(25_000.)
+        );
+        assert_eq!(
+            smooth_decision(TreasuryDecision::Sell(100_000.), None),
+            TreasuryDecision::Sell(100_000.)
+        );
+        assert_eq!(
+            smooth_decision(TreasuryDecision::DoNothing, Some(0.25)),
+            TreasuryDecision::DoNothing
+        );
+    }
+
+    #[test]
+    fn test_extrapolation_confidence() {
+        let time_points = vec![-7., -6., -5., -4., -3., -2., -1., -0.];
+
+        // Inside the window.
+        assert_eq!(extrapolation_confidence(&time_points, -3., 1.), 1.);
+        // Near the window.
+        assert_eq!(extrapolation_confidence(&time_points, 0.7, 1.), 1. / 1.1);
+        // Far from the window.
+        assert_eq!(extrapolation_confidence(&time_points, 14., 1.), 1. / 3.);
+        assert_eq!(extrapolation_confidence(&time_points, -21., 1.), 1. / 3.);
+        // Decay is disabled.
+        assert_eq!(extrapolation_confidence(&time_points, 14., 0.), 1.);
+        assert_eq!(
+            extrapolation_confidence(&time_points, f64::INFINITY, 0.),
+            1.
+        );
+    }
+
+    #[test]
+    fn test_make_treasury_decision_buy_with_decay() {
+        // Concave trend with the vertex at `vertex`.
+        let decide = |vertex: f64, extrapolation_decay| {
+            let time_points = vec![-7., -6., -5., -4., -3., -2., -1., -0.];
+            let exchange_rates = time_points
+                .iter()
+                .map(|t: &f64| 6. - 0.001 * (t - vertex).powi(2))
+                .collect();
+            let params = DecisionParams {
+                t_buy_step: 1e12,
+                extrapolation_decay,
+                ..Default::default()
+            };
+            match make_treasury_decision(
+                &params,
+                exchange_rates,
+                time_points,
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 500000000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                false,
+            ) {
+                TreasuryDecision::Buy(amount) => amount,
+                _ => panic!("Expected the buy decision"),
+            }
+        };
+
+        let near_window = decide(1., 1.) / decide(1., 0.);
+        let far_window = decide(14., 1.) / decide(14., 0.);
+        assert!((near_window - 7. / 8.).abs() < 1e-6);
+        assert!((far_window - 1. / 3.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_make_treasury_decision_collapsed_time_points() {
+        let decide = |time_points: Vec<f64>| {
+            let exchange_rates = vec![6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611];
+            make_treasury_decision(
+                &DecisionParams::default(),
+                exchange_rates,
+                time_points,
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 500000000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                true,
+            )
+        };
+
+        // All samples in the same block.
+        assert_eq!(decide(vec![-0.5; 8]), TreasuryDecision::DoNothing);
+        assert_eq!(
+            get_logs(),
+            vec!["Time points are too close to fit the trend"]
+        );
+
+        // Two distinct timestamps can't shape a parabola.
+        let two = vec![-1., -1., -1., -1., 0., 0., 0., 0.];
+        assert_eq!(decide(two), TreasuryDecision::DoNothing);
+
+        // Distinct, but within a fraction of a second.
+        let close = (0..8).map(|k| k as f64 * -1e-4).rev().collect();
+        assert_eq!(decide(close), TreasuryDecision::DoNothing);
+
+        assert!(has_time_spread(&[-7., -6., -5., -4., -3., -2., -1., -0.]));
+    }
+
+    #[test]
+    fn test_make_treasury_decision_without_smoothing() {
+        let decide = |smoothing_enabled| {
+            let time_points = vec![-7., -6., -5., -4., -3., -2., -1., -0.];
+            let exchange_rates = time_points
+                .iter()
+                .map(|t: &f64| 6. - 0.001 * (t - 1.).powi(2))
+                .collect();
+            let params = DecisionParams {
+                t_buy_step: 1e12,
+                smoothing_enabled,
+                ..Default::default()
+            };
+            match make_treasury_decision(
+                &params,
+                exchange_rates,
+                time_points,
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 500000000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                true,
+            ) {
+                TreasuryDecision::Buy(amount) => amount,
+                _ => panic!("Expected the buy decision"),
+            }
+        };
+
+        // Averaging shifts the parabola, so its fit explains the raw rates worse.
+        let smoothed = decide(true);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Trend:") && !log.ends_with("R2 = 1")));
+        let raw = decide(false);
+        assert!(get_logs().iter().any(|log| log.ends_with("R2 = 1")));
+        assert!(raw > smoothed);
+    }
+
+    #[test]
+    fn test_make_treasury_decision_min_expected_edge() {
+        let decide = |curvature: f64, min_expected_edge_bps| {
+            let time_points = vec![-7., -6., -5., -4., -3., -2., -1., -0.];
+            let exchange_rates = time_points
+                .iter()
+                .map(|t: &f64| 6. - curvature * (t - 1.).powi(2))
+                .collect();
+            let params = DecisionParams {
+                t_buy_step: 1e12,
+                smoothing_enabled: false,
+                min_expected_edge_bps,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                exchange_rates,
+                time_points,
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 500000000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                true,
+            )
+        };
+
+        // The rate falls from 5.9999 to 5.9964 by the horizon: 5.8 bps.
+        assert!(matches!(decide(0.0001, 0), TreasuryDecision::Buy(_)));
+        assert_eq!(decide(0.0001, 20), TreasuryDecision::DoNothing);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.starts_with("Expected edge 5.8")));
+        // The rate falls from 5.999 to 5.964: 58 bps.
+        assert!(matches!(decide(0.001, 20), TreasuryDecision::Buy(_)));
+        assert_eq!(decide(0.001, 60), TreasuryDecision::DoNothing);
+    }
+
+    #[test]
+    fn test_make_treasury_decision_clamped_c() {
+        let decide = |c_min, c_max| {
+            let params = DecisionParams {
+                t_buy_step: 1e12,
+                c_min,
+                c_max,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                vec![
+                    5.6584, 5.809, 5.7635, 5.8331, 5.8555, 5.8643, 5.8565, 5.8699,
+                ],
+                vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 1000039562.72316,
+                    limit: None,
+                    elapsed: None,
+                },
+                true,
+            )
+        };
+
+        // C = -0.1958 scales the buy linearly.
+        let unclamped = match decide(f64::MIN, f64::MAX) {
+            TreasuryDecision::Buy(amount) => amount,
+            decision => panic!("Expected a buy, got {:?}", decision),
+        };
+        assert_eq!(unclamped, 207013.8891493543);
+        let clamped = match decide(-0.05, 0.05) {
+            TreasuryDecision::Buy(amount) => amount,
+            decision => panic!("Expected a buy, got {:?}", decision),
+        };
+        assert!((clamped / unclamped - 0.05 / 0.1958181443336818).abs() < 1e-9);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log == "Trend coefficient -0.1958181443336818 is clamped"));
+        // The bound outside of C doesn't change it.
+        assert_eq!(decide(-0.5, 0.05), TreasuryDecision::Buy(unclamped));
+    }
+
+    #[test]
+    fn test_debug_trend_fit() {
+        let (_, mut contract) = usn_contract();
+        contract.set_decision_params(DecisionParams {
+            smoothing_enabled: false,
+            ..Default::default()
+        });
+        let time_points = vec![-7., -6., -5., -4., -3., -2., -1., -0.];
+        let exchange_rates: Vec<f64> = time_points
+            .iter()
+            .map(|t: &f64| 6. - 0.001 * (t - 1.).powi(2))
+            .collect();
+
+        let fit = contract.debug_trend_fit(exchange_rates.clone(), time_points.clone());
+        assert_eq!(fit.x, time_points);
+        assert_eq!(fit.y, exchange_rates);
+        assert_eq!(fit.basis[0], vec![1., -7., 49.]);
+        assert_eq!(fit.w, vec![fit.c, fit.b, fit.a]);
+        // 6 - 0.001 · (t - 1)² = -0.001 · t² + 0.002 · t + 5.999
+        assert!((fit.a + 0.001).abs() < 1e-9);
+        assert!((fit.b - 0.002).abs() < 1e-9);
+        assert!((fit.c - 5.999).abs() < 1e-9);
+        assert!((fit.r_squared - 1.).abs() < 1e-9);
+        // -1 / ((0 - 1)⁴ + 1)
+        assert!((fit.trend_coefficient + 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_decide_from_series() {
+        let (_, contract) = usn_contract();
+        let decision = contract.decide_from_series(
+            vec![6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611],
+            vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+            191937460.53121,
+            1241195491.76577,
+            1367351872.04769,
+            Some(20000),
+        );
+        assert_eq!(decision, TreasuryDecision::Sell(20000.));
+    }
+
+    #[test]
+    fn test_break_even_rate() {
+        let (_, mut contract) = usn_contract();
+        // 1000 NEAR cover 25% of 40000 USN at $10.
+        assert_eq!(contract.break_even_rate(1000., 40000.), 10.);
+
+        let rate = contract.break_even_rate(191937460.53121, 1241195491.76577);
+        assert!((rate - 1.616667).abs() < 1e-6, "{}", rate);
+
+        contract.set_decision_params(DecisionParams {
+            n_dn: 0.5,
+            ..Default::default()
+        });
+        assert_eq!(contract.break_even_rate(1000., 40000.), 20.);
+
+        // The floor is met exactly at the break-even rate.
+        assert_eq!(
+            0.5 * 40000. - collateral_break_even_rate(0.5, 1000., 40000.) * 1000.,
+            0.
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "NEAR of the reserve must be positive")]
+    fn test_break_even_rate_without_near() {
+        let (_, contract) = usn_contract();
+        contract.break_even_rate(0., 40000.);
+    }
+
+    #[test]
+    #[should_panic(expected = "8 exchange rates and time points are required")]
+    fn test_decide_from_short_series() {
+        let (_, contract) = usn_contract();
+        contract.decide_from_series(
+            vec![6.615, 6.62, 6.628],
+            vec![-2., -1., -0.],
+            191937460.53121,
+            1241195491.76577,
+            1367351872.04769,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_make_treasury_decision_by_gap() {
+        let decide = |elapsed| {
+            let time_points = vec![-7., -6., -5., -4., -3., -2., -1., -0.];
+            let exchange_rates = time_points
+                .iter()
+                .map(|t: &f64| 6. - 0.001 * (t - 1.).powi(2))
+                .collect();
+            let params = DecisionParams {
+                t_buy_step: 1e12,
+                full_size_gap_sec: 3600,
+                ..Default::default()
+            };
+            match make_treasury_decision(
+                &params,
+                exchange_rates,
+                time_points,
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 500000000.,
+                    limit: None,
+                    elapsed,
+                },
+                false,
+            ) {
+                TreasuryDecision::Buy(amount) => amount,
+                _ => panic!("Expected the buy decision"),
+            }
+        };
+
+        let full = decide(None);
+        // 15 minutes after the last trade.
+        assert!((decide(Some(900.)) / full - 0.25).abs() < 1e-9);
+        // 2 hours after the last trade.
+        assert_eq!(decide(Some(7200.)), full);
+        assert_eq!(gap_factor(Some(900.), 0), 1.);
+    }
+
+    fn with_promise_results(context: &VMContextBuilder, results: Vec<PromiseResult>) {
+        testing_env!(
+            context.build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidity removal failed")]
+    fn test_sell_stops_on_failed_removal() {
+        let (context, mut contract) = usn_contract();
+        with_promise_results(&context, vec![PromiseResult::Failed]);
+        drop(contract.handle_swap_after_remove_liquidity(0, vec![], U128(1), U128(0)));
+    }
+
+    #[test]
+    fn test_sell_swaps_after_removal() {
+        let (context, mut contract) = usn_contract();
+        with_promise_results(&context, vec![PromiseResult::Successful(vec![])]);
+        drop(contract.handle_swap_after_remove_liquidity(0, vec![], U128(1), U128(0)));
+
+        let function_names: Vec<String> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(function_names, vec!["swap", "handle_withdraw_after_swap"]);
+    }
+
+    #[test]
+    fn test_sell_stops_on_failed_swap() {
+        let (_, mut contract) = usn_contract();
+        assert!(matches!(
+            contract.handle_withdraw_after_swap(
+                0,
+                1.,
+                U128(1),
+                SwapBounds {
+                    min_amount_out: U128(2),
+                    min_fill: U128(2)
+                },
+                Err(PromiseError::Failed)
+            ),
+            PromiseOrValue::Value(())
+        ));
+        assert!(get_created_receipts().is_empty());
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"swap_failed","data":[{"pool_id":0,"token_out":"wrap.test.near","min_amount_out":"2","reason":"failed"}]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_reports_failed_swap() {
+        let (context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.in_flight.insert(0, 100);
+        treasury.in_flight.insert(1, 100);
+        contract.treasury.replace(&treasury);
+
+        // The buy of the pool 1 fails inside the batch of the pools 0 and 1.
+        drop(contract.handle_liquidity_after_swap(
+            1,
+            "usdt.test.near".parse().unwrap(),
+            U128(1),
+            SwapBounds {
+                min_amount_out: U128(2),
+                min_fill: U128(2),
+            },
+            Err(PromiseError::Failed),
+        ));
+        assert!(matches!(
+            contract.finish_treasury_balancing(0, TreasuryDecision::Sell(10.)),
+            PromiseOrValue::Value(TreasuryDecision::Sell(_))
+        ));
+        testing_env!(context.build());
+        assert!(matches!(
+            contract.finish_treasury_balancing(1, TreasuryDecision::Buy(10.)),
+            PromiseOrValue::Promise(_)
+        ));
+        assert_eq!(created_function_names(), vec!["fail_treasury_balancing"]);
+        let treasury = contract.treasury.get().unwrap();
+        assert!(treasury.in_flight.is_empty());
+        assert!(treasury.failed_swaps.is_empty());
+
+        with_promise_results(
+            &context,
+            vec![
+                PromiseResult::Successful(
+                    near_sdk::serde_json::to_vec(&TreasuryDecision::Sell(10.)).unwrap(),
+                ),
+                PromiseResult::Failed,
+            ],
+        );
+        assert_eq!(
+            contract.handle_balance_treasuries(vec![0, 1]),
+            vec![
+                PerPoolResult {
+                    pool_id: 0,
+                    decision: Some(TreasuryDecision::Sell(10.)),
+                    error: None,
+                },
+                PerPoolResult {
+                    pool_id: 1,
+                    decision: None,
+                    error: Some("Balancing failed".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap of the pool 1 failed")]
+    fn test_fail_treasury_balancing() {
+        let (_, contract) = usn_contract();
+        contract.fail_treasury_balancing(1);
+    }
+
+    #[test]
+    fn test_buy_stops_on_failed_swap() {
+        let (_, mut contract) = usn_contract();
+        assert!(matches!(
+            contract.handle_liquidity_after_swap(
+                0,
+                "usdt.test.near".parse().unwrap(),
+                U128(1),
+                SwapBounds {
+                    min_amount_out: U128(2),
+                    min_fill: U128(2)
+                },
+                Err(PromiseError::Failed)
+            ),
+            PromiseOrValue::Value(())
+        ));
+        assert!(get_created_receipts().is_empty());
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"swap_failed","data":[{"pool_id":0,"token_out":"usdt.test.near","min_amount_out":"2","reason":"failed"}]}"#,
+            ]
+        );
+    }
+
+    fn created_function_names() -> Vec<String> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_buy_fill_verification() {
+        let buy_fill = |amount_out| {
+            let (_, mut contract) = usn_contract();
+            drop(contract.handle_liquidity_after_swap(
+                0,
+                "usdt.test.near".parse().unwrap(),
+                U128(1),
+                SwapBounds {
+                    min_amount_out: U128(2),
+                    min_fill: U128(100),
+                },
+                Ok(U128(amount_out)),
+            ));
+            created_function_names()
+        };
+
+        assert_eq!(
+            buy_fill(100),
+            vec![
+                "get_stable_pool",
+                "handle_add_stable_liquidity",
+                "handle_add_liquidity"
+            ]
+        );
+        assert_eq!(buy_fill(99), vec!["withdraw", "handle_rejected_fill"]);
+        assert!(get_logs().contains(
+            &r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"fill_rejected","data":[{"pool_id":0,"token_out":"usdt.test.near","amount_out":"99","min_fill":"100"}]}"#.to_string()
+        ));
+    }
+
+    #[test]
+    fn test_sell_fill_verification() {
+        let sell_fill = |amount_out| {
+            let (_, mut contract) = usn_contract();
+            drop(contract.handle_withdraw_after_swap(
+                0,
+                1.,
+                U128(1),
+                SwapBounds {
+                    min_amount_out: U128(2),
+                    min_fill: U128(100),
+                },
+                Ok(U128(amount_out)),
+            ));
+            created_function_names()
+        };
+
+        assert_eq!(
+            sell_fill(100),
+            vec!["withdraw", "withdraw", "handle_unwrap_after_withdraw"]
+        );
+        assert_eq!(
+            sell_fill(99),
+            vec!["withdraw", "withdraw", "handle_rejected_fill"]
+        );
+    }
+
+    #[test]
+    fn test_treasury_pnl() {
+        let (context, mut contract) = warmed_usn_contract(&[6.5, 5.]);
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+        // Every callback runs in a fresh receipt.
+        let buy = |contract: &mut Contract, near: u128, usdt: u128| {
+            testing_env!(context.build());
+            drop(contract.handle_liquidity_after_swap(
+                0,
+                usdt_id.clone(),
+                U128(near * ONE_NEAR),
+                SwapBounds {
+                    min_amount_out: U128(0),
+                    min_fill: U128(0),
+                },
+                Ok(U128(usdt * 10u128.pow(6))),
+            ));
+        };
+        let sell = |contract: &mut Contract, usd: f64, near: u128| {
+            testing_env!(context.build());
+            drop(contract.handle_withdraw_after_swap(
+                0,
+                usd,
+                U128(1),
+                SwapBounds {
+                    min_amount_out: U128(0),
+                    min_fill: U128(0),
+                },
+                Ok(U128(near * ONE_NEAR)),
+            ));
+        };
+
+        buy(&mut contract, 10, 60);
+        let pnl = contract.get_treasury_pnl();
+        assert_eq!(pnl.flows.near_spent, U128(10 * ONE_NEAR));
+        assert_eq!(pnl.flows.usd_received, 60.);
+        // 10 NEAR are worth $50 at the latest rate.
+        assert!((pnl.pnl - 10.).abs() < 1e-9);
+
+        sell(&mut contract, 40., 9);
+        sell(&mut contract, 20., 3);
+        // Failed swaps realize nothing.
+        testing_env!(context.build());
+        drop(contract.handle_liquidity_after_swap(
+            0,
+            usdt_id.clone(),
+            U128(ONE_NEAR),
+            SwapBounds {
+                min_amount_out: U128(0),
+                min_fill: U128(0),
+            },
+            Err(PromiseError::Failed),
+        ));
+
+        let pnl = contract.get_treasury_pnl();
+        assert_eq!(
+            pnl.flows,
+            TreasuryFlows {
+                near_spent: U128(10 * ONE_NEAR),
+                near_received: U128(12 * ONE_NEAR),
+                usd_received: 60.,
+                usd_spent: 60.,
+            }
+        );
+        assert_eq!(pnl.exchange_rate, 5.);
+        assert!((pnl.pnl - 10.).abs() < 1e-9);
+    }
+
+    #[test]
+    #[should_panic(expected = "No cached exchange rate")]
+    fn test_treasury_pnl_without_rate() {
+        let (_, contract) = usn_contract();
+        contract.get_treasury_pnl();
+    }
+
+    #[test]
+    fn test_rejected_fill_kept_in_reserve() {
+        let (context, mut contract) = usn_contract();
+        with_promise_results(
+            &context,
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Successful(vec![]),
+            ],
+        );
+        contract.handle_rejected_fill("wrap.test.near".parse().unwrap(), U128(99));
+        contract.handle_rejected_fill("wrap.test.near".parse().unwrap(), U128(1));
+        assert_eq!(
+            contract
+                .treasury
+                .get()
+                .unwrap()
+                .reserve
+                .get(&"wrap.test.near".parse().unwrap()),
+            Some(&U128(100))
+        );
+    }
+
+    #[test]
+    fn test_buy_reports_rejected_add_liquidity() {
+        let (_, mut contract) = usn_contract();
+        contract.handle_add_liquidity(
+            0,
+            "usdt.test.near".parse().unwrap(),
+            U128(1000),
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"add_liquidity_failed","data":[{"pool_id":0,"token_id":"usdt.test.near","amount":"1000","reason":"failed"}]}"#,
+            ]
+        );
+
+        contract.handle_add_liquidity(
+            0,
+            "usdt.test.near".parse().unwrap(),
+            U128(1000),
+            Ok(U128(5)),
+        );
+        assert_eq!(get_logs().len(), 1);
+    }
+
+    /// USN and USDT of the pool 0 position in whole tokens.
+    fn position(usn: u128, usdt: u128) -> Vec<U128> {
+        vec![U128(usn * 10u128.pow(18)), U128(usdt * 10u128.pow(6))]
+    }
+
+    #[test]
+    fn test_audit_treasury_matching() {
+        let (context, mut contract) = usn_contract();
+        assert_eq!(
+            contract.handle_audit_treasury(0, position(100, 200)),
+            ReserveAudit {
+                value_usd: 300.,
+                expected_usd: None,
+                flagged: false,
+            }
+        );
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"audit_result","data":[{"pool_id":0,"value_usd":300.0,"flagged":false}]}"#,
+            ]
+        );
+
+        // A buy adds 50 USDT, a sell removes 20 USDT and 20 USN.
+        contract.handle_add_liquidity(
+            0,
+            "usdt.test.near".parse().unwrap(),
+            U128(50_000_000),
+            Ok(U128(5)),
+        );
+        with_promise_results(&context, vec![PromiseResult::Successful(vec![])]);
+        drop(contract.handle_swap_after_remove_liquidity(
+            0,
+            vec![SwapAction {
+                pool_id: 1,
+                token_in: "usdt.test.near".parse().unwrap(),
+                amount_in: Some(U128(20_000_000)),
+                token_out: "wrap.test.near".parse().unwrap(),
+                min_amount_out: U128(0),
+            }],
+            U128(20 * 10u128.pow(18)),
+            U128(0),
+        ));
+
+        testing_env!(context.build());
+        assert_eq!(
+            contract.handle_audit_treasury(0, position(80, 230)),
+            ReserveAudit {
+                value_usd: 310.,
+                expected_usd: Some(310.),
+                flagged: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_audit_treasury_drift() {
+        let (_, mut contract) = usn_contract();
+        contract.handle_audit_treasury(0, position(100, 200));
+
+        // 50 USDT left the position without a trade.
+        assert!(
+            contract
+                .handle_audit_treasury(0, position(100, 150))
+                .flagged
+        );
+        assert_eq!(
+            get_logs()[1],
+            r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"audit_result","data":[{"pool_id":0,"value_usd":250.0,"expected_usd":300.0,"drift_usd":-50.0,"flagged":true}]}"#
+        );
+
+        // The flagged value is the new snapshot, pool fees stay within the drift.
+        let audit =
+            contract.handle_audit_treasury(0, vec![U128(100 * 10u128.pow(18)), U128(150_200_000)]);
+        assert_eq!(audit.expected_usd, Some(250.));
+        assert!(!audit.flagged);
+
+        // Other owner operations reset the snapshot.
+        contract.internal_reset_reserve_snapshot(0);
+        assert_eq!(
+            contract
+                .handle_audit_treasury(0, position(0, 0))
+                .expected_usd,
+            None
+        );
+    }
+
+    #[test]
+    fn test_warmup_from_pool() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context.block_timestamp(7 * 300_000_000_000).build());
+        contract.handle_warmup_from_pool(1, 8, sell_scenario_pools(6.611).2);
+
+        let treasury = contract.treasury.get().unwrap();
+        let (_, rates) = treasury
+            .cache
+            .collect(7 * 300_000_000_000, 300_000_000_000)
+            .unwrap();
+        assert_eq!(rates.len(), 8);
+        assert!(rates.iter().all(|rate| (rate - 6.611).abs() < 1e-9));
+    }
+
+    #[test]
+    #[should_panic(expected = "From 1 to 8 samples are allowed")]
+    fn test_warmup_from_pool_too_many_samples() {
+        let (_, mut contract) = usn_contract();
+        drop(contract.warmup_from_pool(1, 9));
+    }
+
+    #[test]
+    #[should_panic(expected = "diverges from the cached average")]
+    fn test_warmup_from_pool_outlier() {
+        let (_, mut contract) =
+            warmed_usn_contract(&[6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611]);
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.config.max_spot_divergence = Some(0.02);
+        contract.treasury.replace(&treasury);
+
+        contract.handle_warmup_from_pool(1, 8, sell_scenario_pools(7.3).2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal failed")]
+    fn test_sell_stops_on_failed_wrap_withdrawal() {
+        let (context, mut contract) = usn_contract();
+        with_promise_results(
+            &context,
+            vec![PromiseResult::Failed, PromiseResult::Successful(vec![])],
+        );
+        drop(contract.handle_unwrap_after_withdraw(U128(1), U128(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal failed")]
+    fn test_sell_stops_on_failed_usn_withdrawal() {
+        let (context, mut contract) = usn_contract();
+        with_promise_results(
+            &context,
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+        );
+        drop(contract.handle_unwrap_after_withdraw(U128(1), U128(2)));
+    }
+
+    #[test]
+    fn test_sell_burns_only_after_unwrap() {
+        let (context, mut contract) = usn_contract();
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        contract.token.internal_deposit(&usn_id, 1000);
+
+        with_promise_results(&context, vec![PromiseResult::Failed]);
+        contract.finish_withdraw_with_burn(U128(400));
+        assert_eq!(contract.token.ft_balance_of(usn_id.clone()), U128(1000));
+
+        with_promise_results(&context, vec![PromiseResult::Successful(vec![])]);
+        contract.finish_withdraw_with_burn(U128(400));
+        assert_eq!(contract.token.ft_balance_of(usn_id), U128(600));
+    }
+
+    #[test]
+    fn test_compound_deposits() {
+        let (_, mut contract) = usn_contract();
+        let deposits = HashMap::from([
+            ("usn.test.near".parse().unwrap(), U128(5)),
+            ("usdt.test.near".parse().unwrap(), U128(1000)),
+        ]);
+
+        match contract.handle_compound_deposits(0, deposits) {
+            PromiseOrValue::Promise(promise) => drop(promise),
+            PromiseOrValue::Value(_) => panic!("Expected the promise"),
+        }
+
+        assert_eq!(
+            created_function_names(),
+            vec!["get_stable_pool", "handle_add_stable_liquidity"]
+        );
+        match &get_created_receipts()[1].actions[0] {
+            VmAction::FunctionCall { args, .. } => assert_eq!(
+                String::from_utf8(args.clone()).unwrap(),
+                "{\"pool_id\":0,\"amounts\":[\"0\",\"1000\"]}"
+            ),
+            _ => panic!("Expected the function call"),
+        }
+    }
+
+    #[test]
+    fn test_add_stable_liquidity_min_shares() {
+        let (_, mut contract) = usn_contract();
+        let (_, mut info, _) = sell_scenario_pools(1.0);
+        info.shares_total_supply = U128(1_367_351_872 * 10u128.pow(18));
+        let amounts = vec![U128(0), U128(1000 * 10u128.pow(6))];
+        let expected = min_shares(&info, &amounts, MIN_SHARES_TOLERANCE_BPS);
+        assert!(expected.0 > 0);
+
+        drop(contract.handle_add_stable_liquidity(0, amounts, info));
+
+        match &get_created_receipts()[0].actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "add_stable_liquidity");
+                assert_eq!(
+                    String::from_utf8(args.clone()).unwrap(),
+                    format!(
+                        "{{\"pool_id\":0,\"amounts\":[\"0\",\"1000000000\"],\"min_shares\":\"{}\"}}",
+                        expected.0
+                    )
+                );
+            }
+            _ => panic!("Expected the function call"),
+        }
+    }
+
+    #[test]
+    fn test_compound_locks_pool() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        drop(contract.compound_treasury_rewards(0));
+
+        assert!(contract.treasury.get().unwrap().in_flight.contains_key(&0));
+        assert_eq!(
+            created_function_names(),
+            vec![
+                "get_deposits",
+                "handle_compound_deposits",
+                "finish_treasury_balancing"
+            ]
+        );
     }
 
-    #[private]
-    #[payable]
-    fn handle_liquidity_after_swap(&mut self, pool_id: u64, #[callback] amount: U128) -> Promise {
-        let pool = Pool::from_config_with_assert(pool_id);
+    #[test]
+    #[should_panic(expected = "Balancing of the pool 0 is in progress")]
+    fn test_compound_in_flight() {
+        let (mut context, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.in_flight.insert(0, 100);
+        contract.treasury.replace(&treasury);
 
-        let add_amounts = pool
-            .tokens
-            .iter()
-            .map(|token_id| {
-                if token_id == &env::current_account_id() {
-                    U128(0u128)
-                } else {
-                    amount
-                }
-            })
-            .collect();
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        drop(contract.compound_treasury_rewards(0));
+    }
 
-        let min_shares = U128::from(0u128);
+    #[test]
+    fn test_compound_no_deposits() {
+        let (_, mut contract) = usn_contract();
+        let deposits = HashMap::from([("usn.test.near".parse().unwrap(), U128(5))]);
 
-        ext_ref_finance::add_stable_liquidity(
-            pool.id,
-            add_amounts,
-            min_shares,
-            pool.ref_id,
-            ONE_YOCTO,
-            GAS_FOR_ADD_LIQUIDITY,
-        )
+        assert!(matches!(
+            contract.handle_compound_deposits(0, deposits),
+            PromiseOrValue::Value(())
+        ));
+        assert!(get_created_receipts().is_empty());
     }
 
-    #[private]
-    fn handle_exchange_rate_cache(&mut self, #[callback] price: PriceData) {
-        let mut treasury = self.treasury.take().unwrap();
-        let rate: ExchangeRate = price.into();
-        let rate = rate.multiplier() as f64 / 10f64.powi((rate.decimals() - NEAR_DECIMALS) as i32);
-        treasury.cache.append(env::block_timestamp(), rate);
-        self.treasury.replace(&treasury);
+    #[test]
+    fn test_withdraw_all_liquidity() {
+        let (mut context, mut contract) = usn_contract();
+        let wrap_id: AccountId = "wrap.test.near".parse().unwrap();
+        let mut treasury = contract.treasury.take().unwrap();
+        treasury.reserve.insert(wrap_id.clone(), U128(5000));
+        contract.treasury.replace(&treasury);
+
+        testing_env!(context.attached_deposit(4 * ONE_YOCTO).build());
+        drop(contract.withdraw_all_liquidity(0));
+
+        assert_eq!(
+            created_function_names(),
+            vec![
+                "get_pool_shares",
+                "handle_remove_all_liquidity",
+                "finish_treasury_balancing",
+                "near_withdraw",
+                "handle_unwrap_reserve"
+            ]
+        );
+        let treasury = contract.treasury.get().unwrap();
+        assert!(treasury.in_flight.contains_key(&0));
+        assert_eq!(treasury.reserve.get(&wrap_id), None);
+
+        // Failed unwrapping keeps wNEAR in the reserve.
+        with_promise_results(&context, vec![PromiseResult::Failed]);
+        contract.handle_unwrap_reserve(U128(5000));
+        let treasury = contract.treasury.get().unwrap();
+        assert_eq!(treasury.reserve.get(&wrap_id), Some(&U128(5000)));
     }
 
-    #[private]
-    fn predict_remove_liquidity(&self, pool_id: u64, #[callback] shares: U128) -> Promise {
-        let pool = Pool::from_config_with_assert(pool_id);
-        ext_ref_finance::predict_remove_liquidity(
-            pool.id,
-            shares,
-            pool.ref_id,
-            NO_DEPOSIT,
-            GAS_FOR_PREDICT_REMOVE_LIQUIDITY,
-        )
+    #[test]
+    #[should_panic(expected = "3 yoctoNEAR of attached deposit is required")]
+    fn test_withdraw_all_liquidity_deposit() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context.attached_deposit(ONE_YOCTO).build());
+        contract.withdraw_all_liquidity(0);
     }
 
-    #[private]
-    fn finish_withdraw_with_burn(&mut self, amount: U128) {
-        if is_promise_success() {
-            self.token
-                .internal_withdraw(&env::current_account_id(), amount.into());
-            event::emit::ft_burn(&env::current_account_id(), amount.into(), None);
+    #[test]
+    fn test_withdraw_removed_liquidity() {
+        let (mut context, mut contract) = usn_contract();
+        testing_env!(context.attached_deposit(2 * ONE_YOCTO).build());
+
+        match contract.handle_withdraw_removed_liquidity(0, U128(10), vec![U128(0), U128(1000)]) {
+            PromiseOrValue::Promise(promise) => drop(promise),
+            PromiseOrValue::Value(_) => panic!("Expected the promise"),
+        }
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"treasury","version":"1.0.0","event":"liquidity_withdrawn","data":[{"pool_id":0,"shares":"10","token_ids":["usn.test.near","usdt.test.near"],"amounts":["0","1000"]}]}"#
+            ]
+        );
+        match &get_created_receipts()[..] {
+            [receipt] => match &receipt.actions[0] {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } => {
+                    assert_eq!(function_name, "withdraw");
+                    assert_eq!(
+                        String::from_utf8(args.clone()).unwrap(),
+                        "{\"token_id\":\"usdt.test.near\",\"amount\":\"1000\",\"unregister\":null}"
+                    );
+                }
+                _ => panic!("Expected the function call"),
+            },
+            receipts => panic!("Expected one withdrawal, got {}", receipts.len()),
         }
     }
-}
 
-fn buy(pool_id: u64, amount: f64, exchange_rate: f64) -> Promise {
-    let wrap_id: AccountId = CONFIG.wrap_id.parse().unwrap();
-    let pool = Pool::from_config_with_assert(pool_id);
-    let near = ((amount / exchange_rate) * ONE_NEAR as f64) as u128;
-    let min_amount = (amount * SWAP_SLIPPAGE * 10f64.powi(USDT_DECIMALS as i32)) as u128;
+    #[test]
+    fn test_quote_buy() {
+        let (_, contract) = usn_contract();
 
-    env::log_str(&format!("Trying to wrap {} NEAR", near));
+        drop(contract.quote_buy(U128(ONE_NEAR), 0, None));
 
-    let usdt_name = pool
-        .tokens
-        .iter()
-        .find(|&token_id| token_id != &env::current_account_id())
-        .unwrap();
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, "ref.test.near".parse().unwrap());
+        match &receipts[0].actions[0] {
+            VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "get_return");
+                assert_eq!(
+                    String::from_utf8(args.clone()).unwrap(),
+                    "{\"pool_id\":3,\"token_in\":\"wrap.test.near\",\
+                     \"amount_in\":\"1000000000000000000000000\",\"token_out\":\"usdt.test.near\"}"
+                );
+            }
+            _ => panic!("Expected the function call"),
+        }
+    }
 
-    let swap_action = SwapAction {
-        pool_id: CONFIG.swap_pool_id,
-        amount_in: Some(near.into()),
-        token_in: wrap_id.clone(),
-        token_out: usdt_name.clone(),
-        min_amount_out: U128(min_amount),
-    };
+    #[test]
+    fn test_preview_sell() {
+        let (_, contract) = usn_contract();
+        let config = contract.treasury.get().unwrap().config;
+        let preview = contract.preview_sell(0, 1000., 5., None);
+        assert_eq!(
+            preview,
+            SellPreview {
+                remove_amounts: vec![U128(999 * 10u128.pow(18)), U128(999 * 10u128.pow(6))],
+                min_amount_out: U128(min_amount_out(
+                    200.,
+                    config.swap_fee(),
+                    config.slippage.slippage(1000.),
+                    NEAR_DECIMALS
+                )),
+                burn_amount: U128(999 * 10u128.pow(18)),
+            }
+        );
+        // 200 wNEAR with 50% of slippage, in yocto.
+        assert_eq!(preview.min_amount_out.0 / ONE_NEAR, 100);
+    }
 
-    ext_ft::near_deposit(wrap_id.clone(), near, GAS_FOR_NEAR_DEPOSIT)
-        .then(ext_ft::ft_transfer_call(
-            pool.ref_id.clone(),
-            near.into(),
-            None,
-            REF_DEPOSIT_ACTION.into(),
-            wrap_id,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER_CALL,
-        ))
-        .then(ext_ref_finance::swap(
-            vec![swap_action],
-            None,
-            pool.ref_id.clone(),
-            NO_DEPOSIT,
-            GAS_FOR_SWAP,
-        ))
-        .then(ext_self::handle_liquidity_after_swap(
-            pool.id,
-            env::current_account_id(),
-            ONE_YOCTO,
-            GAS_SURPLUS + GAS_FOR_ADD_LIQUIDITY,
-        ))
-}
+    #[test]
+    fn test_sell_full_position_with_safety_margin() {
+        let (_, mut contract) = usn_contract();
+        // The whole USDT position of the pool and the decided amount
+        // rounded a unit above it.
+        let position = 1_367_351_872_047_690u128;
+        let amount = (position + 1) as f64 / 1e6;
 
-fn sell(pool_id: u64, amount: f64, exchange_rate: f64) -> Promise {
-    let wrap_id = CONFIG.wrap_id.parse().unwrap();
-    let pool = Pool::from_config_with_assert(pool_id);
-    let min_amount =
-        ((amount * SWAP_SLIPPAGE / exchange_rate) * 10f64.powi(USN_DECIMALS as i32)) as u128;
+        contract.set_sell_safety_margin(0);
+        let preview = contract.preview_sell(0, amount, 5., None);
+        assert!(preview.remove_amounts[1].0 > position);
 
-    let remove_amounts: Vec<U128> = pool
-        .decimals
-        .iter()
-        .map(|decimals| U128((amount * 10f64.powi(*decimals as i32)) as u128))
-        .collect();
+        contract.set_sell_safety_margin(1);
+        let preview = contract.preview_sell(0, amount, 5., None);
+        assert!(preview.remove_amounts[1].0 <= position);
+        assert_eq!(preview.remove_amounts[1].0, 1_367_215_136_860_486);
+    }
 
-    let usn_amount = extract_amount(&pool, &remove_amounts, Extract::Usn);
+    #[test]
+    #[should_panic(expected = "Exchange rate must be a positive number")]
+    fn test_preview_sell_zero_rate() {
+        let (_, contract) = usn_contract();
+        contract.preview_sell(0, 1000., 0., None);
+    }
 
-    let (usdt_name, usdt_amount) = pool
-        .tokens
-        .into_iter()
-        .zip(remove_amounts.clone().into_iter())
-        .find(|(token_id, _amount)| token_id != &env::current_account_id())
-        .unwrap();
+    #[test]
+    #[should_panic(expected = "Nothing to swap")]
+    fn test_buy_zero_amount() {
+        usn_contract();
+        let pool = Pool::from_config_with_assert(0);
+        buy(&TreasuryConfig::default(), pool, 1, 0., 6.6, None);
+    }
 
-    let swap_action = SwapAction {
-        pool_id: CONFIG.swap_pool_id,
-        amount_in: Some(usdt_amount),
-        token_in: usdt_name,
-        token_out: wrap_id,
-        min_amount_out: min_amount.into(),
-    };
+    #[test]
+    #[should_panic(expected = "Swap amount 1000000000 exceeds the swap pool reserve 999999999")]
+    fn test_sell_oversized_amount() {
+        usn_contract();
+        let config = TreasuryConfig {
+            sell_safety_margin_bps: Some(0),
+            ..Default::default()
+        };
+        let pool = Pool::from_config_with_assert(0);
+        sell(&config, pool, 1, 1000., 6.6, Some(999_999_999));
+    }
 
-    let max_burn_shares = U128(u128::MAX); // TODO: Any limits?
+    #[test]
+    fn test_burn_gas_is_reserved() {
+        let (_, mut contract) = usn_contract();
+        contract.set_burn_gas(Gas(20_000_000_000_000));
 
-    ext_ref_finance::remove_liquidity_by_tokens(
-        pool.id,
-        remove_amounts.clone(),
-        max_burn_shares,
-        pool.ref_id.clone(),
-        ONE_YOCTO,
-        GAS_FOR_REMOVE_LIQUIDITY,
-    )
-    .then(ext_ref_finance::swap(
-        vec![swap_action],
-        None,
-        pool.ref_id,
-        NO_DEPOSIT,
-        GAS_FOR_SWAP,
-    ))
-    .then(ext_self::handle_withdraw_after_swap(
-        pool.id,
-        usn_amount,
-        env::current_account_id(),
-        2 * ONE_YOCTO,
-        GAS_SURPLUS * 3 + GAS_FOR_WITHDRAW * 2 + GAS_FOR_NEAR_WITHDRAW + GAS_FOR_FINISH_BURNING,
-    ))
-}
+        drop(contract.handle_unwrap_after_withdraw(U128(1), U128(2)));
 
-fn make_treasury_decision(
-    exchange_rates: Vec<f64>,
-    time_points: Vec<f64>,
-    near: f64,
-    usn: f64,
-    usdt: f64,
-    limit: Option<f64>,
-) -> TreasuryDecision {
-    // 1. Set constant values for further calculations
-    const M: i32 = 4;
-    const N_DN: f64 = 0.25;
-    const U_UP: f64 = 1.1;
-    const U_DN: f64 = 1.;
-    const P_DN: f64 = 0.6;
-    const P_UP: f64 = 0.7;
-    const T_BUY_MIN: f64 = 1000.;
-    const T_SELL_MIN: f64 = 1000.;
-    const T_BUY_STEP: f64 = 3_000_000.;
-    const T_SELL_STEP: f64 = 3_000_000.;
-    const T_0: f64 = 0.;
+        let burn_gas = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name, gas, ..
+                } if function_name == "finish_withdraw_with_burn" => Some(gas),
+                _ => None,
+            });
+        assert_eq!(burn_gas, Some(Gas(20_000_000_000_000)));
+    }
 
-    let n = near;
-    let q = usn;
-    let u = usdt;
+    #[test]
+    #[should_panic(expected = "Burn gas must be at least 7000000000000")]
+    fn test_burn_gas_too_low() {
+        let (_, mut contract) = usn_contract();
+        contract.set_burn_gas(Gas(1_000_000_000_000));
+    }
 
-    debug_assert_eq!(exchange_rates.len(), time_points.len());
-    debug_assert_eq!(exchange_rates.len(), 8);
+    #[test]
+    fn test_swaps_fit_in_prepaid_gas() {
+        let (mut context, _) = usn_contract();
+        testing_env!(context.prepaid_gas(Gas(100_000_000_000_000)).build());
 
-    // 2. Set NER = ER[t − 0] = V8
-    let n_er = exchange_rates.last().unwrap();
+        assert_eq!(max_sequential_swaps(Gas(30_000_000_000_000)), 2);
+        assert_eq!(max_sequential_swaps(Gas(80_000_000_000_000)), 0);
+        assert_eq!(max_sequential_swaps(Gas(200_000_000_000_000)), 0);
+    }
 
-    // 3. Make the data smoothing with moving average
-    let mut x: Vec<f64> = Vec::new();
-    let mut y: Vec<f64> = Vec::new();
-    for k in 1..7 {
-        x.push((time_points[k - 1] + time_points[k] + time_points[k + 1]) / 3.);
-        y.push((exchange_rates[k - 1] + exchange_rates[k] + exchange_rates[k + 1]) / 3.);
+    #[test]
+    fn test_burn_gas_fits() {
+        let (mut context, _) = usn_contract();
+        // 282 TGas of the chain before the burn and 7 TGas of the burn itself.
+        testing_env!(context.prepaid_gas(Gas(290_000_000_000_000)).build());
+        assert_burn_gas_fits(&TreasuryConfig::default());
     }
 
-    // 4. Fit a quadratic trend into the 6 NEAR/USDT smoothed exchange rate values collected using OLS:
-    let x: Matrix<f64> = Matrix::column(x);
-    let y: Matrix<f64> = Matrix::column(y);
+    #[test]
+    #[should_panic(expected = "of 7000000000000 gas required by the burn callback")]
+    fn test_burn_gas_starved() {
+        let (mut context, _) = usn_contract();
+        testing_env!(context.prepaid_gas(Gas(288_999_999_999_999)).build());
+        assert_burn_gas_fits(&TreasuryConfig::default());
+    }
 
-    let mut basis = x.clone();
-    basis.insert_column(0, 1.0);
-    basis.insert_column_with(2, x.column_iter(0).map(|x| x * x));
+    #[test]
+    #[should_panic(expected = "Not enough prepaid gas for the swap chain")]
+    fn test_swap_chain_out_of_gas() {
+        let (mut context, _) = usn_contract();
+        testing_env!(context.prepaid_gas(Gas(100_000_000_000_000)).build());
 
-    let w = (basis.transpose() * &basis).inverse().unwrap() * (basis.transpose() * &y);
+        assert_swaps_fit(GAS_FOR_REMOVE_LIQUIDITY + GAS_FOR_WITHDRAW * 2);
+    }
 
-    // 5. Get coefficients a, b, c and R2 for this trend
-    let a = w.get(2, 0);
-    let b = w.get(1, 0);
-    let c = w.get(0, 0);
+    #[test]
+    fn test_make_treasury_decision_collateral_first() {
+        // The reserve is below the NEAR floor and the trend says buy.
+        let decide = |priority| {
+            let params = DecisionParams {
+                priority,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                vec![
+                    5.6584, 5.809, 5.7635, 5.8331, 5.8555, 5.8643, 5.8565, 5.8699,
+                ],
+                vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+                ReserveState {
+                    near: 10000000.,
+                    usn: 1001096736.9184,
+                    usdt: 10000000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                false,
+            )
+        };
 
-    // Stot = ∑((Y − Y _mean)2)
-    let er_mean: f64 = exchange_rates.clone().iter().sum::<f64>() / exchange_rates.len() as f64;
+        assert_eq!(
+            decide(DecisionPriority::CollateralFirst),
+            TreasuryDecision::Sell(3000000.)
+        );
+        assert!(matches!(
+            decide(DecisionPriority::TrendFirst),
+            TreasuryDecision::Buy(_)
+        ));
+    }
 
-    let s_tot = exchange_rates
-        .clone()
-        .iter()
-        .map(|er| (er - er_mean).powi(2))
-        .sum::<f64>();
+    #[test]
+    fn test_make_treasury_decision_reference_window() {
+        // The latest print jumps from ~5.86 to 7.
+        let exchange_rates = vec![5.6584, 5.809, 5.7635, 5.8331, 5.8555, 5.8643, 5.8565, 7.];
+        let decide = |reference_window| {
+            let params = DecisionParams {
+                reference_window,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                exchange_rates.clone(),
+                vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+                ReserveState {
+                    near: 38_000.,
+                    usn: 1_000_000.,
+                    usdt: 1_000_000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                false,
+            )
+        };
 
-    // Sres = ∑(Vk − (a · Tk^2 + b · Tk + c))2
-    let mut s_res: f64 = 0.;
-    for n in 0..exchange_rates.len() {
-        s_res +=
-            (exchange_rates[n] - (time_points[n].powi(2) * a + time_points[n] * b + c)).powi(2);
+        // 38000 NEAR at 7 cover the floor of 250000 USN.
+        let reference = exchange_rates[5..].iter().sum::<f64>() / 3.;
+        let floor_sell = TreasuryDecision::Sell(0.25 * 1_000_000. - reference * 38_000.);
+        assert_ne!(decide(1), floor_sell);
+        // At the 3-sample average of ~6.24 they don't.
+        assert_eq!(decide(3), floor_sell);
     }
 
-    // R2 = 1 − Sres/Stot
-    let r_squared = 1. - s_res / s_tot;
+    #[test]
+    fn test_whole_units_of_huge_reserve() {
+        // 14.8 billion USDT.
+        let usdt: u128 = 14_797_785_325_233_453;
+        assert_eq!(usdt as f64 / 1e6, 14_797_785_325.233452);
+        assert_eq!(whole_units(usdt, 6), 14_797_785_325.233454);
 
-    // 5. Calculate coefficient C
-    // C = sign(a) · R^2/(t0 + b/2a)^m + 1)
-    let c = f64::signum(a) * r_squared / ((T_0 + b / (2. * a)).powi(M) + 1.);
+        assert_eq!(whole_units(1_500_000, 6), 1.5);
+        assert_eq!(whole_units(ONE_NEAR / 4, NEAR_DECIMALS), 0.25);
+        assert_eq!(whole_units(u128::MAX, 0), u128::MAX as f64);
+    }
 
-    if N_DN * q - n_er * n >= 0. {
-        let r_sell = min(
-            min(min(N_DN * q - n_er * n, T_SELL_STEP), u),
-            limit.unwrap_or(T_SELL_STEP),
+    #[test]
+    fn test_reference_aggregation_with_outliers() {
+        // A spike and a crash print in the tail.
+        let rates = [6.6, 6.6, 6.6, 6.61, 9.9, 6.62, 6.63, 1.2];
+
+        // The latest sample is the crash print itself.
+        assert_eq!(reference_rate(&rates, 1, ReferenceAggregation::Mean), 1.2);
+        assert_eq!(reference_rate(&rates, 1, ReferenceAggregation::Median), 1.2);
+        // The mean is dragged by both outliers.
+        let mean = reference_rate(&rates, 5, ReferenceAggregation::Mean);
+        assert!((mean - 6.192).abs() < 1e-9, "{}", mean);
+        // The median ignores them.
+        assert_eq!(
+            reference_rate(&rates, 5, ReferenceAggregation::Median),
+            6.62
+        );
+        assert_eq!(
+            reference_rate(&rates, 4, ReferenceAggregation::Median),
+            (6.62 + 6.63) / 2.
         );
+    }
 
-        if r_sell >= T_SELL_MIN {
-            TreasuryDecision::Sell(r_sell)
-        } else {
-            TreasuryDecision::DoNothing
+    #[test]
+    fn test_make_treasury_decision_median_reference() {
+        // The NEAR floor is only crossed at the crash print.
+        let decide = |reference_window, reference_aggregation| {
+            let params = DecisionParams {
+                reference_window,
+                reference_aggregation,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                vec![6.6, 6.6, 6.6, 6.61, 9.9, 6.62, 6.63, 1.2],
+                vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+                ReserveState {
+                    near: 40_000_000.,
+                    usn: 1_000_000_000.,
+                    usdt: 650_000_000.,
+                    limit: None,
+                    elapsed: None,
+                },
+                false,
+            )
+        };
+
+        assert!(matches!(
+            decide(1, ReferenceAggregation::Mean),
+            TreasuryDecision::Sell(_)
+        ));
+        assert!(matches!(
+            decide(5, ReferenceAggregation::Mean),
+            TreasuryDecision::Sell(_)
+        ));
+        assert!(!matches!(
+            decide(5, ReferenceAggregation::Median),
+            TreasuryDecision::Sell(_)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "reference_window must be in [1; 8]")]
+    fn test_reference_window_out_of_cache() {
+        DecisionParams {
+            reference_window: 9,
+            ..Default::default()
         }
-    } else if N_DN * q - n_er * n < 0. && c > 0. {
-        let u_sell = max(c * (u - min(P_UP * (u + n_er * n), U_UP * q)), 0.);
+        .assert_valid();
+    }
 
-        let r_sell = min(
-            min(min(u_sell, T_SELL_STEP), u),
-            limit.unwrap_or(T_SELL_STEP),
+    #[test]
+    fn test_make_treasury_decision_dead_band() {
+        // USDT is about a half of the reserve, the target is 0.65.
+        let decide = |dead_band| {
+            let params = DecisionParams {
+                dead_band,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                vec![
+                    5.6584, 5.809, 5.7635, 5.8331, 5.8555, 5.8643, 5.8565, 5.8699,
+                ],
+                vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 1000039562.72316,
+                    limit: None,
+                    elapsed: None,
+                },
+                false,
+            )
+        };
+
+        // Outside the band.
+        assert_eq!(decide(0.1), TreasuryDecision::Buy(207013.8891493543));
+        // Inside the band.
+        assert_eq!(decide(0.15), TreasuryDecision::DoNothing);
+    }
+
+    #[test]
+    fn test_make_treasury_decision_neutral_zone() {
+        let _ = usn_contract();
+        let decide = |neutral_zone| {
+            let params = DecisionParams {
+                dead_band: 0.15,
+                neutral_zone,
+                ..Default::default()
+            };
+            make_treasury_decision(
+                &params,
+                vec![
+                    5.6584, 5.809, 5.7635, 5.8331, 5.8555, 5.8643, 5.8565, 5.8699,
+                ],
+                vec![-7., -6., -5., -4., -3., -2., -1., -0.],
+                ReserveState {
+                    near: 167270746.338665,
+                    usn: 1001096736.9184,
+                    usdt: 1000039562.72316,
+                    limit: None,
+                    elapsed: None,
+                },
+                true,
+            )
+        };
+
+        // The full path fits the trend.
+        assert_eq!(decide(0.), TreasuryDecision::DoNothing);
+        assert!(get_logs().iter().any(|log| log.starts_with("Trend:")));
+
+        // The fast path comes to the same decision without it.
+        let full_path_logs = get_logs().len();
+        assert_eq!(decide(0.15), TreasuryDecision::DoNothing);
+        let logs = get_logs().split_off(full_path_logs);
+        assert_eq!(
+            logs,
+            vec!["USDT share of the reserve 0.5045857483669515 is within the neutral zone"]
         );
 
-        if r_sell >= T_SELL_MIN {
-            TreasuryDecision::Sell(r_sell)
-        } else {
-            TreasuryDecision::DoNothing
+        // Out of the zone the full path runs.
+        decide(0.1);
+        assert!(get_logs()[full_path_logs + 1..]
+            .iter()
+            .any(|log| log.starts_with("Trend:")));
+    }
+
+    #[test]
+    #[should_panic(expected = "neutral_zone must not exceed dead_band")]
+    fn test_neutral_zone_out_of_dead_band() {
+        DecisionParams {
+            dead_band: 0.1,
+            neutral_zone: 0.2,
+            ..Default::default()
         }
-    } else {
-        let u_buy = c * min(u - min(P_DN * (u + n_er * n), U_DN * q), 0.);
+        .assert_valid();
+    }
 
-        let r_buy = min(
-            min(min(u_buy, T_BUY_STEP), n_er * n),
-            limit.unwrap_or(T_BUY_STEP),
-        );
+    #[test]
+    #[should_panic(expected = "[c_min; c_max] must contain zero")]
+    fn test_c_bounds_without_zero() {
+        DecisionParams {
+            c_min: 0.1,
+            ..Default::default()
+        }
+        .assert_valid();
+    }
 
-        if r_buy >= T_BUY_MIN {
-            TreasuryDecision::Buy(r_buy)
-        } else {
-            TreasuryDecision::DoNothing
+    #[test]
+    #[should_panic(expected = "dead_band must be less than 1")]
+    fn test_dead_band_too_wide() {
+        DecisionParams {
+            dead_band: 1.,
+            ..Default::default()
         }
+        .assert_valid();
     }
-}
 
-#[cfg(all(test, not(target_arch = "wasm32")))]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_make_treasury_decision_fuzz() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let params = DecisionParams::default();
+
+        for _ in 0..5000 {
+            let jitter = rng.gen_range(0.0..1.0);
+            let time_points: Vec<f64> = (0..8).map(|k| (k - 7) as f64 - jitter).collect();
+            // Flat series have no trend at all.
+            let flat = rng.gen_bool(0.05);
+            let mut rate = rng.gen_range(0.5..50.0);
+            let exchange_rates: Vec<f64> = (0..8)
+                .map(|_| {
+                    if !flat && rng.gen_bool(0.8) {
+                        rate *= 1. + rng.gen_range(-0.05..0.05);
+                    }
+                    rate
+                })
+                .collect();
+            let near = rng.gen_range(0.0..1e9);
+            let usn = rng.gen_range(0.0..1e10);
+            let usdt = rng.gen_range(0.0..1e10);
+            let limit = if rng.gen_bool(0.5) {
+                Some(rng.gen_range(1_000u64..10_000_000) as f64)
+            } else {
+                None
+            };
+            let elapsed = Some(rng.gen_range(0.0..1e5));
+
+            let decision = make_treasury_decision(
+                &params,
+                exchange_rates.clone(),
+                time_points,
+                ReserveState {
+                    near,
+                    usn,
+                    usdt,
+                    limit,
+                    elapsed,
+                },
+                false,
+            );
+
+            let (amount, step, reserve) = match decision {
+                TreasuryDecision::DoNothing => continue,
+                TreasuryDecision::Buy(amount) => (
+                    amount,
+                    params.t_buy_step,
+                    exchange_rates.last().unwrap() * near,
+                ),
+                TreasuryDecision::Sell(amount) => (amount, params.t_sell_step, usdt),
+            };
+            assert!(amount.is_finite() && amount > 0., "{:?}", decision);
+            assert!(amount <= step && amount <= reserve, "{:?}", decision);
+            assert!(amount <= limit.unwrap_or(f64::MAX), "{:?}", decision);
+        }
+    }
 
     #[test]
     fn test_make_treasury_decision_sell() {
         let treasury_decision = make_treasury_decision(
+            &DecisionParams::default(),
             vec![6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611],
             vec![-7., -6., -5., -4., -3., -2., -1., -0.],
-            191937460.53121,
-            1241195491.76577,
-            1367351872.04769,
-            None,
+            ReserveState {
+                near: 191937460.53121,
+                usn: 1241195491.76577,
+                usdt: 1367351872.04769,
+                limit: None,
+                elapsed: None,
+            },
+            false,
         );
 
         assert_eq!(
@@ -637,12 +8653,17 @@ mod tests {
     #[test]
     fn test_make_treasury_decision_sell_with_limit() {
         let treasury_decision = make_treasury_decision(
+            &DecisionParams::default(),
             vec![6.615, 6.62, 6.628, 6.623, 6.578, 6.6, 6.577, 6.611],
             vec![-7., -6., -5., -4., -3., -2., -1., -0.],
-            191937460.53121,
-            1241195491.76577,
-            1367351872.04769,
-            Some(20000.),
+            ReserveState {
+                near: 191937460.53121,
+                usn: 1241195491.76577,
+                usdt: 1367351872.04769,
+                limit: Some(20000.),
+                elapsed: None,
+            },
+            false,
         );
 
         assert_eq!(treasury_decision, TreasuryDecision::Sell(20000.));
@@ -651,14 +8672,19 @@ mod tests {
     #[test]
     fn test_make_treasury_decision_do_nothing() {
         let treasury_decision = make_treasury_decision(
+            &DecisionParams::default(),
             vec![
                 5.9519, 5.9222, 5.9189, 5.9242, 5.9194, 5.9173, 5.8818, 5.8741,
             ],
             vec![-7., -6., -5., -4., -3., -2., -1., -0.],
-            167242050.870139,
-            1001497797.34406,
-            1000522964.94309,
-            None,
+            ReserveState {
+                near: 167242050.870139,
+                usn: 1001497797.34406,
+                usdt: 1000522964.94309,
+                limit: None,
+                elapsed: None,
+            },
+            false,
         );
 
         assert_eq!(treasury_decision, TreasuryDecision::DoNothing);
@@ -667,14 +8693,19 @@ mod tests {
     #[test]
     fn test_make_treasury_decision_buy() {
         let treasury_decision = make_treasury_decision(
+            &DecisionParams::default(),
             vec![
                 5.6584, 5.809, 5.7635, 5.8331, 5.8555, 5.8643, 5.8565, 5.8699,
             ],
             vec![-7., -6., -5., -4., -3., -2., -1., -0.],
-            167270746.338665,
-            1001096736.9184,
-            1000039562.72316,
-            None,
+            ReserveState {
+                near: 167270746.338665,
+                usn: 1001096736.9184,
+                usdt: 1000039562.72316,
+                limit: None,
+                elapsed: None,
+            },
+            false,
         );
 
         assert_eq!(treasury_decision, TreasuryDecision::Buy(207013.8891493543));
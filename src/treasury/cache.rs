@@ -1,8 +1,8 @@
-use near_sdk::Timestamp;
+use near_sdk::{require, CryptoHash, Timestamp};
 
 use crate::*;
 
-const MAX_CACHE_SIZE: usize = 8;
+pub const MAX_CACHE_SIZE: usize = 8;
 const FIVE_MINUTES: Timestamp = 5 * 60 * 1000_000_000;
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
@@ -20,12 +20,13 @@ impl CacheItem {
         self.timestamp / FIVE_MINUTES
     }
 
-    /// Normalized time: 5 minutes - > 1.0, -5 minutes -> -1.0, -15 minutes -> -3.
-    pub fn normalized_time(&self, now: Timestamp) -> f64 {
+    /// Time relative to `now` in `unit`s, e.g. for 5 minutes units:
+    /// 5 minutes - > 1.0, -5 minutes -> -1.0, -15 minutes -> -3.
+    pub fn normalized_time(&self, now: Timestamp, unit: Timestamp) -> f64 {
         if self.timestamp > now {
-            return (self.timestamp - now) as f64 / FIVE_MINUTES as f64;
+            return (self.timestamp - now) as f64 / unit as f64;
         } else {
-            return -((now - self.timestamp) as f64) / FIVE_MINUTES as f64;
+            return -((now - self.timestamp) as f64) / unit as f64;
         }
     }
 }
@@ -45,13 +46,70 @@ impl Default for IntervalCache {
     }
 }
 
+/// Changes made by `IntervalCache::repair`.
+#[derive(Serialize, Deserialize, Default)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct CacheRepairReport {
+    /// Items weren't in increasing order of timestamps.
+    pub reordered: bool,
+    /// Items dropped for sharing a 5 minute slot with a later item.
+    pub duplicates_removed: u32,
+    /// Items dropped for non-positive rates, no samples or timestamps after `now`.
+    pub invalid_removed: u32,
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum CacheError {
-    NotReady,
+    /// Cached exchange rates: (have, need), nothing is cached if `have` is zero.
+    Insufficient(usize, usize),
     Gaps,
 }
 
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::Insufficient(0, _) => {
+                write!(f, "Treasury cache is empty. Use `warmup`.")
+            }
+            CacheError::Insufficient(have, need) => write!(
+                f,
+                "Treasury cache has {} of {} exchange rates. Use `warmup` {} more times in 5 minute intervals.",
+                have,
+                need,
+                need - have
+            ),
+            CacheError::Gaps => write!(
+                f,
+                "Treasury cache has gaps between exchange rates. Use `warmup`."
+            ),
+        }
+    }
+}
+
 impl IntervalCache {
+    /// Checks imported items could have been produced by `append`.
+    pub fn assert_valid(&self, now: Timestamp) {
+        require!(
+            self.items.len() <= MAX_CACHE_SIZE,
+            &format!("Cache can't have more than {} items", MAX_CACHE_SIZE)
+        );
+        for item in &self.items {
+            require!(
+                item.value.is_finite() && item.value > 0.,
+                "Cached exchange rates must be positive numbers"
+            );
+            require!(item.n > 0, "Cached item must have at least one sample");
+            require!(item.timestamp <= now, "Cached item is from the future");
+        }
+        require!(
+            self.items
+                .windows(2)
+                .all(|pair| pair[0].time_slot() < pair[1].time_slot()),
+            "Cached items must be in 5 minute slots in increasing order"
+        );
+    }
+
     /// Stores a new value into time-aligned evenly distributed series,
     /// 8 values in series at most.
     ///
@@ -96,9 +154,76 @@ impl IntervalCache {
         }
     }
 
-    pub fn collect(&self, now: Timestamp) -> Result<(Vec<f64>, Vec<f64>), CacheError> {
-        if self.items.len() < MAX_CACHE_SIZE {
-            return Result::Err(CacheError::NotReady);
+    /// Fills `samples` trailing 5 minute slots up to `now` with `value`, rates cached
+    /// in these slots or later are replaced.
+    pub fn backfill(&mut self, now: Timestamp, samples: usize, value: f64) {
+        let first_slot = (now / FIVE_MINUTES).saturating_sub(samples as u64 - 1);
+        self.items.retain(|item| item.time_slot() < first_slot);
+        for slot in first_slot..now / FIVE_MINUTES {
+            self.items.push(CacheItem {
+                timestamp: slot * FIVE_MINUTES,
+                value,
+                n: 1,
+            });
+        }
+        self.items.push(CacheItem {
+            timestamp: now,
+            value,
+            n: 1,
+        });
+
+        if self.items.len() > MAX_CACHE_SIZE {
+            self.items.drain(..self.items.len() - MAX_CACHE_SIZE);
+        }
+    }
+
+    /// Brings the cache to the state `assert_valid` accepts: drops invalid items,
+    /// sorts the rest by timestamps and keeps the latest item of every 5 minute slot.
+    pub fn repair(&mut self, now: Timestamp) -> CacheRepairReport {
+        let mut report = CacheRepairReport::default();
+
+        let count = self.items.len();
+        self.items.retain(|item| {
+            item.value.is_finite() && item.value > 0. && item.n > 0 && item.timestamp <= now
+        });
+        report.invalid_removed = (count - self.items.len()) as u32;
+
+        report.reordered = !self
+            .items
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp);
+        self.items.sort_by_key(|item| item.timestamp);
+
+        let count = self.items.len();
+        let mut items: Vec<CacheItem> = Vec::with_capacity(count);
+        for item in self.items.drain(..) {
+            match items.last_mut() {
+                Some(last) if last.time_slot() == item.time_slot() => *last = item,
+                _ => items.push(item),
+            }
+        }
+        report.duplicates_removed = (count - items.len()) as u32;
+        self.items = items;
+
+        report
+    }
+
+    /// Identifies the state of the cache.
+    pub fn hash(&self) -> CryptoHash {
+        env::sha256_array(&self.try_to_vec().unwrap())
+    }
+
+    /// Returns time points relative to `now` in `time_unit`s and exchange rates.
+    ///
+    /// Partial data is never returned: the cache must hold exactly `MAX_CACHE_SIZE`
+    /// rates without gaps, otherwise the error tells how many rates are missing.
+    pub fn collect(
+        &self,
+        now: Timestamp,
+        time_unit: Timestamp,
+    ) -> Result<(Vec<f64>, Vec<f64>), CacheError> {
+        if self.items.len() != MAX_CACHE_SIZE {
+            return Result::Err(CacheError::Insufficient(self.items.len(), MAX_CACHE_SIZE));
         }
 
         let mut x = Vec::<f64>::new();
@@ -111,7 +236,7 @@ impl IntervalCache {
                 return Result::Err(CacheError::Gaps);
             }
 
-            x.push(item.normalized_time(now));
+            x.push(item.normalized_time(now, time_unit));
             y.push(item.value);
             fresh_time_slot = item.time_slot();
         }
@@ -170,6 +295,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cache_backfill() {
+        let mut cache = IntervalCache::default();
+        cache.append(0, 7.2);
+        cache.append(FIVE_MINUTES, 7.4);
+        cache.append(2 * FIVE_MINUTES, 7.9);
+
+        // The last cached slot is overwritten, older ones are kept.
+        cache.backfill(4 * FIVE_MINUTES + 10, 3, 6.6);
+        let slots: Vec<(u64, f64)> = cache
+            .items
+            .iter()
+            .map(|item| (item.time_slot(), item.value))
+            .collect();
+        assert_eq!(
+            slots,
+            vec![(0, 7.2), (1, 7.4), (2, 6.6), (3, 6.6), (4, 6.6)]
+        );
+        assert_eq!(cache.items.last().unwrap().timestamp, 4 * FIVE_MINUTES + 10);
+
+        cache.backfill(20 * FIVE_MINUTES, MAX_CACHE_SIZE, 6.7);
+        assert_eq!(cache.items.len(), MAX_CACHE_SIZE);
+        assert!(cache.collect(20 * FIVE_MINUTES, FIVE_MINUTES).is_ok());
+    }
+
     #[test]
     fn test_cache_collect_not_ready() {
         const ONE_MINUTE: u64 = FIVE_MINUTES / 5;
@@ -185,7 +335,80 @@ mod tests {
         cache.append(14 * ONE_MINUTE, 7.8);
         cache.append(17 * ONE_MINUTE, 7.5);
 
-        assert_eq!(cache.collect(18 * ONE_MINUTE), Err(CacheError::NotReady));
+        assert_eq!(
+            cache.collect(18 * ONE_MINUTE, FIVE_MINUTES),
+            Err(CacheError::Insufficient(4, 8))
+        );
+    }
+
+    #[test]
+    fn test_cache_collect_empty() {
+        let cache = IntervalCache::default();
+        assert_eq!(
+            cache.collect(FIVE_MINUTES, FIVE_MINUTES),
+            Err(CacheError::Insufficient(0, 8))
+        );
+        assert_eq!(
+            CacheError::Insufficient(0, 8).to_string(),
+            "Treasury cache is empty. Use `warmup`."
+        );
+    }
+
+    #[test]
+    fn test_cache_collect_partial() {
+        let mut cache = IntervalCache::default();
+
+        for i in 0..7 {
+            cache.append(i * FIVE_MINUTES, 6.5);
+        }
+
+        assert_eq!(
+            cache.collect(7 * FIVE_MINUTES, FIVE_MINUTES),
+            Err(CacheError::Insufficient(7, 8))
+        );
+
+        cache.append(7 * FIVE_MINUTES, 6.5);
+        assert!(cache.collect(8 * FIVE_MINUTES, FIVE_MINUTES).is_ok());
+    }
+
+    #[test]
+    fn test_cache_repair() {
+        let item = |timestamp, value| CacheItem {
+            timestamp,
+            value,
+            n: 1,
+        };
+        let mut cache = IntervalCache {
+            items: vec![
+                item(2 * FIVE_MINUTES, 6.7),
+                item(0, 6.5),
+                item(3 * FIVE_MINUTES, -1.),
+                item(FIVE_MINUTES, 6.6),
+                item(FIVE_MINUTES + 1, 6.61),
+                item(10 * FIVE_MINUTES, 6.8),
+            ],
+        };
+
+        assert_eq!(
+            cache.repair(5 * FIVE_MINUTES),
+            CacheRepairReport {
+                reordered: true,
+                duplicates_removed: 1,
+                invalid_removed: 2,
+            }
+        );
+        assert_eq!(
+            cache.items,
+            vec![
+                item(0, 6.5),
+                item(FIVE_MINUTES + 1, 6.61),
+                item(2 * FIVE_MINUTES, 6.7)
+            ]
+        );
+        cache.assert_valid(5 * FIVE_MINUTES);
+
+        // A valid cache stays the same.
+        assert_eq!(cache.repair(5 * FIVE_MINUTES), CacheRepairReport::default());
     }
 
     #[test]
@@ -197,7 +420,7 @@ mod tests {
         }
 
         assert_eq!(
-            cache.collect(8 * FIVE_MINUTES),
+            cache.collect(8 * FIVE_MINUTES, FIVE_MINUTES),
             Ok((
                 vec![-8.0, -7.0, -6.0, -5.0, -4.0, -3.0, -2.0, -1.0],
                 vec![6.5, 6.5, 6.5, 6.5, 6.5, 6.5, 6.5, 6.5]
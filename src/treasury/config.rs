@@ -0,0 +1,1470 @@
+use std::collections::HashMap;
+
+use near_sdk::{require, Balance, BlockHeightDelta, Duration, Gas, PromiseError, Timestamp};
+
+use crate::*;
+
+use super::execution::ExecutionVenue;
+use super::gas::*;
+use super::pool::{counter_tokens, ref_address, Pool};
+use super::ref_finance::{ext_ref_finance, PoolInfo, StablePoolInfo};
+use super::timelock::TreasuryConfigChange;
+
+/// Defines which stable token of a multi-token pool the treasury trades against.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum CounterTokenPolicy {
+    /// The token with the largest pool reserve.
+    #[default]
+    DeepestReserve,
+    /// The token which pool reserve is the closest to the USN one.
+    TightestPeg,
+    /// The explicitly chosen token.
+    Preferred(AccountId),
+}
+
+impl CounterTokenPolicy {
+    /// Returns an index of the counter-token in the pool.
+    pub fn select(&self, pool: &Pool, info: &StablePoolInfo) -> usize {
+        let usn_id = env::current_account_id();
+
+        require!(
+            pool.tokens == info.token_account_ids,
+            "Wrong pool structure"
+        );
+
+        let usn_amount = pool
+            .tokens
+            .iter()
+            .zip(info.c_amounts.iter())
+            .find_map(|(token_id, amount)| {
+                if token_id == &usn_id {
+                    Some(amount.0)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(|| env::panic_str("USN is not in the pool"));
+
+        let mut counters = pool
+            .tokens
+            .iter()
+            .zip(info.c_amounts.iter())
+            .enumerate()
+            .filter(|(_, (token_id, _))| *token_id != &usn_id);
+
+        let selected = match self {
+            CounterTokenPolicy::DeepestReserve => counters
+                .max_by_key(|(_, (_, amount))| amount.0)
+                .map(|(idx, _)| idx),
+            CounterTokenPolicy::TightestPeg => counters
+                .min_by_key(|(_, (_, amount))| {
+                    (amount.0 as i128 - usn_amount as i128).unsigned_abs()
+                })
+                .map(|(idx, _)| idx),
+            CounterTokenPolicy::Preferred(preferred_id) => counters
+                .find(|(_, (token_id, _))| *token_id == preferred_id)
+                .map(|(idx, _)| idx),
+        };
+
+        selected.unwrap_or_else(|| {
+            env::panic_str(&format!("No counter-token found in the pool {}", pool.id))
+        })
+    }
+}
+
+/// Defines how the counter-token reserve is valued in USD.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq, Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum CounterPriceSource {
+    /// The counter-token is worth exactly $1.
+    #[default]
+    Peg,
+    /// The counter-token is worth as much USN as the pool holds per the counter-token.
+    PoolRatio,
+    /// The counter-token is worth its latest oracle price, see `set_oracle_asset_id`.
+    Oracle,
+}
+
+impl CounterPriceSource {
+    /// Returns the USD price of the counter-token at `counter_idx`.
+    /// `oracle_prices` are USD prices of counter-tokens fetched from the oracle.
+    pub fn price(
+        &self,
+        pool: &Pool,
+        info: &StablePoolInfo,
+        counter_idx: usize,
+        oracle_prices: &HashMap<AccountId, f64>,
+    ) -> f64 {
+        match self {
+            CounterPriceSource::Peg => 1.,
+            CounterPriceSource::Oracle => oracle_prices
+                .get(&pool.tokens[counter_idx])
+                .copied()
+                // Without the oracle price, assume the peg.
+                .unwrap_or(1.),
+            CounterPriceSource::PoolRatio => {
+                let usn_id = env::current_account_id();
+                let usn_amount = pool
+                    .tokens
+                    .iter()
+                    .position(|token_id| token_id == &usn_id)
+                    .map(|idx| info.c_amounts[idx].0)
+                    .unwrap_or_else(|| env::panic_str("USN is not in the pool"));
+                let counter_amount = info.c_amounts[counter_idx].0;
+                if usn_amount == 0 || counter_amount == 0 {
+                    // Nothing to compare against, assume the peg.
+                    1.
+                } else {
+                    usn_amount as f64 / counter_amount as f64
+                }
+            }
+        }
+    }
+}
+
+/// Defines how much of the treasury decision making is logged.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum LogVerbosity {
+    /// Nothing is logged.
+    Off,
+    /// Only the decision is logged.
+    #[default]
+    Summary,
+    /// The decision, the pool data, the reserve inputs and the trend coefficients
+    /// are logged.
+    Verbose,
+}
+
+/// Defines how a decision limit above the reserve the decision trades is treated.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ExcessLimitPolicy {
+    /// The decision is bounded by the reserve.
+    #[default]
+    Clamp,
+    /// The limit is taken for a misconfiguration, the treasury does nothing.
+    Reject,
+}
+
+/// Defines how an oracle price older than the latest cached rate is treated.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OutOfOrderPricePolicy {
+    /// The oracle price is taken as unavailable, other sources or retries follow.
+    #[default]
+    Skip,
+    /// Caching fails without trying other sources.
+    Abort,
+}
+
+/// Defines units the treasury decision amount is reported in.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Denomination {
+    #[default]
+    Usd,
+    Usn,
+}
+
+/// Defines units of the `balance_treasury` limits, decisions convert them into USD.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum LimitUnit {
+    #[default]
+    Usd,
+    Usn,
+    Near,
+}
+
+impl LimitUnit {
+    /// USD value of the `amount` in these units at the NEAR/USD `near_rate`.
+    /// USN is minted and burned at $1.
+    pub fn to_usd(self, amount: f64, near_rate: f64) -> f64 {
+        match self {
+            LimitUnit::Usd | LimitUnit::Usn => amount,
+            LimitUnit::Near => amount * near_rate,
+        }
+    }
+
+    /// Amount in these units worth `usd` at the NEAR/USD `near_rate`.
+    pub fn usd_in(self, usd: f64, near_rate: f64) -> f64 {
+        match self {
+            LimitUnit::Usd | LimitUnit::Usn => usd,
+            LimitUnit::Near => usd / near_rate,
+        }
+    }
+}
+
+/// Defines which rule acts when both the collateral floor and the trend want to trade.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DecisionPriority {
+    /// A reserve below the NEAR floor always sells, whatever the trend says.
+    #[default]
+    CollateralFirst,
+    /// The trend acts first, the collateral sell happens only if the trend does nothing.
+    TrendFirst,
+}
+
+/// How the latest rates of the reference window form the reference rate.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ReferenceAggregation {
+    #[default]
+    Mean,
+    /// Resists a single outlier of the window.
+    Median,
+}
+
+/// Upper bound of the swap slippage tolerance.
+const MAX_SWAP_SLIPPAGE: f64 = 0.9;
+
+/// Swap slippage tolerance depending on the order size:
+/// `slippage(x) = base + linear · x + quadratic · x²`, where `x` is the order size in
+/// millions of USD. The tolerance never exceeds 90%.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SlippageCurve {
+    pub base: f64,
+    pub linear: f64,
+    pub quadratic: f64,
+}
+
+impl SlippageCurve {
+    pub fn assert_valid(&self) {
+        require!(
+            [self.base, self.linear, self.quadratic]
+                .iter()
+                .all(|k| k.is_finite() && *k >= 0.),
+            "Slippage coefficients must be non-negative numbers"
+        );
+        require!(
+            self.base <= MAX_SWAP_SLIPPAGE,
+            &format!("Base slippage is greater than {}", MAX_SWAP_SLIPPAGE)
+        );
+    }
+
+    /// Returns the slippage tolerance for the order of `amount` USD.
+    pub fn slippage(&self, amount: f64) -> f64 {
+        let x = amount / 1_000_000.;
+        let slippage = self.base + self.linear * x + self.quadratic * x * x;
+        slippage.min(MAX_SWAP_SLIPPAGE)
+    }
+}
+
+impl Default for SlippageCurve {
+    fn default() -> Self {
+        // 50% slippage: minimizing chance to get failed but not too much.
+        Self {
+            base: 0.5,
+            linear: 0.,
+            quadratic: 0.,
+        }
+    }
+}
+
+/// Parameters of the treasury decision algorithm. Amounts are in USD.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DecisionParams {
+    /// Power of the trend coefficient denominator.
+    pub m: i32,
+    /// Lower bound of the NEAR part of the reserve relative to circulating USN.
+    pub n_dn: f64,
+    /// Upper bound of the USDT part of the reserve relative to circulating USN.
+    pub u_up: f64,
+    /// Lower bound of the USDT part of the reserve relative to circulating USN.
+    pub u_dn: f64,
+    /// Lower bound of the USDT part of the whole reserve.
+    pub p_dn: f64,
+    /// Upper bound of the USDT part of the whole reserve.
+    pub p_up: f64,
+    pub t_buy_min: f64,
+    pub t_sell_min: f64,
+    pub t_buy_step: f64,
+    pub t_sell_step: f64,
+    pub t_0: f64,
+    /// Shrinks trend-driven amounts as the trend vertex goes away from the observed
+    /// time window, measured in window lengths. Zero disables the decay.
+    pub extrapolation_decay: f64,
+    /// Trend-driven amounts grow linearly with time since the last trade and reach
+    /// the full size after this many seconds. Zero disables the scaling.
+    pub full_size_gap_sec: u64,
+    /// No trend-driven trades happen while the USDT part of the whole reserve is
+    /// within this distance from the target, the middle of [p_dn; p_up].
+    pub dead_band: f64,
+    /// The decision is DoNothing without fitting the trend while the USDT part of
+    /// the whole reserve is within this distance from the target and the collateral
+    /// gap is below `t_sell_min`. Must not exceed `dead_band`, zero disables it.
+    pub neutral_zone: f64,
+    pub priority: DecisionPriority,
+    /// Length of the time unit the trend is fitted in, in seconds.
+    pub time_unit_sec: u64,
+    /// Fits the trend into 3-point moving averages of the rates instead of the raw rates.
+    pub smoothing_enabled: bool,
+    /// Trend-driven trades happen only if the trend moves the rate in their favor
+    /// by more than this many basis points. Zero disables the check.
+    pub min_expected_edge_bps: u16,
+    /// Range the trend coefficient `C` is clamped to.
+    pub c_min: f64,
+    pub c_max: f64,
+    /// Number of the latest rates averaged into the reference rate of the collateral
+    /// floor. 1 takes the latest rate as is.
+    pub reference_window: u8,
+    pub reference_aggregation: ReferenceAggregation,
+}
+
+impl DecisionParams {
+    /// Time unit of the trend in nanoseconds.
+    pub fn time_unit(&self) -> Timestamp {
+        self.time_unit_sec * 1_000_000_000
+    }
+
+    pub fn assert_valid(&self) {
+        require!(
+            self.m > 0 && self.m % 2 == 0,
+            "m must be a positive even number"
+        );
+        require!(
+            [
+                self.n_dn,
+                self.u_up,
+                self.u_dn,
+                self.p_dn,
+                self.p_up,
+                self.t_buy_min,
+                self.t_sell_min,
+                self.t_buy_step,
+                self.t_sell_step,
+                self.extrapolation_decay,
+                self.dead_band,
+                self.neutral_zone,
+            ]
+            .iter()
+            .all(|k| k.is_finite() && *k >= 0.)
+                && self.t_0.is_finite(),
+            "Decision parameters must be non-negative numbers"
+        );
+        require!(
+            self.n_dn <= 1. && self.p_dn <= self.p_up && self.p_up <= 1.,
+            "Reserve shares must be in [0; 1] and p_dn <= p_up"
+        );
+        require!(self.u_dn <= self.u_up, "u_dn must not exceed u_up");
+        require!(self.dead_band < 1., "dead_band must be less than 1");
+        require!(
+            self.neutral_zone <= self.dead_band,
+            "neutral_zone must not exceed dead_band"
+        );
+        require!(
+            self.c_min <= 0. && self.c_max >= 0.,
+            "[c_min; c_max] must contain zero"
+        );
+        require!(self.time_unit_sec > 0, "time_unit_sec must be positive");
+        require!(
+            (1..=8).contains(&self.reference_window),
+            "reference_window must be in [1; 8]"
+        );
+        require!(
+            self.t_buy_min <= self.t_buy_step && self.t_sell_min <= self.t_sell_step,
+            "Minimal amounts must not exceed steps"
+        );
+    }
+}
+
+impl Default for DecisionParams {
+    fn default() -> Self {
+        Self {
+            m: 4,
+            n_dn: 0.25,
+            u_up: 1.1,
+            u_dn: 1.,
+            p_dn: 0.6,
+            p_up: 0.7,
+            t_buy_min: 1000.,
+            t_sell_min: 1000.,
+            t_buy_step: 3_000_000.,
+            t_sell_step: 3_000_000.,
+            t_0: 0.,
+            extrapolation_decay: 0.,
+            full_size_gap_sec: 0,
+            dead_band: 0.,
+            neutral_zone: 0.,
+            priority: DecisionPriority::CollateralFirst,
+            time_unit_sec: 300,
+            smoothing_enabled: true,
+            min_expected_edge_bps: 0,
+            c_min: f64::MIN,
+            c_max: f64::MAX,
+            reference_window: 1,
+            reference_aggregation: ReferenceAggregation::Mean,
+        }
+    }
+}
+
+/// Strategy independent bounds of a single trade in USD.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TradeSizeBounds {
+    pub min_usd: f64,
+    pub max_usd: f64,
+}
+
+impl TradeSizeBounds {
+    pub fn assert_valid(&self) {
+        require!(
+            self.min_usd.is_finite() && self.max_usd.is_finite() && self.min_usd >= 0.,
+            "Trade size bounds must be non-negative numbers"
+        );
+        require!(
+            self.min_usd <= self.max_usd,
+            "min_usd must not exceed max_usd"
+        );
+    }
+}
+
+/// NEAR of the account balance kept out of the reserve the treasury trades.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum NearBuffer {
+    /// Yocto NEAR.
+    Absolute(U128),
+    /// Fraction of the account balance.
+    Fraction(f64),
+}
+
+impl NearBuffer {
+    pub fn assert_valid(&self) {
+        if let NearBuffer::Fraction(fraction) = self {
+            require!(
+                *fraction >= 0. && *fraction < 1.,
+                "NEAR buffer fraction must be in [0; 1)"
+            );
+        }
+    }
+
+    /// Yocto NEAR of the `balance` kept out of the reserve.
+    pub fn amount(&self, balance: Balance) -> Balance {
+        match self {
+            NearBuffer::Absolute(amount) => amount.0,
+            NearBuffer::Fraction(fraction) => (balance as f64 * fraction) as Balance,
+        }
+    }
+}
+
+/// Hard ceiling of USN burned after a single sell.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BurnCeiling {
+    pub max_amount: U128,
+    /// Burns `max_amount` of larger amounts instead of burning nothing.
+    /// USN above the ceiling stays in the treasury either way.
+    pub clamp: bool,
+}
+
+impl BurnCeiling {
+    pub fn assert_valid(&self) {
+        require!(self.max_amount.0 > 0, "Burn ceiling must be positive");
+    }
+
+    /// Returns the part of `amount` allowed to be burned.
+    pub fn allowed(&self, amount: Balance) -> Balance {
+        if amount <= self.max_amount.0 {
+            amount
+        } else if self.clamp {
+            self.max_amount.0
+        } else {
+            0
+        }
+    }
+}
+
+/// Source of the NEAR/USD exchange rate.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum RateSource {
+    Oracle,
+    /// Ratio of the swap pool reserves, the counter-token is taken at $1.
+    SwapPool,
+}
+
+/// Order the exchange rate sources are used in.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RatePrecedence {
+    /// The first available source provides the rate.
+    pub sources: Vec<RateSource>,
+    /// Maximum relative difference of the rates if several sources are available.
+    /// The rate isn't cached beyond it.
+    pub max_disagreement: Option<f64>,
+}
+
+impl RatePrecedence {
+    pub fn assert_valid(&self) {
+        require!(
+            !self.sources.is_empty(),
+            "At least one exchange rate source is required"
+        );
+        for (idx, source) in self.sources.iter().enumerate() {
+            require!(
+                !self.sources[..idx].contains(source),
+                "Exchange rate sources must be distinct"
+            );
+        }
+        if let Some(max_disagreement) = self.max_disagreement {
+            require!(
+                max_disagreement.is_finite() && max_disagreement > 0.,
+                "max_disagreement must be a positive number"
+            );
+        }
+    }
+}
+
+impl Default for RatePrecedence {
+    fn default() -> Self {
+        Self {
+            sources: vec![RateSource::Oracle],
+            max_disagreement: None,
+        }
+    }
+}
+
+/// Share of a decided amount traded in the pool.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SplitTarget {
+    pub pool_id: u64,
+    pub fraction: f64,
+}
+
+/// Checks the targets are distinct treasury pools which fractions sum to 1.
+pub fn assert_valid_split(targets: &[SplitTarget]) {
+    require!(targets.len() > 1, "At least 2 split targets are required");
+    for (idx, target) in targets.iter().enumerate() {
+        Pool::from_config_with_assert(target.pool_id);
+        require!(
+            target.fraction.is_finite() && target.fraction > 0.,
+            "Split fractions must be positive numbers"
+        );
+        require!(
+            targets[..idx]
+                .iter()
+                .all(|other| other.pool_id != target.pool_id),
+            &format!("The pool {} is split into twice", target.pool_id)
+        );
+    }
+    let sum: f64 = targets.iter().map(|target| target.fraction).sum();
+    require!((sum - 1.).abs() < 1e-9, "Split fractions must sum to 1");
+}
+
+/// Route of swaps between wNEAR and stable tokens.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapConfig {
+    pub wrap_id: AccountId,
+    pub swap_pool_id: u64,
+    pub swap_ref_id: AccountId,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        let (wrap_id, swap_pool_id) = if cfg!(feature = "mainnet") {
+            ("wrap.near", 4)
+        } else if cfg!(feature = "testnet") {
+            ("wrap.testnet", 34)
+        } else {
+            ("wrap.test.near", 3)
+        };
+
+        Self {
+            wrap_id: wrap_id.parse().unwrap(),
+            swap_pool_id,
+            swap_ref_id: ref_address(),
+        }
+    }
+}
+
+/// Treasury settings adjustable by the owner.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryConfig {
+    pub counter_token_policy: CounterTokenPolicy,
+    /// Maximum deviation of the latest exchange rate from the mean of the cached rates
+    /// in standard deviations. The treasury doesn't act if the market is more volatile.
+    pub max_volatility: Option<f64>,
+    pub log_verbosity: LogVerbosity,
+    pub swap: SwapConfig,
+    pub slippage: SlippageCurve,
+    /// Units of logged and returned decisions, USN amounts are converted at the USN
+    /// price of the decided pool. Decisions are always made and emitted in USD.
+    pub denomination: Denomination,
+    pub decision: DecisionParams,
+    /// Fees charged by tokens on transfer, in basis points.
+    pub transfer_fees: HashMap<AccountId, u16>,
+    /// Expected effective fees of ref-finance swap pools net of rebates, in basis points.
+    /// Minimal swap outputs are reduced by the fee before the slippage tolerance applies.
+    pub swap_fees: HashMap<u64, u16>,
+    /// Final clamp of decided amounts.
+    pub trade_size_bounds: Option<TradeSizeBounds>,
+    /// Minimum USD value of the pool reserves the treasury trades against.
+    pub min_pool_tvl: Option<f64>,
+    pub counter_price_source: CounterPriceSource,
+    /// Delay in blocks of sensitive changes, zero allows to change them at once.
+    pub change_delay: BlockHeightDelta,
+    /// Gas reserved for burning of USN after a sell.
+    pub burn_gas: Gas,
+    /// Range in whole USD which `balance_treasury` limits must fall within.
+    pub limit_bounds: Option<[u64; 2]>,
+    /// Keeps wNEAR received in sells instead of unwrapping it.
+    pub keep_wrapped: bool,
+    /// Fraction of the decided amount traded per rebalance, the rest is left
+    /// for subsequent calls. Trades the whole amount if not set.
+    pub smoothing: Option<f64>,
+    /// Fraction of sold amounts left in the pool to absorb rounding, in basis points.
+    /// `DEFAULT_SELL_SAFETY_MARGIN_BPS` if not set.
+    pub sell_safety_margin_bps: Option<u16>,
+    /// Maximum relative divergence of the swap pool price from the average
+    /// of the cached rates. The treasury doesn't trade beyond it.
+    pub max_spot_divergence: Option<f64>,
+    /// Emits decision events for `DoNothing` outcomes, true if not set.
+    pub emit_on_do_nothing: Option<bool>,
+    /// Pools sharing amounts decided for any of them.
+    pub split_targets: Option<Vec<SplitTarget>>,
+    pub rate_precedence: RatePrecedence,
+    pub excess_limit_policy: ExcessLimitPolicy,
+    /// Maximum shortfall of a swap output from the expected one as a fraction.
+    /// Worse fills are withdrawn to the treasury instead of being added to the pool
+    /// or burned. Only `slippage` applies if not set.
+    pub fill_tolerance: Option<f64>,
+    /// Buys and sells are enabled unless disabled.
+    pub buys_enabled: Option<bool>,
+    pub sells_enabled: Option<bool>,
+    /// Minimum time in nanoseconds between executed rebalances.
+    pub min_trade_interval: Option<Duration>,
+    /// Sells aren't executed until `ensure_withdraw_registration` confirms the contract
+    /// is registered with wNEAR and the counter-token of the sell.
+    pub require_withdraw_registration: bool,
+    /// Oracle asset ids of counter-tokens, requested along with the NEAR price.
+    /// Counter-tokens without an asset id have no oracle price.
+    pub oracle_asset_ids: HashMap<AccountId, String>,
+    pub out_of_order_price_policy: OutOfOrderPricePolicy,
+    /// NEAR which buys never spend, nothing is kept if not set.
+    pub near_buffer: Option<NearBuffer>,
+    /// Decisions take the wNEAR balance of the contract fetched from the wrap contract
+    /// instead of the wNEAR recorded in the reserve, at the cost of an extra call.
+    pub fetch_wrap_balance: bool,
+    pub burn_ceiling: Option<BurnCeiling>,
+    pub execution_venue: ExecutionVenue,
+    /// Maximum relative divergence of the swap pool price of NEAR from the price
+    /// implied by the latest rate and the stable pool ratio of the counter-token.
+    /// The treasury doesn't trade beyond it.
+    pub max_cross_pool_divergence: Option<f64>,
+    /// Decimals decided amounts are rounded to in logs, events and returned decisions.
+    /// Trades are sized with the full precision. Nothing is rounded if not set.
+    pub decision_decimals: Option<u8>,
+    /// Decisions are made, logged and emitted, but never executed whatever the caller asks.
+    pub shadow_mode: bool,
+    /// Relative drift of a pool position from the expected value flagged by audits.
+    /// `DEFAULT_MAX_AUDIT_DRIFT` if not set.
+    pub max_audit_drift: Option<f64>,
+}
+
+pub(super) const MAX_BPS: u16 = 10_000;
+const DEFAULT_SELL_SAFETY_MARGIN_BPS: u16 = 10;
+/// Pool fees and transfer fees move positions a bit without trades.
+const DEFAULT_MAX_AUDIT_DRIFT: f64 = 0.001;
+/// f64 has no more significant decimals.
+const MAX_DECISION_DECIMALS: u8 = 15;
+
+impl TreasuryConfig {
+    /// Gas of the final burn callback, never less than `GAS_FOR_FINISH_BURNING`.
+    pub fn burn_gas(&self) -> Gas {
+        std::cmp::max(self.burn_gas, GAS_FOR_FINISH_BURNING)
+    }
+
+    pub fn emit_on_do_nothing(&self) -> bool {
+        self.emit_on_do_nothing.unwrap_or(true)
+    }
+
+    pub fn buys_enabled(&self) -> bool {
+        self.buys_enabled.unwrap_or(true)
+    }
+
+    pub fn sells_enabled(&self) -> bool {
+        self.sells_enabled.unwrap_or(true)
+    }
+
+    pub fn oracle_asset_id(&self, token_id: &AccountId) -> Option<&String> {
+        self.oracle_asset_ids.get(token_id)
+    }
+
+    /// Asset ids of all mapped counter-tokens in a stable order.
+    pub fn counter_asset_ids(&self) -> Vec<String> {
+        let mut asset_ids: Vec<String> = self.oracle_asset_ids.values().cloned().collect();
+        asset_ids.sort();
+        asset_ids.dedup();
+        asset_ids
+    }
+
+    /// Returns the least swap output of the `amount` USD trade which is added
+    /// to the pool or burned, never less than `min_amount_out`.
+    pub fn min_fill(&self, amount: f64, min_amount_out: u128) -> u128 {
+        match self.fill_tolerance {
+            Some(tolerance) => {
+                let expected = min_amount_out as f64 / (1. - self.slippage.slippage(amount));
+                std::cmp::max((expected * (1. - tolerance)) as u128, min_amount_out)
+            }
+            None => min_amount_out,
+        }
+    }
+
+    pub fn max_audit_drift(&self) -> f64 {
+        self.max_audit_drift.unwrap_or(DEFAULT_MAX_AUDIT_DRIFT)
+    }
+
+    /// Returns the part of `amount` requested from the pool on sells.
+    pub fn net_of_sell_safety_margin(&self, amount: u128) -> u128 {
+        let margin = self
+            .sell_safety_margin_bps
+            .unwrap_or(DEFAULT_SELL_SAFETY_MARGIN_BPS);
+        amount * (MAX_BPS - margin) as u128 / MAX_BPS as u128
+    }
+
+    /// Expected fee of the configured swap pool as a fraction.
+    pub fn swap_fee(&self) -> f64 {
+        let fee = self
+            .swap_fees
+            .get(&self.swap.swap_pool_id)
+            .copied()
+            .unwrap_or(0);
+        fee as f64 / MAX_BPS as f64
+    }
+
+    /// Returns the amount arriving after the transfer of `amount` tokens.
+    pub fn net_of_transfer_fee(&self, token_id: &AccountId, amount: U128) -> U128 {
+        let fee = self.transfer_fees.get(token_id).copied().unwrap_or(0);
+        U128(amount.0 * (MAX_BPS - fee) as u128 / MAX_BPS as u128)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_counter_token_policy(&mut self, policy: CounterTokenPolicy) {
+        self.internal_change_treasury_config(TreasuryConfigChange::CounterTokenPolicy(policy));
+    }
+
+    pub fn set_max_volatility(&mut self, max_volatility: Option<f64>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::MaxVolatility(max_volatility));
+    }
+
+    pub fn set_counter_price_source(&mut self, source: CounterPriceSource) {
+        self.internal_change_treasury_config(TreasuryConfigChange::CounterPriceSource(source));
+    }
+
+    pub fn set_min_pool_tvl(&mut self, min_pool_tvl: Option<f64>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::MinPoolTvl(min_pool_tvl));
+    }
+
+    pub fn set_max_spot_divergence(&mut self, max_divergence: Option<f64>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::MaxSpotDivergence(
+            max_divergence,
+        ));
+    }
+
+    pub fn set_max_cross_pool_divergence(&mut self, max_divergence: Option<f64>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::MaxCrossPoolDivergence(
+            max_divergence,
+        ));
+    }
+
+    pub fn set_burn_gas(&mut self, gas: Gas) {
+        self.internal_change_treasury_config(TreasuryConfigChange::BurnGas(gas));
+    }
+
+    pub fn set_keep_wrapped(&mut self, keep_wrapped: bool) {
+        self.internal_change_treasury_config(TreasuryConfigChange::KeepWrapped(keep_wrapped));
+    }
+
+    pub fn set_log_verbosity(&mut self, verbosity: LogVerbosity) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.log_verbosity = verbosity;
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_require_withdraw_registration(&mut self, required: bool) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.require_withdraw_registration = required;
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_emit_on_do_nothing(&mut self, emit: bool) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.emit_on_do_nothing = Some(emit);
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_buys_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.buys_enabled = Some(enabled);
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_sells_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.sells_enabled = Some(enabled);
+        self.treasury.replace(&treasury);
+    }
+
+    /// Returns `[buys_enabled, sells_enabled]`.
+    pub fn get_trade_directions(&self) -> [bool; 2] {
+        let config = self.treasury.get().expect("Valid treasury").config;
+        [config.buys_enabled(), config.sells_enabled()]
+    }
+
+    pub fn set_excess_limit_policy(&mut self, policy: ExcessLimitPolicy) {
+        self.internal_change_treasury_config(TreasuryConfigChange::ExcessLimitPolicy(policy));
+    }
+
+    pub fn set_execution_venue(&mut self, venue: ExecutionVenue) {
+        self.internal_change_treasury_config(TreasuryConfigChange::ExecutionVenue(venue));
+    }
+
+    pub fn set_shadow_mode(&mut self, enabled: bool) {
+        self.internal_change_treasury_config(TreasuryConfigChange::ShadowMode(enabled));
+    }
+
+    pub fn set_fetch_wrap_balance(&mut self, fetch: bool) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.fetch_wrap_balance = fetch;
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_out_of_order_price_policy(&mut self, policy: OutOfOrderPricePolicy) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.out_of_order_price_policy = policy;
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_decision_decimals(&mut self, decimals: Option<u8>) {
+        self.assert_owner();
+        require!(
+            decimals.map_or(true, |decimals| decimals <= MAX_DECISION_DECIMALS),
+            &format!(
+                "Decision decimals must not exceed {}",
+                MAX_DECISION_DECIMALS
+            )
+        );
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.decision_decimals = decimals;
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_decision_denomination(&mut self, denomination: Denomination) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.denomination = denomination;
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn set_decision_params(&mut self, params: DecisionParams) {
+        self.internal_change_treasury_config(TreasuryConfigChange::DecisionParams(params));
+    }
+
+    pub fn set_trade_size_bounds(&mut self, bounds: Option<TradeSizeBounds>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::TradeSizeBounds(bounds));
+    }
+
+    pub fn set_rate_precedence(&mut self, precedence: RatePrecedence) {
+        self.internal_change_treasury_config(TreasuryConfigChange::RatePrecedence(precedence));
+    }
+
+    pub fn set_split_targets(&mut self, targets: Option<Vec<SplitTarget>>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::SplitTargets(targets));
+    }
+
+    pub fn set_limit_bounds(&mut self, bounds: Option<[u64; 2]>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::LimitBounds(bounds));
+    }
+
+    pub fn set_decision_smoothing(&mut self, smoothing: Option<f64>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::Smoothing(smoothing));
+    }
+
+    pub fn set_sell_safety_margin(&mut self, margin_bps: u16) {
+        self.internal_change_treasury_config(TreasuryConfigChange::SellSafetyMargin(margin_bps));
+    }
+
+    pub fn set_max_audit_drift(&mut self, max_drift: Option<f64>) {
+        self.assert_owner();
+        require!(
+            max_drift.map_or(true, |max_drift| max_drift.is_finite() && max_drift >= 0.),
+            "Audit drift must be a non-negative number"
+        );
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.max_audit_drift = max_drift;
+        self.treasury.replace(&treasury);
+    }
+
+    /// Sets the fee a token charges on transfer, zero means no fee.
+    pub fn set_transfer_fee(&mut self, token_id: AccountId, fee_bps: u16) {
+        self.internal_change_treasury_config(TreasuryConfigChange::TransferFee(token_id, fee_bps));
+    }
+
+    /// Sets the expected fee of a ref-finance swap pool, zero means no fee.
+    pub fn set_swap_fee(&mut self, swap_pool_id: u64, fee_bps: u16) {
+        self.internal_change_treasury_config(TreasuryConfigChange::SwapFee(swap_pool_id, fee_bps));
+    }
+
+    /// Sets the oracle asset id of a pool counter-token, `None` removes it.
+    pub fn set_oracle_asset_id(&mut self, token_id: AccountId, asset_id: Option<String>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::OracleAssetId(
+            token_id, asset_id,
+        ));
+    }
+
+    pub fn get_oracle_asset_id(&self, token_id: AccountId) -> Option<String> {
+        let treasury = self.treasury.get().expect("Valid treasury");
+        treasury.config.oracle_asset_id(&token_id).cloned()
+    }
+
+    pub fn set_swap_slippage(&mut self, slippage: SlippageCurve) {
+        self.internal_change_treasury_config(TreasuryConfigChange::SwapSlippage(slippage));
+    }
+
+    pub fn set_fill_tolerance(&mut self, tolerance: Option<f64>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::FillTolerance(tolerance));
+    }
+
+    pub fn set_min_trade_interval(&mut self, interval: Option<Duration>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::MinTradeInterval(interval));
+    }
+
+    pub fn set_burn_ceiling(&mut self, ceiling: Option<BurnCeiling>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::BurnCeiling(ceiling));
+    }
+
+    pub fn set_near_buffer(&mut self, buffer: Option<NearBuffer>) {
+        self.internal_change_treasury_config(TreasuryConfigChange::NearBuffer(buffer));
+    }
+
+    /// Updates the swap route at once after making sure the swap pool
+    /// exists on ref-finance and trades wNEAR against every counter-token.
+    /// The pool is verified only here, trades rely on the stored config.
+    pub fn set_swap_config(&mut self, config: SwapConfig) -> Promise {
+        self.assert_owner();
+
+        // Swapped tokens go to the same ref-finance deposit the treasury pools use.
+        require!(
+            config.swap_ref_id == ref_address(),
+            "Swap pool must be hosted by the treasury ref-finance contract"
+        );
+
+        ext_ref_finance::get_pool(
+            config.swap_pool_id,
+            config.swap_ref_id.clone(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_POOL,
+        )
+        .then(ext_self::handle_swap_config(
+            config,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_HANDLE_SWAP_CONFIG,
+        ))
+    }
+}
+
+#[ext_contract(ext_self)]
+trait ConfigHandler {
+    #[private]
+    fn handle_swap_config(
+        &mut self,
+        config: SwapConfig,
+        #[callback_result] info: Result<PoolInfo, PromiseError>,
+    );
+}
+
+trait ConfigHandler {
+    fn handle_swap_config(&mut self, config: SwapConfig, info: Result<PoolInfo, PromiseError>);
+}
+
+#[near_bindgen]
+impl ConfigHandler for Contract {
+    #[private]
+    fn handle_swap_config(
+        &mut self,
+        config: SwapConfig,
+        #[callback_result] info: Result<PoolInfo, PromiseError>,
+    ) {
+        let info = info.unwrap_or_else(|_| {
+            env::panic_str(&format!(
+                "Swap pool {} doesn't exist on {}",
+                config.swap_pool_id, config.swap_ref_id
+            ))
+        });
+
+        require!(
+            info.token_account_ids.contains(&config.wrap_id),
+            &format!(
+                "Swap pool {} doesn't contain {}",
+                config.swap_pool_id, config.wrap_id
+            )
+        );
+
+        for counter_id in counter_tokens() {
+            require!(
+                info.token_account_ids.contains(&counter_id),
+                &format!(
+                    "Swap pool {} doesn't contain {}",
+                    config.swap_pool_id, counter_id
+                )
+            );
+        }
+
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.config.swap = config;
+        self.treasury.replace(&treasury);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    use super::*;
+
+    fn pool() -> (Pool, StablePoolInfo) {
+        let tokens: Vec<AccountId> = vec![
+            "usn.test.near".parse().unwrap(),
+            "usdt.test.near".parse().unwrap(),
+            "usdc.test.near".parse().unwrap(),
+        ];
+        let pool = Pool {
+            ref_id: "ref.test.near".parse().unwrap(),
+            id: 0,
+            tokens: tokens.clone(),
+            decimals: vec![18, 6, 6],
+        };
+        let info = StablePoolInfo {
+            token_account_ids: tokens,
+            decimals: vec![18, 6, 6],
+            amounts: vec![
+                U128(1_000_000 * 10u128.pow(18)),
+                U128(3_000_000 * 10u128.pow(6)),
+                U128(1_100_000 * 10u128.pow(6)),
+            ],
+            c_amounts: vec![
+                U128(1_000_000 * 10u128.pow(18)),
+                U128(3_000_000 * 10u128.pow(18)),
+                U128(1_100_000 * 10u128.pow(18)),
+            ],
+            total_fee: 5,
+            shares_total_supply: U128(0),
+            amp: 240,
+        };
+        (pool, info)
+    }
+
+    fn setup() {
+        let mut context = VMContextBuilder::new();
+        context.current_account_id("usn.test.near".parse().unwrap());
+        testing_env!(context.build());
+    }
+
+    fn swap_pool(tokens: &[&str]) -> PoolInfo {
+        PoolInfo {
+            pool_kind: "SIMPLE_POOL".to_string(),
+            token_account_ids: tokens.iter().map(|t| t.parse().unwrap()).collect(),
+            amounts: vec![U128(0); tokens.len()],
+            total_fee: 30,
+            shares_total_supply: U128(0),
+            amp: 0,
+        }
+    }
+
+    fn swap_config() -> SwapConfig {
+        SwapConfig {
+            wrap_id: "wrap.test.near".parse().unwrap(),
+            swap_pool_id: 7,
+            swap_ref_id: "ref.test.near".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_min_fill() {
+        let mut config = TreasuryConfig::default();
+        assert_eq!(config.min_fill(1000., 500), 500);
+        // 50% slippage by default: 1000 is expected.
+        config.fill_tolerance = Some(0.1);
+        assert_eq!(config.min_fill(1000., 500), 900);
+        config.fill_tolerance = Some(0.9);
+        assert_eq!(config.min_fill(1000., 500), 500);
+    }
+
+    #[test]
+    fn test_counter_price_source() {
+        setup();
+        let (pool, mut info) = pool();
+
+        let oracle_prices = HashMap::from([(pool.tokens[2].clone(), 0.97)]);
+
+        assert_eq!(
+            CounterPriceSource::Peg.price(&pool, &info, 1, &oracle_prices),
+            1.
+        );
+        // 1M USN against 1.1M USDC: USDC is depegged down.
+        let price = CounterPriceSource::PoolRatio.price(&pool, &info, 2, &oracle_prices);
+        assert!((price - 1. / 1.1).abs() < 1e-12);
+
+        info.c_amounts[0] = U128(0);
+        assert_eq!(
+            CounterPriceSource::PoolRatio.price(&pool, &info, 2, &oracle_prices),
+            1.
+        );
+
+        assert_eq!(
+            CounterPriceSource::Oracle.price(&pool, &info, 2, &oracle_prices),
+            0.97
+        );
+        // No oracle price of the token.
+        assert_eq!(
+            CounterPriceSource::Oracle.price(&pool, &info, 1, &oracle_prices),
+            1.
+        );
+    }
+
+    #[test]
+    fn test_slippage_curve() {
+        let flat = SlippageCurve::default();
+        assert_eq!(flat.slippage(1_000.), 0.5);
+        assert_eq!(flat.slippage(3_000_000.), 0.5);
+
+        let curve = SlippageCurve {
+            base: 0.01,
+            linear: 0.02,
+            quadratic: 0.01,
+        };
+        assert!((curve.slippage(1_000.) - 0.01002001).abs() < 1e-12);
+        assert!((curve.slippage(3_000_000.) - 0.16).abs() < 1e-12);
+        assert_eq!(curve.slippage(100_000_000.), MAX_SWAP_SLIPPAGE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Slippage coefficients must be non-negative numbers")]
+    fn test_slippage_curve_negative() {
+        SlippageCurve {
+            base: 0.01,
+            linear: -0.02,
+            quadratic: 0.,
+        }
+        .assert_valid();
+    }
+
+    #[test]
+    fn test_set_decision_params() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        let params = DecisionParams {
+            m: 2,
+            t_sell_step: 1_000_000.,
+            ..Default::default()
+        };
+        contract.set_decision_params(params.clone());
+        assert_eq!(contract.treasury.get().unwrap().config.decision, params);
+    }
+
+    #[test]
+    #[should_panic(expected = "m must be a positive even number")]
+    fn test_decision_params_odd_power() {
+        DecisionParams {
+            m: 3,
+            ..Default::default()
+        }
+        .assert_valid();
+    }
+
+    #[test]
+    #[should_panic(expected = "Reserve shares must be in [0; 1] and p_dn <= p_up")]
+    fn test_decision_params_shares() {
+        DecisionParams {
+            p_dn: 0.8,
+            p_up: 0.7,
+            ..Default::default()
+        }
+        .assert_valid();
+    }
+
+    #[test]
+    #[should_panic(expected = "min_usd must not exceed max_usd")]
+    fn test_set_trade_size_bounds_inverted() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_trade_size_bounds(Some(TradeSizeBounds {
+            min_usd: 10_000.,
+            max_usd: 5_000.,
+        }));
+    }
+
+    #[test]
+    fn test_oracle_asset_ids() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+
+        // Missing mappings have no oracle price.
+        assert_eq!(contract.get_oracle_asset_id(usdt_id.clone()), None);
+        assert!(contract
+            .treasury
+            .get()
+            .unwrap()
+            .config
+            .counter_asset_ids()
+            .is_empty());
+
+        contract.set_oracle_asset_id(usdt_id.clone(), Some("usdt.test.near".to_string()));
+        assert_eq!(
+            contract.get_oracle_asset_id(usdt_id.clone()),
+            Some("usdt.test.near".to_string())
+        );
+        assert_eq!(
+            contract.treasury.get().unwrap().config.counter_asset_ids(),
+            vec!["usdt.test.near".to_string()]
+        );
+
+        contract.set_oracle_asset_id(usdt_id.clone(), None);
+        assert_eq!(contract.get_oracle_asset_id(usdt_id), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "dai.test.near is not a counter-token of the treasury pools")]
+    fn test_oracle_asset_id_unknown_token() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_oracle_asset_id("dai.test.near".parse().unwrap(), Some("dai".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "wrap.test.near is the NEAR asset id")]
+    fn test_oracle_asset_id_near_feed() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_oracle_asset_id(
+            "usdt.test.near".parse().unwrap(),
+            Some("wrap.test.near".to_string()),
+        );
+    }
+
+    #[test]
+    fn test_near_buffer() {
+        let balance = 1000 * ONE_NEAR;
+        assert_eq!(
+            NearBuffer::Absolute(U128(10 * ONE_NEAR)).amount(balance),
+            10 * ONE_NEAR
+        );
+        assert_eq!(NearBuffer::Fraction(0.25).amount(1_000_000), 250_000);
+        assert_eq!(NearBuffer::Fraction(0.).amount(balance), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "NEAR buffer fraction must be in [0; 1)")]
+    fn test_near_buffer_whole_balance() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_near_buffer(Some(NearBuffer::Fraction(1.)));
+    }
+
+    #[test]
+    fn test_transfer_fee() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+        let usdc_id: AccountId = "usdc.test.near".parse().unwrap();
+
+        contract.set_transfer_fee(usdt_id.clone(), 25);
+        let config = contract.treasury.get().unwrap().config;
+        assert_eq!(
+            config.net_of_transfer_fee(&usdt_id, U128(1_000_000_000)),
+            U128(997_500_000)
+        );
+        assert_eq!(
+            config.net_of_transfer_fee(&usdc_id, U128(1_000_000_000)),
+            U128(1_000_000_000)
+        );
+
+        contract.set_transfer_fee(usdt_id.clone(), 0);
+        assert!(contract
+            .treasury
+            .get()
+            .unwrap()
+            .config
+            .transfer_fees
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfer fee must be less than 100%")]
+    fn test_transfer_fee_too_high() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_transfer_fee("usdt.test.near".parse().unwrap(), 10_000);
+    }
+
+    #[test]
+    fn test_set_swap_config() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_swap_config(swap_config());
+        contract.handle_swap_config(
+            swap_config(),
+            Ok(swap_pool(&["wrap.test.near", "usdt.test.near"])),
+        );
+        assert_eq!(contract.treasury().config.swap, swap_config());
+    }
+
+    #[test]
+    #[should_panic(expected = "Split fractions must sum to 1")]
+    fn test_split_fractions_sum() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_split_targets(Some(vec![
+            SplitTarget {
+                pool_id: 0,
+                fraction: 0.5,
+            },
+            SplitTarget {
+                pool_id: 1,
+                fraction: 0.4,
+            },
+        ]));
+    }
+
+    #[test]
+    fn test_set_split_targets() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        let targets = vec![
+            SplitTarget {
+                pool_id: 0,
+                fraction: 0.7,
+            },
+            SplitTarget {
+                pool_id: 1,
+                fraction: 0.3,
+            },
+        ];
+        contract.set_split_targets(Some(targets.clone()));
+        assert_eq!(contract.treasury().config.split_targets, Some(targets));
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap pool must be hosted by the treasury ref-finance contract")]
+    fn test_set_swap_config_wrong_ref() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.set_swap_config(SwapConfig {
+            swap_ref_id: "ref2.test.near".parse().unwrap(),
+            ..swap_config()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap pool 7 doesn't contain wrap.test.near")]
+    fn test_set_swap_config_without_wrap() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.handle_swap_config(
+            swap_config(),
+            Ok(swap_pool(&["usdc.test.near", "usdt.test.near"])),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap pool 7 doesn't contain usdt.test.near")]
+    fn test_set_swap_config_without_counter_token() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.handle_swap_config(
+            swap_config(),
+            Ok(swap_pool(&["wrap.test.near", "usdc.test.near"])),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap pool 7 doesn't exist on ref.test.near")]
+    fn test_set_swap_config_missing_pool() {
+        setup();
+        let mut contract = Contract::new(env::predecessor_account_id());
+        contract.handle_swap_config(swap_config(), Err(PromiseError::Failed));
+    }
+
+    #[test]
+    fn test_counter_token_deepest_reserve() {
+        setup();
+        let (pool, info) = pool();
+        assert_eq!(CounterTokenPolicy::DeepestReserve.select(&pool, &info), 1);
+    }
+
+    #[test]
+    fn test_counter_token_tightest_peg() {
+        setup();
+        let (pool, info) = pool();
+        assert_eq!(CounterTokenPolicy::TightestPeg.select(&pool, &info), 2);
+    }
+
+    #[test]
+    fn test_counter_token_preferred() {
+        setup();
+        let (pool, info) = pool();
+        let policy = CounterTokenPolicy::Preferred("usdc.test.near".parse().unwrap());
+        assert_eq!(policy.select(&pool, &info), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "No counter-token found in the pool 0")]
+    fn test_counter_token_preferred_missing() {
+        setup();
+        let (pool, info) = pool();
+        let policy = CounterTokenPolicy::Preferred("dai.test.near".parse().unwrap());
+        policy.select(&pool, &info);
+    }
+
+    #[test]
+    #[should_panic(expected = "No counter-token found in the pool 0")]
+    fn test_counter_token_preferred_usn() {
+        setup();
+        let (pool, info) = pool();
+        let policy = CounterTokenPolicy::Preferred("usn.test.near".parse().unwrap());
+        policy.select(&pool, &info);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::*;
+
+use super::balance_treasury::{buy, sell};
+use super::config::TreasuryConfig;
+use super::pool::Pool;
+
+/// Trade of a decision in terms common to DEXes.
+pub struct TradeOrder {
+    pub pool: Pool,
+    /// Index of the pool token traded against NEAR.
+    pub counter_idx: usize,
+    /// USD amount of the decision.
+    pub amount: f64,
+    pub exchange_rate: f64,
+    /// Swap input limit, usually the swap pool reserve.
+    pub max_in: Option<u128>,
+}
+
+/// Swap and liquidity plumbing the decisions are executed with.
+pub trait ExecutionAdapter {
+    /// Swaps NEAR into the counter-token and adds it to the pool.
+    fn execute_buy(&self, config: &TreasuryConfig, order: TradeOrder) -> Promise;
+
+    /// Removes USN and the counter-token from the pool, swaps the counter-token
+    /// into NEAR and burns USN.
+    fn execute_sell(&self, config: &TreasuryConfig, order: TradeOrder) -> Promise;
+}
+
+/// Executes decisions with ref-finance pools.
+pub struct RefFinanceAdapter;
+
+impl ExecutionAdapter for RefFinanceAdapter {
+    fn execute_buy(&self, config: &TreasuryConfig, order: TradeOrder) -> Promise {
+        buy(
+            config,
+            order.pool,
+            order.counter_idx,
+            order.amount,
+            order.exchange_rate,
+            order.max_in,
+        )
+    }
+
+    fn execute_sell(&self, config: &TreasuryConfig, order: TradeOrder) -> Promise {
+        sell(
+            config,
+            order.pool,
+            order.counter_idx,
+            order.amount,
+            order.exchange_rate,
+            order.max_in,
+        )
+    }
+}
+
+/// Adapter selection stored in the treasury config.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ExecutionVenue {
+    #[default]
+    RefFinance,
+}
+
+impl ExecutionVenue {
+    pub fn adapter(&self) -> &'static dyn ExecutionAdapter {
+        match self {
+            ExecutionVenue::RefFinance => &RefFinanceAdapter,
+        }
+    }
+}
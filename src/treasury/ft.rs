@@ -18,4 +18,18 @@ pub trait Ft {
 
     #[payable]
     fn near_withdraw(&mut self, amount: U128);
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+
+    fn storage_balance_of(
+        &self,
+        account_id: AccountId,
+    ) -> Option<near_contract_standards::storage_management::StorageBalance>;
+
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> near_contract_standards::storage_management::StorageBalance;
 }
@@ -3,12 +3,25 @@ use near_sdk::Gas;
 pub const GAS_FOR_GET_DEPOSITS: Gas = Gas(7_000_000_000_000);
 pub const GAS_FOR_GET_SHARES: Gas = Gas(7_000_000_000_000);
 pub const GAS_FOR_GET_STABLE_POOL: Gas = Gas(7_000_000_000_000);
+pub const GAS_FOR_GET_POOL: Gas = Gas(7_000_000_000_000);
+pub const GAS_FOR_GET_RETURN: Gas = Gas(7_000_000_000_000);
+pub const GAS_FOR_FT_BALANCE_OF: Gas = Gas(5_000_000_000_000);
+pub const GAS_FOR_STORAGE_BALANCE_OF: Gas = Gas(5_000_000_000_000);
+pub const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_HANDLE_SWAP_CONFIG: Gas = Gas(7_000_000_000_000);
+pub const GAS_FOR_HANDLE_POOL_CONFIG: Gas = Gas(7_000_000_000_000);
 pub const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(45_000_000_000_000);
 pub const GAS_FOR_ADD_LIQUIDITY: Gas = Gas(17_000_000_000_000);
 pub const GAS_FOR_PREDICT_REMOVE_LIQUIDITY: Gas = Gas(13_000_000_000_000);
 pub const GAS_FOR_REMOVE_LIQUIDITY: Gas = Gas(17_000_000_000_000);
 pub const GAS_FOR_WITHDRAW: Gas = Gas(55_000_000_000_000);
+pub const GAS_FOR_HANDLE_ADD_LIQUIDITY: Gas = Gas(5_000_000_000_000);
+pub const GAS_FOR_HANDLE_STABLE_POOL: Gas = Gas(7_000_000_000_000);
 pub const GAS_FOR_FINISH_BURNING: Gas = Gas(7_000_000_000_000);
+pub const GAS_FOR_FINISH_BALANCING: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_FAIL_BALANCING: Gas = Gas(3_000_000_000_000);
+pub const GAS_FOR_PREVIEW_BALANCING: Gas = Gas(30_000_000_000_000);
+pub const GAS_FOR_HANDLE_BALANCE_BATCH: Gas = Gas(10_000_000_000_000);
 pub const GAS_FOR_HANDLE_RESERVE: Gas = Gas(13_000_000_000_000);
 pub const GAS_FOR_NEAR_DEPOSIT: Gas = Gas(25_000_000_000_000);
 pub const GAS_FOR_NEAR_WITHDRAW: Gas = Gas(25_000_000_000_000);
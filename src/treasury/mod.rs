@@ -1,11 +1,14 @@
 mod balance_stable_pool;
 mod balance_treasury;
 mod cache;
+mod config;
+mod execution;
 mod ft;
 mod gas;
 mod pool;
 mod ref_finance;
+mod timelock;
 mod transfer_stable_liquidity;
 mod treasury;
 
-pub use treasury::TreasuryData;
+pub use treasury::{TreasuryData, TreasuryDataV1};
@@ -1,10 +1,19 @@
+use near_sdk::{require, PromiseError};
+
 use crate::*;
 
+use super::gas::*;
+use super::ref_finance::{ext_ref_finance, PoolInfo, StablePoolInfo};
+
 pub(super) const USDT_DECIMALS: u8 = 6;
+/// Tolerance of the minted LP shares below the proportional part of the pool.
+pub(super) const MIN_SHARES_TOLERANCE_BPS: u16 = 100;
+
+type PoolTokens = &'static [&'static (&'static str, u8)];
 
 struct PoolsConfig {
     pub ref_address: &'static str,
-    pub pools: &'static [(u64, &'static [&'static (&'static str, u8)])],
+    pub pools: &'static [(u64, PoolTokens)],
 }
 
 const CONFIG: PoolsConfig = if cfg!(feature = "mainnet") {
@@ -59,6 +68,236 @@ impl Contract {
     pub fn pools(&self) -> Vec<u64> {
         CONFIG.pools.iter().map(|&(pool_id, _)| pool_id).collect()
     }
+
+    /// Panics if decimals of the token and the treasury pools disagree.
+    pub fn verify_config(&self) {
+        assert_consistent_decimals(CONFIG.pools, &env::current_account_id(), self.decimals());
+    }
+
+    /// Checks the pool on ref-finance before it's added to the treasury pools.
+    /// Only can be called by owner.
+    pub fn validate_pool_config(&self, config: PoolConfig) -> Promise {
+        self.assert_owner();
+        require!(
+            config.token_ids.len() == config.decimals.len(),
+            "Every pool token must have decimals"
+        );
+
+        ext_ref_finance::get_pool(config.pool_id, ref_address(), NO_DEPOSIT, GAS_FOR_GET_POOL)
+            .and(ext_ref_finance::get_stable_pool(
+                config.pool_id,
+                ref_address(),
+                NO_DEPOSIT,
+                GAS_FOR_GET_STABLE_POOL,
+            ))
+            .then(ext_self::handle_pool_config(
+                config,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_HANDLE_POOL_CONFIG,
+            ))
+    }
+}
+
+/// Pool proposed for the treasury with its tokens in the pool order.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolConfig {
+    pub pool_id: u64,
+    pub token_ids: Vec<AccountId>,
+    pub decimals: Vec<u8>,
+}
+
+/// Result of `validate_pool_config`, the pool is valid if `errors` is empty.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolConfigReport {
+    pub exists: bool,
+    pub is_stable: bool,
+    pub contains_usn: bool,
+    /// Decimals agree with the pool, USN and the treasury pools.
+    pub consistent_decimals: bool,
+    pub errors: Vec<String>,
+}
+
+#[ext_contract(ext_self)]
+trait PoolHandler {
+    #[private]
+    fn handle_pool_config(
+        &self,
+        config: PoolConfig,
+        #[callback_result] info: Result<PoolInfo, PromiseError>,
+        #[callback_result] stable_info: Result<StablePoolInfo, PromiseError>,
+    ) -> PoolConfigReport;
+}
+
+trait PoolHandler {
+    fn handle_pool_config(
+        &self,
+        config: PoolConfig,
+        info: Result<PoolInfo, PromiseError>,
+        stable_info: Result<StablePoolInfo, PromiseError>,
+    ) -> PoolConfigReport;
+}
+
+#[near_bindgen]
+impl PoolHandler for Contract {
+    #[private]
+    fn handle_pool_config(
+        &self,
+        config: PoolConfig,
+        #[callback_result] info: Result<PoolInfo, PromiseError>,
+        #[callback_result] stable_info: Result<StablePoolInfo, PromiseError>,
+    ) -> PoolConfigReport {
+        let pool_id = config.pool_id;
+        let mut errors = Vec::new();
+
+        let exists = info.is_ok();
+        if !exists {
+            errors.push(format!(
+                "Pool {} doesn't exist on {}",
+                pool_id,
+                ref_address()
+            ));
+        }
+
+        // ref-finance provides stable pool info of stable pools only.
+        let is_stable = stable_info.is_ok();
+        if exists && !is_stable {
+            errors.push(format!("Pool {} is not a stable pool", pool_id));
+        }
+
+        let usn_id = env::current_account_id();
+        let contains_usn = config.token_ids.contains(&usn_id);
+        if !contains_usn {
+            errors.push(format!("Pool {} doesn't contain {}", pool_id, usn_id));
+        }
+
+        if let Ok(info) = &info {
+            if info.token_account_ids != config.token_ids {
+                errors.push(format!(
+                    "Pool {} tokens are {:?}",
+                    pool_id, info.token_account_ids
+                ));
+            }
+        }
+
+        let decimals_errors = config_decimals_errors(&config, stable_info.ok().as_ref());
+        let consistent_decimals = decimals_errors.is_empty();
+        errors.extend(decimals_errors);
+
+        PoolConfigReport {
+            exists,
+            is_stable,
+            contains_usn,
+            consistent_decimals,
+            errors,
+        }
+    }
+}
+
+/// Disagreements of the proposed decimals with USN, tokens of the treasury pools
+/// and the pool itself.
+fn config_decimals_errors(
+    config: &PoolConfig,
+    stable_info: Option<&StablePoolInfo>,
+) -> Vec<String> {
+    let usn_id = env::current_account_id();
+    let mut errors = Vec::new();
+    for (idx, (token_id, &decimals)) in config
+        .token_ids
+        .iter()
+        .zip(config.decimals.iter())
+        .enumerate()
+    {
+        let known = if token_id == &usn_id {
+            Some(USN_DECIMALS)
+        } else {
+            token_decimals(token_id)
+        };
+        let reported = stable_info
+            .filter(|info| info.token_account_ids.get(idx) == Some(token_id))
+            .and_then(|info| info.decimals.get(idx).copied());
+        if let Some(expected) = known
+            .into_iter()
+            .chain(reported)
+            .find(|&expected| expected != decimals)
+        {
+            errors.push(format!(
+                "Pool {} has {} decimals of {}, expected {}",
+                config.pool_id, decimals, token_id, expected
+            ));
+        }
+    }
+    errors
+}
+
+/// Every pool must hold USN with `USN_DECIMALS` which the token metadata reports as well,
+/// a token shared by pools must have the same decimals in each of them.
+fn assert_consistent_decimals(pools: &[(u64, PoolTokens)], usn_id: &AccountId, ft_decimals: u8) {
+    require!(
+        ft_decimals == USN_DECIMALS,
+        &format!(
+            "Token decimals {} don't match USN decimals {}",
+            ft_decimals, USN_DECIMALS
+        )
+    );
+    let mut known: Vec<(&str, u8)> = Vec::new();
+    for &(pool_id, tokens) in pools {
+        require!(
+            tokens.iter().any(|token| token.0 == usn_id.as_str()),
+            &format!("Pool {} doesn't contain {}", pool_id, usn_id)
+        );
+        for &&(token_id, decimals) in tokens.iter() {
+            let expected = if token_id == usn_id.as_str() {
+                Some(USN_DECIMALS)
+            } else {
+                known
+                    .iter()
+                    .find(|(known_id, _)| *known_id == token_id)
+                    .map(|&(_, known_decimals)| known_decimals)
+            };
+            match expected {
+                Some(expected) if expected != decimals => env::panic_str(&format!(
+                    "Pool {} has {} decimals of {}, expected {}",
+                    pool_id, decimals, token_id, expected
+                )),
+                Some(_) => {}
+                None => known.push((token_id, decimals)),
+            }
+        }
+    }
+}
+
+/// Returns the ref-finance contract hosting the treasury pools.
+pub fn ref_address() -> AccountId {
+    CONFIG.ref_address.parse().unwrap()
+}
+
+/// Returns all non-USN tokens of the treasury pools without duplicates.
+pub fn counter_tokens() -> Vec<AccountId> {
+    let usn_id = env::current_account_id();
+    let mut tokens: Vec<AccountId> = Vec::new();
+    for &(_, pool_tokens) in CONFIG.pools {
+        for token in pool_tokens.iter() {
+            let token_id: AccountId = token.0.parse().unwrap();
+            if token_id != usn_id && !tokens.contains(&token_id) {
+                tokens.push(token_id);
+            }
+        }
+    }
+    tokens
+}
+
+/// Returns decimals of a token of the treasury pools.
+pub fn token_decimals(token_id: &AccountId) -> Option<u8> {
+    CONFIG.pools.iter().find_map(|&(_, pool_tokens)| {
+        pool_tokens
+            .iter()
+            .find(|token| token.0 == token_id.as_str())
+            .map(|token| token.1)
+    })
 }
 
 pub struct Pool {
@@ -76,7 +315,7 @@ impl Pool {
             .find_map(|&(id, tokens)| {
                 if pool_id == id {
                     Some(Self {
-                        ref_id: CONFIG.ref_address.parse().unwrap(),
+                        ref_id: ref_address(),
                         id: pool_id,
                         tokens: tokens.iter().map(|t| t.0.parse().unwrap()).collect(),
                         decimals: tokens.iter().map(|t| t.1).collect(),
@@ -109,3 +348,321 @@ pub fn extend_decimals(whole: u128, decimals: u8) -> u128 {
 pub fn remove_decimals(amount: u128, decimals: u8) -> u128 {
     amount / 10u128.pow(decimals as u32)
 }
+
+/// Returns the minimum LP shares for adding `amounts` of pool tokens,
+/// `tolerance_bps` below the proportional part of the pool shares.
+pub fn min_shares(info: &StablePoolInfo, amounts: &[U128], tolerance_bps: u16) -> U128 {
+    let reserve: u128 = info.c_amounts.iter().map(|amount| amount.0).sum();
+    if reserve == 0 {
+        return U128(0);
+    }
+
+    // Bring token amounts to the share precision.
+    let share_decimals = share_decimals(info);
+    let to_share_units = |amount: u128, decimals: u8| {
+        if decimals < share_decimals {
+            extend_decimals(amount, share_decimals - decimals)
+        } else {
+            remove_decimals(amount, decimals - share_decimals)
+        }
+    };
+    let added: u128 = amounts
+        .iter()
+        .zip(info.decimals.iter())
+        .map(|(amount, &decimals)| to_share_units(amount.0, decimals))
+        .sum();
+
+    let shares = U256::from(added) * U256::from(info.shares_total_supply.0) / U256::from(reserve);
+    U128((shares * U256::from(10_000 - tolerance_bps) / U256::from(10_000)).as_u128())
+}
+
+/// Precision of `c_amounts` the LP shares use, derived from a non-empty token
+/// of the pool as its decimals scaled by `c_amounts / amounts`.
+fn share_decimals(info: &StablePoolInfo) -> u8 {
+    let scale = |mut ratio: u128| {
+        let mut digits = 0;
+        while ratio >= 10 {
+            ratio /= 10;
+            digits += 1;
+        }
+        digits
+    };
+
+    info.amounts
+        .iter()
+        .zip(info.c_amounts.iter())
+        .zip(info.decimals.iter())
+        .find(|((amount, c_amount), _)| amount.0 > 0 && c_amount.0 > 0)
+        .map_or_else(
+            || info.decimals.iter().copied().max().unwrap_or_default(),
+            |((amount, c_amount), &decimals)| {
+                if c_amount.0 >= amount.0 {
+                    decimals + scale(c_amount.0 / amount.0)
+                } else {
+                    decimals - scale(amount.0 / c_amount.0)
+                }
+            },
+        )
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn usn_contract() -> Contract {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id("usn.test.near".parse().unwrap());
+        testing_env!(context.build());
+        Contract::new("owner.test.near".parse().unwrap())
+    }
+
+    fn pool_config(tokens: &[(&str, u8)]) -> PoolConfig {
+        PoolConfig {
+            pool_id: 2,
+            token_ids: tokens.iter().map(|t| t.0.parse().unwrap()).collect(),
+            decimals: tokens.iter().map(|t| t.1).collect(),
+        }
+    }
+
+    fn pool_info(config: &PoolConfig, pool_kind: &str) -> PoolInfo {
+        PoolInfo {
+            pool_kind: pool_kind.to_string(),
+            token_account_ids: config.token_ids.clone(),
+            amounts: vec![U128(0); config.token_ids.len()],
+            total_fee: 5,
+            shares_total_supply: U128(0),
+            amp: 240,
+        }
+    }
+
+    fn stable_pool_info(tokens: &[(&str, u8)]) -> StablePoolInfo {
+        StablePoolInfo {
+            token_account_ids: tokens.iter().map(|t| t.0.parse().unwrap()).collect(),
+            decimals: tokens.iter().map(|t| t.1).collect(),
+            amounts: vec![U128(0); tokens.len()],
+            c_amounts: vec![U128(0); tokens.len()],
+            total_fee: 5,
+            shares_total_supply: U128(0),
+            amp: 240,
+        }
+    }
+
+    #[test]
+    fn test_valid_pool_config() {
+        let contract = usn_contract();
+        let tokens = [("usn.test.near", USN_DECIMALS), ("usdc.test.near", 6)];
+        let config = pool_config(&tokens);
+
+        let report = contract.handle_pool_config(
+            config.clone(),
+            Ok(pool_info(&config, "STABLE_SWAP")),
+            Ok(stable_pool_info(&tokens)),
+        );
+        assert_eq!(
+            report,
+            PoolConfigReport {
+                exists: true,
+                is_stable: true,
+                contains_usn: true,
+                consistent_decimals: true,
+                errors: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_pool_config() {
+        let contract = usn_contract();
+        let config = pool_config(&[("usn.test.near", USN_DECIMALS), ("usdc.test.near", 6)]);
+
+        let report = contract.handle_pool_config(
+            config,
+            Err(PromiseError::Failed),
+            Err(PromiseError::Failed),
+        );
+        assert!(!report.exists);
+        assert_eq!(report.errors, vec!["Pool 2 doesn't exist on ref.test.near"]);
+    }
+
+    #[test]
+    fn test_not_stable_pool_config() {
+        let contract = usn_contract();
+        let config = pool_config(&[("usn.test.near", USN_DECIMALS), ("usdc.test.near", 6)]);
+
+        let report = contract.handle_pool_config(
+            config.clone(),
+            Ok(pool_info(&config, "SIMPLE_POOL")),
+            Err(PromiseError::Failed),
+        );
+        assert!(report.exists && !report.is_stable);
+        assert_eq!(report.errors, vec!["Pool 2 is not a stable pool"]);
+    }
+
+    #[test]
+    fn test_pool_config_without_usn() {
+        let contract = usn_contract();
+        let tokens = [("usdt.test.near", USDT_DECIMALS), ("usdc.test.near", 6)];
+        let config = pool_config(&tokens);
+
+        let report = contract.handle_pool_config(
+            config.clone(),
+            Ok(pool_info(&config, "STABLE_SWAP")),
+            Ok(stable_pool_info(&tokens)),
+        );
+        assert!(!report.contains_usn);
+        assert_eq!(report.errors, vec!["Pool 2 doesn't contain usn.test.near"]);
+    }
+
+    #[test]
+    fn test_pool_config_inconsistent_decimals() {
+        let contract = usn_contract();
+        // usdt.test.near has 6 decimals in the treasury pools.
+        let tokens = [("usn.test.near", USN_DECIMALS), ("usdt.test.near", 18)];
+        let config = pool_config(&tokens);
+
+        let report = contract.handle_pool_config(
+            config.clone(),
+            Ok(pool_info(&config, "STABLE_SWAP")),
+            Ok(stable_pool_info(&tokens)),
+        );
+        assert!(!report.consistent_decimals);
+        assert_eq!(
+            report.errors,
+            vec!["Pool 2 has 18 decimals of usdt.test.near, expected 6"]
+        );
+
+        // The decimals reported by the pool count as well.
+        let tokens = [("usn.test.near", USN_DECIMALS), ("usdc.test.near", 6)];
+        let report = contract.handle_pool_config(
+            pool_config(&tokens),
+            Ok(pool_info(&pool_config(&tokens), "STABLE_SWAP")),
+            Ok(stable_pool_info(&[
+                ("usn.test.near", USN_DECIMALS),
+                ("usdc.test.near", 8),
+            ])),
+        );
+        assert_eq!(
+            report.errors,
+            vec!["Pool 2 has 6 decimals of usdc.test.near, expected 8"]
+        );
+    }
+
+    #[test]
+    fn test_consistent_decimals() {
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        assert_consistent_decimals(CONFIG.pools, &usn_id, USN_DECIMALS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 1 has 18 decimals of usdt.test.near, expected 6")]
+    fn test_inconsistent_counter_decimals() {
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        assert_consistent_decimals(
+            &[
+                (
+                    0,
+                    &[
+                        &("usn.test.near", USN_DECIMALS),
+                        &("usdt.test.near", USDT_DECIMALS),
+                    ],
+                ),
+                (
+                    1,
+                    &[
+                        &("usn.test.near", USN_DECIMALS),
+                        &("usdt.test.near", USN_DECIMALS),
+                    ],
+                ),
+            ],
+            &usn_id,
+            USN_DECIMALS,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 0 has 6 decimals of usn.test.near, expected 18")]
+    fn test_inconsistent_usn_decimals() {
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        assert_consistent_decimals(
+            &[(
+                0,
+                &[
+                    &("usn.test.near", USDT_DECIMALS),
+                    &("usdt.test.near", USDT_DECIMALS),
+                ],
+            )],
+            &usn_id,
+            USN_DECIMALS,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Token decimals 6 don't match USN decimals 18")]
+    fn test_inconsistent_token_decimals() {
+        let usn_id: AccountId = "usn.test.near".parse().unwrap();
+        assert_consistent_decimals(CONFIG.pools, &usn_id, 6);
+    }
+
+    #[test]
+    fn test_min_shares() {
+        let info = StablePoolInfo {
+            token_account_ids: vec![
+                "usn.test.near".parse().unwrap(),
+                "usdt.test.near".parse().unwrap(),
+            ],
+            decimals: vec![USN_DECIMALS, USDT_DECIMALS],
+            amounts: vec![
+                U128(1_000_000 * 10u128.pow(18)),
+                U128(1_000_000 * 10u128.pow(6)),
+            ],
+            c_amounts: vec![
+                U128(1_000_000 * 10u128.pow(18)),
+                U128(1_000_000 * 10u128.pow(18)),
+            ],
+            total_fee: 5,
+            shares_total_supply: U128(2_000_000 * 10u128.pow(18)),
+            amp: 240,
+        };
+
+        // 1000 USDT brings 1000 shares, not 1000 * 10^6 share units.
+        assert_eq!(
+            min_shares(&info, &[U128(0), U128(1000 * 10u128.pow(6))], 0),
+            U128(1000 * 10u128.pow(18))
+        );
+        assert_eq!(
+            min_shares(&info, &[U128(1000 * 10u128.pow(18)), U128(0)], 100),
+            U128(990 * 10u128.pow(18))
+        );
+    }
+
+    #[test]
+    fn test_share_decimals() {
+        let mut info = StablePoolInfo {
+            token_account_ids: vec![
+                "usn.test.near".parse().unwrap(),
+                "usdt.test.near".parse().unwrap(),
+            ],
+            decimals: vec![USN_DECIMALS, USDT_DECIMALS],
+            amounts: vec![U128(0), U128(1_000_000 * 10u128.pow(6))],
+            c_amounts: vec![U128(0), U128(1_000_000 * 10u128.pow(18))],
+            total_fee: 5,
+            shares_total_supply: U128(1_000_000 * 10u128.pow(18)),
+            amp: 240,
+        };
+        assert_eq!(share_decimals(&info), 18);
+
+        // Shares of a pool with 24 digits of comparable precision.
+        info.c_amounts = vec![U128(0), U128(1_000_000 * 10u128.pow(24))];
+        info.shares_total_supply = U128(1_000_000 * 10u128.pow(24));
+        assert_eq!(share_decimals(&info), 24);
+        assert_eq!(
+            min_shares(&info, &[U128(0), U128(1000 * 10u128.pow(6))], 0),
+            U128(1000 * 10u128.pow(24))
+        );
+    }
+}
@@ -17,6 +17,22 @@ pub struct StablePoolInfo {
     pub amp: u64,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolInfo {
+    /// Pool kind.
+    pub pool_kind: String,
+    /// List of tokens in the pool.
+    pub token_account_ids: Vec<AccountId>,
+    /// Pool token balances.
+    pub amounts: Vec<U128>,
+    /// Fee charged for swap.
+    pub total_fee: u32,
+    /// Total number of shares.
+    pub shares_total_supply: U128,
+    pub amp: u64,
+}
+
 /// Single swap action.
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -37,6 +53,8 @@ pub struct SwapAction {
 
 #[ext_contract(ext_ref_finance)]
 trait RefFinance {
+    fn get_pool(&self, pool_id: u64) -> PoolInfo;
+
     fn get_stable_pool(&self, pool_id: u64) -> StablePoolInfo;
 
     fn get_deposits(&self, account_id: AccountId) -> HashMap<AccountId, U128>;
@@ -45,6 +63,14 @@ trait RefFinance {
 
     fn predict_remove_liquidity(&self, pool_id: u64, shares: U128) -> Vec<U128>;
 
+    fn get_return(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> U128;
+
     #[payable]
     fn add_stable_liquidity(&mut self, pool_id: u64, amounts: Vec<U128>, min_shares: U128) -> U128;
 
@@ -56,6 +82,10 @@ trait RefFinance {
         max_burn_shares: U128,
     ) -> U128;
 
+    #[payable]
+    fn remove_liquidity(&mut self, pool_id: u64, shares: U128, min_amounts: Vec<U128>)
+        -> Vec<U128>;
+
     #[payable]
     fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>);
 
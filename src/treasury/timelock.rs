@@ -0,0 +1,384 @@
+use near_sdk::{require, BlockHeight, BlockHeightDelta, Duration, Gas};
+
+use crate::*;
+
+use super::config::{
+    assert_valid_split, BurnCeiling, CounterPriceSource, CounterTokenPolicy, DecisionParams,
+    ExcessLimitPolicy, NearBuffer, RatePrecedence, SlippageCurve, SplitTarget, TradeSizeBounds,
+    TreasuryConfig, MAX_BPS,
+};
+use super::execution::ExecutionVenue;
+use super::gas::GAS_FOR_FINISH_BURNING;
+use super::pool::counter_tokens;
+
+/// Change of a sensitive treasury setting, every setting changing what or how
+/// the treasury trades is one.
+///
+/// Settings exempt from the timelock are set at once: the kill switches
+/// `buys_enabled` and `sells_enabled`, the safety check `require_withdraw_registration`,
+/// the swap route verified by `set_swap_config`, and the settings of logs, events,
+/// views and audits.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub enum TreasuryConfigChange {
+    SwapSlippage(SlippageCurve),
+    DecisionParams(DecisionParams),
+    MaxVolatility(Option<f64>),
+    TradeSizeBounds(Option<TradeSizeBounds>),
+    MinPoolTvl(Option<f64>),
+    LimitBounds(Option<[u64; 2]>),
+    Smoothing(Option<f64>),
+    MaxSpotDivergence(Option<f64>),
+    MaxCrossPoolDivergence(Option<f64>),
+    SplitTargets(Option<Vec<SplitTarget>>),
+    RatePrecedence(RatePrecedence),
+    FillTolerance(Option<f64>),
+    MinTradeInterval(Option<Duration>),
+    NearBuffer(Option<NearBuffer>),
+    BurnCeiling(Option<BurnCeiling>),
+    CounterTokenPolicy(CounterTokenPolicy),
+    CounterPriceSource(CounterPriceSource),
+    ExcessLimitPolicy(ExcessLimitPolicy),
+    ExecutionVenue(ExecutionVenue),
+    ShadowMode(bool),
+    KeepWrapped(bool),
+    BurnGas(Gas),
+    SellSafetyMargin(u16),
+    /// Expected fee of a swap pool in bps, zero removes it.
+    SwapFee(u64, u16),
+    /// Transfer fee of a token in bps, zero removes it.
+    TransferFee(AccountId, u16),
+    /// Oracle asset id of a counter-token, `None` removes it.
+    OracleAssetId(AccountId, Option<String>),
+    /// Delay in blocks between proposing and committing a change.
+    ChangeDelay(BlockHeightDelta),
+}
+
+impl TreasuryConfigChange {
+    pub fn assert_valid(&self) {
+        match self {
+            TreasuryConfigChange::SwapSlippage(slippage) => slippage.assert_valid(),
+            TreasuryConfigChange::DecisionParams(params) => params.assert_valid(),
+            TreasuryConfigChange::MaxVolatility(Some(max_volatility)) => require!(
+                max_volatility.is_finite() && *max_volatility > 0.,
+                "max_volatility must be a positive number"
+            ),
+            TreasuryConfigChange::TradeSizeBounds(Some(bounds)) => bounds.assert_valid(),
+            TreasuryConfigChange::MinPoolTvl(Some(min_pool_tvl)) => require!(
+                min_pool_tvl.is_finite() && *min_pool_tvl > 0.,
+                "min_pool_tvl must be a positive number"
+            ),
+            TreasuryConfigChange::LimitBounds(Some(bounds)) => require!(
+                bounds[0] <= bounds[1],
+                "`limit_bounds` must be in [min; max] format"
+            ),
+            TreasuryConfigChange::Smoothing(Some(smoothing)) => require!(
+                *smoothing > 0. && *smoothing <= 1.,
+                "smoothing must be in (0; 1]"
+            ),
+            TreasuryConfigChange::MaxSpotDivergence(Some(max_divergence)) => require!(
+                max_divergence.is_finite() && *max_divergence > 0.,
+                "max_spot_divergence must be a positive number"
+            ),
+            TreasuryConfigChange::MaxCrossPoolDivergence(Some(max_divergence)) => require!(
+                max_divergence.is_finite() && *max_divergence > 0.,
+                "max_cross_pool_divergence must be a positive number"
+            ),
+            TreasuryConfigChange::SplitTargets(Some(targets)) => assert_valid_split(targets),
+            TreasuryConfigChange::RatePrecedence(precedence) => precedence.assert_valid(),
+            TreasuryConfigChange::FillTolerance(Some(tolerance)) => require!(
+                *tolerance >= 0. && *tolerance < 1.,
+                "fill_tolerance must be in [0; 1)"
+            ),
+            TreasuryConfigChange::MinTradeInterval(Some(interval)) => {
+                require!(*interval > 0, "min_trade_interval must be positive")
+            }
+            TreasuryConfigChange::NearBuffer(Some(buffer)) => buffer.assert_valid(),
+            TreasuryConfigChange::BurnCeiling(Some(ceiling)) => ceiling.assert_valid(),
+            TreasuryConfigChange::BurnGas(gas) => require!(
+                *gas >= GAS_FOR_FINISH_BURNING,
+                &format!("Burn gas must be at least {}", GAS_FOR_FINISH_BURNING.0)
+            ),
+            TreasuryConfigChange::SellSafetyMargin(margin_bps) => require!(
+                *margin_bps < MAX_BPS,
+                "Sell safety margin must be less than 100%"
+            ),
+            TreasuryConfigChange::SwapFee(_, fee_bps) => {
+                require!(*fee_bps < MAX_BPS, "Swap fee must be less than 100%")
+            }
+            TreasuryConfigChange::TransferFee(_, fee_bps) => {
+                require!(*fee_bps < MAX_BPS, "Transfer fee must be less than 100%")
+            }
+            TreasuryConfigChange::OracleAssetId(token_id, asset_id) => {
+                require!(
+                    counter_tokens().contains(token_id),
+                    &format!("{} is not a counter-token of the treasury pools", token_id)
+                );
+                if let Some(asset_id) = asset_id {
+                    require!(!asset_id.is_empty(), "Asset id must not be empty");
+                    require!(
+                        *asset_id != Oracle::asset_id(),
+                        &format!("{} is the NEAR asset id", asset_id)
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn apply(self, config: &mut TreasuryConfig) {
+        match self {
+            TreasuryConfigChange::SwapSlippage(slippage) => config.slippage = slippage,
+            TreasuryConfigChange::DecisionParams(params) => config.decision = params,
+            TreasuryConfigChange::MaxVolatility(max_volatility) => {
+                config.max_volatility = max_volatility
+            }
+            TreasuryConfigChange::TradeSizeBounds(bounds) => config.trade_size_bounds = bounds,
+            TreasuryConfigChange::MinPoolTvl(min_pool_tvl) => config.min_pool_tvl = min_pool_tvl,
+            TreasuryConfigChange::LimitBounds(bounds) => config.limit_bounds = bounds,
+            TreasuryConfigChange::Smoothing(smoothing) => config.smoothing = smoothing,
+            TreasuryConfigChange::MaxSpotDivergence(max_divergence) => {
+                config.max_spot_divergence = max_divergence
+            }
+            TreasuryConfigChange::MaxCrossPoolDivergence(max_divergence) => {
+                config.max_cross_pool_divergence = max_divergence
+            }
+            TreasuryConfigChange::SplitTargets(targets) => config.split_targets = targets,
+            TreasuryConfigChange::RatePrecedence(precedence) => config.rate_precedence = precedence,
+            TreasuryConfigChange::FillTolerance(tolerance) => config.fill_tolerance = tolerance,
+            TreasuryConfigChange::MinTradeInterval(interval) => {
+                config.min_trade_interval = interval
+            }
+            TreasuryConfigChange::NearBuffer(buffer) => config.near_buffer = buffer,
+            TreasuryConfigChange::BurnCeiling(ceiling) => config.burn_ceiling = ceiling,
+            TreasuryConfigChange::CounterTokenPolicy(policy) => {
+                config.counter_token_policy = policy
+            }
+            TreasuryConfigChange::CounterPriceSource(source) => {
+                config.counter_price_source = source
+            }
+            TreasuryConfigChange::ExcessLimitPolicy(policy) => config.excess_limit_policy = policy,
+            TreasuryConfigChange::ExecutionVenue(venue) => config.execution_venue = venue,
+            TreasuryConfigChange::ShadowMode(enabled) => config.shadow_mode = enabled,
+            TreasuryConfigChange::KeepWrapped(keep_wrapped) => config.keep_wrapped = keep_wrapped,
+            TreasuryConfigChange::BurnGas(gas) => config.burn_gas = gas,
+            TreasuryConfigChange::SellSafetyMargin(margin_bps) => {
+                config.sell_safety_margin_bps = Some(margin_bps)
+            }
+            TreasuryConfigChange::SwapFee(swap_pool_id, 0) => {
+                config.swap_fees.remove(&swap_pool_id);
+            }
+            TreasuryConfigChange::SwapFee(swap_pool_id, fee_bps) => {
+                config.swap_fees.insert(swap_pool_id, fee_bps);
+            }
+            TreasuryConfigChange::TransferFee(token_id, 0) => {
+                config.transfer_fees.remove(&token_id);
+            }
+            TreasuryConfigChange::TransferFee(token_id, fee_bps) => {
+                config.transfer_fees.insert(token_id, fee_bps);
+            }
+            TreasuryConfigChange::OracleAssetId(token_id, Some(asset_id)) => {
+                config.oracle_asset_ids.insert(token_id, asset_id);
+            }
+            TreasuryConfigChange::OracleAssetId(token_id, None) => {
+                config.oracle_asset_ids.remove(&token_id);
+            }
+            TreasuryConfigChange::ChangeDelay(delay) => config.change_delay = delay,
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingConfigChange {
+    pub change: TreasuryConfigChange,
+    /// The change can be committed since this block.
+    pub unlock_at: BlockHeight,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Stages the change to be committed after the configured delay.
+    pub fn propose_treasury_config_change(&mut self, change: TreasuryConfigChange) -> BlockHeight {
+        self.assert_owner();
+        change.assert_valid();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        require!(
+            treasury.pending_change.is_none(),
+            "Another treasury config change is pending"
+        );
+        let unlock_at = env::block_height() + treasury.config.change_delay;
+        treasury.pending_change = Some(PendingConfigChange { change, unlock_at });
+        self.treasury.replace(&treasury);
+        unlock_at
+    }
+
+    pub fn commit_treasury_config_change(&mut self) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        let pending = treasury
+            .pending_change
+            .take()
+            .unwrap_or_else(|| env::panic_str("No pending treasury config change"));
+        require!(
+            env::block_height() >= pending.unlock_at,
+            &format!(
+                "Treasury config change is locked until block {}",
+                pending.unlock_at
+            )
+        );
+        pending.change.apply(&mut treasury.config);
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn cancel_treasury_config_change(&mut self) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        require!(
+            treasury.pending_change.take().is_some(),
+            "No pending treasury config change"
+        );
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn pending_treasury_config_change(&self) -> Option<PendingConfigChange> {
+        self.treasury.get().expect("Valid treasury").pending_change
+    }
+}
+
+impl Contract {
+    /// Applies the change at once, allowed only if changes aren't timelocked.
+    pub fn internal_change_treasury_config(&mut self, change: TreasuryConfigChange) {
+        self.assert_owner();
+        change.assert_valid();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        require!(
+            treasury.config.change_delay == 0,
+            "Treasury config is timelocked, use `propose_treasury_config_change`"
+        );
+        change.apply(&mut treasury.config);
+        self.treasury.replace(&treasury);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn timelocked_contract(delay: BlockHeightDelta) -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id(accounts(1))
+            .block_index(100);
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.internal_change_treasury_config(TreasuryConfigChange::ChangeDelay(delay));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_timelocked_change() {
+        let (mut context, mut contract) = timelocked_contract(50);
+        let change = TreasuryConfigChange::MaxVolatility(Some(2.));
+
+        assert_eq!(contract.propose_treasury_config_change(change.clone()), 150);
+        assert_eq!(
+            contract.pending_treasury_config_change(),
+            Some(PendingConfigChange {
+                change,
+                unlock_at: 150
+            })
+        );
+
+        testing_env!(context.block_index(150).build());
+        contract.commit_treasury_config_change();
+
+        let treasury = contract.treasury.get().unwrap();
+        assert_eq!(treasury.config.max_volatility, Some(2.));
+        assert_eq!(treasury.pending_change, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Treasury config change is locked until block 150")]
+    fn test_commit_locked_change() {
+        let (mut context, mut contract) = timelocked_contract(50);
+        contract.propose_treasury_config_change(TreasuryConfigChange::MinPoolTvl(Some(1e6)));
+
+        testing_env!(context.block_index(149).build());
+        contract.commit_treasury_config_change();
+    }
+
+    #[test]
+    fn test_cancel_change() {
+        let (_, mut contract) = timelocked_contract(50);
+        contract.propose_treasury_config_change(TreasuryConfigChange::MinPoolTvl(Some(1e6)));
+        contract.cancel_treasury_config_change();
+
+        assert_eq!(contract.pending_treasury_config_change(), None);
+        // A new change can be proposed.
+        contract.propose_treasury_config_change(TreasuryConfigChange::MinPoolTvl(Some(2e6)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Another treasury config change is pending")]
+    fn test_propose_twice() {
+        let (_, mut contract) = timelocked_contract(50);
+        contract.propose_treasury_config_change(TreasuryConfigChange::MinPoolTvl(Some(1e6)));
+        contract.propose_treasury_config_change(TreasuryConfigChange::MinPoolTvl(Some(2e6)));
+    }
+
+    #[test]
+    #[should_panic(expected = "max_volatility must be a positive number")]
+    fn test_propose_invalid_change() {
+        let (_, mut contract) = timelocked_contract(50);
+        contract.propose_treasury_config_change(TreasuryConfigChange::MaxVolatility(Some(-1.)));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Treasury config is timelocked, use `propose_treasury_config_change`"
+    )]
+    fn test_direct_change_when_timelocked() {
+        let (_, mut contract) = timelocked_contract(50);
+        contract.set_max_volatility(Some(2.));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Treasury config is timelocked, use `propose_treasury_config_change`"
+    )]
+    fn test_direct_fee_change_when_timelocked() {
+        let (_, mut contract) = timelocked_contract(50);
+        contract.set_swap_fee(1, 30);
+    }
+
+    #[test]
+    fn test_timelocked_fee_changes() {
+        let (mut context, mut contract) = timelocked_contract(50);
+        let usdt_id: AccountId = "usdt.test.near".parse().unwrap();
+
+        contract.propose_treasury_config_change(TreasuryConfigChange::SwapFee(1, 30));
+        testing_env!(context.block_index(150).build());
+        contract.commit_treasury_config_change();
+        contract
+            .propose_treasury_config_change(TreasuryConfigChange::TransferFee(usdt_id.clone(), 10));
+        testing_env!(context.block_index(200).build());
+        contract.commit_treasury_config_change();
+
+        let config = contract.treasury.get().unwrap().config;
+        assert_eq!(config.swap_fees.get(&1), Some(&30));
+        assert_eq!(config.transfer_fees.get(&usdt_id), Some(&10));
+
+        // Zero fees are removed.
+        contract.propose_treasury_config_change(TreasuryConfigChange::SwapFee(1, 0));
+        testing_env!(context.block_index(250).build());
+        contract.commit_treasury_config_change();
+        assert!(contract.treasury.get().unwrap().config.swap_fees.is_empty());
+    }
+}
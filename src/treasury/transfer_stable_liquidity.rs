@@ -49,6 +49,8 @@ impl Contract {
             "The token amount must be not zero"
         );
 
+        self.internal_reset_reserve_snapshot(pool.id);
+
         let usn_id = env::current_account_id();
 
         ext_ref_finance::get_deposits(
@@ -1,22 +1,343 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use near_sdk::{BlockHeight, Gas, Timestamp, ONE_NEAR};
 
 use crate::*;
 
+use super::balance_treasury::{LastDecision, PendingLeg};
 use super::cache::IntervalCache;
+use super::config::TreasuryConfig;
+use super::timelock::PendingConfigChange;
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+/// Number of collateral ratios kept in the treasury.
+const MAX_COLLATERAL_HISTORY: u64 = 256;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
 #[serde(crate = "near_sdk::serde")]
 pub struct TreasuryData {
     pub reserve: HashMap<AccountId, U128>,
     pub cache: IntervalCache,
+    pub config: TreasuryConfig,
+    /// Pools being balanced at the moment with block heights the balancing started at.
+    pub in_flight: HashMap<u64, BlockHeight>,
+    /// NEAR in yocto committed to buys of pools being balanced at the moment.
+    pub committed_near: HashMap<u64, U128>,
+    /// Pools being balanced at the moment which swap has failed.
+    pub failed_swaps: HashSet<u64>,
+    pub pending_change: Option<PendingConfigChange>,
+    /// Time the latest trade was started at.
+    pub last_trade_at: Option<Timestamp>,
+    /// Decisions of the latest balancing without execution by pools.
+    pub last_decisions: HashMap<u64, LastDecision>,
+    /// Accounts allowed to call `balance_treasury` besides the owner and guardians.
+    pub relayers: HashSet<AccountId>,
+    /// Shares of split decisions waiting for `execute_treasury_leg` by pools.
+    pub pending_legs: HashMap<u64, PendingLeg>,
+    pub stats: TreasuryStats,
+    /// Number of collateral ratios ever recorded. The latest `MAX_COLLATERAL_HISTORY`
+    /// of them are kept in a ring buffer under their own storage key.
+    pub collateral_snapshots: u64,
+    /// Tokens the contract is known to be registered with to receive withdrawals.
+    pub registered_tokens: HashSet<AccountId>,
+    pub flows: TreasuryFlows,
+    /// Treasury positions in pools observed by the latest audits.
+    pub reserve_snapshots: HashMap<u64, ReserveSnapshot>,
+    /// Gas used by the callbacks of pools being balanced at the moment.
+    pub gas_checkpoints: HashMap<u64, Vec<GasCheckpoint>>,
+    /// USD prices of counter-tokens along with the latest oracle NEAR price.
+    pub counter_prices: HashMap<AccountId, f64>,
+    /// USD price of 1 USN observed by the latest decision, see `usn_usd_rate`.
+    pub usn_usd_rate: Option<f64>,
+    /// USN of sells withheld by the burn ceiling, burned within the ceiling of next sells.
+    pub withheld_usn: Option<U128>,
+}
+
+impl TreasuryData {
+    /// Appends the ratio overwriting the oldest one above `MAX_COLLATERAL_HISTORY`.
+    pub fn record_collateral_ratio(&mut self, timestamp: Timestamp, ratio: f64) {
+        collateral_history().insert(
+            &(self.collateral_snapshots % MAX_COLLATERAL_HISTORY),
+            &CollateralSnapshot { timestamp, ratio },
+        );
+        self.collateral_snapshots += 1;
+    }
+
+    /// Adds USD put into the pool position by a trade to the latest audit snapshot.
+    pub fn record_position_change(&mut self, pool_id: u64, usd: f64) {
+        if let Some(snapshot) = self.reserve_snapshots.get_mut(&pool_id) {
+            snapshot.recorded_usd += usd;
+        }
+    }
+
+    /// Adds the gas used so far by the callback to the balancing of the pool.
+    pub fn record_gas_checkpoint(&mut self, pool_id: u64, checkpoint: &str) {
+        if let Some(checkpoints) = self.gas_checkpoints.get_mut(&pool_id) {
+            checkpoints.push(GasCheckpoint {
+                checkpoint: checkpoint.to_string(),
+                used_gas: env::used_gas(),
+            });
+        }
+    }
+}
+
+fn collateral_history() -> LookupMap<u64, CollateralSnapshot> {
+    LookupMap::new(StorageKey::CollateralHistory)
+}
+
+/// Collateral ratio of the reserve: USD value of NEAR and the counter-token
+/// per circulating USN.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollateralSnapshot {
+    pub timestamp: Timestamp,
+    pub ratio: f64,
+}
+
+/// USD value of the treasury position in a pool, every stable token at $1,
+/// observed by an audit.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReserveSnapshot {
+    pub timestamp: Timestamp,
+    pub value_usd: f64,
+    /// Net USD added to the position by trades since the audit, negative for sells.
+    pub recorded_usd: f64,
 }
 
-impl Default for TreasuryData {
+/// Gas used by a callback of the balancing chain until the checkpoint.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct GasCheckpoint {
+    pub checkpoint: String,
+    pub used_gas: Gas,
+}
+
+/// Lifetime counters of executed treasury decisions.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryStats {
+    pub buys: u64,
+    pub sells: u64,
+    pub do_nothings: u64,
+    /// USD amounts of executed buys and sells.
+    pub bought_usd: f64,
+    pub sold_usd: f64,
+}
+
+/// Lifetime amounts realized by swaps of buys and sells.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryFlows {
+    /// Yocto NEAR swapped into the counter-token by buys.
+    pub near_spent: U128,
+    /// Yocto NEAR received for the counter-token by sells.
+    pub near_received: U128,
+    /// USD of the counter-token received by buys.
+    pub usd_received: f64,
+    /// USD of the counter-token swapped into NEAR by sells.
+    pub usd_spent: f64,
+}
+
+impl Default for TreasuryFlows {
     fn default() -> Self {
         Self {
-            reserve: HashMap::new(),
-            cache: IntervalCache::default(),
+            near_spent: U128(0),
+            near_received: U128(0),
+            usd_received: 0.,
+            usd_spent: 0.,
+        }
+    }
+}
+
+impl TreasuryFlows {
+    pub fn record_buy(&mut self, near: u128, usd: f64) {
+        self.near_spent = U128(self.near_spent.0 + near);
+        self.usd_received += usd;
+    }
+
+    pub fn record_sell(&mut self, usd: f64, near: u128) {
+        self.usd_spent += usd;
+        self.near_received = U128(self.near_received.0 + near);
+    }
+
+    /// Net USD of the swaps with the net NEAR valued at the `exchange_rate`.
+    pub fn pnl(&self, exchange_rate: f64) -> f64 {
+        let net_near = (self.near_received.0 as f64 - self.near_spent.0 as f64) / ONE_NEAR as f64;
+        self.usd_received - self.usd_spent + net_near * exchange_rate
+    }
+}
+
+/// Treasury state of v1.0.6, before the configuration was introduced.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct TreasuryDataV1 {
+    pub reserve: HashMap<AccountId, U128>,
+    pub cache: IntervalCache,
+}
+
+impl From<TreasuryDataV1> for TreasuryData {
+    fn from(treasury: TreasuryDataV1) -> Self {
+        Self {
+            reserve: treasury.reserve,
+            cache: treasury.cache,
+            ..Default::default()
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Allows the account to call `balance_treasury`. Only can be called by owner.
+    pub fn add_treasury_relayer(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        treasury.relayers.insert(account_id);
+        self.treasury.replace(&treasury);
+    }
+
+    /// Only can be called by owner.
+    pub fn remove_treasury_relayer(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        let mut treasury = self.treasury.take().expect("Valid treasury");
+        if !treasury.relayers.remove(&account_id) {
+            env::panic_str(&format!("The relayer '{}' doesn't exist", account_id));
+        }
+        self.treasury.replace(&treasury);
+    }
+
+    pub fn treasury_relayers(&self) -> Vec<AccountId> {
+        let treasury = self.treasury.get().expect("Valid treasury");
+        treasury.relayers.into_iter().collect()
+    }
+
+    /// Returns up to `limit` collateral ratios observed since the `from` timestamp,
+    /// the oldest first.
+    pub fn get_collateral_history(&self, from: Timestamp, limit: u64) -> Vec<CollateralSnapshot> {
+        let recorded = self
+            .treasury
+            .get()
+            .expect("Valid treasury")
+            .collateral_snapshots;
+        let history = collateral_history();
+        (recorded.saturating_sub(MAX_COLLATERAL_HISTORY)..recorded)
+            .map(|idx| history.get(&(idx % MAX_COLLATERAL_HISTORY)).unwrap())
+            .filter(|snapshot| snapshot.timestamp >= from)
+            .take(limit as usize)
+            .collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_owner_or_guardian_or_relayer(&self) {
+        let predecessor_id = env::predecessor_account_id();
+        if predecessor_id != self.owner_id
+            && !self.guardians.contains(&predecessor_id)
+            && !self
+                .treasury
+                .get()
+                .expect("Valid treasury")
+                .relayers
+                .contains(&predecessor_id)
+        {
+            env::panic_str("This method can be called only by owner, guardian or relayer")
         }
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::*;
+
+    fn usn_contract() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id("usn.test.near".parse().unwrap())
+            .predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+        (context, Contract::new(accounts(1)))
+    }
+
+    #[test]
+    fn test_treasury_relayers() {
+        let (mut context, mut contract) = usn_contract();
+        contract.add_treasury_relayer(accounts(2));
+        assert_eq!(contract.treasury_relayers(), vec![accounts(2)]);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.assert_owner_or_guardian_or_relayer();
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.remove_treasury_relayer(accounts(2));
+        assert!(contract.treasury_relayers().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "This method can be called only by owner, guardian or relayer")]
+    fn test_not_relayer() {
+        let (mut context, mut contract) = usn_contract();
+        contract.add_treasury_relayer(accounts(2));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.assert_owner_or_guardian_or_relayer();
+    }
+
+    #[test]
+    #[should_panic(expected = "This method can be called only by owner")]
+    fn test_add_relayer_by_relayer() {
+        let (mut context, mut contract) = usn_contract();
+        contract.add_treasury_relayer(accounts(2));
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.add_treasury_relayer(accounts(3));
+    }
+
+    #[test]
+    fn test_collateral_history() {
+        let (_, mut contract) = usn_contract();
+        let mut treasury = contract.treasury.take().unwrap();
+        for i in 0..MAX_COLLATERAL_HISTORY + 2 {
+            treasury.record_collateral_ratio(i * 1000, 1. + i as f64 / 100.);
+        }
+        contract.treasury.replace(&treasury);
+
+        // The oldest two are evicted.
+        let history = contract.get_collateral_history(0, 3);
+        assert_eq!(
+            history,
+            vec![
+                CollateralSnapshot {
+                    timestamp: 2000,
+                    ratio: 1.02
+                },
+                CollateralSnapshot {
+                    timestamp: 3000,
+                    ratio: 1.03
+                },
+                CollateralSnapshot {
+                    timestamp: 4000,
+                    ratio: 1.04
+                },
+            ]
+        );
+        assert_eq!(
+            contract.get_collateral_history(2000, 1000).len() as u64,
+            MAX_COLLATERAL_HISTORY
+        );
+        assert_eq!(contract.get_collateral_history(257_000, 10).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The relayer 'charlie' doesn't exist")]
+    fn test_remove_missing_relayer() {
+        let (_, mut contract) = usn_contract();
+        contract.remove_treasury_relayer(accounts(2));
+    }
+}